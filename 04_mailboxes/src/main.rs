@@ -63,6 +63,12 @@ fn kernel_entry() -> ! {
 
     // send the message to the GPU and receive answer
     match mbox.call(mbox::channel::PROP) {
+        Err(mbox::MboxError::Timeout) => uart.puts("[i] Unable to query serial: timeout!\n"),
+        Err(mbox::MboxError::ResponseError(code)) => {
+            uart.puts("[i] Unable to query serial: response code 0x");
+            uart.hex(code);
+            uart.puts("\n");
+        }
         Err(_) => uart.puts("[i] Unable to query serial!\n"),
         Ok(()) => {
             uart.puts("[i] My serial number is: 0x");
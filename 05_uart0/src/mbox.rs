@@ -39,6 +39,7 @@ register_bitfields! {
 }
 
 const VIDEOCORE_MBOX: u32 = MMIO_BASE + 0xB880;
+const SYS_TIMER_CLO: u32 = MMIO_BASE + 0x3004;
 
 #[allow(non_snake_case)]
 #[repr(C)]
@@ -51,9 +52,16 @@ pub struct RegisterBlock {
 }
 
 // Custom errors
+#[derive(Debug)]
 pub enum MboxError {
-    ResponseError,
-    UnknownError,
+    /// The firmware did not process the request. Carries the response code,
+    /// e.g. 0x8000_0001 for a malformed buffer.
+    ResponseError(u32),
+    /// The Videocore did not react in time
+    Timeout,
+    /// A response arrived that does not belong to our request
+    ChannelMismatch,
+    InvalidBufferAddress,
 }
 pub type Result<T> = ::core::result::Result<T, MboxError>;
 
@@ -77,18 +85,39 @@ pub mod clock {
 // Responses
 mod response {
     pub const SUCCESS: u32 = 0x8000_0000;
-    pub const ERROR: u32 = 0x8000_0001; // error parsing request buffer (partial response)
 }
 
 pub const REQUEST: u32 = 0;
 
+/// How long to wait for the Videocore before giving up on a call
+pub const DEFAULT_TIMEOUT_US: u32 = 500_000;
+
+/// The message buffer of a mailbox call
+///
+/// The address of the buffer needs to be 16-byte aligned, because its lower
+/// four bits carry the channel number when it is written to the mailbox. This
+/// type guarantees the alignment wherever the buffer is placed.
+#[repr(C, align(16))]
+pub struct MboxBuffer(pub [u32; 36]);
+
+impl ops::Deref for MboxBuffer {
+    type Target = [u32; 36];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl ops::DerefMut for MboxBuffer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
 // Public interface to the mailbox
-#[repr(C)]
-#[repr(align(16))]
 pub struct Mbox {
-    // The address for buffer needs to be 16-byte aligned so that the
-    // Videcore can handle it properly.
-    pub buffer: [u32; 36],
+    pub buffer: MboxBuffer,
+    timeout_us: u32,
 }
 
 /// Deref to RegisterBlock
@@ -111,7 +140,10 @@ impl ops::Deref for Mbox {
 
 impl Mbox {
     pub fn new() -> Mbox {
-        Mbox { buffer: [0; 36] }
+        Mbox {
+            buffer: MboxBuffer([0; 36]),
+            timeout_us: DEFAULT_TIMEOUT_US,
+        }
     }
 
     /// Returns a pointer to the register block
@@ -119,44 +151,95 @@ impl Mbox {
         VIDEOCORE_MBOX as *const _
     }
 
+    /// Set how long `call()` waits for the Videocore
+    pub fn set_timeout(&mut self, timeout_us: u32) {
+        self.timeout_us = timeout_us;
+    }
+
     /// Make a mailbox call. Returns Err(MboxError) on failure, Ok(()) success
-    pub fn call(&self, channel: u32) -> Result<()> {
-        // wait until we can write to the mailbox
-        loop {
-            if !self.STATUS.is_set(STATUS::FULL) {
-                break;
-            }
+    pub fn call(&mut self, channel: u32) -> Result<()> {
+        let buf_addr = self.buffer.as_ptr() as usize;
+        debug_assert_eq!(buf_addr & 0xF, 0, "mailbox buffer is not 16-byte aligned");
 
-            unsafe { asm!("nop" :::: "volatile") };
+        // the Videocore can only address the lower 4 GiB
+        if buf_addr > u32::max_value() as usize {
+            return Err(MboxError::InvalidBufferAddress);
         }
+        let buf_ptr = buf_addr as u32;
 
-        let buf_ptr = self.buffer.as_ptr() as u32;
+        // make sure that all stores to the buffer have completed before the
+        // Videocore is signaled
+        unsafe { asm!("dsb sy" ::: "memory" : "volatile") };
+
+        // wait until we can write to the mailbox
+        wait_until(self.timeout_us, || !self.STATUS.is_set(STATUS::FULL))?;
 
         // write the address of our message to the mailbox with channel identifier
         self.WRITE.set((buf_ptr & !0xF) | (channel & 0xF));
 
         // now wait for the response
-        loop {
-            // is there a response?
-            loop {
-                if !self.STATUS.is_set(STATUS::EMPTY) {
-                    break;
-                }
-
-                unsafe { asm!("nop" :::: "volatile") };
-            }
-
-            let resp: u32 = self.READ.get();
-
-            // is it a response to our message?
-            if ((resp & 0xF) == channel) && ((resp & !0xF) == buf_ptr) {
-                // is it a valid successful response?
-                return match self.buffer[1] {
-                    response::SUCCESS => Ok(()),
-                    response::ERROR => Err(MboxError::ResponseError),
-                    _ => Err(MboxError::UnknownError),
-                };
-            }
+        wait_until(self.timeout_us, || !self.STATUS.is_set(STATUS::EMPTY))?;
+        let resp: u32 = self.READ.get();
+
+        // is it a response to our message? Only one call is ever in flight,
+        // so anything else means that the mailbox is out of sync.
+        if ((resp & 0xF) != channel) || ((resp & !0xF) != buf_ptr) {
+            return Err(MboxError::ChannelMismatch);
+        }
+
+        // do not read the buffer before the response arrived
+        unsafe { asm!("dmb sy" ::: "memory" : "volatile") };
+
+        // is it a valid successful response?
+        match self.buffer[1] {
+            response::SUCCESS => Ok(()),
+            code => Err(MboxError::ResponseError(code)),
         }
     }
 }
+
+/// Spin until `cond` is true. Gives up after `timeout_us` microseconds, as
+/// measured by the BCM System Timer.
+///
+/// QEMU does not emulate the System Timer and reads constant zero from it, so
+/// in that case the ARM generic timer measures the timeout instead.
+fn wait_until<F>(timeout_us: u32, cond: F) -> Result<()>
+where
+    F: Fn() -> bool,
+{
+    let clo = SYS_TIMER_CLO as *const u32;
+    let use_clo = unsafe { core::ptr::read_volatile(clo) } != 0;
+    let now_us = || {
+        if use_clo {
+            unsafe { core::ptr::read_volatile(clo) }
+        } else {
+            generic_timer_us()
+        }
+    };
+    let start = now_us();
+
+    while !cond() {
+        if now_us().wrapping_sub(start) > timeout_us {
+            return Err(MboxError::Timeout);
+        }
+
+        unsafe { asm!("nop" :::: "volatile") };
+    }
+
+    Ok(())
+}
+
+/// Microseconds counted by the ARM generic timer, truncated to 32 bits like
+/// the lower word of the System Timer
+fn generic_timer_us() -> u32 {
+    let cnt: u64;
+    let frq: u64;
+    unsafe {
+        asm!("mrs $0, CNTPCT_EL0" : "=r"(cnt) ::: "volatile");
+        asm!("mrs $0, CNTFRQ_EL0" : "=r"(frq) ::: "volatile");
+    }
+
+    // split into seconds and remainder, so that the multiplication can not
+    // overflow
+    ((cnt / frq) * 1_000_000 + (cnt % frq) * 1_000_000 / frq) as u32
+}
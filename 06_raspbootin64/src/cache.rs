@@ -0,0 +1,61 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+//! Cache maintenance for handing over freshly written code.
+//!
+//! The received kernel is written through the data cache, but fetched by the
+//! instruction side. Before jumping to it, the data must be in memory, and no
+//! stale instructions may be left in the instruction cache.
+
+/// The size of the smallest data cache line of all caches in the system.
+fn dcache_line_size() -> usize {
+    let ctr: u64;
+    unsafe { asm!("mrs $0, CTR_EL0" : "=r"(ctr) ::: "volatile") };
+
+    // CTR_EL0.DminLine is the log2 of the number of words in a line.
+    4 << ((ctr >> 16) & 0xF)
+}
+
+/// Write dirty lines of `[addr, addr + len)` back to memory and discard them.
+pub fn clean_invalidate_dcache_range(addr: usize, len: usize) {
+    let line = dcache_line_size();
+    let end = addr + len;
+
+    let mut line_addr = addr & !(line - 1);
+    while line_addr < end {
+        unsafe { asm!("dc civac, $0" :: "r"(line_addr) :: "volatile") };
+        line_addr += line;
+    }
+
+    unsafe { asm!("dsb sy" :::: "volatile") };
+}
+
+/// Discard the whole instruction cache.
+pub fn invalidate_icache_all() {
+    unsafe {
+        asm!("ic iallu
+              dsb sy
+              isb"
+             :::: "volatile")
+    };
+}
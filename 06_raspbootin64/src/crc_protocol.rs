@@ -0,0 +1,133 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+//! raspbootcom's raw protocol, extended by a size echo and a CRC-32.
+//!
+//! The host starts with `MAGIC`, followed by the little endian size and
+//! CRC-32 of the kernel. The loader echoes the size, followed by `OK`, or by
+//! `SE` if the kernel does not fit. After the data, the loader answers `OK` if
+//! the checksum matches, or `ER`, after which the host may start over with
+//! `MAGIC`, up to `MAX_ATTEMPTS` times.
+
+use crate::{timer::Deadline, uart};
+
+/// Read as a size of the legacy protocol, this would be way beyond the
+/// loader's limit, so it can not be mistaken for one.
+pub const MAGIC: [u8; 4] = *b"CRCK";
+
+/// Transfers per handshake, including the first one.
+const MAX_ATTEMPTS: usize = 3;
+
+/// How long the host may pause within a transfer.
+const BYTE_TIMEOUT_US: u64 = 1_000_000;
+
+pub enum CrcError {
+    /// The kernel does not fit into the memory below `max_size`.
+    TooBig,
+    /// The host went silent in the middle of the transfer.
+    Timeout,
+    /// A retry did not start with `MAGIC`.
+    OutOfSync,
+    /// The checksum did not match for `MAX_ATTEMPTS` times in a row.
+    Mismatch,
+}
+
+/// CRC-32 (IEEE 802.3): reflected polynomial 0xEDB8_8320, all bits set
+/// initially and inverted at the end, like zlib's `crc32()`.
+///
+/// Computed bitwise instead of with a table to keep the loader small.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc: u32 = !0;
+
+    for &byte in data {
+        crc ^= u32::from(byte);
+
+        for _ in 0..8 {
+            if crc & 1 != 0 {
+                crc = (crc >> 1) ^ 0xEDB8_8320;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+
+    !crc
+}
+
+fn recv_u32(uart: &uart::Uart) -> Result<u32, CrcError> {
+    let mut bytes = [0; 4];
+
+    if uart.recv_exact_until(&mut bytes, Deadline::from_now(BYTE_TIMEOUT_US)) != 4 {
+        return Err(CrcError::Timeout);
+    }
+
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn send_bytes(uart: &uart::Uart, bytes: &[u8]) {
+    for &b in bytes {
+        uart.send(b as char);
+    }
+}
+
+/// Receive a kernel to `dest`, after `MAGIC` has already been read.
+///
+/// Returns the size of the kernel.
+pub fn receive(uart: &uart::Uart, dest: *mut u8, max_size: usize) -> Result<usize, CrcError> {
+    for attempt in 0..MAX_ATTEMPTS {
+        if attempt > 0 {
+            let mut magic = [0; 4];
+            if uart.recv_exact_until(&mut magic, Deadline::from_now(BYTE_TIMEOUT_US)) != 4 {
+                return Err(CrcError::Timeout);
+            }
+            if magic != MAGIC {
+                return Err(CrcError::OutOfSync);
+            }
+        }
+
+        let size = recv_u32(uart)? as usize;
+        let expected_crc = recv_u32(uart)?;
+
+        // Let the host check that the size arrived intact
+        send_bytes(uart, &(size as u32).to_le_bytes());
+        if size > max_size {
+            send_bytes(uart, b"SE");
+            return Err(CrcError::TooBig);
+        }
+        send_bytes(uart, b"OK");
+
+        let kernel = unsafe { core::slice::from_raw_parts_mut(dest, size) };
+        for byte in kernel.iter_mut() {
+            let deadline = Deadline::from_now(BYTE_TIMEOUT_US);
+            *byte = uart.getc_until(deadline).ok_or(CrcError::Timeout)?;
+        }
+
+        if crc32(kernel) == expected_crc {
+            send_bytes(uart, b"OK");
+            return Ok(size);
+        }
+        send_bytes(uart, b"ER");
+    }
+
+    Err(CrcError::Mismatch)
+}
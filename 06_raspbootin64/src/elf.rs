@@ -0,0 +1,255 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Minimal ELF64 loader.
+//!
+//! Copies the `PT_LOAD` segments of an AArch64 executable to their physical
+//! addresses and zeroes their BSS tails. Everything is validated before the
+//! first byte is copied, so a malformed file never leaves a half loaded
+//! kernel behind.
+
+use crate::cache;
+use core::ops::Range;
+
+const MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const TYPE_EXEC: u16 = 2;
+const MACHINE_AARCH64: u16 = 183;
+const PT_LOAD: u32 = 1;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+
+pub enum ElfError {
+    /// Not an ELF file, or not a 64-bit little endian one.
+    BadMagic,
+    /// A valid ELF file, but not an AArch64 `ET_EXEC`.
+    NotAarch64Exec,
+    /// A header or segment extends beyond the end of the file.
+    Truncated,
+    /// A segment would be copied to memory that does not exist or is in use
+    /// by the loader.
+    SegmentOutsideRam,
+    /// Two segments would occupy the same memory.
+    SegmentsOverlap,
+    /// `e_entry` does not point into any of the segments.
+    EntryNotLoaded,
+    /// The mailbox did not tell how much RAM there is.
+    UnknownRam,
+}
+
+impl ElfError {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            ElfError::BadMagic => "not a little endian ELF64 file",
+            ElfError::NotAarch64Exec => "not an AArch64 executable",
+            ElfError::Truncated => "headers or segments beyond the end of the file",
+            ElfError::SegmentOutsideRam => "segment outside of the usable RAM",
+            ElfError::SegmentsOverlap => "overlapping segments",
+            ElfError::EntryNotLoaded => "entry point outside of the loaded segments",
+            ElfError::UnknownRam => "could not query the usable RAM",
+        }
+    }
+}
+
+pub type Result<T> = ::core::result::Result<T, ElfError>;
+
+/// The parts of a program header that the loader needs.
+struct Segment {
+    offset: usize,
+    vaddr: usize,
+    paddr: usize,
+    filesz: usize,
+    memsz: usize,
+}
+
+impl Segment {
+    fn dest(&self) -> Range<usize> {
+        self.paddr..self.paddr + self.memsz
+    }
+}
+
+fn field(file: &[u8], at: usize, len: usize) -> Result<&[u8]> {
+    at.checked_add(len)
+        .and_then(|end| file.get(at..end))
+        .ok_or(ElfError::Truncated)
+}
+
+fn read_u16(file: &[u8], at: usize) -> Result<u16> {
+    let mut raw = [0; 2];
+    raw.copy_from_slice(field(file, at, 2)?);
+    Ok(u16::from_le_bytes(raw))
+}
+
+fn read_u32(file: &[u8], at: usize) -> Result<u32> {
+    let mut raw = [0; 4];
+    raw.copy_from_slice(field(file, at, 4)?);
+    Ok(u32::from_le_bytes(raw))
+}
+
+fn read_usize(file: &[u8], at: usize) -> Result<usize> {
+    let mut raw = [0; 8];
+    raw.copy_from_slice(field(file, at, 8)?);
+    Ok(u64::from_le_bytes(raw) as usize)
+}
+
+pub fn is_elf(file: &[u8]) -> bool {
+    file.starts_with(&MAGIC)
+}
+
+/// A parsed and validated ELF file.
+pub struct Elf<'a> {
+    file: &'a [u8],
+    entry: usize,
+    phoff: usize,
+    phentsize: usize,
+    phnum: usize,
+}
+
+impl<'a> Elf<'a> {
+    /// Check the file header and all `PT_LOAD` segments. Every byte of every
+    /// segment must end up in `ram`.
+    pub fn parse(file: &'a [u8], ram: Range<usize>) -> Result<Elf<'a>> {
+        if file.len() < EHDR_SIZE
+            || !is_elf(file)
+            || file[4] != CLASS_64
+            || file[5] != DATA_LITTLE_ENDIAN
+        {
+            return Err(ElfError::BadMagic);
+        }
+
+        if read_u16(file, 16)? != TYPE_EXEC || read_u16(file, 18)? != MACHINE_AARCH64 {
+            return Err(ElfError::NotAarch64Exec);
+        }
+
+        let elf = Elf {
+            file,
+            entry: read_usize(file, 24)?,
+            phoff: read_usize(file, 32)?,
+            phentsize: read_u16(file, 54)? as usize,
+            phnum: read_u16(file, 56)? as usize,
+        };
+
+        if elf.phentsize < PHDR_SIZE {
+            return Err(ElfError::Truncated);
+        }
+
+        for i in 0..elf.phnum {
+            let seg = match elf.segment(i)? {
+                Some(seg) => seg,
+                None => continue,
+            };
+
+            match seg.offset.checked_add(seg.filesz) {
+                Some(end) if end <= file.len() && seg.filesz <= seg.memsz => (),
+                _ => return Err(ElfError::Truncated),
+            }
+
+            match seg.paddr.checked_add(seg.memsz) {
+                Some(end) if seg.paddr >= ram.start && end <= ram.end => (),
+                _ => return Err(ElfError::SegmentOutsideRam),
+            }
+
+            for j in 0..i {
+                if let Some(other) = elf.segment(j)? {
+                    let (a, b) = (seg.dest(), other.dest());
+                    if a.start < b.end && b.start < a.end {
+                        return Err(ElfError::SegmentsOverlap);
+                    }
+                }
+            }
+        }
+
+        elf.entry_paddr()?;
+
+        Ok(elf)
+    }
+
+    /// The `PT_LOAD` segment at program header `index`, if it is one and it
+    /// occupies any memory.
+    fn segment(&self, index: usize) -> Result<Option<Segment>> {
+        let at = index
+            .checked_mul(self.phentsize)
+            .and_then(|off| off.checked_add(self.phoff))
+            .ok_or(ElfError::Truncated)?;
+        field(self.file, at, PHDR_SIZE)?;
+
+        if read_u32(self.file, at)? != PT_LOAD {
+            return Ok(None);
+        }
+
+        let seg = Segment {
+            offset: read_usize(self.file, at + 8)?,
+            vaddr: read_usize(self.file, at + 16)?,
+            paddr: read_usize(self.file, at + 24)?,
+            filesz: read_usize(self.file, at + 32)?,
+            memsz: read_usize(self.file, at + 40)?,
+        };
+
+        if seg.memsz == 0 {
+            return Ok(None);
+        }
+        Ok(Some(seg))
+    }
+
+    /// `e_entry` is a virtual address. Kernels that are linked to a different
+    /// virtual address than they are loaded to, like the higher half kernel of
+    /// the later tutorials, must still be entered at the physical one, since
+    /// they are started with the MMU off.
+    fn entry_paddr(&self) -> Result<usize> {
+        for i in 0..self.phnum {
+            if let Some(seg) = self.segment(i)? {
+                if self.entry >= seg.vaddr && self.entry - seg.vaddr < seg.memsz {
+                    return Ok(seg.paddr + (self.entry - seg.vaddr));
+                }
+            }
+        }
+
+        Err(ElfError::EntryNotLoaded)
+    }
+
+    /// Copy all segments to their physical addresses and zero their BSS
+    /// tails. Returns the physical address of the entry point.
+    ///
+    /// The file itself must not be in the way of any segment.
+    pub fn load(&self) -> Result<usize> {
+        for i in 0..self.phnum {
+            if let Some(seg) = self.segment(i)? {
+                let src = &self.file[seg.offset..seg.offset + seg.filesz];
+                let dest = seg.paddr as *mut u8;
+
+                unsafe {
+                    core::ptr::copy_nonoverlapping(src.as_ptr(), dest, seg.filesz);
+                    core::ptr::write_bytes(dest.add(seg.filesz), 0, seg.memsz - seg.filesz);
+                }
+
+                // The segment was written as data, but may be executed
+                cache::clean_invalidate_dcache_range(seg.paddr, seg.memsz);
+            }
+        }
+
+        self.entry_paddr()
+    }
+}
@@ -28,9 +28,170 @@
 
 const MMIO_BASE: u32 = 0x3F00_0000;
 
+mod cache;
+mod crc_protocol;
+mod elf;
 mod gpio;
 mod mbox;
+mod timer;
 mod uart;
+mod xmodem;
+
+use core::{
+    ops::Range,
+    sync::atomic::{compiler_fence, Ordering},
+};
+
+/// Where `boot_cores.S` relocates the loader to, see `link.ld`. It sits right
+/// below the GPU's memory, which starts at 0x3C00_0000 with the default split
+/// of `gpu_mem=64` on a 1 GiB board. A larger `gpu_mem` in `config.txt` puts
+/// the GPU's memory over the loader, so this address and the one in `link.ld`
+/// must then be moved down together.
+const LOADER_ADDR: usize = 0x3B00_0000;
+
+/// The loader's stack grows down from `LOADER_ADDR`.
+const STACK_SIZE: usize = 0x10_000;
+
+/// The kernel is loaded to 0x80_000 and must not run into our stack.
+const KERNEL_MAX_SIZE: usize = LOADER_ADDR - STACK_SIZE - 0x80_000;
+
+/// How long to wait for an answer before asking the host again.
+const HANDSHAKE_TIMEOUT_US: u64 = 1_000_000;
+
+/// How long the host may pause while sending a raw kernel.
+const RAW_TIMEOUT_US: u64 = 1_000_000;
+
+fn puts(uart: &uart::Uart, string: &str) {
+    for c in string.chars() {
+        uart.send(c);
+    }
+}
+
+/// Receive a kernel to `kernel_addr` with whatever protocol the host speaks,
+/// and return its size. Failed transfers are retried.
+fn receive_kernel(uart: &uart::Uart, kernel_addr: *mut u8) -> usize {
+    loop {
+        // Ask both raspbootcom (three breaks) and an XMODEM-CRC sender ('C')
+        // for the kernel, and repeat until one of them answers.
+        let first = loop {
+            uart.send(3 as char);
+            uart.send(3 as char);
+            uart.send(3 as char);
+            uart.send(xmodem::CRC_MODE as char);
+
+            if let Some(c) = uart.getc_until(timer::Deadline::from_now(HANDSHAKE_TIMEOUT_US)) {
+                break c;
+            }
+        };
+
+        if first == xmodem::SOH {
+            match xmodem::receive(uart, kernel_addr, KERNEL_MAX_SIZE) {
+                Ok(size) => return size,
+                Err(xmodem::XmodemError::Timeout) => {
+                    puts(uart, "TIMEOUT\r\n");
+                    continue;
+                }
+                Err(_) => {
+                    puts(uart, "ERR\r\n");
+                    continue;
+                }
+            }
+        }
+
+        // Not XMODEM, so this is either the CRC protocol's magic, or the first
+        // byte of the kernel's size
+        let mut size_bytes = [first, 0, 0, 0];
+        let deadline = timer::Deadline::from_now(RAW_TIMEOUT_US);
+        if uart.recv_exact_until(&mut size_bytes[1..], deadline) != 3 {
+            puts(uart, "TIMEOUT\r\n");
+            continue;
+        }
+
+        if size_bytes == crc_protocol::MAGIC {
+            match crc_protocol::receive(uart, kernel_addr, KERNEL_MAX_SIZE) {
+                Ok(size) => return size,
+                Err(crc_protocol::CrcError::Timeout) => {
+                    puts(uart, "TIMEOUT\r\n");
+                    continue;
+                }
+                Err(_) => {
+                    puts(uart, "ERR\r\n");
+                    continue;
+                }
+            }
+        }
+
+        // The legacy protocol, without any checks but the size
+        let size = u32::from_le_bytes(size_bytes);
+
+        // raspbootcom gives up on a size error, like the CRC protocol's host
+        if size as usize > KERNEL_MAX_SIZE {
+            uart.send('S');
+            uart.send('E');
+            continue;
+        }
+        uart.send('O');
+        uart.send('K');
+
+        // Read the kernel byte by byte
+        let kernel = unsafe { core::slice::from_raw_parts_mut(kernel_addr, size as usize) };
+        let mut stalled = false;
+        for byte in kernel.iter_mut() {
+            match uart.getc_until(timer::Deadline::from_now(RAW_TIMEOUT_US)) {
+                Some(c) => *byte = c,
+                None => {
+                    stalled = true;
+                    break;
+                }
+            }
+        }
+
+        if stalled {
+            puts(uart, "TIMEOUT\r\n");
+            continue;
+        }
+
+        return size as usize;
+    }
+}
+
+/// The RAM that the firmware leaves to the ARM cores, as reported by the
+/// mailbox.
+fn arm_memory(mbox: &mut mbox::Mbox) -> mbox::Result<Range<usize>> {
+    mbox.buffer[0] = 8 * 4;
+    mbox.buffer[1] = mbox::REQUEST;
+    mbox.buffer[2] = mbox::tag::GETARMMEMORY;
+    mbox.buffer[3] = 8;
+    mbox.buffer[4] = 0;
+    mbox.buffer[5] = 0; // base address
+    mbox.buffer[6] = 0; // size in bytes
+    mbox.buffer[7] = mbox::tag::LAST;
+
+    compiler_fence(Ordering::Release);
+    mbox.call(mbox::channel::PROP)?;
+
+    let base = mbox.buffer[5] as usize;
+    Ok(base..base + mbox.buffer[6] as usize)
+}
+
+/// Load the ELF `file` that was received to 0x80_000, and return the physical
+/// address of its entry point.
+///
+/// The segments will most likely be loaded to 0x80_000 as well, so the file is
+/// first moved up, right below our stack. The segments may then use any RAM
+/// below the moved file.
+fn load_elf(mbox: &mut mbox::Mbox, file: &[u8]) -> elf::Result<usize> {
+    let ram = arm_memory(mbox).map_err(|_| elf::ElfError::UnknownRam)?;
+
+    let staging = (LOADER_ADDR - STACK_SIZE - file.len()) & !0xFFF;
+    let staged = unsafe {
+        core::ptr::copy(file.as_ptr(), staging as *mut u8, file.len());
+        core::slice::from_raw_parts(staging as *const u8, file.len())
+    };
+
+    let usable = ram.start..ram.end.min(staging);
+    elf::Elf::parse(staged, usable)?.load()
+}
 
 fn kernel_entry() -> ! {
     let mut mbox = mbox::Mbox::new();
@@ -42,35 +203,33 @@ fn kernel_entry() -> ! {
     }
 
     // Say hello
-    for c in "RBIN64\r\n".chars() {
-        uart.send(c);
-    }
+    puts(&uart, "RBIN64\r\n");
 
-    // Notify raspbootcom to send the kernel
-    uart.send(3 as char);
-    uart.send(3 as char);
-    uart.send(3 as char);
+    let kernel_addr: *mut u8 = 0x80_000 as *mut u8;
 
-    // Read the kernel's size
-    let mut size: u32 = u32::from(uart.getc());
-    size |= u32::from(uart.getc()) << 8;
-    size |= u32::from(uart.getc()) << 16;
-    size |= u32::from(uart.getc()) << 24;
+    let entry = loop {
+        let kernel_size = receive_kernel(&uart, kernel_addr);
+        let file = unsafe { core::slice::from_raw_parts(kernel_addr, kernel_size) };
 
-    // For now, blindly trust it's not too big
-    uart.send('O');
-    uart.send('K');
+        if !elf::is_elf(file) {
+            // The kernel was written as data, but is about to be executed
+            cache::clean_invalidate_dcache_range(kernel_addr as usize, kernel_size);
+            break kernel_addr as usize;
+        }
 
-    let kernel_addr: *mut u8 = 0x80_000 as *mut u8;
-    unsafe {
-        // Read the kernel byte by byte
-        for i in 0..size {
-            *kernel_addr.offset(i as isize) = uart.getc();
+        match load_elf(&mut mbox, file) {
+            Ok(entry) => break entry,
+            Err(e) => {
+                puts(&uart, "ELF: ");
+                puts(&uart, e.reason());
+                puts(&uart, "\r\n");
+            }
         }
-    }
+    };
+    cache::invalidate_icache_all();
 
-    // Use black magic to get a function pointer to 0x80_000
-    let kernel: extern "C" fn() -> ! = unsafe { core::mem::transmute(kernel_addr as *const ()) };
+    // Use black magic to get a function pointer to the entry point
+    let kernel: extern "C" fn() -> ! = unsafe { core::mem::transmute(entry as *const ()) };
 
     // Jump to loaded kernel and never return!
     kernel()
@@ -0,0 +1,104 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use super::MMIO_BASE;
+
+const SYS_TIMER_CLO: *const u32 = (MMIO_BASE + 0x3004) as *const u32;
+const SYS_TIMER_CHI: *const u32 = (MMIO_BASE + 0x3008) as *const u32;
+
+const MICROS_PER_SEC: u64 = 1_000_000;
+
+/// Microseconds since the SoC came out of reset
+///
+/// Read from the BCM System Timer. QEMU does not emulate it and reads constant
+/// zero from it, so in that case the ARM generic timer is used instead.
+pub fn now_us() -> u64 {
+    let (mut hi, mut lo) = unsafe {
+        (
+            core::ptr::read_volatile(SYS_TIMER_CHI),
+            core::ptr::read_volatile(SYS_TIMER_CLO),
+        )
+    };
+
+    // repeat if the high word changed during the read
+    if hi != unsafe { core::ptr::read_volatile(SYS_TIMER_CHI) } {
+        hi = unsafe { core::ptr::read_volatile(SYS_TIMER_CHI) };
+        lo = unsafe { core::ptr::read_volatile(SYS_TIMER_CLO) };
+    }
+
+    let st = (u64::from(hi) << 32) | u64::from(lo);
+    if st != 0 {
+        return st;
+    }
+
+    let cnt: u64;
+    let frq: u64;
+    unsafe {
+        asm!("mrs $0, CNTPCT_EL0" : "=r"(cnt) ::: "volatile");
+        asm!("mrs $0, CNTFRQ_EL0" : "=r"(frq) ::: "volatile");
+    }
+
+    // split into seconds and remainder, so that the multiplication can not
+    // overflow
+    (cnt / frq) * MICROS_PER_SEC + (cnt % frq) * MICROS_PER_SEC / frq
+}
+
+/// The farthest that a deadline can be ahead. Wrapping arithmetic can only
+/// tell which of two counter values is the later one while they are less than
+/// half of the counter's range apart.
+const MAX_DEADLINE_US: u64 = i64::max_value() as u64;
+
+/// A point in time by which something must be done, on the clock of
+/// `now_us()`
+///
+/// Unlike a timeout, one deadline can bound several waits in a row.
+#[derive(Copy, Clone)]
+pub struct Deadline(u64);
+
+impl Deadline {
+    /// The deadline `us` microseconds from now
+    pub fn from_now(us: u64) -> Deadline {
+        Deadline(now_us().wrapping_add(us.min(MAX_DEADLINE_US)))
+    }
+
+    /// Whether the deadline has passed
+    ///
+    /// Computed with wrapping arithmetic, so the result stays correct if the
+    /// counter rolls over in between.
+    pub fn expired(&self) -> bool {
+        now_us().wrapping_sub(self.0) as i64 >= 0
+    }
+
+    /// Microseconds until the deadline, zero once it has passed
+    #[allow(dead_code)]
+    pub fn remaining_us(&self) -> u64 {
+        let now = now_us();
+
+        if now.wrapping_sub(self.0) as i64 >= 0 {
+            0
+        } else {
+            self.0.wrapping_sub(now)
+        }
+    }
+}
@@ -25,6 +25,7 @@
 use super::MMIO_BASE;
 use crate::gpio;
 use crate::mbox;
+use crate::timer;
 use core::{
     ops,
     sync::atomic::{compiler_fence, Ordering},
@@ -243,4 +244,44 @@ impl Uart {
         // read it and return
         self.DR.get() as u8
     }
+
+    /// Receive a character if one is available, without waiting
+    pub fn try_getc(&self) -> Option<u8> {
+        if self.FR.is_set(FR::RXFE) {
+            return None;
+        }
+
+        Some(self.DR.get() as u8)
+    }
+
+    /// Receive a character, or None if nothing arrived before `deadline`
+    pub fn getc_until(&self, deadline: timer::Deadline) -> Option<u8> {
+        loop {
+            if let Some(c) = self.try_getc() {
+                return Some(c);
+            }
+
+            if deadline.expired() {
+                return None;
+            }
+        }
+    }
+
+    /// Fill `buf`, giving up once `deadline` passed
+    ///
+    /// Returns how many bytes were actually received.
+    pub fn recv_exact_until(&self, buf: &mut [u8], deadline: timer::Deadline) -> usize {
+        let mut received = 0;
+
+        while received < buf.len() {
+            if let Some(c) = self.try_getc() {
+                buf[received] = c;
+                received += 1;
+            } else if deadline.expired() {
+                break;
+            }
+        }
+
+        received
+    }
 }
@@ -0,0 +1,146 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! XMODEM-CRC receiver.
+//!
+//! 128 byte blocks, each protected by a CRC-16, with NAK based
+//! retransmission. Works with stock senders like `sx` from lrzsz.
+
+use crate::{timer::Deadline, uart};
+
+pub const SOH: u8 = 0x01;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+
+/// Sent by the receiver to request a CRC-16 instead of a checksum transfer.
+pub const CRC_MODE: u8 = b'C';
+
+const BLOCK_SIZE: usize = 128;
+
+pub enum XmodemError {
+    /// The sender cancelled, or sent a block that is neither the expected
+    /// nor a repeated one.
+    Aborted,
+    /// The transfer ended without a single block.
+    Empty,
+    /// The image does not fit into the memory below `max_size`.
+    TooBig,
+    /// The sender went silent in the middle of the transfer.
+    Timeout,
+}
+
+/// How long the sender may pause within a transfer. Senders wait up to 10
+/// seconds for an ACK/NAK themselves.
+const BYTE_TIMEOUT_US: u64 = 10_000_000;
+
+fn getc(uart: &uart::Uart) -> Result<u8, XmodemError> {
+    uart.getc_until(Deadline::from_now(BYTE_TIMEOUT_US)).ok_or(XmodemError::Timeout)
+}
+
+/// CRC-16/XMODEM: polynomial 0x1021, initial value 0
+///
+/// Computed bitwise instead of with a table to keep the loader small.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+
+    for &byte in data {
+        crc ^= u16::from(byte) << 8;
+
+        for _ in 0..8 {
+            if crc & 0x8000 != 0 {
+                crc = (crc << 1) ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+
+    crc
+}
+
+/// Receive a file to `dest`, after the first SOH has already been read.
+///
+/// Returns the number of bytes received, which is a multiple of 128 because
+/// the sender pads the last block.
+pub fn receive(uart: &uart::Uart, dest: *mut u8, max_size: usize) -> Result<usize, XmodemError> {
+    let mut expected_seq: u8 = 1;
+    let mut total: usize = 0;
+    let mut header = SOH;
+
+    loop {
+        match header {
+            SOH => {
+                let seq = getc(uart)?;
+                let seq_inv = getc(uart)?;
+
+                if total + BLOCK_SIZE > max_size {
+                    uart.send(CAN as char);
+                    return Err(XmodemError::TooBig);
+                }
+
+                // Receive in place. Until the block is ACKed, `total` does not
+                // advance, so a bad block is simply overwritten by its repetition.
+                let block = unsafe { core::slice::from_raw_parts_mut(dest.add(total), BLOCK_SIZE) };
+                if uart.recv_exact_until(block, Deadline::from_now(BYTE_TIMEOUT_US)) != BLOCK_SIZE {
+                    return Err(XmodemError::Timeout);
+                }
+
+                let crc = (u16::from(getc(uart)?) << 8) | u16::from(getc(uart)?);
+
+                if seq != !seq_inv || crc != crc16(block) {
+                    uart.send(NAK as char);
+                } else if seq == expected_seq.wrapping_sub(1) {
+                    // Our previous ACK got lost, and the sender repeated the
+                    // block that we already have.
+                    uart.send(ACK as char);
+                } else if seq != expected_seq {
+                    uart.send(CAN as char);
+                    return Err(XmodemError::Aborted);
+                } else {
+                    total += BLOCK_SIZE;
+                    expected_seq = expected_seq.wrapping_add(1);
+                    uart.send(ACK as char);
+                }
+            }
+
+            EOT => {
+                uart.send(ACK as char);
+
+                if total == 0 {
+                    return Err(XmodemError::Empty);
+                }
+                return Ok(total);
+            }
+
+            CAN => return Err(XmodemError::Aborted),
+
+            // Line noise between blocks
+            _ => uart.send(NAK as char),
+        }
+
+        header = getc(uart)?;
+    }
+}
@@ -32,6 +32,29 @@ mod mbox;
 mod rand;
 mod uart;
 
+/// Sort some dice throws into buckets and return the chi-square statistic of
+/// the bucket counts. A good RNG stays below 21 for all but 0.1% of the runs.
+///
+/// The dice come from a seeded PRNG, so that a failing run can be repeated.
+fn chi_square(rng: &mut rand::Xoshiro256StarStar) -> u32 {
+    const BUCKETS: usize = 6;
+    const EXPECTED: u32 = 1000;
+
+    let mut counts = [0u32; BUCKETS];
+    for _ in 0..(EXPECTED as usize * BUCKETS) {
+        counts[rng.rand_range(0..BUCKETS as u32) as usize] += 1;
+    }
+
+    counts
+        .iter()
+        .map(|&c| {
+            let d = if c > EXPECTED { c - EXPECTED } else { EXPECTED - c };
+            d * d
+        })
+        .sum::<u32>()
+        / EXPECTED
+}
+
 fn kernel_entry() -> ! {
     let mut mbox = mbox::Mbox::new();
     let uart = uart::Uart::new();
@@ -50,18 +73,55 @@ fn kernel_entry() -> ! {
 
     // set up random number generator
     let rng = rand::Rng::new();
-    rng.init();
-
-    uart.puts("[2] RNG ready.\n\n");
+    match rng.init(&mut mbox) {
+        rand::Source::Hardware => uart.puts("[2] RNG ready.\n"),
+        rand::Source::Fallback => {
+            uart.puts("[2] No working hardware RNG, falling back to a PRNG!\n");
+            uart.puts("[i] Its numbers are predictable, do not use them for secrets.\n");
+        }
+    }
 
-    uart.puts("Press any key to generate random numbers.\n");
+    uart.puts("\nPress any key to generate random numbers, or 's' for a statistics check.\n");
+    uart.puts("'r' repeats the last statistics check with the same seed.\n");
 
-    // echo everything back
+    let mut nonce = [0u8; 16];
+    let mut last_seed = None;
     loop {
-        uart.getc();
+        let c = uart.getc();
+        if c == 's' || c == 'r' {
+            let mut prng = match last_seed {
+                Some(seed) if c == 'r' => rand::Xoshiro256StarStar::from_seed(seed),
+                _ => rng.seeded_from_hw(&mut mbox),
+            };
+            last_seed = Some(prng.seed());
+
+            uart.puts("seed = 0x");
+            uart.hex((prng.seed() >> 32) as u32);
+            uart.hex(prng.seed() as u32);
+
+            let x = chi_square(&mut prng);
+
+            uart.puts("  chi-square = 0x");
+            uart.hex(x);
+            uart.puts(if x < 21 { " (pass)\n" } else { " (FAIL)\n" });
+            continue;
+        }
 
         uart.puts("0x");
-        uart.hex(rng.rand(0, 4_294_967_295));
+        uart.hex(rng.next_u32());
+        uart.puts("  dice: ");
+        uart.send((b'1' + rng.rand_range(0..6) as u8) as char);
+
+        let r = rng.next_u64();
+        uart.puts("  u64: 0x");
+        uart.hex((r >> 32) as u32);
+        uart.hex(r as u32);
+
+        rng.fill_bytes(&mut nonce);
+        uart.puts("  nonce: 0x");
+        for word in nonce.chunks(4) {
+            uart.hex(u32::from_be_bytes([word[0], word[1], word[2], word[3]]));
+        }
         uart.puts("\n");
     }
 }
@@ -23,8 +23,10 @@
  */
 
 use super::MMIO_BASE;
-use core::ops;
-use cortex_a::asm;
+use crate::mbox;
+use core::ops::{self, Range};
+use core::sync::atomic::{compiler_fence, AtomicBool, AtomicU64, Ordering};
+use cortex_a::{asm, regs::*};
 use register::{mmio::*, register_bitfields};
 
 register_bitfields! {
@@ -48,6 +50,30 @@ register_bitfields! {
 const RNG_BASE: u32 = MMIO_BASE + 0x104_000;
 const RNG_WARMUP_COUNT: u32 = 0x40_000;
 
+// How long to wait for a word from the RNG during init. The warm-up takes
+// about a millisecond on real hardware.
+const RNG_INIT_TIMEOUT_US: u64 = 100_000;
+
+// How many words the health check at init looks at
+const HEALTH_CHECK_WORDS: usize = 16;
+
+// Set by init() once the hardware RNG passed its health check
+static HW_AVAILABLE: AtomicBool = AtomicBool::new(false);
+
+// State of the fallback PRNG. Must never be zero.
+static FALLBACK_STATE: AtomicU64 = AtomicU64::new(0x9E37_79B9_7F4A_7C15);
+
+/// Where the numbers handed out by `Rng` come from
+#[derive(Copy, Clone, PartialEq)]
+pub enum Source {
+    /// The hardware RNG, which passed its health check
+    Hardware,
+    /// A xorshift PRNG seeded from the CPU counter and the board serial,
+    /// because the hardware RNG is missing (e.g. on QEMU) or broken. Good
+    /// enough for dice, but NOT for keys or nonces.
+    Fallback,
+}
+
 #[allow(non_snake_case)]
 #[repr(C)]
 pub struct RegisterBlock {
@@ -80,28 +106,314 @@ impl Rng {
     }
 
     /// Initialize the RNG
-    pub fn init(&self) {
+    ///
+    /// Waits for the warm-up to finish and checks that the RNG delivers
+    /// numbers that are not stuck. If it doesn't, the fallback PRNG is seeded
+    /// instead, which is queried from then on.
+    pub fn init(&self, mbox: &mut mbox::Mbox) -> Source {
         // Disable interrupts
         self.INT_MASK.modify(INT_MASK::INT_OFF::True);
 
         // Set warm-up count and enable
         self.STATUS.set(RNG_WARMUP_COUNT);
         self.CTRL.modify(CTRL::ENABLE::True);
+
+        if self.health_check() {
+            HW_AVAILABLE.store(true, Ordering::Relaxed);
+            return Source::Hardware;
+        }
+
+        HW_AVAILABLE.store(false, Ordering::Relaxed);
+
+        // xorshift gets stuck at zero, so make sure that at least one bit is
+        // set
+        let seed = CNTPCT_EL0.get() ^ board_serial(mbox).rotate_left(32);
+        FALLBACK_STATE.store(seed | 1, Ordering::Relaxed);
+
+        Source::Fallback
     }
 
-    /// Return a random number between [min..max]
-    pub fn rand(&self, min: u32, max: u32) -> u32 {
+    /// Whether the numbers come from the hardware RNG, see `Source`
+    pub fn is_available(&self) -> bool {
+        HW_AVAILABLE.load(Ordering::Relaxed)
+    }
+
+    /// Read some words and make sure that they are not all the same, which
+    /// catches an RNG that is stuck at zero as well
+    fn health_check(&self) -> bool {
+        let mut words = [0u32; HEALTH_CHECK_WORDS];
+
+        for w in words.iter_mut() {
+            if !self.wait_for_words_timeout(RNG_INIT_TIMEOUT_US) {
+                return false;
+            }
+
+            *w = self.DATA.get();
+        }
+
+        words.iter().any(|&w| w != words[0])
+    }
+
+    /// Wait up to `timeout_us` until the RNG has words in its FIFO
+    ///
+    /// The timeout is measured with the CPU's counter, because QEMU emulates
+    /// neither the RNG nor the BCM System Timer.
+    fn wait_for_words_timeout(&self, timeout_us: u64) -> bool {
+        let ticks = u64::from(CNTFRQ_EL0.get()) * timeout_us / 1_000_000;
+        let start = CNTPCT_EL0.get();
+
+        while self.words_available() == 0 {
+            if CNTPCT_EL0.get().wrapping_sub(start) >= ticks {
+                return false;
+            }
+
+            asm::nop();
+        }
+
+        true
+    }
+
+    /// Number of words waiting in the RNG FIFO
+    fn words_available(&self) -> u32 {
+        self.STATUS.get() >> 24
+    }
+
+    /// Wait until the RNG has words in its FIFO and return how many
+    fn wait_for_words(&self) -> u32 {
         // wait for gaining some entropy
         loop {
-            if (self.STATUS.get() >> 24) != 0 {
-                break;
+            let n = self.words_available();
+            if n != 0 {
+                return n;
             }
 
             asm::nop();
         }
+    }
+
+    /// Return a random 32 bit word
+    pub fn next_u32(&self) -> u32 {
+        if !self.is_available() {
+            return (fallback_next() >> 32) as u32;
+        }
+
+        self.wait_for_words();
+
+        self.DATA.get()
+    }
+
+    /// Return a random 64 bit word, composed of two reads
+    pub fn next_u64(&self) -> u64 {
+        (u64::from(self.next_u32()) << 32) | u64::from(self.next_u32())
+    }
+
+    /// Fill `dest` with random bytes
+    ///
+    /// Reads as many words as the FIFO holds before checking the status
+    /// register again.
+    pub fn fill_bytes(&self, dest: &mut [u8]) {
+        if !self.is_available() {
+            for chunk in dest.chunks_mut(4) {
+                let word = self.next_u32().to_ne_bytes();
+                chunk.copy_from_slice(&word[..chunk.len()]);
+            }
+
+            return;
+        }
+
+        let mut chunks = dest.chunks_mut(4).peekable();
+
+        while chunks.peek().is_some() {
+            let n = self.wait_for_words() as usize;
+
+            for chunk in chunks.by_ref().take(n) {
+                let word = self.DATA.get().to_ne_bytes();
+                chunk.copy_from_slice(&word[..chunk.len()]);
+            }
+        }
+    }
+
+    /// Return a uniformly distributed random number in `range`, which
+    /// excludes `range.end`. Returns `range.start` for an empty range.
+    pub fn rand_range(&self, range: Range<u32>) -> u32 {
+        rand_range(range, || self.next_u32())
+    }
+
+    /// Return a PRNG whose seed comes from the hardware RNG, or like the
+    /// fallback's from the CPU counter and the board serial without it
+    ///
+    /// Print its `seed()`, so that a run that went wrong can be repeated
+    /// with `Xoshiro256StarStar::from_seed()`.
+    pub fn seeded_from_hw(&self, mbox: &mut mbox::Mbox) -> Xoshiro256StarStar {
+        let seed = if self.is_available() {
+            self.next_u64()
+        } else {
+            CNTPCT_EL0.get() ^ board_serial(mbox).rotate_left(32)
+        };
+
+        Xoshiro256StarStar::from_seed(seed)
+    }
+}
+
+/// Uses Lemire's multiply-and-reject method, so that ranges that do not
+/// divide 2^32 are not biased towards their lower numbers.
+fn rand_range<F>(range: Range<u32>, mut next_u32: F) -> u32
+where
+    F: FnMut() -> u32,
+{
+    if range.start >= range.end {
+        return range.start;
+    }
+
+    let span = range.end - range.start;
+    let mut m = u64::from(next_u32()) * u64::from(span);
+
+    // Reject the few products whose lower half falls into the part of
+    // 2^32 that is not a multiple of span
+    if (m as u32) < span {
+        let threshold = span.wrapping_neg() % span;
+
+        while (m as u32) < threshold {
+            m = u64::from(next_u32()) * u64::from(span);
+        }
+    }
+
+    range.start + (m >> 32) as u32
+}
+
+/// A tiny PRNG, used to expand a single seed into the state of
+/// `Xoshiro256StarStar`
+pub struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    pub fn from_seed(seed: u64) -> SplitMix64 {
+        SplitMix64 { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+/// The xoshiro256** PRNG
+///
+/// Unlike the hardware RNG, it gives the same numbers for the same seed,
+/// which makes tests that use them repeatable.
+pub struct Xoshiro256StarStar {
+    s: [u64; 4],
+    seed: u64,
+}
+
+impl Xoshiro256StarStar {
+    pub fn from_seed(seed: u64) -> Xoshiro256StarStar {
+        let mut sm = SplitMix64::from_seed(seed);
+        let s = [sm.next_u64(), sm.next_u64(), sm.next_u64(), sm.next_u64()];
+
+        Xoshiro256StarStar { s, seed }
+    }
+
+    /// The seed that this PRNG started from
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let result = self.s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = self.s[1] << 17;
+
+        self.s[2] ^= self.s[0];
+        self.s[3] ^= self.s[1];
+        self.s[1] ^= self.s[2];
+        self.s[0] ^= self.s[3];
+        self.s[2] ^= t;
+        self.s[3] = self.s[3].rotate_left(45);
+
+        result
+    }
+
+    /// The upper half of `next_u64()`, whose bits are the better ones
+    pub fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    pub fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_ne_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    /// Like `Rng::rand_range()`
+    pub fn rand_range(&mut self, range: Range<u32>) -> u32 {
+        rand_range(range, || self.next_u32())
+    }
+}
+
+/// The fallback PRNG, xorshift64*
+fn fallback_next() -> u64 {
+    let mut x = FALLBACK_STATE.load(Ordering::Relaxed);
+    x ^= x >> 12;
+    x ^= x << 25;
+    x ^= x >> 27;
+    FALLBACK_STATE.store(x, Ordering::Relaxed);
+
+    x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+}
+
+/// Query the board's serial number, or 0 if the mailbox call fails
+fn board_serial(mbox: &mut mbox::Mbox) -> u64 {
+    mbox.buffer[0] = 8 * 4; // length of the message
+    mbox.buffer[1] = mbox::REQUEST; // this is a request message
+    mbox.buffer[2] = mbox::tag::GETSERIAL; // get serial number command
+    mbox.buffer[3] = 8; // buffer size
+    mbox.buffer[4] = 8;
+    mbox.buffer[5] = 0; // clear output buffer
+    mbox.buffer[6] = 0;
+    mbox.buffer[7] = mbox::tag::LAST;
+
+    // Insert a compiler fence that ensures that all stores to the
+    // mbox buffer are finished before the GPU is signaled (which is
+    // done by a store operation as well).
+    compiler_fence(Ordering::Release);
+
+    match mbox.call(mbox::channel::PROP) {
+        Ok(()) => (u64::from(mbox.buffer[6]) << 32) | u64::from(mbox.buffer[5]),
+        Err(_) => 0,
+    }
+}
+
+/// Use the hardware RNG with crates that build on `rand_core`
+#[cfg(feature = "rand_core")]
+impl rand_core::RngCore for Rng {
+    fn next_u32(&mut self) -> u32 {
+        Rng::next_u32(self)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        Rng::next_u64(self)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        Rng::fill_bytes(self, dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        if !self.is_available() {
+            return Err(rand_core::Error::new(
+                rand_core::ErrorKind::NotReady,
+                "hardware RNG not initialized or not available",
+            ));
+        }
 
-        let r = self.DATA.get();
+        Rng::fill_bytes(self, dest);
 
-        r % (max - min) + min
+        Ok(())
     }
 }
@@ -53,7 +53,10 @@ impl ops::Deref for SysTmr {
 }
 
 impl SysTmr {
-    pub fn new() -> SysTmr {
+    /// # Safety
+    ///
+    /// There must be only one instance, use `peripherals::take()` instead.
+    pub(crate) unsafe fn new() -> SysTmr {
         SysTmr
     }
 
@@ -81,17 +84,25 @@ impl SysTmr {
     }
 
     /// Wait N microsec (with BCM System Timer)
+    ///
+    /// The System Timer is a free-running counter clocked at 1 MHz, so one
+    /// tick is exactly one microsecond.
     pub fn wait_usec_st(&self, n: u64) {
-        let t = self.get_system_timer();
+        let start = self.get_system_timer();
 
         // We must check if it's non-zero, because qemu does not
         // emulate system timer, and returning constant zero would
         // mean infinite loop
-        if t > 0 {
-            loop {
-                if self.get_system_timer() > (t + n) {
-                    break;
-                }
+        if start == 0 {
+            return;
+        }
+
+        // Compare the elapsed ticks instead of computing `start + n`, so that
+        // the deadline stays correct even if it lies beyond the point where
+        // the 64 bit counter wraps around.
+        loop {
+            if self.get_system_timer().wrapping_sub(start) >= n {
+                break;
             }
         }
     }
@@ -23,6 +23,7 @@
  */
 
 use super::MMIO_BASE;
+use core::ops;
 use register::{mmio::ReadWrite, register_bitfields};
 
 // Descriptions taken from
@@ -66,10 +67,39 @@ register_bitfields! {
     ]
 }
 
-pub const GPFSEL1: *const ReadWrite<u32, GPFSEL1::Register> =
-    (MMIO_BASE + 0x0020_0004) as *const ReadWrite<u32, GPFSEL1::Register>;
+const GPIO_BASE: u32 = MMIO_BASE + 0x0020_0000;
 
-pub const GPPUD: *const ReadWrite<u32> = (MMIO_BASE + 0x0020_0094) as *const ReadWrite<u32>;
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct RegisterBlock {
+    __reserved_0: u32,                                  // 0x00
+    pub GPFSEL1: ReadWrite<u32, GPFSEL1::Register>,     // 0x04
+    __reserved_1: [u32; 35],                            // 0x08
+    pub GPPUD: ReadWrite<u32>,                          // 0x94
+    pub GPPUDCLK0: ReadWrite<u32, GPPUDCLK0::Register>, // 0x98
+}
+
+/// Public interface to the GPIO registers
+pub struct Gpio;
 
-pub const GPPUDCLK0: *const ReadWrite<u32, GPPUDCLK0::Register> =
-    (MMIO_BASE + 0x0020_0098) as *const ReadWrite<u32, GPPUDCLK0::Register>;
+impl ops::Deref for Gpio {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*Self::ptr() }
+    }
+}
+
+impl Gpio {
+    /// # Safety
+    ///
+    /// There must be only one instance, use `peripherals::take()` instead.
+    pub(crate) unsafe fn new() -> Gpio {
+        Gpio
+    }
+
+    /// Returns a pointer to the register block
+    fn ptr() -> *const RegisterBlock {
+        GPIO_BASE as *const _
+    }
+}
@@ -30,14 +30,39 @@ const MMIO_BASE: u32 = 0x3F00_0000;
 mod delays;
 mod gpio;
 mod mbox;
+mod peripherals;
 mod uart;
 
+use core::sync::atomic::{compiler_fence, Ordering};
+use cortex_a::regs::*;
+
+/// Switch the ACT LED on or off, so that waits can be checked by eye.
+fn set_act_led(mbox: &mut mbox::Mbox, on: bool) -> mbox::Result<()> {
+    mbox.buffer[0] = 8 * 4;
+    mbox.buffer[1] = mbox::REQUEST;
+    mbox.buffer[2] = mbox::tag::SETGPIOSTATE;
+    mbox.buffer[3] = 8;
+    mbox.buffer[4] = 0;
+    mbox.buffer[5] = mbox::gpio::ACT_LED;
+    mbox.buffer[6] = u32::from(on);
+    mbox.buffer[7] = mbox::tag::LAST;
+
+    compiler_fence(Ordering::Release);
+
+    mbox.call(mbox::channel::PROP)
+}
+
 fn kernel_entry() -> ! {
-    let mut mbox = mbox::Mbox::new();
-    let uart = uart::Uart::new();
+    // The only call, so it never panics
+    let peripherals::Peripherals {
+        GPIO: gpio,
+        MBOX: mut mbox,
+        SYSTMR: t,
+        UART: uart,
+    } = peripherals::take().unwrap();
 
     // set up serial console
-    match uart.init(&mut mbox) {
+    match uart.init(&mut mbox, &gpio) {
         Ok(_) => uart.puts("\n[0] UART is live!\n"),
         Err(_) => loop {
             cortex_a::asm::wfe() // If UART fails, abort early
@@ -56,16 +81,39 @@ fn kernel_entry() -> ! {
     delays::wait_usec(1_000_000);
     uart.puts("OK\n");
 
-    let t = delays::SysTmr::new();
     if t.get_system_timer() != 0 {
         uart.puts("[i] Waiting 1 second (BCM System Timer): ");
+
+        // Cross-check the wait against the ARM generic timer, so that an
+        // early return is visible on the console. The LED is lit for as long
+        // as the wait takes. It is best effort, so errors are ignored.
+        let frq = u64::from(CNTFRQ_EL0.get());
+        let _ = set_act_led(&mut mbox, true);
+        let start = CNTPCT_EL0.get();
         t.wait_usec_st(1_000_000);
-        uart.puts("OK\n");
+        let elapsed_usec = (CNTPCT_EL0.get() - start) * 1_000_000 / frq;
+        let _ = set_act_led(&mut mbox, false);
+
+        // Allow for a bit of rounding between the two timers.
+        if elapsed_usec >= 999_000 {
+            uart.puts("OK\n");
+        } else {
+            uart.puts("FAILED (returned early)\n");
+        }
     }
 
     uart.puts("[i] Looping forever now!\n");
+    let mut led_on = false;
     loop {
-        delays::wait_usec(1_000_000);
+        // Blink along, on the System Timer where it is available
+        if t.get_system_timer() != 0 {
+            t.wait_usec_st(1_000_000);
+        } else {
+            delays::wait_usec(1_000_000);
+        }
+
+        led_on = !led_on;
+        let _ = set_act_led(&mut mbox, led_on);
         uart.puts("Tick: 1s\n");
     }
 }
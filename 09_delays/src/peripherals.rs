@@ -0,0 +1,74 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Ownership of the peripherals.
+//!
+//! Each driver is handed out exactly once, by the first call to `take()`.
+//! Code that wants to use a peripheral has to be given its driver, so two
+//! pieces of code can not poke the same hardware without anybody noticing.
+
+use super::{delays::SysTmr, gpio::Gpio, mbox::Mbox, uart::Uart};
+
+/// All drivers of this tutorial
+#[allow(non_snake_case)]
+pub struct Peripherals {
+    pub GPIO: Gpio,
+    pub MBOX: Mbox,
+    pub SYSTMR: SysTmr,
+    pub UART: Uart,
+}
+
+/// Set by the first `take()`. Only the boot core runs, and there are no
+/// interrupts, so a plain bool is enough. The MMU is off, so atomics could not
+/// be used anyways.
+static mut TAKEN: bool = false;
+
+/// Get the peripherals. Returns `None` if they were taken before.
+pub fn take() -> Option<Peripherals> {
+    unsafe {
+        if TAKEN {
+            return None;
+        }
+        TAKEN = true;
+
+        Some(steal())
+    }
+}
+
+/// Get the peripherals, even if they were taken before.
+///
+/// # Safety
+///
+/// The drivers are likely in use elsewhere already. Only meant for code that
+/// never returns there, like a panic handler that prints a last message.
+pub unsafe fn steal() -> Peripherals {
+    TAKEN = true;
+
+    Peripherals {
+        GPIO: Gpio::new(),
+        MBOX: Mbox::new(),
+        SYSTMR: SysTmr::new(),
+        UART: Uart::new(),
+    }
+}
@@ -153,7 +153,10 @@ impl ops::Deref for Uart {
 }
 
 impl Uart {
-    pub fn new() -> Uart {
+    /// # Safety
+    ///
+    /// There must be only one instance, use `peripherals::take()` instead.
+    pub(crate) unsafe fn new() -> Uart {
         Uart
     }
 
@@ -163,7 +166,7 @@ impl Uart {
     }
 
     ///Set baud rate and characteristics (115200 8N1) and map to GPIO
-    pub fn init(&self, mbox: &mut mbox::Mbox) -> Result<()> {
+    pub fn init(&self, mbox: &mut mbox::Mbox, gpio: &gpio::Gpio) -> Result<()> {
         // turn off UART0
         self.CR.set(0);
 
@@ -188,19 +191,17 @@ impl Uart {
         };
 
         // map UART0 to GPIO pins
-        unsafe {
-            (*gpio::GPFSEL1).modify(gpio::GPFSEL1::FSEL14::TXD0 + gpio::GPFSEL1::FSEL15::RXD0);
+        gpio.GPFSEL1.modify(gpio::GPFSEL1::FSEL14::TXD0 + gpio::GPFSEL1::FSEL15::RXD0);
 
-            (*gpio::GPPUD).set(0); // enable pins 14 and 15
-            delays::wait_cycles(150);
+        gpio.GPPUD.set(0); // enable pins 14 and 15
+        delays::wait_cycles(150);
 
-            (*gpio::GPPUDCLK0).modify(
-                gpio::GPPUDCLK0::PUDCLK14::AssertClock + gpio::GPPUDCLK0::PUDCLK15::AssertClock,
-            );
-            delays::wait_cycles(150);
+        gpio.GPPUDCLK0.modify(
+            gpio::GPPUDCLK0::PUDCLK14::AssertClock + gpio::GPPUDCLK0::PUDCLK15::AssertClock,
+        );
+        delays::wait_cycles(150);
 
-            (*gpio::GPPUDCLK0).set(0);
-        }
+        gpio.GPPUDCLK0.set(0);
 
         self.ICR.write(ICR::ALL::CLEAR);
         self.IBRD.write(IBRD::IBRD.val(2)); // Results in 115200 baud
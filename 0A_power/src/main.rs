@@ -32,6 +32,7 @@ mod gpio;
 mod mbox;
 mod power;
 mod uart;
+mod watchdog;
 
 fn kernel_entry() -> ! {
     let gpio = gpio::GPIO::new();
@@ -52,20 +53,109 @@ fn kernel_entry() -> ! {
     uart.puts("Greetings fellow Rustacean!\n");
 
     loop {
-        uart.puts("\n 1 - power off\n 2 - reset\nChoose one: ");
+        uart.puts("\n 1 - power off\n 2 - reset\n 3 - power domains\n 4 - toggle USB power");
+        uart.puts("\n 5 - halt\n 6 - reset into partition 0-9\n 7 - watchdog\nChoose one: ");
         let c = uart.getc();
         uart.send(c);
 
         match c {
             '1' => {
-                if power.off(&mut mbox, &gpio).is_err() {
-                    uart.puts("Mailbox error in Power::off()");
+                if let Err(power::PowerError::MailboxError(e)) = power.off(&mut mbox, &gpio) {
+                    uart.puts("\nMailbox error in Power::off(): ");
+                    uart.puts(match e {
+                        mbox::MboxError::Timeout => "timed out\n",
+                        mbox::MboxError::ResponseError(_) => "request rejected\n",
+                        mbox::MboxError::ChannelMismatch => "unexpected response\n",
+                        mbox::MboxError::InvalidBufferAddress => "buffer not addressable\n",
+                    });
                 }
             }
             '2' => power.reset(),
+            '3' => {
+                uart.puts("\n");
+                for &dev in power::Device::ALL.iter() {
+                    uart.puts(dev.name());
+                    uart.puts(match power.device_power(&mut mbox, dev) {
+                        Ok(power::PowerState::On) => ": on\n",
+                        Ok(power::PowerState::Off) => ": off\n",
+                        Err(power::PowerError::NoSuchDevice) => ": not present\n",
+                        Err(_) => ": mailbox error\n",
+                    });
+                }
+            }
+            '4' => {
+                let on = match power.device_power(&mut mbox, power::Device::UsbHcd) {
+                    Ok(power::PowerState::On) => false,
+                    _ => true,
+                };
+
+                uart.puts(match power.set_device_power(&mut mbox, power::Device::UsbHcd, on) {
+                    Ok(power::PowerState::On) => "\nUSB HCD is on now\n",
+                    Ok(power::PowerState::Off) => "\nUSB HCD is off now\n",
+                    Err(_) => "\nCould not switch the USB HCD\n",
+                });
+            }
+            '5' => power.halt(),
+            '6' => {
+                uart.puts("\nPartition: ");
+                let p = uart.getc();
+                uart.send(p);
+
+                match p.to_digit(10) {
+                    Some(p) => {
+                        let _ = power.reset_to_partition(p as u8);
+                    }
+                    None => uart.puts("\nNot a partition number\n"),
+                }
+            }
+            '7' => watchdog_demo(&uart),
             _ => {}
         }
     }
 }
 
+/// Feed a 3 second watchdog once per second for a while, stop it, and then
+/// restart it without feeding, which resets the board
+fn watchdog_demo(uart: &uart::Uart) {
+    const TIMEOUT_MS: u32 = 3000;
+
+    if watchdog::start(TIMEOUT_MS).is_err() {
+        uart.puts("\nInvalid watchdog timeout\n");
+        return;
+    }
+
+    uart.puts("\nWatchdog started, feeding it");
+    for _ in 0..5 {
+        // The watchdog's own countdown doubles as the clock
+        while watchdog::remaining_ms().unwrap_or(0) > TIMEOUT_MS - 1000 {}
+
+        watchdog::feed();
+        uart.send('.');
+    }
+
+    // A stopped watchdog must not bite, however long it is left alone
+    watchdog::stop();
+    uart.puts("\nStopped, waiting");
+    for _ in 0..5 {
+        // Roughly a second, with two cycles per iteration at 1.2 GHz
+        delays::wait_cycles(600_000_000);
+        uart.send('.');
+    }
+
+    let _ = watchdog::start(TIMEOUT_MS);
+    uart.puts("\nRestarted, but not feeding anymore, reset in ");
+    let mut last = 10;
+    loop {
+        let secs = watchdog::remaining_ms().unwrap_or(0) / 1000 + 1;
+
+        if secs != last {
+            if let Some(c) = core::char::from_digit(secs, 10) {
+                uart.send(c);
+                uart.send(' ');
+            }
+            last = secs;
+        }
+    }
+}
+
 raspi3_boot::entry!(kernel_entry);
@@ -27,7 +27,7 @@ use crate::delays;
 use crate::gpio;
 use crate::mbox;
 use core::ops;
-use core::sync::atomic::{compiler_fence, Ordering};
+use core::sync::atomic::{compiler_fence, AtomicBool, Ordering};
 use register::mmio::*;
 
 const POWER_BASE: u32 = MMIO_BASE + 0x100_01C;
@@ -43,18 +43,83 @@ pub struct RegisterBlock {
 const PM_PASSWORD: u32 = 0x5a_000_000;
 const PM_RSTC_WRCFG_CLR: u32 = 0xffff_ffcf;
 const PM_RSTC_WRCFG_FULL_RESET: u32 = 0x0000_0020;
+const PM_RSTC_RESET: u32 = 0x0000_0102;
+const PM_WDOG_TIME_SET: u32 = 0x000f_ffff;
+
+/// Set once a reset is under way, after which the watchdog may no longer be
+/// re-armed or disarmed.
+static RESET_PENDING: AtomicBool = AtomicBool::new(false);
 
 // The Raspberry Pi firmware uses the RSTS register to know which
 // partition to boot from. The partition value is spread into bits 0, 2,
 // 4, 6, 8, 10. Partition 63 is a special partition used by the
 // firmware to indicate halt.
-const PM_RSTS_RASPBERRYPI_HALT: u32 = 0x555;
+const PM_RSTS_PARTITION_CLR: u32 = 0xffff_faaa;
+const PARTITION_HALT: u8 = 63;
+
+// Power state request and response bits of the mailbox power tags
+const POWER_STATE_ON: u32 = 1 << 0;
+const POWER_STATE_WAIT: u32 = 1 << 1; // in requests
+const POWER_STATE_NO_DEVICE: u32 = 1 << 1; // in responses
 
+#[derive(Debug)]
 pub enum PowerError {
-    MailboxError,
+    MailboxError(mbox::MboxError),
+    /// The firmware says that the device does not exist on this board
+    NoSuchDevice,
+    /// Boot partitions range from 0 to 62
+    InvalidPartition,
 }
 pub type Result<T> = ::core::result::Result<T, PowerError>;
 
+/// Devices with a power domain that the firmware can switch
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Device {
+    SdCard = 0,
+    Uart0 = 1,
+    Uart1 = 2,
+    UsbHcd = 3,
+    I2c0 = 4,
+    I2c1 = 5,
+    I2c2 = 6,
+    Spi = 7,
+    Ccp2Tx = 8,
+}
+
+impl Device {
+    pub const ALL: [Device; 9] = [
+        Device::SdCard,
+        Device::Uart0,
+        Device::Uart1,
+        Device::UsbHcd,
+        Device::I2c0,
+        Device::I2c1,
+        Device::I2c2,
+        Device::Spi,
+        Device::Ccp2Tx,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Device::SdCard => "SD card",
+            Device::Uart0 => "UART0",
+            Device::Uart1 => "UART1",
+            Device::UsbHcd => "USB HCD",
+            Device::I2c0 => "I2C0",
+            Device::I2c1 => "I2C1",
+            Device::I2c2 => "I2C2",
+            Device::Spi => "SPI",
+            Device::Ccp2Tx => "CCP2TX",
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PowerState {
+    Off,
+    On,
+}
+
 /// Public interface to the Power subsystem
 pub struct Power;
 
@@ -76,6 +141,26 @@ impl Power {
         POWER_BASE as *const _
     }
 
+    /// Turn the power domain of `dev` on or off
+    ///
+    /// Waits until the device is stable and returns the state that the
+    /// firmware reports afterwards.
+    pub fn set_device_power(
+        &self,
+        mbox: &mut mbox::Mbox,
+        dev: Device,
+        on: bool,
+    ) -> Result<PowerState> {
+        let state = if on { POWER_STATE_ON } else { 0 };
+
+        power_call(mbox, mbox::tag::SETPOWER, dev, state | POWER_STATE_WAIT)
+    }
+
+    /// Read the current state of the power domain of `dev`
+    pub fn device_power(&self, mbox: &mut mbox::Mbox, dev: Device) -> Result<PowerState> {
+        power_call(mbox, mbox::tag::GETPOWER, dev, 0)
+    }
+
     /// Shutdown the board
     pub fn off(&self, mbox: &mut mbox::Mbox, gpio: &gpio::GPIO) -> Result<()> {
         // power off devices one by one
@@ -94,9 +179,7 @@ impl Power {
             // is done by a store operation as well).
             compiler_fence(Ordering::Release);
 
-            if mbox.call(mbox::channel::PROP).is_err() {
-                return Err(PowerError::MailboxError);
-            };
+            mbox.call(mbox::channel::PROP).map_err(PowerError::MailboxError)?;
         }
 
         // power off gpio pins (but not VCC pins)
@@ -118,26 +201,125 @@ impl Power {
         gpio.GPPUDCLK0.set(0);
         gpio.GPPUDCLK1.set(0);
 
-        // We set the watchdog hard reset bit here to distinguish this
-        // reset from the normal (full) reset. bootcode.bin will not
-        // reboot after a hard reset.
-        let mut val = self.PM_RSTS.get();
-        val |= PM_PASSWORD | PM_RSTS_RASPBERRYPI_HALT;
-        self.PM_RSTS.set(val);
+        self.halt();
+    }
 
-        // Continue with normal reset mechanism
-        self.reset();
+    /// Stop the SoC in a low-power state
+    ///
+    /// Resets into the special halt partition, after which bootcode.bin does
+    /// not boot again. Only power cycling wakes the board up.
+    pub fn halt(&self) -> ! {
+        self.set_boot_partition(PARTITION_HALT);
+        self.watchdog_reset();
     }
 
     /// Reboot
+    ///
+    /// Leaves the boot partition in RSTS alone, so the firmware boots the
+    /// default partition unless something else selected one.
     pub fn reset(&self) -> ! {
+        self.watchdog_reset();
+    }
+
+    /// Reboot into `partition` of the SD card, e.g. for an A/B kernel setup
+    /// or a recovery partition
+    ///
+    /// Does not return, unless `partition` is out of range. Use `halt()`
+    /// for the special partition 63.
+    pub fn reset_to_partition(&self, partition: u8) -> Result<()> {
+        if partition >= PARTITION_HALT {
+            return Err(PowerError::InvalidPartition);
+        }
+
+        self.set_boot_partition(partition);
+        self.watchdog_reset();
+    }
+
+    /// Tell the firmware which partition to boot after the next reset
+    ///
+    /// This is the only place that writes RSTS, so that halting and
+    /// rebooting into a partition cannot clobber each other's bits.
+    fn set_boot_partition(&self, partition: u8) {
+        // Spread the six partition bits into the even bits 0 to 10
+        let p = u32::from(partition);
+        let rsts = (0..6).fold(0, |acc, bit| acc | ((p & (1 << bit)) << bit));
+
+        let mut val = self.PM_RSTS.get();
+        val &= PM_RSTS_PARTITION_CLR;
+        val |= PM_PASSWORD | rsts;
+        self.PM_RSTS.set(val);
+    }
+
+    /// Let the watchdog trigger a full reset
+    fn watchdog_reset(&self) -> ! {
+        RESET_PENDING.store(true, Ordering::SeqCst);
+
         // use a timeout of 10 ticks (~150us)
-        self.PM_WDOG.set(PM_PASSWORD | 10);
+        self.write_watchdog(10);
+
+        loop {}
+    }
+
+    /// Arm the watchdog to reset the board after `ticks` of 1/65536 s, or
+    /// re-arm it if it is already running
+    ///
+    /// Has no effect once `reset()`, `halt()` or `reset_to_partition()` armed
+    /// it themselves, so that feeding cannot postpone a reboot in progress.
+    pub fn arm_watchdog(&self, ticks: u32) {
+        if !RESET_PENDING.load(Ordering::SeqCst) {
+            self.write_watchdog(ticks);
+        }
+    }
+
+    /// Stop the watchdog, unless a reset is already under way
+    pub fn disarm_watchdog(&self) {
+        if !RESET_PENDING.load(Ordering::SeqCst) {
+            self.PM_RSTC.set(PM_PASSWORD | PM_RSTC_RESET);
+        }
+    }
+
+    /// The ticks that are left until the watchdog resets the board
+    pub fn watchdog_ticks_left(&self) -> u32 {
+        self.PM_WDOG.get() & PM_WDOG_TIME_SET
+    }
+
+    /// The only place that arms the watchdog, for both resets and the
+    /// watchdog API
+    fn write_watchdog(&self, ticks: u32) {
+        self.PM_WDOG.set(PM_PASSWORD | (ticks & PM_WDOG_TIME_SET));
         let mut val = self.PM_RSTC.get();
         val &= PM_RSTC_WRCFG_CLR;
         val |= PM_PASSWORD | PM_RSTC_WRCFG_FULL_RESET;
         self.PM_RSTC.set(val);
+    }
+}
 
-        loop {}
+/// Send a single power tag for `dev` and decode the reported state
+fn power_call(mbox: &mut mbox::Mbox, tag: u32, dev: Device, state: u32) -> Result<PowerState> {
+    mbox.buffer[0] = 8 * 4;
+    mbox.buffer[1] = mbox::REQUEST;
+    mbox.buffer[2] = tag;
+    mbox.buffer[3] = 8;
+    mbox.buffer[4] = 8;
+    mbox.buffer[5] = dev as u32; // device id
+    mbox.buffer[6] = state;
+    mbox.buffer[7] = mbox::tag::LAST;
+
+    // Insert a compiler fence that ensures that all stores to the
+    // mbox buffer are finished before the GPU is signaled (which
+    // is done by a store operation as well).
+    compiler_fence(Ordering::Release);
+
+    mbox.call(mbox::channel::PROP).map_err(PowerError::MailboxError)?;
+
+    let state = mbox.buffer[6];
+    if state & POWER_STATE_NO_DEVICE != 0 {
+        return Err(PowerError::NoSuchDevice);
+    }
+
+    if state & POWER_STATE_ON != 0 {
+        Ok(PowerState::On)
+    } else {
+        Ok(PowerState::Off)
     }
 }
@@ -0,0 +1,99 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! The PM watchdog, used as what it is named after.
+//!
+//! Once started, the board resets unless `feed()` is called again within the
+//! timeout. `Power::reset()` and friends use the same hardware with a timeout
+//! of a few ticks, so all register accesses go through `power::Power`.
+
+use crate::power;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// The watchdog counts down in ticks of 1/65536 s, i.e. its 20 bit counter
+/// holds seconds with 16 fractional bits.
+const TICKS_PER_SEC: u64 = 65536;
+const MAX_TICKS: u64 = 0x000f_ffff;
+
+/// The longest timeout, just under 16 seconds
+pub const MAX_TIMEOUT_MS: u32 = (MAX_TICKS * 1000 / TICKS_PER_SEC) as u32;
+
+/// The timeout that `feed()` re-arms with, or zero if the watchdog is stopped
+static TIMEOUT_TICKS: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Debug)]
+pub enum WatchdogError {
+    /// Timeouts range from 1 to `MAX_TIMEOUT_MS` milliseconds
+    InvalidTimeout,
+}
+pub type Result<T> = ::core::result::Result<T, WatchdogError>;
+
+fn ms_to_ticks(ms: u32) -> u32 {
+    (u64::from(ms) * TICKS_PER_SEC / 1000) as u32
+}
+
+fn ticks_to_ms(ticks: u32) -> u32 {
+    (u64::from(ticks) * 1000 / TICKS_PER_SEC) as u32
+}
+
+/// Reset the board unless `feed()` is called within `timeout_ms`
+///
+/// Calling it again while the watchdog runs changes the timeout.
+pub fn start(timeout_ms: u32) -> Result<()> {
+    if timeout_ms == 0 || timeout_ms > MAX_TIMEOUT_MS {
+        return Err(WatchdogError::InvalidTimeout);
+    }
+
+    let ticks = ms_to_ticks(timeout_ms);
+    TIMEOUT_TICKS.store(ticks, Ordering::SeqCst);
+    power::Power::new().arm_watchdog(ticks);
+
+    Ok(())
+}
+
+/// Restart the countdown with the timeout given to `start()`
+///
+/// Does nothing if the watchdog was not started.
+pub fn feed() {
+    let ticks = TIMEOUT_TICKS.load(Ordering::SeqCst);
+
+    if ticks != 0 {
+        power::Power::new().arm_watchdog(ticks);
+    }
+}
+
+/// Milliseconds until the board resets, or `None` if the watchdog is stopped
+pub fn remaining_ms() -> Option<u32> {
+    if TIMEOUT_TICKS.load(Ordering::SeqCst) == 0 {
+        return None;
+    }
+
+    Some(ticks_to_ms(power::Power::new().watchdog_ticks_left()))
+}
+
+/// Stop the watchdog
+pub fn stop() {
+    TIMEOUT_TICKS.store(0, Ordering::SeqCst);
+    power::Power::new().disarm_watchdog();
+}
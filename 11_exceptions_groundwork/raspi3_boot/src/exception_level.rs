@@ -0,0 +1,215 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Exception levels, and the drop from the one that the firmware started the
+//! kernel in to EL1.
+//!
+//! Depending on the firmware and `config.txt`, the kernel is entered in EL3,
+//! EL2 or EL1. `transition_to_el1()` handles all of them.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+use cortex_a::regs::*;
+
+/// An exception level
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EL {
+    /// Applications
+    EL0,
+    /// OS kernel
+    EL1,
+    /// Hypervisor
+    EL2,
+    /// Secure monitor
+    EL3,
+}
+
+impl EL {
+    fn from_bits(bits: u32) -> EL {
+        match bits & 0x3 {
+            0 => EL::EL0,
+            1 => EL::EL1,
+            2 => EL::EL2,
+            _ => EL::EL3,
+        }
+    }
+}
+
+/// Marks `BOOT_EL` as not recorded yet.
+const UNKNOWN: u8 = 0xFF;
+
+/// The exception level that `transition_to_el1()` was called in.
+///
+/// Must not live in the BSS, which is only zeroed after the transition.
+static BOOT_EL: AtomicU8 = AtomicU8::new(UNKNOWN);
+
+extern "C" {
+    // Top of the exception stack, reserved in the linker script
+    static __exception_stack_end: u64;
+}
+
+/// The exception level of the executing core.
+pub fn current() -> EL {
+    EL::from_bits(CurrentEL.read(CurrentEL::EL))
+}
+
+/// The exception level that the firmware started the kernel in.
+pub fn boot_el() -> EL {
+    match BOOT_EL.load(Ordering::Relaxed) {
+        UNKNOWN => current(),
+        bits => EL::from_bits(u32::from(bits)),
+    }
+}
+
+/// Drop to EL1, and continue at `entry` on the stack at `stack_top`.
+///
+/// - From EL3, the core first drops to non-secure EL2 and continues there.
+/// - From EL2, EL1 is set up for AArch64, with access to the timers and the
+///   PMU.
+/// - In EL1, there is nothing left to do except setting up the stacks.
+///
+/// In all cases, `entry` runs on SP_EL0 with all interrupts masked, while
+/// exceptions are taken on SP_EL1, which points to the top of the exception
+/// stack.
+///
+/// Parks the core if it is in EL0, where the kernel can not have been started.
+pub unsafe fn transition_to_el1(stack_top: u64, entry: unsafe fn() -> !) -> ! {
+    let el = current();
+
+    BOOT_EL.store(el as u8, Ordering::Relaxed);
+
+    transition_to_el1_with_stacks(
+        stack_top,
+        &__exception_stack_end as *const _ as u64,
+        entry,
+    )
+}
+
+/// Like `transition_to_el1()`, but with an exception stack of the caller's
+/// choice, for the secondary cores. `BOOT_EL` is left alone.
+pub unsafe fn transition_to_el1_with_stacks(
+    stack_top: u64,
+    exception_stack_top: u64,
+    entry: unsafe fn() -> !,
+) -> ! {
+    let entry = entry as *const () as u64;
+
+    match current() {
+        EL::EL3 => el3_to_el2(stack_top, exception_stack_top, entry),
+        EL::EL2 => el2_to_el1(stack_top, exception_stack_top, entry),
+        EL::EL1 => el1_set_up_stacks(stack_top, exception_stack_top, entry),
+        EL::EL0 => loop {
+            cortex_a::asm::wfe();
+        },
+    }
+}
+
+/// Prepare and execute the transition from EL3 to EL2.
+///
+/// `el2_to_el1()` is entered via `eret`, which leaves the general purpose
+/// registers untouched. Hence, the arguments are handed over in x0 to x2, like
+/// for any other call of an `extern "C"` function.
+unsafe fn el3_to_el2(stack_top: u64, exception_stack_top: u64, entry: u64) -> ! {
+    // SCR_EL3: Lower ELs are non-secure (NS), HVC is enabled (HCE) and EL2 is
+    // AArch64 (RW). Bits 4 and 5 are RES1.
+    const SCR_EL3_VALUE: u64 = (1 << 10) | (1 << 8) | (0b11 << 4) | 1;
+
+    // SPSR_EL3: All interrupts masked, EL2 using SP_EL2
+    const SPSR_EL3_VALUE: u64 = (0b1111 << 6) | 0b1001;
+
+    // Normally done by the firmware's armstub. CNTFRQ_EL0 is only writable in
+    // EL3, and the counter runs off the 19.2 MHz crystal.
+    const CNTFRQ: u64 = 19_200_000;
+
+    asm!("msr CNTFRQ_EL0, $0" :: "r"(CNTFRQ) :: "volatile");
+    asm!("msr SCR_EL3, $0" :: "r"(SCR_EL3_VALUE) :: "volatile");
+    asm!("msr SPSR_EL3, $0" :: "r"(SPSR_EL3_VALUE) :: "volatile");
+    asm!("msr ELR_EL3, $0" :: "r"(el2_to_el1 as *const () as u64) :: "volatile");
+
+    // EL2 borrows the kernel stack until it drops to EL1
+    asm!("msr SP_EL2, $0" :: "r"(stack_top) :: "volatile");
+
+    asm!("eret" :: "{x0}"(stack_top), "{x1}"(exception_stack_top), "{x2}"(entry) :: "volatile");
+
+    core::hint::unreachable_unchecked()
+}
+
+/// Prepare and execute the transition from EL2 to EL1.
+unsafe extern "C" fn el2_to_el1(stack_top: u64, exception_stack_top: u64, entry: u64) -> ! {
+    // Enable timer counter registers for EL1
+    CNTHCTL_EL2.write(CNTHCTL_EL2::EL1PCEN::SET + CNTHCTL_EL2::EL1PCTEN::SET);
+
+    // No offset for reading the counters
+    CNTVOFF_EL2.set(0);
+
+    // Do not trap PMU accesses of EL1, and hand it all the event counters
+    // (MDCR_EL2.HPMN = PMCR_EL0.N).
+    let pmcr: u64;
+    asm!("mrs $0, PMCR_EL0" : "=r"(pmcr) ::: "volatile");
+    asm!("msr MDCR_EL2, $0" :: "r"((pmcr >> 11) & 0x1F) :: "volatile");
+
+    // Set EL1 execution state to AArch64
+    HCR_EL2.write(HCR_EL2::RW::EL1IsAarch64);
+
+    // Set up a simulated exception return.
+    //
+    // First, fake a saved program status, where all interrupts were
+    // masked and SP_EL0 was used as a stack pointer (EL1t).
+    SPSR_EL2.write(
+        SPSR_EL2::D::Masked
+            + SPSR_EL2::A::Masked
+            + SPSR_EL2::I::Masked
+            + SPSR_EL2::F::Masked
+            + SPSR_EL2::M::EL1t,
+    );
+
+    // Second, let the link register point to the entry function.
+    ELR_EL2.set(entry);
+
+    // Set up SP_EL0 (stack pointer), which will be used by EL1 once
+    // we "return" to it.
+    SP_EL0.set(stack_top);
+
+    // Exceptions taken to EL1 switch to SP_EL1, which gets a stack of its
+    // own. A fault in a handler can then not corrupt the interrupted context.
+    SP_EL1.set(exception_stack_top);
+
+    // Use `eret` to "return" to EL1. This will result in execution of
+    // `entry` in EL1.
+    cortex_a::asm::eret()
+}
+
+/// Already in EL1: Mask interrupts, switch to the stacks that `el2_to_el1()`
+/// would have set up, and jump to `entry`.
+unsafe fn el1_set_up_stacks(stack_top: u64, exception_stack_top: u64, entry: u64) -> ! {
+    asm!("msr DAIFSet, #0xF
+          msr SPSel, #1
+          mov sp, $1
+          msr SP_EL0, $0
+          msr SPSel, #0
+          br  $2"
+         :: "r"(stack_top), "r"(exception_stack_top), "r"(entry)
+         :: "volatile");
+
+    core::hint::unreachable_unchecked()
+}
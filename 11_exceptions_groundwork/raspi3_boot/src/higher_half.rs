@@ -0,0 +1,216 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+//! The switch of the kernel into the higher half of the address space.
+//!
+//! The kernel is linked to run at `KERNEL_OFFSET + 0x8_0000`, but the firmware
+//! starts it at the physical address `0x8_0000`. Until the MMU is on, only code
+//! that addresses memory PC-relative works, which is what the compiler emits
+//! for functions and statics. Addresses that are stored in memory, like those
+//! in vtables, are virtual ones and must not be used yet.
+//!
+//! `enable_mmu_and_jump()` maps the first 2 GiB of physical address space,
+//! and on the Pi 4 also the fourth GiB with the peripherals, twice, with the
+//! same tables: Identity mapped through TTBR0, so that the code
+//! switching on the MMU keeps running, and at `KERNEL_OFFSET` through TTBR1.
+//! After the jump, the identity map is switched off again, which leaves TTBR0
+//! free for user space.
+//!
+//! These early tables map DRAM as RWX in 2 MiB blocks. The kernel replaces them
+//! with its own, fine grained ones as soon as it can.
+
+use cortex_a::{barrier, regs::*};
+
+/// The virtual address that physical address 0 is mapped to in the kernel's
+/// half of the address space. Must match `KERNEL_OFFSET` in `link.ld`.
+pub const KERNEL_OFFSET: usize = 0xFFFF_0000_0000_0000;
+
+/// On the Pi 2 and 3, everything from here to the end of the first GiB is
+/// device MMIO, the peripherals.
+const PI3_MMIO_BASE: u64 = 0x3F00_0000;
+
+/// The part number of the Cortex-A72 in MIDR_EL1. Only the BCM2711 of the Pi 4
+/// has one.
+const PART_CORTEX_A72: u64 = 0xD08;
+
+const TWO_MIB: u64 = 2 * 1024 * 1024;
+const ONE_GIB: u64 = 1024 * 1024 * 1024;
+
+// Stage 1 descriptor bits, see the kernel's `memory/mmu.rs` for the details.
+const VALID: u64 = 1 << 0;
+const TYPE_TABLE: u64 = 1 << 1;
+const ATTR_INDX_DEVICE: u64 = 0 << 2;
+const ATTR_INDX_NORMAL: u64 = 1 << 2;
+const SH_OUTER: u64 = 0b10 << 8;
+const SH_INNER: u64 = 0b11 << 8;
+const AF: u64 = 1 << 10;
+const PXN: u64 = 1 << 53;
+
+const NORMAL_BLOCK: u64 = VALID | ATTR_INDX_NORMAL | SH_INNER | AF;
+const DEVICE_BLOCK: u64 = VALID | ATTR_INDX_DEVICE | SH_OUTER | AF | PXN;
+
+// MAIR_EL1: Attribute 0 is Device-nGnRE, attribute 1 normal write-back DRAM and
+// attribute 2 normal non-cacheable DRAM. The same as in the kernel's
+// `memory::mmu::set_up_mair()`.
+const MAIR_EL1_VALUE: u64 = 0x0044_FF04;
+
+// The TTBR1 half of TCR_EL1, which is missing in the cortex-a crate: A 48 bit
+// address space (T1SZ = 16) that is walked starting at LVL0, with a 4 KiB
+// granule (TG1) and cacheable, inner shareable walks.
+const TCR_EL1_T1SZ: u64 = 16 << 16;
+const TCR_EL1_IRGN1_WRITEBACK: u64 = 0b01 << 24;
+const TCR_EL1_ORGN1_WRITEBACK: u64 = 0b01 << 26;
+const TCR_EL1_SH1_INNER: u64 = 0b11 << 28;
+const TCR_EL1_TG1_4KIB: u64 = 0b10 << 30;
+
+#[repr(C)]
+#[repr(align(4096))]
+struct PageTable([u64; 512]);
+
+// All three live in the BSS, which is zeroed before they are filled.
+static mut LVL0_TABLE: PageTable = PageTable([0; 512]);
+static mut LVL1_TABLE: PageTable = PageTable([0; 512]);
+static mut LVL2_TABLE: PageTable = PageTable([0; 512]);
+
+/// A table descriptor pointing to `table`.
+///
+/// With the MMU off, the address of a static is its physical address.
+fn table_descriptor(table: &PageTable) -> u64 {
+    table as *const _ as u64 | TYPE_TABLE | VALID
+}
+
+/// Whether we run on a Pi 4, by the part number in MIDR_EL1, like the
+/// kernel's `memory::mmio_base::detect()` does it.
+fn is_pi4() -> bool {
+    let midr: u64;
+    unsafe { asm!("mrs $0, MIDR_EL1" : "=r"(midr) ::: "volatile") };
+
+    (midr >> 4) & 0xFFF == PART_CORTEX_A72
+}
+
+/// Map the first GiB in 2 MiB blocks, and the ARM local peripherals in the
+/// second GiB as one 1 GiB block.
+///
+/// On the Pi 4, the first two GiB are SDRAM, and the peripherals, the ARM
+/// local ones and the GIC are in the fourth GiB, mapped as one block as well.
+///
+/// The layout is picked by the CPU we run on, not by the `rpi4` feature, so
+/// that the window the kernel detects is always mapped, even if the kernel is
+/// built for the other board. It then gets as far as `board::check()`, which
+/// says so.
+unsafe fn populate_tables() {
+    LVL0_TABLE.0[0] = table_descriptor(&LVL1_TABLE);
+
+    LVL1_TABLE.0[0] = table_descriptor(&LVL2_TABLE);
+    let mmio_base = if is_pi4() {
+        LVL1_TABLE.0[1] = ONE_GIB | NORMAL_BLOCK;
+        LVL1_TABLE.0[3] = 3 * ONE_GIB | DEVICE_BLOCK;
+
+        ONE_GIB
+    } else {
+        LVL1_TABLE.0[1] = ONE_GIB | DEVICE_BLOCK;
+
+        PI3_MMIO_BASE
+    };
+
+    for (i, entry) in LVL2_TABLE.0.iter_mut().enumerate() {
+        let addr = i as u64 * TWO_MIB;
+
+        *entry = addr | if addr < mmio_base { NORMAL_BLOCK } else { DEVICE_BLOCK };
+    }
+}
+
+/// Map the kernel into the higher half, switch on the MMU, and continue at the
+/// virtual address of `entry`, with both stack pointers moved along.
+///
+/// Must be called with the MMU off, after the BSS was zeroed, on the stacks
+/// that `exception_level::transition_to_el1()` set up.
+pub unsafe fn enable_mmu_and_jump(entry: unsafe fn() -> !) -> ! {
+    populate_tables();
+
+    switch_on_and_jump(entry)
+}
+
+/// Like `enable_mmu_and_jump()`, but for a secondary core that joins after the
+/// boot core already filled the early tables.
+pub unsafe fn enable_mmu_and_jump_secondary(entry: unsafe fn() -> !) -> ! {
+    switch_on_and_jump(entry)
+}
+
+unsafe fn switch_on_and_jump(entry: unsafe fn() -> !) -> ! {
+    MAIR_EL1.set(MAIR_EL1_VALUE);
+
+    // LVL1 serves both halves. TTBR0 walks of a 2 GiB address space start
+    // there, TTBR1 walks start one level above.
+    TTBR0_EL1.set_baddr(&LVL1_TABLE as *const _ as u64);
+    asm!("msr TTBR1_EL1, $0" :: "r"(&LVL0_TABLE as *const _ as u64) :: "volatile");
+
+    let ips = ID_AA64MMFR0_EL1.read(ID_AA64MMFR0_EL1::PARange);
+    let ttbr0_half = TCR_EL1::TBI0::Ignored
+        + TCR_EL1::IPS.val(ips)
+        + TCR_EL1::TG0::KiB_4
+        + TCR_EL1::SH0::Inner
+        + TCR_EL1::ORGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
+        + TCR_EL1::IRGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
+        + TCR_EL1::EPD0::EnableTTBR0Walks
+        + TCR_EL1::T0SZ.val(33);
+    TCR_EL1.set(
+        ttbr0_half.value
+            | TCR_EL1_TG1_4KIB
+            | TCR_EL1_SH1_INNER
+            | TCR_EL1_ORGN1_WRITEBACK
+            | TCR_EL1_IRGN1_WRITEBACK
+            | TCR_EL1_T1SZ,
+    );
+
+    barrier::isb(barrier::SY);
+    SCTLR_EL1.modify(SCTLR_EL1::M::Enable + SCTLR_EL1::C::Cacheable + SCTLR_EL1::I::Cacheable);
+    barrier::isb(barrier::SY);
+
+    // The trampoline: Still executing from the identity map, move SP_EL1 and
+    // SP_EL0 to their aliases in the higher half, and jump to the alias of
+    // `entry`. The stacks' contents are never returned to, so the physical
+    // addresses in there do not matter.
+    asm!("msr SPSel, #1
+          add sp, sp, $0
+          msr SPSel, #0
+          add sp, sp, $0
+          br  $1"
+         :: "r"(KERNEL_OFFSET as u64), "r"(entry as *const () as u64 + KERNEL_OFFSET as u64)
+         :: "volatile");
+
+    core::hint::unreachable_unchecked()
+}
+
+/// Switch off the identity map once running in the higher half.
+///
+/// TTBR0 walks are disabled, so that any access to the lower half faults. The
+/// identity mapped LVL1 table stays in TTBR0 until user space brings its own.
+pub unsafe fn disable_identity_map() {
+    TCR_EL1.modify(TCR_EL1::EPD0::DisableTTBR0Walks);
+    barrier::isb(barrier::SY);
+
+    asm!("tlbi vmalle1" :::: "volatile");
+    barrier::dsb(barrier::SY);
+    barrier::isb(barrier::SY);
+}
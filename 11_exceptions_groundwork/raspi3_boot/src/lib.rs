@@ -25,11 +25,39 @@
 
 #![deny(missing_docs)]
 #![deny(warnings)]
+#![feature(asm)]
 #![no_std]
 
 //! Low-level boot of the Raspberry's processor
+//!
+//! The kernel must provide its own `#[panic_handler]`.
 
-extern crate panic_abort;
+pub mod exception_level;
+pub mod higher_half;
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Marks `BOOT_DTB` as not recorded yet. Even if the firmware passed no device
+/// tree at all, zero gets recorded.
+const NOT_RECORDED: usize = usize::max_value();
+
+/// The physical address of the device tree blob that the firmware passed in
+/// x0.
+///
+/// Like `exception_level::BOOT_EL`, this must not live in the BSS.
+static BOOT_DTB: AtomicUsize = AtomicUsize::new(NOT_RECORDED);
+
+/// The physical address of the device tree blob that the firmware passed to
+/// the kernel, if any.
+///
+/// Nothing is validated here, the address may as well be garbage from a
+/// firmware that does not pass a device tree.
+pub fn boot_dtb() -> Option<usize> {
+    match BOOT_DTB.load(Ordering::Relaxed) {
+        NOT_RECORDED | 0 => None,
+        addr => Some(addr),
+    }
+}
 
 /// Type check the user-supplied entry function.
 #[macro_export]
@@ -45,9 +73,25 @@ macro_rules! entry {
     };
 }
 
+/// Type check the user-supplied entry function of the secondary cores, see
+/// `_secondary_entry()`.
+#[macro_export]
+macro_rules! secondary_entry {
+    ($path:path) => {
+        #[export_name = "secondary_main"]
+        pub unsafe fn __secondary_main() -> ! {
+            // type check the given path
+            let f: fn() -> ! = $path;
+
+            f()
+        }
+    };
+}
+
 /// Reset function.
 ///
-/// Initializes the bss section before calling into the user's `main()`.
+/// Initializes the bss section and moves the kernel into the higher half,
+/// which continues with `higher_half_entry()`.
 unsafe fn reset() -> ! {
     extern "C" {
         // Boundaries of the .bss section, provided by the linker script
@@ -58,6 +102,14 @@ unsafe fn reset() -> ! {
     // Zeroes the .bss section
     r0::zero_bss(&mut __bss_start, &mut __bss_end);
 
+    higher_half::enable_mmu_and_jump(higher_half_entry)
+}
+
+/// Runs at the kernel's virtual address, and calls into the user's `main()`
+/// once the identity map of the boot code is gone.
+unsafe fn higher_half_entry() -> ! {
+    higher_half::disable_identity_map();
+
     extern "Rust" {
         fn main() -> !;
     }
@@ -65,65 +117,95 @@ unsafe fn reset() -> ! {
     main()
 }
 
-/// Prepare and execute transition from EL2 to EL1.
-#[inline]
-fn setup_and_enter_el1_from_el2() -> ! {
+/// Entrypoint of the processor.
+///
+/// Parks all cores except core0, which records the device tree pointer from
+/// x0, drops to EL1 and continues with `reset()`.
+#[link_section = ".text.boot"]
+#[no_mangle]
+pub unsafe extern "C" fn _boot_cores(dtb: usize) -> ! {
     use cortex_a::{asm, regs::*};
 
-    const STACK_START: u64 = 0x80_000;
+    extern "C" {
+        // Top of the kernel stack, reserved in the linker script
+        static __kernel_stack_end: u64;
+    }
+
+    const CORE_0: u64 = 0;
+    const CORE_MASK: u64 = 0x3;
 
-    // Enable timer counter registers for EL1
-    CNTHCTL_EL2.write(CNTHCTL_EL2::EL1PCEN::SET + CNTHCTL_EL2::EL1PCTEN::SET);
+    if CORE_0 == MPIDR_EL1.get() & CORE_MASK {
+        BOOT_DTB.store(dtb, Ordering::Relaxed);
+        exception_level::transition_to_el1(&__kernel_stack_end as *const _ as u64, reset)
+    }
 
-    // No offset for reading the counters
-    CNTVOFF_EL2.set(0);
+    // if not core0, infinitely wait for events
+    loop {
+        asm::wfe();
+    }
+}
+
+/// The secondary cores' counterpart of `reset()`.
+///
+/// The BSS was zeroed and the early tables filled by the boot core long ago.
+/// Only the FPU, which the firmware leaves trapped, and the MMU need to be
+/// switched on.
+unsafe fn secondary_reset() -> ! {
+    use cortex_a::barrier;
 
-    // Set EL1 execution state to AArch64
-    HCR_EL2.write(HCR_EL2::RW::EL1IsAarch64);
+    // CPACR_EL1.FPEN: Do not trap FP and SIMD instructions
+    asm!("msr CPACR_EL1, $0" :: "r"(0b11u64 << 20) :: "volatile");
+    barrier::isb(barrier::SY);
 
-    // Set up a simulated exception return.
-    //
-    // First, fake a saved program status, where all interrupts were
-    // masked and SP_EL1 was used as a stack pointer.
-    SPSR_EL2.write(
-        SPSR_EL2::D::Masked
-            + SPSR_EL2::A::Masked
-            + SPSR_EL2::I::Masked
-            + SPSR_EL2::F::Masked
-            + SPSR_EL2::M::EL1h,
-    );
+    higher_half::enable_mmu_and_jump_secondary(secondary_higher_half_entry)
+}
 
-    // Second, let the link register point to reset().
-    ELR_EL2.set(reset as *const () as u64);
+unsafe fn secondary_higher_half_entry() -> ! {
+    higher_half::disable_identity_map();
 
-    // Set up SP_EL1 (stack pointer), which will be used by EL1 once
-    // we "return" to it.
-    SP_EL1.set(STACK_START);
+    extern "Rust" {
+        fn secondary_main() -> !;
+    }
 
-    // Use `eret` to "return" to EL1. This will result in execution of
-    // `reset()` in EL1.
-    asm::eret()
+    secondary_main()
 }
 
-/// Entrypoint of the processor.
+/// Entrypoint of a secondary core, once its address was written to the core's
+/// spin table mailbox. Runs at its physical address, like `_boot_cores()`.
 ///
-/// Parks all cores except core0 and checks if we started in EL2. If
-/// so, proceeds with setting up EL1.
+/// Each of the cores 1 to 3 gets a stack and an exception stack of its own,
+/// reserved in the linker script, before it drops to EL1 and continues with
+/// `secondary_reset()`.
 #[link_section = ".text.boot"]
 #[no_mangle]
-pub unsafe extern "C" fn _boot_cores() -> ! {
-    use cortex_a::{asm, regs::*};
+pub unsafe extern "C" fn _secondary_entry() -> ! {
+    use cortex_a::regs::*;
+
+    extern "C" {
+        static __secondary_stacks_start: u64;
+        static __secondary_stacks_end: u64;
+        static __secondary_exception_stacks_start: u64;
+        static __secondary_exception_stacks_end: u64;
+    }
 
-    const CORE_0: u64 = 0;
     const CORE_MASK: u64 = 0x3;
-    const EL2: u32 = CurrentEL::EL::EL2.value;
+    const SECONDARY_CORES: u64 = 3;
 
-    if (CORE_0 == MPIDR_EL1.get() & CORE_MASK) && (EL2 == CurrentEL.get()) {
-        setup_and_enter_el1_from_el2()
-    }
+    let core = MPIDR_EL1.get() & CORE_MASK;
 
-    // if not core0 or EL != 2, infinitely wait for events
-    loop {
-        asm::wfe();
-    }
+    // There is no stack yet, so keep this simple: Core n uses the n-th of
+    // three equally sized stacks, which ends at `start + n * size`. The guard
+    // page at the bottom of each is part of the size.
+    let stacks = &__secondary_stacks_start as *const _ as u64;
+    let stack_size = (&__secondary_stacks_end as *const _ as u64 - stacks) / SECONDARY_CORES;
+
+    let exc_stacks = &__secondary_exception_stacks_start as *const _ as u64;
+    let exc_stack_size =
+        (&__secondary_exception_stacks_end as *const _ as u64 - exc_stacks) / SECONDARY_CORES;
+
+    exception_level::transition_to_el1_with_stacks(
+        stacks + core * stack_size,
+        exc_stacks + core * exc_stack_size,
+        secondary_reset,
+    )
 }
@@ -0,0 +1,117 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+//! EMMC (SDHCI) computations
+//!
+//! There is no EMMC driver yet. This is the part of it that needs no card.
+
+/// Largest value of the 10 bit SDCLK divider
+const MAX_DIVIDER: u32 = 0x3FF;
+
+/// Compute the SDCLK divider N for a card clock of at most `target` Hz from
+/// the EMMC base clock of `base` Hz, which the firmware reports for
+/// `Clock::Emmc`.
+///
+/// In the 10 bit divided clock mode of SDHCI 3.0, the card runs at
+/// base / (2 * N), or at the base clock itself for N = 0. N is rounded up, so
+/// that the card is never clocked faster than asked for. `None` if even the
+/// largest divider is too fast, or `target` is zero.
+pub fn clock_divider(base: u32, target: u32) -> Option<u32> {
+    if target == 0 {
+        return None;
+    }
+
+    if target >= base {
+        return Some(0);
+    }
+
+    let div = (u64::from(base) + 2 * u64::from(target) - 1) / (2 * u64::from(target));
+    if div > u64::from(MAX_DIVIDER) {
+        return None;
+    }
+
+    Some(div as u32)
+}
+
+/// The card clock in Hz that divider `div` yields from `base` Hz.
+pub fn sd_clock(base: u32, div: u32) -> u32 {
+    if div == 0 {
+        base
+    } else {
+        base / (2 * div)
+    }
+}
+
+/// The divider `div` as the CLK_FREQ8 (bits 15:8) and CLK_FREQ_MS2 (bits 7:6)
+/// fields of CONTROL1.
+pub fn control1_divider_bits(div: u32) -> u32 {
+    ((div & 0xFF) << 8) | (((div >> 8) & 0x3) << 6)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact_rates() {
+        const BASE: u32 = 100_000_000;
+
+        // Card identification, default speed, high speed, and the base clock
+        assert_eq!(clock_divider(BASE, 400_000), Some(125));
+        assert_eq!(clock_divider(BASE, 25_000_000), Some(2));
+        assert_eq!(clock_divider(BASE, 50_000_000), Some(1));
+        assert_eq!(clock_divider(BASE, BASE), Some(0));
+        assert_eq!(clock_divider(BASE, 2 * BASE), Some(0));
+
+        assert_eq!(sd_clock(BASE, 125), 400_000);
+        assert_eq!(sd_clock(BASE, 0), BASE);
+    }
+
+    #[test]
+    fn rounds_to_the_slower_clock() {
+        // The base clock of the Pi 3's firmware
+        const BASE: u32 = 250_000_000;
+
+        // 312.5 would be 400 kHz exactly, 313 is just below
+        let div = clock_divider(BASE, 400_000).unwrap();
+        assert_eq!(div, 313);
+        assert!(sd_clock(BASE, div) <= 400_000);
+        assert!(sd_clock(BASE, div - 1) > 400_000);
+    }
+
+    #[test]
+    fn rates_that_do_not_fit() {
+        // base / (2 * 0x3FF) is the slowest clock
+        assert_eq!(clock_divider(200_000_000, 97_800), Some(0x3FF));
+        assert_eq!(clock_divider(200_000_000, 97_700), None);
+        assert_eq!(clock_divider(200_000_000, 0), None);
+    }
+
+    #[test]
+    fn control1_fields() {
+        assert_eq!(control1_divider_bits(0), 0);
+        assert_eq!(control1_divider_bits(125), 0x7D00);
+        assert_eq!(control1_divider_bits(0x155), 0x5540);
+        assert_eq!(control1_divider_bits(MAX_DIVIDER), 0xFFC0);
+    }
+}
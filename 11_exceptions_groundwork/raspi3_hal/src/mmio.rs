@@ -0,0 +1,273 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Where the drivers find their registers
+//!
+//! Every driver gets the address of its registers from `base()`. In the
+//! kernel, that is the physical address of the peripheral, passed through
+//! the function that `set_map()` installed, i.e. `memory::map_mmio()`.
+//!
+//! In unit tests on the host, `base()` points to a page of RAM per peripheral
+//! instead. RAM does not change by itself like registers do, so `mod test`
+//! can also install a hook that runs before each `read()`, e.g. to clear the
+//! mailbox's FULL flag after a few polls.
+
+use core::ptr;
+
+#[cfg(not(feature = "rpi4"))]
+pub const MMIO_BASE: usize = 0x3F00_0000;
+#[cfg(feature = "rpi4")]
+pub const MMIO_BASE: usize = 0xFE00_0000;
+
+// ARM local peripherals (core timers, core interrupt routing, ...).
+#[cfg(not(feature = "rpi4"))]
+pub const LOCAL_CTRL_BASE: usize = 0x4000_0000;
+#[cfg(feature = "rpi4")]
+pub const LOCAL_CTRL_BASE: usize = 0xFF80_0000;
+
+/// The peripherals that have a driver
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Peripheral {
+    SysTimer,
+    Dma,
+    IrqCtrl,
+    VideocoreMbox,
+    /// The power management block, which has the watchdog
+    Pm,
+    ClockManager,
+    Rng,
+    Gpio,
+    Pl011Uart,
+    Spi0,
+    Pwm,
+    MiniUart,
+    I2c1,
+    LocalCtrl,
+    /// The GIC-400 of the Pi 4, within the ARM local peripherals
+    #[cfg(feature = "rpi4")]
+    Gicd,
+    #[cfg(feature = "rpi4")]
+    Gicc,
+}
+
+impl Peripheral {
+    /// The physical address of the registers
+    #[rustfmt::skip]
+    pub fn phys(self) -> usize {
+        match self {
+            Peripheral::SysTimer      => MMIO_BASE + 0x0000_3000,
+            Peripheral::Dma           => MMIO_BASE + 0x0000_7000,
+            Peripheral::IrqCtrl       => MMIO_BASE + 0x0000_B200,
+            Peripheral::VideocoreMbox => MMIO_BASE + 0x0000_B880,
+            Peripheral::Pm            => MMIO_BASE + 0x0010_0000,
+            Peripheral::ClockManager  => MMIO_BASE + 0x0010_1000,
+            Peripheral::Rng           => MMIO_BASE + 0x0010_4000,
+            Peripheral::Gpio          => MMIO_BASE + 0x0020_0000,
+            Peripheral::Pl011Uart     => MMIO_BASE + 0x0020_1000,
+            Peripheral::Spi0          => MMIO_BASE + 0x0020_4000,
+            Peripheral::Pwm           => MMIO_BASE + 0x0020_C000,
+            Peripheral::MiniUart      => MMIO_BASE + 0x0021_5000,
+            Peripheral::I2c1          => MMIO_BASE + 0x0080_4000,
+            Peripheral::LocalCtrl     => LOCAL_CTRL_BASE,
+            #[cfg(feature = "rpi4")]
+            Peripheral::Gicd          => LOCAL_CTRL_BASE + 0x0004_1000,
+            #[cfg(feature = "rpi4")]
+            Peripheral::Gicc          => LOCAL_CTRL_BASE + 0x0004_2000,
+        }
+    }
+}
+
+/// Turns a physical MMIO address into the one that the CPU uses
+pub type Map = fn(usize) -> usize;
+
+fn identity(phys: usize) -> usize {
+    phys
+}
+
+static mut MAP: Map = identity;
+
+/// Pass all physical addresses through `map` from now on.
+///
+/// Until this is called, `base()` returns them unchanged, which is right as
+/// long as the MMU is off.
+///
+/// # Safety
+///
+/// Must not race with `base()`, i.e. be called before the other cores run.
+pub unsafe fn set_map(map: Map) {
+    MAP = map;
+}
+
+/// The address of the registers of `p`
+#[cfg(not(test))]
+pub fn base(p: Peripheral) -> *mut u32 {
+    unsafe { MAP(p.phys()) as *mut u32 }
+}
+
+/// The address of the RAM that stands in for the registers of `p`
+#[cfg(test)]
+pub fn base(p: Peripheral) -> *mut u32 {
+    test::page(p)
+}
+
+/// Read the register at byte `offset` of `p`
+pub fn read(p: Peripheral, offset: usize) -> u32 {
+    #[cfg(test)]
+    test::before_read(p, offset);
+
+    unsafe { ptr::read_volatile(base(p).add(offset / 4)) }
+}
+
+/// Write `value` to the register at byte `offset` of `p`
+pub fn write(p: Peripheral, offset: usize, value: u32) {
+    unsafe { ptr::write_volatile(base(p).add(offset / 4), value) }
+}
+
+/// RAM instead of registers, for unit tests on the host
+///
+/// Each test runs in a thread of its own, so the pages are thread-local. That
+/// way, every test starts with all registers zero and without hooks.
+#[cfg(test)]
+pub mod test {
+    use super::Peripheral;
+    use std::{boxed::Box, cell::RefCell, vec::Vec};
+
+    /// The size of the RAM behind each peripheral, in u32s
+    pub const PAGE_WORDS: usize = 1024;
+
+    type Hook = Box<dyn FnMut(usize, &mut [u32])>;
+
+    struct Page {
+        peripheral: Peripheral,
+        ram: Box<[u32; PAGE_WORDS]>,
+        hook: Option<Hook>,
+    }
+
+    std::thread_local! {
+        // const initializers are newer than the kernel's nightly
+        #[allow(clippy::missing_const_for_thread_local)]
+        static PAGES: RefCell<Vec<Page>> = RefCell::new(Vec::new());
+    }
+
+    /// Run `f` on the page of `p`, which is zeroed when first used
+    fn with_page<F, R>(p: Peripheral, f: F) -> R
+    where
+        F: FnOnce(&mut Page) -> R,
+    {
+        PAGES.with(|pages| {
+            let mut pages = pages.borrow_mut();
+
+            let i = match pages.iter().position(|page| page.peripheral == p) {
+                Some(i) => i,
+                None => {
+                    pages.push(Page {
+                        peripheral: p,
+                        ram: Box::new([0; PAGE_WORDS]),
+                        hook: None,
+                    });
+                    pages.len() - 1
+                }
+            };
+
+            f(&mut pages[i])
+        })
+    }
+
+    pub(super) fn page(p: Peripheral) -> *mut u32 {
+        with_page(p, |page| page.ram.as_mut_ptr())
+    }
+
+    pub(super) fn before_read(p: Peripheral, offset: usize) {
+        with_page(p, |page| {
+            if let Some(hook) = page.hook.as_mut() {
+                hook(offset, &mut page.ram[..]);
+            }
+        })
+    }
+
+    /// Set the register at byte `offset` of `p`, without running any hook
+    pub fn set(p: Peripheral, offset: usize, value: u32) {
+        with_page(p, |page| page.ram[offset / 4] = value)
+    }
+
+    /// The register at byte `offset` of `p`, without running any hook
+    pub fn get(p: Peripheral, offset: usize) -> u32 {
+        with_page(p, |page| page.ram[offset / 4])
+    }
+
+    /// Call `hook` before each `read()` of `p`, with the offset that is read
+    /// and the RAM of `p`, which it may change.
+    ///
+    /// The hook must not access any register through `mmio` itself.
+    pub fn on_read<F>(p: Peripheral, hook: F)
+    where
+        F: FnMut(usize, &mut [u32]) + 'static,
+    {
+        with_page(p, |page| page.hook = Some(Box::new(hook)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peripherals_have_separate_pages() {
+        write(Peripheral::Gpio, 0x1C, 1 << 21);
+
+        assert_eq!(read(Peripheral::Gpio, 0x1C), 1 << 21);
+        assert_eq!(read(Peripheral::Pl011Uart, 0x1C), 0);
+        assert_eq!(test::get(Peripheral::Gpio, 0x1C), 1 << 21);
+    }
+
+    #[test]
+    fn read_hook_toggles_a_flag() {
+        // The TXFF flag in FR of the PL011 goes away on every other poll
+        const FR: usize = 0x18;
+        const TXFF: u32 = 1 << 5;
+
+        test::set(Peripheral::Pl011Uart, FR, TXFF);
+        test::on_read(Peripheral::Pl011Uart, |offset, ram| {
+            if offset == FR {
+                ram[FR / 4] ^= TXFF;
+            }
+        });
+
+        assert_eq!(read(Peripheral::Pl011Uart, FR), 0);
+        assert_eq!(read(Peripheral::Pl011Uart, FR), TXFF);
+        assert_eq!(read(Peripheral::Pl011Uart, FR), 0);
+
+        // Only reads run the hook
+        write(Peripheral::Pl011Uart, FR, TXFF);
+        assert_eq!(test::get(Peripheral::Pl011Uart, FR), TXFF);
+    }
+
+    #[test]
+    #[cfg(not(feature = "rpi4"))]
+    fn pi3_addresses() {
+        assert_eq!(Peripheral::VideocoreMbox.phys(), 0x3F00_B880);
+        assert_eq!(Peripheral::Pl011Uart.phys(), 0x3F20_1000);
+        assert_eq!(Peripheral::LocalCtrl.phys(), 0x4000_0000);
+    }
+}
@@ -0,0 +1,87 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! PL011 UART computations
+
+/// Compute the IBRD and FBRD values for `baud` at a UART clock of `clock` Hz.
+///
+/// The divisor is clock / (16 * baud), with 6 fractional bits in FBRD. `None`
+/// if it does not fit into IBRD, or if the resulting baud rate is off by more
+/// than 2%.
+pub fn baud_divisors(clock: u32, baud: u32) -> Option<(u32, u32)> {
+    if baud == 0 {
+        return None;
+    }
+
+    let clock = u64::from(clock);
+    let baud = u64::from(baud);
+
+    // 64 * clock / (16 * baud), rounded to nearest
+    let div = (clock * 8 / baud + 1) / 2;
+    let ibrd = div >> 6;
+    let fbrd = div & 0x3F;
+
+    if ibrd == 0 || ibrd > 0xFFFF {
+        return None;
+    }
+
+    let actual = clock * 4 / div;
+    let deviation = if actual > baud {
+        actual - baud
+    } else {
+        baud - actual
+    };
+
+    if deviation * 50 > baud {
+        return None;
+    }
+
+    Some((ibrd as u32, fbrd as u32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The UART clock that the kernel asks the firmware for
+    const CLOCK: u32 = 48_000_000;
+
+    #[test]
+    fn common_rates() {
+        // 26.042 and 3.255, with the fraction in 64ths
+        assert_eq!(baud_divisors(CLOCK, 115_200), Some((26, 3)));
+        assert_eq!(baud_divisors(CLOCK, 921_600), Some((3, 16)));
+
+        // Exactly 312.5
+        assert_eq!(baud_divisors(CLOCK, 9_600), Some((312, 32)));
+    }
+
+    #[test]
+    fn rates_that_do_not_fit() {
+        // IBRD would be 0, or exceed its 16 bits
+        assert_eq!(baud_divisors(CLOCK, 4_000_000), None);
+        assert_eq!(baud_divisors(CLOCK, 10), None);
+        assert_eq!(baud_divisors(CLOCK, 0), None);
+    }
+}
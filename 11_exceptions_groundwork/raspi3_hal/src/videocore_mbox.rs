@@ -0,0 +1,673 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! The Videocore mailbox, and the messages of its property channel
+//!
+//! `send()` and `receive()` do the register part of a call. Caches and the
+//! buffer's memory are the kernel's business, it combines all of this in
+//! `VideocoreMbox`, which is the `Mailbox` that `PropertyMessage::call()`
+//! goes through.
+
+use crate::mmio::{self, Peripheral};
+use core::sync::atomic::{compiler_fence, Ordering};
+
+// Register offsets
+const READ: usize = 0x00;
+const STATUS: usize = 0x18;
+const WRITE: usize = 0x20;
+
+// Flags in STATUS
+const FULL: u32 = 1 << 31;
+const EMPTY: u32 = 1 << 30;
+
+// Custom errors
+#[derive(Debug, PartialEq)]
+pub enum VideocoreMboxError {
+    /// The firmware did not process the request. Carries the response code,
+    /// e.g. 0x8000_0001 for a malformed buffer.
+    ResponseError(u32),
+    UnknownError,
+    /// The Videocore did not react in time
+    Timeout,
+    /// A response arrived that does not belong to our request
+    ChannelMismatch,
+    TooManyTags,
+    BufferTooSmall,
+    /// The firmware did not answer the tag with this ID properly
+    TagRejected(u32),
+    RateOutOfRange,
+}
+pub type Result<T> = ::core::result::Result<T, VideocoreMboxError>;
+
+// Channels
+pub mod channel {
+    pub const PROP: u32 = 8;
+}
+
+// Tags
+pub mod tag {
+    pub const GETCLKRATE: u32 = 0x30002;
+    pub const GETMAXCLKRATE: u32 = 0x30004;
+    pub const GETTEMP: u32 = 0x30006;
+    pub const GETMINCLKRATE: u32 = 0x30007;
+    pub const GETMAXTEMP: u32 = 0x3000A;
+    pub const GETEDIDBLOCK: u32 = 0x30020;
+    pub const GETTHROTTLED: u32 = 0x30046;
+    pub const SETCLKRATE: u32 = 0x38002;
+    pub const GETBOARDREV: u32 = 0x10002;
+    pub const GETMACADDR: u32 = 0x10003;
+    pub const GETSERIAL: u32 = 0x10004;
+    pub const GETARMMEM: u32 = 0x10005;
+    pub const GETVCMEM: u32 = 0x10006;
+    pub const GETCMDLINE: u32 = 0x50001;
+    pub const GETDMACHANNELS: u32 = 0x60001;
+    pub const SETGPIOSTATE: u32 = 0x38041;
+    pub const LAST: u32 = 0;
+}
+
+// Tag request and response codes
+pub mod tag_code {
+    pub const REQUEST: u32 = 0;
+    pub const RESPONSE: u32 = 0x8000_0000;
+}
+
+// Responses
+pub mod response {
+    pub const SUCCESS: u32 = 0x8000_0000;
+}
+
+pub const REQUEST: u32 = 0;
+
+/// Poll `cond` until it returns true, or until `expired` does.
+///
+/// `cond` is checked at least once, even if `expired` is true from the start.
+fn poll<C, E>(cond: C, expired: E) -> Result<()>
+where
+    C: Fn() -> bool,
+    E: Fn() -> bool,
+{
+    loop {
+        if cond() {
+            return Ok(());
+        }
+
+        if expired() {
+            return Err(VideocoreMboxError::Timeout);
+        }
+    }
+}
+
+/// Hand the message at bus address `msg` to the Videocore on `channel`,
+/// once there is room in the mailbox. Gives up when `expired` returns true.
+///
+/// `msg` must be 16-byte aligned, its lower four bits carry the channel.
+pub fn send<E>(channel: u32, msg: u32, expired: E) -> Result<()>
+where
+    E: Fn() -> bool,
+{
+    poll(|| mmio::read(Peripheral::VideocoreMbox, STATUS) & FULL == 0, expired)?;
+
+    mmio::write(Peripheral::VideocoreMbox, WRITE, (msg & !0xF) | (channel & 0xF));
+
+    Ok(())
+}
+
+/// Wait for the answer to the message that `send()` handed over. Gives up
+/// when `expired` returns true.
+///
+/// The Videocore answers with the same address and channel. Only one call is
+/// ever in flight, so anything else means that the mailbox is out of sync.
+pub fn receive<E>(channel: u32, msg: u32, expired: E) -> Result<()>
+where
+    E: Fn() -> bool,
+{
+    poll(|| mmio::read(Peripheral::VideocoreMbox, STATUS) & EMPTY == 0, expired)?;
+
+    let resp = mmio::read(Peripheral::VideocoreMbox, READ);
+    if (resp & 0xF) != channel || (resp & !0xF) != msg {
+        return Err(VideocoreMboxError::ChannelMismatch);
+    }
+
+    Ok(())
+}
+
+/// What `PropertyMessage::call()` sends its message through
+pub trait Mailbox {
+    /// The message buffer
+    fn buffer(&mut self) -> &mut [u32];
+
+    /// Make a call on `channel` with the message in `buffer()`, and check that
+    /// the firmware processed it.
+    fn call(&mut self, channel: u32) -> Result<()>;
+}
+
+/// Clocks that can be queried and set with property tags
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Clock {
+    Emmc = 1,
+    Uart = 2,
+    Arm = 3,
+    Core = 4,
+    V3d = 5,
+    H264 = 6,
+    Isp = 7,
+    Sdram = 8,
+    Pixel = 9,
+    Pwm = 10,
+}
+
+/// A property tag, as the request that is sent to the firmware
+#[derive(Copy, Clone, Debug)]
+pub enum Tag {
+    GetBoardRevision,
+    GetBoardMacAddress,
+    GetBoardSerial,
+    GetArmMemory,
+    GetVcMemory,
+    GetClockRate { clock: Clock },
+    GetMaxClockRate { clock: Clock },
+    GetMinClockRate { clock: Clock },
+    SetClockRate { clock: Clock, rate: u32, skip_turbo: bool },
+    SetGpioState { pin: u32, state: bool },
+    GetTemperature,
+    GetMaxTemperature,
+    GetThrottled,
+    GetDmaChannels,
+}
+
+/// The decoded response to a `Tag`, in the same order as the tags were added
+#[derive(Copy, Clone, Debug)]
+pub enum Response {
+    BoardRevision(u32),
+    MacAddress([u8; 6]),
+    BoardSerial(u64),
+    Memory(MemoryRegion),
+    ClockRate { clock: u32, rate: u32 },
+    GpioState { pin: u32, status: u32 },
+    /// In thousandths of a degree Celsius
+    Temperature(u32),
+    Throttled(u32),
+    /// Bit n is set if DMA channel n may be used by the ARM
+    DmaChannels(u32),
+}
+
+impl Tag {
+    fn id(&self) -> u32 {
+        match self {
+            Tag::GetBoardRevision => tag::GETBOARDREV,
+            Tag::GetBoardMacAddress => tag::GETMACADDR,
+            Tag::GetBoardSerial => tag::GETSERIAL,
+            Tag::GetArmMemory => tag::GETARMMEM,
+            Tag::GetVcMemory => tag::GETVCMEM,
+            Tag::GetClockRate { .. } => tag::GETCLKRATE,
+            Tag::GetMaxClockRate { .. } => tag::GETMAXCLKRATE,
+            Tag::GetMinClockRate { .. } => tag::GETMINCLKRATE,
+            Tag::SetClockRate { .. } => tag::SETCLKRATE,
+            Tag::SetGpioState { .. } => tag::SETGPIOSTATE,
+            Tag::GetTemperature => tag::GETTEMP,
+            Tag::GetMaxTemperature => tag::GETMAXTEMP,
+            Tag::GetThrottled => tag::GETTHROTTLED,
+            Tag::GetDmaChannels => tag::GETDMACHANNELS,
+        }
+    }
+
+    /// Request values, and how many of them are used
+    fn request(&self) -> ([u32; 3], usize) {
+        match *self {
+            Tag::GetBoardRevision
+            | Tag::GetBoardMacAddress
+            | Tag::GetBoardSerial
+            | Tag::GetArmMemory
+            | Tag::GetVcMemory
+            | Tag::GetDmaChannels => ([0; 3], 0),
+            Tag::GetClockRate { clock }
+            | Tag::GetMaxClockRate { clock }
+            | Tag::GetMinClockRate { clock } => ([clock as u32, 0, 0], 1),
+            Tag::SetClockRate {
+                clock,
+                rate,
+                skip_turbo,
+            } => ([clock as u32, rate, skip_turbo as u32], 3),
+            Tag::SetGpioState { pin, state } => ([pin, state as u32, 0], 2),
+            // ID zero is the only temperature sensor
+            Tag::GetTemperature | Tag::GetMaxTemperature | Tag::GetThrottled => ([0; 3], 1),
+        }
+    }
+
+    /// Number of u32 values in the response
+    fn response_len(&self) -> usize {
+        match self {
+            Tag::GetBoardRevision | Tag::GetThrottled | Tag::GetDmaChannels => 1,
+            _ => 2,
+        }
+    }
+
+    /// Size of the value buffer in u32s, which must fit request and response
+    fn value_len(&self) -> usize {
+        let (_, req_len) = self.request();
+
+        ::core::cmp::max(req_len, self.response_len())
+    }
+
+    fn decode(&self, v: &[u32]) -> Response {
+        match self {
+            Tag::GetBoardRevision => Response::BoardRevision(v[0]),
+            Tag::GetBoardMacAddress => {
+                let (lo, hi) = (v[0].to_le_bytes(), v[1].to_le_bytes());
+                Response::MacAddress([lo[0], lo[1], lo[2], lo[3], hi[0], hi[1]])
+            }
+            Tag::GetBoardSerial => Response::BoardSerial(u64::from(v[1]) << 32 | u64::from(v[0])),
+            Tag::GetArmMemory | Tag::GetVcMemory => Response::Memory(MemoryRegion {
+                base: v[0] as usize,
+                size: v[1] as usize,
+            }),
+            Tag::GetClockRate { .. }
+            | Tag::GetMaxClockRate { .. }
+            | Tag::GetMinClockRate { .. }
+            | Tag::SetClockRate { .. } => Response::ClockRate {
+                clock: v[0],
+                rate: v[1],
+            },
+            Tag::SetGpioState { .. } => Response::GpioState {
+                pin: v[0],
+                status: v[1],
+            },
+            Tag::GetTemperature | Tag::GetMaxTemperature => Response::Temperature(v[1]),
+            Tag::GetThrottled => Response::Throttled(v[0]),
+            Tag::GetDmaChannels => Response::DmaChannels(v[0]),
+        }
+    }
+}
+
+/// Maximum number of tags in one `PropertyMessage`
+const MAX_TAGS: usize = 8;
+
+/// Builds the buffer of a property channel call from typed tags, so that
+/// nobody has to count buffer indices by hand:
+///
+/// ```ignore
+/// let resp = PropertyMessage::new()
+///     .with(Tag::GetClockRate { clock: Clock::Uart })
+///     .call(&mut v_mbox)?;
+/// ```
+///
+/// For tags that are not modeled yet, fill `Mailbox::buffer()` and use
+/// `Mailbox::call()` directly.
+pub struct PropertyMessage {
+    tags: [Option<Tag>; MAX_TAGS],
+    len: usize,
+    overflow: bool,
+}
+
+impl Default for PropertyMessage {
+    fn default() -> PropertyMessage {
+        PropertyMessage::new()
+    }
+}
+
+impl PropertyMessage {
+    pub fn new() -> PropertyMessage {
+        PropertyMessage {
+            tags: [None; MAX_TAGS],
+            len: 0,
+            overflow: false,
+        }
+    }
+
+    /// Append a tag to the message
+    pub fn with(mut self, tag: Tag) -> PropertyMessage {
+        if self.len < MAX_TAGS {
+            self.tags[self.len] = Some(tag);
+            self.len += 1;
+        } else {
+            self.overflow = true;
+        }
+
+        self
+    }
+
+    fn tags(&self) -> impl Iterator<Item = &Tag> {
+        self.tags[..self.len].iter().filter_map(|t| t.as_ref())
+    }
+
+    /// Serialize all tags into one buffer, make a single call and decode the
+    /// responses.
+    ///
+    /// Tags that depend on each other, like the physical and virtual size of a
+    /// framebuffer, must be sent in one message for the firmware to accept
+    /// them.
+    ///
+    /// Fails with `TagRejected` if the firmware did not answer a tag, or
+    /// answered it with fewer values than expected.
+    pub fn call<M: Mailbox>(&self, mbox: &mut M) -> Result<Responses> {
+        self.serialize(mbox.buffer())?;
+
+        // Insert a compiler fence that ensures that all stores to the mbox
+        // buffer are finished before the GPU is signaled (which is done by a
+        // store operation as well).
+        compiler_fence(Ordering::Release);
+
+        mbox.call(channel::PROP)?;
+
+        self.decode(mbox.buffer())
+    }
+
+    /// Write the request into `buf`, and return how many u32s of it are used.
+    pub fn serialize(&self, buf: &mut [u32]) -> Result<usize> {
+        if self.overflow {
+            return Err(VideocoreMboxError::TooManyTags);
+        }
+
+        let total: usize = 2 + self.tags().map(|t| 3 + t.value_len()).sum::<usize>() + 1;
+        if total > buf.len() {
+            return Err(VideocoreMboxError::BufferTooSmall);
+        }
+
+        buf[0] = (total * 4) as u32;
+        buf[1] = REQUEST;
+
+        let mut i = 2;
+        for t in self.tags() {
+            let (req, req_len) = t.request();
+            let value_len = t.value_len();
+
+            buf[i] = t.id();
+            buf[i + 1] = (value_len * 4) as u32;
+            buf[i + 2] = tag_code::REQUEST;
+            for (j, v) in buf[i + 3..i + 3 + value_len].iter_mut().enumerate() {
+                *v = if j < req_len { req[j] } else { 0 };
+            }
+
+            i += 3 + value_len;
+        }
+        buf[i] = tag::LAST;
+
+        Ok(total)
+    }
+
+    /// Decode the responses that the firmware wrote over the request in `buf`.
+    pub fn decode(&self, buf: &[u32]) -> Result<Responses> {
+        let mut resp = Responses {
+            items: [None; MAX_TAGS],
+            len: 0,
+        };
+
+        let mut i = 2;
+        for t in self.tags() {
+            let value_len = t.value_len();
+            let code = buf[i + 2];
+            // in bytes, e.g. 6 for the MAC address
+            let resp_len = ((code & !tag_code::RESPONSE) as usize + 3) / 4;
+
+            if buf[i] != t.id() || code & tag_code::RESPONSE == 0 || resp_len < t.response_len() {
+                return Err(VideocoreMboxError::TagRejected(t.id()));
+            }
+
+            resp.items[resp.len] = Some((t.id(), t.decode(&buf[i + 3..i + 3 + value_len])));
+            resp.len += 1;
+            i += 3 + value_len;
+        }
+
+        Ok(resp)
+    }
+}
+
+/// The responses of a `PropertyMessage`, one per tag
+pub struct Responses {
+    items: [Option<(u32, Response)>; MAX_TAGS],
+    len: usize,
+}
+
+impl Responses {
+    /// The response to the `n`-th tag
+    pub fn get(&self, n: usize) -> Option<Response> {
+        self.items[..self.len].get(n).and_then(|r| r.map(|(_, r)| r))
+    }
+
+    /// The response to the first tag with ID `tag_id`, see `mod tag`
+    pub fn by_id(&self, tag_id: u32) -> Option<Response> {
+        self.iter().find(|&(id, _)| id == tag_id).map(|(_, r)| r)
+    }
+
+    /// All responses, together with the ID of their tag
+    pub fn iter(&self) -> impl Iterator<Item = (u32, Response)> + '_ {
+        self.items[..self.len].iter().filter_map(|r| *r)
+    }
+}
+
+/// A memory region as reported by the firmware
+#[derive(Copy, Clone, Debug)]
+pub struct MemoryRegion {
+    pub base: usize,
+    pub size: usize,
+}
+
+/// The part of the SDRAM that belongs to the ARM cores
+pub type ArmRegion = MemoryRegion;
+
+/// The part of the SDRAM that the firmware keeps for the Videocore. Its size
+/// is set by `gpu_mem` in config.txt.
+pub type VcRegion = MemoryRegion;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mmio::test;
+    use core::cell::Cell;
+
+    /// A message buffer at a bus address like the kernel's
+    const MSG: u32 = 0xC020_0040;
+
+    #[test]
+    fn send_waits_until_not_full() {
+        test::set(Peripheral::VideocoreMbox, STATUS, FULL);
+
+        // The Videocore takes the previous message on the third poll
+        let mut polls = 0;
+        test::on_read(Peripheral::VideocoreMbox, move |offset, ram| {
+            assert_eq!(ram[WRITE / 4], 0, "written while FULL");
+
+            if offset == STATUS {
+                polls += 1;
+                if polls == 3 {
+                    ram[STATUS / 4] &= !FULL;
+                }
+            }
+        });
+
+        assert_eq!(send(channel::PROP, MSG, || false), Ok(()));
+        assert_eq!(test::get(Peripheral::VideocoreMbox, WRITE), MSG | channel::PROP);
+    }
+
+    #[test]
+    fn send_times_out() {
+        test::set(Peripheral::VideocoreMbox, STATUS, FULL);
+
+        let checks = Cell::new(0);
+        let expired = || {
+            checks.set(checks.get() + 1);
+            checks.get() > 5
+        };
+
+        assert_eq!(send(channel::PROP, MSG, expired), Err(VideocoreMboxError::Timeout));
+        assert_eq!(test::get(Peripheral::VideocoreMbox, WRITE), 0);
+    }
+
+    #[test]
+    fn send_checks_once_even_if_expired() {
+        assert_eq!(send(channel::PROP, MSG, || true), Ok(()));
+    }
+
+    #[test]
+    fn receive_waits_for_the_answer() {
+        test::set(Peripheral::VideocoreMbox, STATUS, EMPTY);
+        test::on_read(Peripheral::VideocoreMbox, |offset, ram| {
+            if offset == STATUS {
+                ram[READ / 4] = MSG | channel::PROP;
+                ram[STATUS / 4] &= !EMPTY;
+            }
+        });
+
+        assert_eq!(receive(channel::PROP, MSG, || false), Ok(()));
+    }
+
+    #[test]
+    fn receive_rejects_other_answers() {
+        test::set(Peripheral::VideocoreMbox, READ, MSG | 1);
+        assert_eq!(
+            receive(channel::PROP, MSG, || false),
+            Err(VideocoreMboxError::ChannelMismatch)
+        );
+
+        test::set(Peripheral::VideocoreMbox, READ, (MSG + 0x10) | channel::PROP);
+        assert_eq!(
+            receive(channel::PROP, MSG, || false),
+            Err(VideocoreMboxError::ChannelMismatch)
+        );
+    }
+
+    /// Answers calls with `answer`, like the firmware would
+    struct Firmware {
+        buffer: [u32; 32],
+        calls: usize,
+        answer: fn(&mut [u32]),
+    }
+
+    impl Firmware {
+        fn new(answer: fn(&mut [u32])) -> Firmware {
+            Firmware {
+                buffer: [0xFFFF_FFFF; 32],
+                calls: 0,
+                answer,
+            }
+        }
+    }
+
+    impl Mailbox for Firmware {
+        fn buffer(&mut self) -> &mut [u32] {
+            &mut self.buffer
+        }
+
+        fn call(&mut self, channel: u32) -> Result<()> {
+            assert_eq!(channel, channel::PROP);
+            self.calls += 1;
+            (self.answer)(&mut self.buffer);
+
+            match self.buffer[1] {
+                response::SUCCESS => Ok(()),
+                code => Err(VideocoreMboxError::ResponseError(code)),
+            }
+        }
+    }
+
+    #[test]
+    fn set_clock_rate() {
+        let msg = PropertyMessage::new().with(Tag::SetClockRate {
+            clock: Clock::Arm,
+            rate: 1_200_000_000,
+            skip_turbo: true,
+        });
+
+        let mut fw = Firmware::new(|buf| {
+            assert_eq!(
+                buf[..9],
+                [36, REQUEST, tag::SETCLKRATE, 12, 0, 3, 1_200_000_000, 1, tag::LAST]
+            );
+
+            // The firmware grants less, and answers with two values
+            buf[1] = response::SUCCESS;
+            buf[4] = tag_code::RESPONSE | 8;
+            buf[6] = 1_000_000_000;
+        });
+
+        let resp = msg.call(&mut fw).unwrap();
+        assert_eq!(fw.calls, 1);
+        match resp.get(0) {
+            Some(Response::ClockRate {
+                clock: 3,
+                rate: 1_000_000_000,
+            }) => (),
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn board_serial_and_mac_address() {
+        let mut fw = Firmware::new(|buf| {
+            buf[1] = response::SUCCESS;
+            buf[4] = tag_code::RESPONSE | 8;
+            buf[5] = 0x89AB_CDEF;
+            buf[6] = 0x0123_4567;
+            buf[9] = tag_code::RESPONSE | 6;
+            buf[10] = 0x3322_11B8;
+            buf[11] = 0x0000_5544;
+        });
+
+        let resp = PropertyMessage::new()
+            .with(Tag::GetBoardSerial)
+            .with(Tag::GetBoardMacAddress)
+            .call(&mut fw)
+            .unwrap();
+
+        match resp.by_id(tag::GETSERIAL) {
+            Some(Response::BoardSerial(0x0123_4567_89AB_CDEF)) => (),
+            r => panic!("{:?}", r),
+        }
+        match resp.by_id(tag::GETMACADDR) {
+            Some(Response::MacAddress([0xB8, 0x11, 0x22, 0x33, 0x44, 0x55])) => (),
+            r => panic!("{:?}", r),
+        }
+        assert_eq!(resp.iter().count(), 2);
+    }
+
+    #[test]
+    fn failed_calls() {
+        // The firmware did not understand the buffer at all
+        let mut fw = Firmware::new(|buf| buf[1] = 0x8000_0001);
+        assert_eq!(
+            PropertyMessage::new().with(Tag::GetBoardRevision).call(&mut fw).err(),
+            Some(VideocoreMboxError::ResponseError(0x8000_0001))
+        );
+
+        // A temperature without the value
+        let mut fw = Firmware::new(|buf| {
+            buf[1] = response::SUCCESS;
+            buf[4] = tag_code::RESPONSE | 4;
+        });
+        assert_eq!(
+            PropertyMessage::new().with(Tag::GetTemperature).call(&mut fw).err(),
+            Some(VideocoreMboxError::TagRejected(tag::GETTEMP))
+        );
+    }
+
+    #[test]
+    fn too_many_tags() {
+        let msg = (0..=MAX_TAGS).fold(PropertyMessage::new(), |msg, _| {
+            msg.with(Tag::GetBoardRevision)
+        });
+
+        let mut fw = Firmware::new(|_| panic!("called"));
+        assert_eq!(msg.call(&mut fw).err(), Some(VideocoreMboxError::TooManyTags));
+    }
+}
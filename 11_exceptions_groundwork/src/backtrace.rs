@@ -0,0 +1,109 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Stack backtraces by frame pointer.
+//!
+//! All lessons are built with `-C force-frame-pointers=yes`. Every function
+//! then pushes a frame record, its caller's `x29` and its own `x30`, and
+//! points `x29` to it. Following the saved `x29`s from record to record
+//! yields the return addresses of all callers.
+//!
+//! The walk must not fault, because it runs when things went wrong already.
+//! So each frame pointer is checked to be 16 byte aligned and within the
+//! stack that the walk started on before it is dereferenced.
+
+use crate::{eprintln, memory, stack_guard, task};
+
+/// Frames printed at most, in case the chain loops or is very deep.
+const MAX_FRAMES: usize = 16;
+
+extern "C" {
+    static __text_start: u64;
+    static __text_end: u64;
+}
+
+/// The stack that `fp` points into, as start and end addresses.
+fn stack_bounds(fp: usize) -> Option<(usize, usize)> {
+    if let Some((start, end)) = task::current_stack() {
+        if start <= fp && fp < end {
+            return Some((start, end));
+        }
+    }
+
+    memory::stack_region(fp).map(|r| (r.start, r.end + 1))
+}
+
+/// Print the return addresses of the frame chain starting at `fp`, e.g. the
+/// `x29` of an exception context or `cpu::frame_pointer()`.
+///
+/// Addresses in the kernel code are also printed relative to `__text_start`,
+/// for `addr2line` on the host.
+pub fn print(fp: usize) {
+    let (text_start, text_end) = unsafe {
+        (
+            &__text_start as *const _ as usize,
+            &__text_end as *const _ as usize,
+        )
+    };
+
+    eprintln!("      Backtrace:");
+
+    let (start, end) = match stack_bounds(fp) {
+        Some(bounds) => bounds,
+        None => {
+            eprintln!("        FP {:#018X} is not on a known stack.", fp);
+            return;
+        }
+    };
+
+    let mut fp = fp;
+    for i in 0..MAX_FRAMES {
+        if fp % 16 != 0 || fp < start || fp + 16 > end || stack_guard::guard_owner(fp).is_some() {
+            return;
+        }
+
+        let record = unsafe { core::slice::from_raw_parts(fp as *const usize, 2) };
+        let (next_fp, lr) = (record[0], record[1]);
+
+        // The outermost frames have no caller
+        if lr == 0 {
+            return;
+        }
+
+        if text_start <= lr && lr < text_end {
+            eprintln!("        #{:<2} {:#018X} (__text_start + {:#X})", i, lr, lr - text_start);
+        } else {
+            eprintln!("        #{:<2} {:#018X}", i, lr);
+        }
+
+        // Callers' frames are further up the stack. Anything else is a
+        // broken chain.
+        if next_fp <= fp {
+            return;
+        }
+        fp = next_fp;
+    }
+
+    eprintln!("        ...");
+}
@@ -0,0 +1,142 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+
+//! What the kernel found out about the hardware, in one place.
+//!
+//! `sysinfo()` asks the firmware and the drivers, and `print()` prints the
+//! result as the banner at the end of the boot. The `sysinfo` shell command
+//! prints it again. Every entry is queried on its own and with a timeout, so
+//! whatever is missing, e.g. most of it in QEMU, shows up as "not present"
+//! instead of stalling the rest.
+
+use crate::{
+    devices::hw::videocore_mbox::{self, clock, BoardInfo, Clock, VideocoreMbox},
+    memory, rand, walltime,
+};
+use core::fmt;
+use cortex_a::regs::*;
+
+/// Shorter than the default, so that a firmware that does not answer holds up
+/// the banner by a fraction of a second per entry only.
+const MBOX_TIMEOUT_US: u64 = 100_000;
+
+/// The hardware inventory
+#[derive(Copy, Clone, Debug)]
+pub struct SysInfo {
+    /// Model, revision, serial and the memory split, from the firmware
+    pub board: Option<BoardInfo>,
+    /// Physical address of the peripherals, see `memory::mmio_base`
+    pub peripheral_base: usize,
+    pub exception_level: u32,
+    pub arm_clock_hz: Option<u32>,
+    pub core_clock_hz: Option<u32>,
+    pub emmc_clock_hz: Option<u32>,
+    pub hw_rng: bool,
+    pub rtc: bool,
+}
+
+/// A clock rate, where zero means that the clock does not exist.
+fn clock_rate(v_mbox: Option<&mut VideocoreMbox>, clock: Clock) -> Option<u32> {
+    match clock::get_rate(v_mbox?, clock) {
+        Ok(0) | Err(_) => None,
+        Ok(hz) => Some(hz),
+    }
+}
+
+/// Gather the inventory.
+pub fn sysinfo() -> SysInfo {
+    let mut v_mbox = VideocoreMbox::new().ok();
+    if let Some(v_mbox) = v_mbox.as_mut() {
+        v_mbox.set_timeout(MBOX_TIMEOUT_US);
+    }
+
+    SysInfo {
+        board: v_mbox
+            .as_mut()
+            .and_then(|v_mbox| videocore_mbox::board_info(v_mbox).ok()),
+        peripheral_base: memory::mmio_base::base(),
+        exception_level: ((CurrentEL.get() >> 2) & 0x3) as u32,
+        arm_clock_hz: clock_rate(v_mbox.as_mut(), Clock::Arm),
+        core_clock_hz: clock_rate(v_mbox.as_mut(), Clock::Core),
+        emmc_clock_hz: clock_rate(v_mbox.as_mut(), Clock::Emmc),
+        hw_rng: rand::hw_rng_present(),
+        rtc: walltime::rtc_present(),
+    }
+}
+
+/// Print the inventory of `sysinfo()`.
+pub fn print() {
+    println!("{}", sysinfo());
+}
+
+/// A clock rate in MHz, or "not present"
+struct Mhz(Option<u32>);
+
+impl fmt::Display for Mhz {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(hz) => write!(f, "{}.{:03} MHz", hz / 1_000_000, (hz / 1000) % 1000),
+            None => write!(f, "not present"),
+        }
+    }
+}
+
+fn present(found: bool) -> &'static str {
+    if found {
+        "present"
+    } else {
+        "not present"
+    }
+}
+
+/// One line per entry
+impl fmt::Display for SysInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.board {
+            Some(b) => {
+                writeln!(f, "Board:           {} (rev {:#010x})", b.model(), b.revision)?;
+                writeln!(f, "Serial:          {:016x}", b.serial)?;
+                writeln!(
+                    f,
+                    "Memory:          ARM {} MiB at {:#010x}, VC {} MiB at {:#010x}",
+                    b.arm_memory.size >> 20,
+                    b.arm_memory.base,
+                    b.vc_memory.size >> 20,
+                    b.vc_memory.base
+                )?;
+            }
+
+            None => writeln!(f, "Board:           not present (no mailbox answer)")?,
+        }
+
+        writeln!(f, "Peripherals:     {:#010x}", self.peripheral_base)?;
+        writeln!(f, "Exception level: EL{}", self.exception_level)?;
+        writeln!(f, "ARM clock:       {}", Mhz(self.arm_clock_hz))?;
+        writeln!(f, "Core clock:      {}", Mhz(self.core_clock_hz))?;
+        writeln!(f, "EMMC clock:      {}", Mhz(self.emmc_clock_hz))?;
+        writeln!(f, "Hardware RNG:    {}", present(self.hw_rng))?;
+        write!(f, "RTC:             {}", present(self.rtc))
+    }
+}
@@ -0,0 +1,250 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Log records as checksummed binary frames, for capture on the host.
+//!
+//! With `console=binlog` on the command line, the log macros no longer print
+//! text lines, but send one frame per record over the PL011 UART. Decode
+//! them with `utils/binlog_decode.rb`. A record is, little-endian:
+//!
+//! ```text
+//! magic: u16 | len: u16 | level: u8 | timestamp_us: u64 | module_id: u16
+//! payload: [u8; len] | crc16: u16
+//! ```
+//!
+//! `crc16` is CRC-16/CCITT-FALSE over everything before it. The record is
+//! COBS encoded, so that it contains no zero byte, and a zero byte goes out
+//! before and after it. Plain `println!` output is not a record and keeps
+//! going out as text in between, the decoder passes it through.
+//!
+//! Module paths are not sent with each record. Instead, `init()` sends one
+//! dictionary record with level 0 for each entry of `MODULES`, which maps
+//! the id to the path. A module that is not in the table gets the id of its
+//! longest parent that is.
+
+use crate::{
+    devices::virt::{ConsoleOps, Output},
+    log::{self, Level},
+    time,
+};
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+pub const MAGIC: u16 = 0xB10C;
+
+/// The level byte of dictionary records
+pub const DICTIONARY: u8 = 0;
+
+/// Longer messages are cut off.
+pub const MAX_PAYLOAD: usize = 200;
+
+const HEADER_LEN: usize = 15;
+const CRC_LEN: usize = 2;
+const MAX_RECORD: usize = HEADER_LEN + MAX_PAYLOAD + CRC_LEN;
+
+/// COBS adds one byte per started 254 bytes
+pub const MAX_FRAME: usize = MAX_RECORD + MAX_RECORD / 254 + 1;
+
+/// The module ids, by their index. Only append, so that old captures still
+/// decode with a new dictionary.
+static MODULES: &[&str] = &[
+    "kernel8",
+    "backtrace",
+    "cmdline",
+    "devices",
+    "exception",
+    "executor",
+    "gdbstub",
+    "interrupt",
+    "led",
+    "log",
+    "memory",
+    "memory::mmu",
+    "rand",
+    "shell",
+    "smp",
+    "task",
+    "thermal",
+    "timer",
+    "walltime",
+    "workqueue",
+];
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Send log records as frames from now on, starting with the dictionary.
+///
+/// Must only be called once the PL011 UART is the console.
+pub fn init() {
+    ENABLED.store(true, Ordering::Relaxed);
+
+    for (id, path) in MODULES.iter().enumerate() {
+        send_record(DICTIONARY, id as u16, path.as_bytes());
+    }
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// The id of the module that `module_path!()` names.
+pub fn module_id(module: &str) -> u16 {
+    let module = log::strip_crate(module);
+
+    MODULES
+        .iter()
+        .enumerate()
+        .filter(|(_, path)| log::is_within(module, path))
+        .max_by_key(|(_, path)| path.len())
+        .map_or(0, |(id, _)| id as u16)
+}
+
+/// CRC-16/CCITT-FALSE: Polynomial 0x1021, initial value 0xFFFF, no
+/// reflection. "123456789" gives 0x29B1.
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xFFFF, |crc, &byte| {
+        let mut crc = crc ^ (u16::from(byte) << 8);
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+
+        crc
+    })
+}
+
+/// COBS encode `data` into `out`, without the terminating zero. Returns the
+/// encoded length, or None if `out` is too small.
+pub fn cobs_encode(data: &[u8], out: &mut [u8]) -> Option<usize> {
+    let mut code_at = 0;
+    let mut len = 1;
+
+    for &byte in data {
+        if byte != 0 {
+            *out.get_mut(len)? = byte;
+            len += 1;
+        }
+
+        let code = len - code_at;
+        if byte == 0 || code == 0xFF {
+            *out.get_mut(code_at)? = code as u8;
+            code_at = len;
+            len += 1;
+        }
+    }
+
+    *out.get_mut(code_at)? = (len - code_at) as u8;
+
+    Some(len)
+}
+
+/// Assemble a record from its fields and COBS encode it into `out`. Returns
+/// the length of the frame, without the zero bytes around it.
+pub fn encode_record(
+    level: u8,
+    timestamp_us: u64,
+    module_id: u16,
+    payload: &[u8],
+    out: &mut [u8; MAX_FRAME],
+) -> usize {
+    let payload = &payload[..payload.len().min(MAX_PAYLOAD)];
+
+    let mut record = [0u8; MAX_RECORD];
+    record[0..2].copy_from_slice(&MAGIC.to_le_bytes());
+    record[2..4].copy_from_slice(&(payload.len() as u16).to_le_bytes());
+    record[4] = level;
+    record[5..13].copy_from_slice(&timestamp_us.to_le_bytes());
+    record[13..15].copy_from_slice(&module_id.to_le_bytes());
+
+    let end = HEADER_LEN + payload.len();
+    record[HEADER_LEN..end].copy_from_slice(payload);
+
+    let crc = crc16(&record[..end]);
+    record[end..end + CRC_LEN].copy_from_slice(&crc.to_le_bytes());
+
+    // MAX_FRAME fits the longest record
+    cobs_encode(&record[..end + CRC_LEN], out).unwrap_or(0)
+}
+
+/// A `fmt::Write` into a fixed buffer, that drops what does not fit.
+struct Payload {
+    buf: [u8; MAX_PAYLOAD],
+    len: usize,
+}
+
+impl fmt::Write for Payload {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(MAX_PAYLOAD - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+
+        Ok(())
+    }
+}
+
+/// Send a frame with the current uptime. Returns false if the console is not
+/// the PL011 UART.
+fn send_record(level: u8, module_id: u16, payload: &[u8]) -> bool {
+    let mut frame = [0u8; MAX_FRAME];
+    let len = encode_record(level, time::uptime(), module_id, payload, &mut frame);
+
+    let mut sent = false;
+    crate::macros::with_console(|con| {
+        if let Output::PL011Uart(uart) = con.output() {
+            for part in &[&[0u8][..], &frame[..len], &[0u8][..]] {
+                let mut done = 0;
+                while done < part.len() {
+                    done += uart.send_nonblocking(&part[done..]);
+                }
+            }
+
+            // Errors are flushed for the same reason as the text ones
+            if level == Level::Error as u8 {
+                con.flush();
+            }
+
+            sent = true;
+        }
+    });
+
+    sent
+}
+
+/// Send a log record. Returns false if it could not be sent, so that the
+/// caller prints it as text instead.
+#[doc(hidden)]
+pub fn _log(level: Level, module: &str, args: fmt::Arguments) -> bool {
+    let mut payload = Payload {
+        buf: [0; MAX_PAYLOAD],
+        len: 0,
+    };
+    let _ = payload.write_fmt(args);
+
+    send_record(level as u8, module_id(module), &payload.buf[..payload.len])
+}
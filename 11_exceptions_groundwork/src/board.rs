@@ -0,0 +1,113 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! The board the kernel is built for.
+//!
+//! The Pi 3 and the Pi 4 differ in more than the peripheral base. The Pi 4's
+//! BCM2711 moves the ARM local peripherals, has a GIC-400 in front of the
+//! cores instead of the BCM interrupt controller, runs the core clock at
+//! 500 MHz and replaces the GPIO pull-up/down sequence by plain registers.
+//! Those differences are picked at build time with the `rpi4` feature,
+//! everything else finds out here which board it is compiled for.
+//!
+//! At runtime, `check()` compares the board revision from the firmware with
+//! the build, because a kernel for the wrong board would only half work.
+
+use crate::devices::hw::videocore_mbox::{PropertyMessage, Response, Tag, VideocoreMbox};
+
+/// Supported boards
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Board {
+    /// Pi 3B, 3B+, 3A+ and the Pi 2B v1.2, all based on the BCM2837
+    Rpi3,
+    /// Pi 4B, based on the BCM2711
+    Rpi4,
+}
+
+impl Board {
+    pub fn name(self) -> &'static str {
+        match self {
+            Board::Rpi3 => "Raspberry Pi 3",
+            Board::Rpi4 => "Raspberry Pi 4",
+        }
+    }
+
+    /// The SoC that a board revision code names, if it is a supported one.
+    ///
+    /// New-style revision codes have bit 23 set and the processor in bits 12
+    /// to 15. All old-style codes are BCM2835 boards.
+    pub fn from_revision(revision: u32) -> Option<Board> {
+        if revision & (1 << 23) == 0 {
+            return None;
+        }
+
+        match (revision >> 12) & 0xF {
+            2 => Some(Board::Rpi3),
+            3 => Some(Board::Rpi4),
+            _ => None,
+        }
+    }
+}
+
+/// The board this kernel is built for.
+#[cfg(not(feature = "rpi4"))]
+pub const BOARD: Board = Board::Rpi3;
+#[cfg(feature = "rpi4")]
+pub const BOARD: Board = Board::Rpi4;
+
+/// Clock of the VPU core, which drives the mini UART, with `enable_uart=1` in
+/// `config.txt`.
+#[cfg(not(feature = "rpi4"))]
+pub const CORE_CLOCK_HZ: u32 = 250_000_000;
+#[cfg(feature = "rpi4")]
+pub const CORE_CLOCK_HZ: u32 = 500_000_000;
+
+#[derive(Debug)]
+pub enum BoardError {
+    /// The revision code names a board that is not supported at all.
+    Unknown(u32),
+    /// The kernel is built for a different board than it runs on.
+    Mismatch(Board),
+}
+pub type Result<T> = ::core::result::Result<T, BoardError>;
+
+/// Query the board revision code from the firmware.
+pub fn revision(v_mbox: &mut VideocoreMbox) -> Option<u32> {
+    let resp = PropertyMessage::new().with(Tag::GetBoardRevision).call(v_mbox);
+
+    match resp.ok().and_then(|r| r.get(0)) {
+        Some(Response::BoardRevision(rev)) => Some(rev),
+        _ => None,
+    }
+}
+
+/// Check that the board with `revision` is the one that the kernel is built
+/// for.
+pub fn check(revision: u32) -> Result<()> {
+    match Board::from_revision(revision) {
+        Some(board) if board == BOARD => Ok(()),
+        Some(board) => Err(BoardError::Mismatch(board)),
+        None => Err(BoardError::Unknown(revision)),
+    }
+}
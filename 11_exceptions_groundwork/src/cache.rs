@@ -0,0 +1,126 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+//! Cache and TLB maintenance.
+//!
+//! With the MMU on, DRAM is cached. Whenever a device reads memory that the CPU
+//! wrote, or the other way round, the affected lines must be cleaned from or
+//! invalidated in the data cache first. All addresses are virtual ones, and the
+//! range operations work to the Point of Coherency, where the CPU and the
+//! devices see the same data.
+
+use crate::cpu;
+use cortex_a::barrier;
+
+/// The size of the smallest data cache line of all caches in the system.
+pub fn dcache_line_size() -> usize {
+    // CTR_EL0.DminLine is the log2 of the number of words in a line.
+    4 << ((cpu::regs::CTR_EL0.get() >> 16) & 0xF)
+}
+
+/// Issue `op` for each data cache line that overlaps `[addr, addr + len)`, and
+/// wait for all of them to complete.
+fn for_each_dcache_line<F: Fn(usize, bool)>(addr: usize, len: usize, op: F) {
+    if len == 0 {
+        return;
+    }
+
+    let line = dcache_line_size();
+    let end = addr + len;
+
+    let mut line_addr = addr & !(line - 1);
+    while line_addr < end {
+        // Whether the line lies completely inside the range
+        let whole = line_addr >= addr && line_addr + line <= end;
+
+        op(line_addr, whole);
+        line_addr += line;
+    }
+
+    unsafe { barrier::dsb(barrier::SY) };
+}
+
+/// Write dirty lines of `[addr, addr + len)` back to memory, e.g. before a
+/// device reads data that the CPU wrote.
+pub fn clean_dcache_range(addr: usize, len: usize) {
+    for_each_dcache_line(addr, len, |a, _| unsafe {
+        asm!("dc cvac, $0" :: "r"(a) :: "volatile")
+    });
+}
+
+/// Discard the cached lines of `[addr, addr + len)`, e.g. before the CPU reads
+/// data that a device wrote.
+///
+/// Lines that are only partially covered by the range are cleaned and
+/// invalidated instead, so that the data next to the range is not lost.
+pub fn invalidate_dcache_range(addr: usize, len: usize) {
+    for_each_dcache_line(addr, len, |a, whole| unsafe {
+        if whole {
+            asm!("dc ivac, $0" :: "r"(a) :: "volatile")
+        } else {
+            asm!("dc civac, $0" :: "r"(a) :: "volatile")
+        }
+    });
+}
+
+/// Write dirty lines of `[addr, addr + len)` back to memory and discard them,
+/// e.g. for a buffer that a device reads and then overwrites.
+pub fn clean_invalidate_dcache_range(addr: usize, len: usize) {
+    for_each_dcache_line(addr, len, |a, _| unsafe {
+        asm!("dc civac, $0" :: "r"(a) :: "volatile")
+    });
+}
+
+/// Discard the whole instruction cache, e.g. after code was written to memory.
+///
+/// The code must have been cleaned from the data cache before.
+pub fn invalidate_icache_all() {
+    unsafe {
+        asm!("ic iallu" :::: "volatile");
+        barrier::dsb(barrier::SY);
+        barrier::isb(barrier::SY);
+    }
+}
+
+/// Throw away all cached EL1 translations, e.g. after changing page tables.
+///
+/// New table entries are made visible to the table walker first.
+pub fn tlb_invalidate_all() {
+    unsafe {
+        barrier::dsb(barrier::SY);
+        asm!("tlbi vmalle1" :::: "volatile");
+        barrier::dsb(barrier::SY);
+        barrier::isb(barrier::SY);
+    }
+}
+
+/// Throw away the cached translations of the 4 KiB page at `va`, for all
+/// ASIDs.
+pub fn tlb_invalidate_page(va: usize) {
+    unsafe {
+        barrier::dsb(barrier::SY);
+        asm!("tlbi vaae1, $0" :: "r"(va >> 12) :: "volatile");
+        barrier::dsb(barrier::SY);
+        barrier::isb(barrier::SY);
+    }
+}
@@ -0,0 +1,122 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Boot-time options from cmdline.txt, so that behavior can be changed without
+//! rebuilding the kernel.
+//!
+//! The command line is fetched once from the mailbox with `init()`. It is a
+//! list of whitespace separated `key=value` pairs and plain flags. Of the
+//! options that the kernel understands, the last occurrence wins:
+//!
+//! - `loglevel=LEVEL[,MODULE=LEVEL...]`: How much log output to print, see the
+//!   `log` module. `LEVEL` is one of `quiet`, `error`, `warn`, `info`, `debug`
+//!   and `trace`.
+//! - `console=pl011|miniuart|fb|binlog`: Which device to use as the console
+//!   after the mailbox is up. `binlog` is the PL011 UART with log records as
+//!   binary frames, see `binlog`.
+//! - `gdb`: Start the GDB stub on the mini UART, and wait for GDB to connect
+//!   before the demos run, see `gdbstub`.
+
+use crate::devices::hw::videocore_mbox::{self, VideocoreMbox};
+use core::{
+    str,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+const MAX_LEN: usize = 1024;
+
+static mut BUF: [u8; MAX_LEN] = [0; MAX_LEN];
+
+/// Length of the command line in `BUF`. Only written by `init()`.
+static LEN: AtomicUsize = AtomicUsize::new(0);
+
+/// Fetch the command line from the firmware. Returns its length.
+pub fn init(v_mbox: &mut VideocoreMbox) -> videocore_mbox::Result<usize> {
+    // Nobody reads BUF before LEN is set
+    let line = videocore_mbox::command_line(v_mbox, unsafe { &mut BUF })?;
+    let len = line.len();
+
+    LEN.store(len, Ordering::Release);
+
+    Ok(len)
+}
+
+/// The whole command line. Empty before `init()`.
+pub fn as_str() -> &'static str {
+    let len = LEN.load(Ordering::Acquire);
+
+    // `command_line()` only returns valid UTF-8
+    unsafe { str::from_utf8_unchecked(&BUF[..len]) }
+}
+
+/// One argument of the command line. `value` is None for plain flags.
+#[derive(Copy, Clone, Debug)]
+pub struct Arg {
+    pub key: &'static str,
+    pub value: Option<&'static str>,
+}
+
+/// Iterate over all arguments, in the order of the command line.
+pub fn args() -> impl Iterator<Item = Arg> {
+    as_str().split_whitespace().map(|arg| {
+        let mut parts = arg.splitn(2, '=');
+
+        Arg {
+            key: parts.next().unwrap_or(""),
+            value: parts.next(),
+        }
+    })
+}
+
+/// The value of the last `key=value` argument with the given key.
+pub fn value(key: &str) -> Option<&'static str> {
+    args().filter(|a| a.key == key).filter_map(|a| a.value).last()
+}
+
+/// Whether the plain flag `key` is on the command line.
+pub fn flag(key: &str) -> bool {
+    args().any(|a| a.key == key && a.value.is_none())
+}
+
+/// The device that should become the console
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ConsoleChoice {
+    /// The PL011 UART, the default
+    PL011Uart,
+    /// Stay on the MiniUart that the kernel starts with
+    MiniUart,
+    /// The framebuffer
+    Framebuffer,
+    /// The PL011 UART, with log records as binary frames
+    BinLog,
+}
+
+pub fn console() -> ConsoleChoice {
+    match value("console") {
+        Some("miniuart") => ConsoleChoice::MiniUart,
+        Some("fb") => ConsoleChoice::Framebuffer,
+        Some("binlog") => ConsoleChoice::BinLog,
+        _ => ConsoleChoice::PL011Uart,
+    }
+}
@@ -0,0 +1,170 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Wrappers for CPU instructions and registers that are not (yet) provided by
+//! the cortex-a crate.
+
+use cortex_a::{asm, regs::*};
+
+pub mod regs;
+
+/// Send Event
+///
+/// Wakes up all cores that sleep in `wfe`.
+#[inline]
+pub fn sev() {
+    unsafe { asm!("sev" :::: "volatile") }
+}
+
+/// Park the executing core for good, sleeping in `wfe` instead of spinning.
+pub fn wait_forever() -> ! {
+    loop {
+        asm::wfe();
+    }
+}
+
+/// The current stack pointer.
+///
+/// Always inlined, so that it is the stack pointer of the caller.
+#[inline(always)]
+pub fn stack_pointer() -> usize {
+    let sp: usize;
+    unsafe { asm!("mov $0, sp" : "=r"(sp) ::: "volatile") };
+
+    sp
+}
+
+/// The current frame pointer, `x29`, which points to the frame record of the
+/// caller.
+///
+/// Always inlined, so that it is the frame pointer of the caller.
+#[inline(always)]
+pub fn frame_pointer() -> usize {
+    let fp: usize;
+    unsafe { asm!("mov $0, x29" : "=r"(fp) ::: "volatile") };
+
+    fp
+}
+
+/// The current link register, i.e. where the calling function returns to.
+///
+/// Always inlined, and only meaningful before the caller made calls of its
+/// own, which overwrite `x30`.
+#[inline(always)]
+pub fn link_register() -> usize {
+    let lr: usize;
+    unsafe { asm!("mov $0, x30" : "=r"(lr) ::: "volatile") };
+
+    lr
+}
+
+/// The number of the core that is executing this code.
+///
+/// Read from TPIDR_EL1, so `init_core_id()` must have run on the core before.
+#[inline]
+pub fn core_id() -> usize {
+    regs::TPIDR_EL1.get() as usize
+}
+
+/// Decode the core number from MPIDR_EL1 once, and keep it in TPIDR_EL1 for
+/// `core_id()`.
+///
+/// Must be the first thing each core does in the kernel, the locks need the
+/// core number already.
+pub fn init_core_id() {
+    const CORE_MASK: u64 = 0x3;
+
+    regs::TPIDR_EL1.set(MPIDR_EL1.get() & CORE_MASK);
+}
+
+/// Unmask IRQs on the executing core.
+#[inline]
+pub fn local_irq_enable() {
+    DAIF.modify(DAIF::I::Unmasked);
+}
+
+/// Mask IRQs on the executing core.
+#[inline]
+pub fn local_irq_disable() {
+    DAIF.modify(DAIF::I::Masked);
+}
+
+/// Unmask FIQs on the executing core.
+#[inline]
+pub fn local_fiq_enable() {
+    DAIF.modify(DAIF::F::Unmasked);
+}
+
+/// Mask FIQs on the executing core.
+#[inline]
+pub fn local_fiq_disable() {
+    DAIF.modify(DAIF::F::Masked);
+}
+
+/// Check if IRQs are masked on the executing core.
+#[inline]
+pub fn local_irq_masked() -> bool {
+    DAIF.is_set(DAIF::I)
+}
+
+/// Mask IRQs and return the previous DAIF value for `local_irq_restore()`.
+#[inline]
+pub fn local_irq_save() -> u32 {
+    let daif = DAIF.get();
+    local_irq_disable();
+
+    daif
+}
+
+/// Restore a DAIF value returned by `local_irq_save()`.
+#[inline]
+pub fn local_irq_restore(daif: u32) {
+    DAIF.set(daif);
+}
+
+/// Execute `f` with IRQs unmasked on the executing core, e.g. to sleep until an
+/// IRQ handler calls `event::notify()`.
+pub fn irq_unmasked<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let daif = DAIF.get();
+    local_irq_enable();
+    let ret = f();
+    local_irq_restore(daif);
+
+    ret
+}
+
+/// Execute `f` with IRQs masked on the executing core.
+pub fn irq_masked<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let daif = local_irq_save();
+    let ret = f();
+    local_irq_restore(daif);
+
+    ret
+}
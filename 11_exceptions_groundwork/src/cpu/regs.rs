@@ -0,0 +1,204 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! System registers missing in the cortex-a crate.
+//!
+//! They implement the same `register::cpu` traits, so they can be used
+//! exactly like the ones from `cortex_a::regs`.
+
+use register::cpu::{RegisterReadOnly, RegisterReadWrite};
+
+macro_rules! sys_reg_rw {
+    ($(#[$attr:meta])* $name:ident, $reg:ident, $asm_name:tt) => {
+        $(#[$attr])*
+        pub struct $reg;
+
+        impl RegisterReadWrite<u64, ()> for $reg {
+            #[inline]
+            fn get(&self) -> u64 {
+                let reg;
+                unsafe { asm!(concat!("mrs $0, ", $asm_name) : "=r"(reg) ::: "volatile") }
+                reg
+            }
+
+            #[inline]
+            fn set(&self, value: u64) {
+                unsafe { asm!(concat!("msr ", $asm_name, ", $0") :: "r"(value) :: "volatile") }
+            }
+        }
+
+        pub static $name: $reg = $reg {};
+    };
+}
+
+macro_rules! sys_reg_ro {
+    ($(#[$attr:meta])* $name:ident, $reg:ident, $asm_name:tt) => {
+        $(#[$attr])*
+        pub struct $reg;
+
+        impl RegisterReadOnly<u64, ()> for $reg {
+            #[inline]
+            fn get(&self) -> u64 {
+                let reg;
+                unsafe { asm!(concat!("mrs $0, ", $asm_name) : "=r"(reg) ::: "volatile") }
+                reg
+            }
+        }
+
+        pub static $name: $reg = $reg {};
+    };
+}
+
+sys_reg_rw!(
+    /// Counter-timer Physical Timer CompareValue register
+    CNTP_CVAL_EL0,
+    CntpCvalEl0,
+    "CNTP_CVAL_EL0"
+);
+
+sys_reg_ro!(
+    /// Cache Type Register
+    CTR_EL0,
+    CtrEl0,
+    "CTR_EL0"
+);
+
+sys_reg_ro!(
+    /// Exception Syndrome Register (EL1)
+    ESR_EL1,
+    EsrEl1,
+    "ESR_EL1"
+);
+
+sys_reg_ro!(
+    /// Fault Address Register (EL1)
+    FAR_EL1,
+    FarEl1,
+    "FAR_EL1"
+);
+
+sys_reg_ro!(
+    /// AArch64 Debug Feature Register 0
+    ID_AA64DFR0_EL1,
+    IdAa64dfr0El1,
+    "ID_AA64DFR0_EL1"
+);
+
+sys_reg_rw!(
+    /// Monitor Debug System Control Register
+    MDSCR_EL1,
+    MdscrEl1,
+    "MDSCR_EL1"
+);
+
+sys_reg_ro!(
+    /// Main ID Register
+    MIDR_EL1,
+    MidrEl1,
+    "MIDR_EL1"
+);
+
+sys_reg_rw!(
+    /// Performance Monitors Control Register
+    PMCR_EL0,
+    PmcrEl0,
+    "PMCR_EL0"
+);
+
+sys_reg_rw!(
+    /// Performance Monitors Count Enable Set register
+    PMCNTENSET_EL0,
+    PmcntensetEl0,
+    "PMCNTENSET_EL0"
+);
+
+sys_reg_rw!(
+    /// Performance Monitors Cycle Count Filter Register
+    PMCCFILTR_EL0,
+    PmccfiltrEl0,
+    "PMCCFILTR_EL0"
+);
+
+sys_reg_rw!(
+    /// Performance Monitors Cycle Count Register
+    PMCCNTR_EL0,
+    PmccntrEl0,
+    "PMCCNTR_EL0"
+);
+
+sys_reg_rw!(
+    /// Performance Monitors Count Enable Clear register
+    PMCNTENCLR_EL0,
+    PmcntenclrEl0,
+    "PMCNTENCLR_EL0"
+);
+
+sys_reg_rw!(
+    /// Performance Monitors Overflow Flag Status Clear Register
+    PMOVSCLR_EL0,
+    PmovsclrEl0,
+    "PMOVSCLR_EL0"
+);
+
+sys_reg_rw!(
+    /// Performance Monitors Event Counter Selection Register
+    PMSELR_EL0,
+    PmselrEl0,
+    "PMSELR_EL0"
+);
+
+sys_reg_rw!(
+    /// Performance Monitors User Enable Register
+    PMUSERENR_EL0,
+    PmuserenrEl0,
+    "PMUSERENR_EL0"
+);
+
+sys_reg_rw!(
+    /// Performance Monitors Selected Event Count Register
+    PMXEVCNTR_EL0,
+    PmxevcntrEl0,
+    "PMXEVCNTR_EL0"
+);
+
+sys_reg_rw!(
+    /// Performance Monitors Selected Event Type Register
+    PMXEVTYPER_EL0,
+    PmxevtyperEl0,
+    "PMXEVTYPER_EL0"
+);
+
+sys_reg_rw!(
+    /// EL1 Software Thread ID Register
+    TPIDR_EL1,
+    TpidrEl1,
+    "TPIDR_EL1"
+);
+
+sys_reg_rw!(
+    /// Translation Table Base Register 1 (EL1)
+    TTBR1_EL1,
+    Ttbr1El1,
+    "TTBR1_EL1"
+);
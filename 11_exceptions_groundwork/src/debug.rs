@@ -0,0 +1,439 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Poor man's debugging without JTAG.
+//!
+//! - `brk #imm` works as a software breakpoint. The handler prints the
+//!   registers and parks the core, unless a hook was registered with
+//!   `set_brk_handler()`. In that case, the hook is called and execution
+//!   resumes after the `brk`.
+//! - `single_step(true)` and `single_step(false)` delimit a region of code
+//!   that is executed one instruction at a time, calling the hook registered
+//!   with `set_step_handler()` after each instruction.
+//!
+//! - `set_watchpoint()` programs one of the hardware watchpoints, which
+//!   traps kernel accesses to a range of up to 8 bytes. The handler prints
+//!   who accessed it, then steps over the access with the watchpoints off, so
+//!   that execution continues.
+//!
+//! Once the `gdbstub` runs, it takes over breakpoints and stepping, see there.
+//!
+//! The debug registers are per core. Watchpoints are set on the calling core
+//! only, and only fire in code that runs with debug exceptions unmasked, which
+//! `set_watchpoint()` does for the code that called it. Exception handlers
+//! always run with them masked.
+
+use crate::{
+    cpu::{self, regs::*},
+    exception::{EsrEL1, ExceptionContext},
+    gdbstub, println, sync,
+};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use cortex_a::{barrier, regs::*};
+
+/// Stepping stops after this many instructions, even without
+/// `single_step(false)`.
+pub const MAX_STEPS: u32 = 10_000;
+
+// MDSCR_EL1: Software step enable, and debug exceptions targeting the current
+// EL (kernel debug enable).
+const MDSCR_SS: u64 = 1 << 0;
+const MDSCR_KDE: u64 = 1 << 13;
+
+// MDSCR_EL1: Monitor debug events, i.e. breakpoints and watchpoints
+const MDSCR_MDE: u64 = 1 << 15;
+
+// SPSR_EL1: Software step, and the debug exception mask
+const SPSR_SS: u64 = 1 << 21;
+const SPSR_D: u64 = 1 << 9;
+
+type Hook = fn(&ExceptionContext);
+
+static BRK_HANDLER: sync::NullLock<Option<Hook>> = sync::NullLock::new(None);
+static STEP_HANDLER: sync::NullLock<Option<Hook>> = sync::NullLock::new(None);
+
+static STEPPING: AtomicBool = AtomicBool::new(false);
+static STEPS: AtomicU32 = AtomicU32::new(0);
+
+/// Most watchpoints that are used. The Cortex-A53 has four, the architecture
+/// allows up to 16.
+pub const MAX_WATCHPOINTS: usize = 4;
+
+// ID_AA64DFR0_EL1: Number of watchpoints, minus one
+const WRPS_SHIFT: u64 = 20;
+const WRPS_MASK: u64 = 0xF;
+
+// DBGWCR<n>_EL1
+const WCR_E: u64 = 1 << 0;
+const WCR_PAC_EL1: u64 = 0b01 << 1; // with SSC and HMC zero, EL1 only
+const WCR_LSC_SHIFT: u64 = 3;
+const WCR_BAS_SHIFT: u64 = 5;
+
+// ESR_EL1 ISS of a watchpoint: Write not Read
+const ISS_WNR: u32 = 1 << 6;
+
+/// Size of a watched range. The address must be aligned to it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WatchLen {
+    Byte = 1,
+    Half = 2,
+    Word = 4,
+    Double = 8,
+}
+
+/// The accesses that a watchpoint traps
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ReadWrite {
+    Read = 0b01,
+    Write = 0b10,
+    Both = 0b11,
+}
+
+#[derive(Debug)]
+pub enum DebugError {
+    /// The address is not aligned to the length
+    Unaligned,
+    /// All watchpoints of the core are in use
+    NoFreeWatchpoint,
+    /// The handle was cleared already
+    InvalidHandle,
+}
+
+pub type Result<T> = ::core::result::Result<T, DebugError>;
+
+/// A watchpoint that is set, from `set_watchpoint()`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct WpHandle(usize);
+
+impl WpHandle {
+    /// The number of the hardware watchpoint
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+/// A watched range, as passed to `set_watchpoint()`
+#[derive(Copy, Clone, Debug)]
+pub struct Watchpoint {
+    pub addr: usize,
+    pub len: WatchLen,
+    pub kind: ReadWrite,
+}
+
+impl Watchpoint {
+    /// Whether an access reported at `far` hit the watched doubleword, which
+    /// is the granularity that the hardware compares.
+    fn covers(&self, far: usize) -> bool {
+        far & !7 == self.addr & !7
+    }
+
+    /// The DBGWCR<n>_EL1 value that enables it
+    fn control(&self) -> u64 {
+        let bas = ((1u64 << self.len as u64) - 1) << (self.addr & 7);
+
+        WCR_E | WCR_PAC_EL1 | ((self.kind as u64) << WCR_LSC_SHIFT) | (bas << WCR_BAS_SHIFT)
+    }
+}
+
+static WATCHPOINTS: sync::NullLock<[Option<Watchpoint>; MAX_WATCHPOINTS]> =
+    sync::NullLock::new([None; MAX_WATCHPOINTS]);
+
+/// The watchpoints are off while the watched access is stepped over.
+static STEPPING_OVER: AtomicBool = AtomicBool::new(false);
+
+/// Call `handler` for each `brk`, and resume execution after it.
+pub fn set_brk_handler(handler: Hook) {
+    cpu::irq_masked(|| BRK_HANDLER.lock(|h| *h = Some(handler)));
+}
+
+/// Call `handler` after each instruction between `single_step(true)` and
+/// `single_step(false)`.
+pub fn set_step_handler(handler: Hook) {
+    cpu::irq_masked(|| STEP_HANDLER.lock(|h| *h = Some(handler)));
+}
+
+/// Start or stop single-stepping the code that follows.
+///
+/// Exception handlers run with debug exceptions masked, so they are not
+/// stepped themselves.
+pub fn single_step(enable: bool) {
+    if !enable {
+        // Stepped itself. The step handler sees the flag and stops re-arming.
+        STEPPING.store(false, Ordering::Relaxed);
+        return;
+    }
+
+    STEPS.store(0, Ordering::Relaxed);
+    STEPPING.store(true, Ordering::Relaxed);
+
+    // Unlock the OS lock, which is set on reset and blocks step exceptions
+    unsafe { asm!("msr OSLAR_EL1, xzr" :::: "volatile") };
+    MDSCR_EL1.set(MDSCR_EL1.get() | MDSCR_KDE | MDSCR_SS);
+
+    // Stepping starts with an exception return that sets SPSR.SS. Fake one
+    // that returns right behind itself, with PSTATE as it is now, except for
+    // debug exceptions being unmasked.
+    let mode = if SPSel.get() & 1 == 0 { 0b0100 } else { 0b0101 };
+    let spsr = (u64::from(DAIF.get()) & !SPSR_D) | SPSR_SS | mode;
+
+    unsafe {
+        barrier::isb(barrier::SY);
+
+        let _tmp: u64;
+        asm!("msr DAIFSet, #0x3
+              adr $0, 1f
+              msr ELR_EL1, $0
+              msr SPSR_EL1, $1
+              eret
+              1:"
+             : "=&r"(_tmp)
+             : "r"(spsr)
+             : "memory"
+             : "volatile");
+    }
+}
+
+/// How many watchpoints can be set, the smaller of what the core has and
+/// `MAX_WATCHPOINTS`.
+pub fn num_watchpoints() -> usize {
+    let wrps = ((ID_AA64DFR0_EL1.get() >> WRPS_SHIFT) & WRPS_MASK) as usize + 1;
+
+    wrps.min(MAX_WATCHPOINTS)
+}
+
+/// Each watchpoint has its own pair of registers, whose number is part of the
+/// instruction.
+fn write_watchpoint(n: usize, wvr: u64, wcr: u64) {
+    macro_rules! set {
+        ($n:tt) => {
+            unsafe {
+                asm!(concat!("msr DBGWVR", $n, "_EL1, $0\n",
+                             "msr DBGWCR", $n, "_EL1, $1")
+                     :: "r"(wvr), "r"(wcr)
+                     :: "volatile")
+            }
+        };
+    }
+
+    match n {
+        0 => set!("0"),
+        1 => set!("1"),
+        2 => set!("2"),
+        3 => set!("3"),
+        _ => (),
+    }
+
+    unsafe { barrier::isb(barrier::SY) };
+}
+
+/// Program all set watchpoints into the hardware, or turn them all off.
+fn load_watchpoints(enable: bool) {
+    let wps = WATCHPOINTS.lock(|wps| *wps);
+
+    for (n, wp) in wps.iter().enumerate() {
+        match wp {
+            Some(wp) if enable => write_watchpoint(n, (wp.addr & !7) as u64, wp.control()),
+            _ => write_watchpoint(n, 0, 0),
+        }
+    }
+}
+
+fn watchpoints_set() -> bool {
+    WATCHPOINTS.lock(|wps| wps.iter().any(|wp| wp.is_some()))
+}
+
+/// Trap `kind` accesses of the kernel to the `len` bytes at `addr`.
+pub fn set_watchpoint(addr: usize, len: WatchLen, kind: ReadWrite) -> Result<WpHandle> {
+    if addr % len as usize != 0 {
+        return Err(DebugError::Unaligned);
+    }
+
+    let available = num_watchpoints();
+    let n = cpu::irq_masked(|| {
+        WATCHPOINTS.lock(|wps| {
+            let n = wps[..available].iter().position(|wp| wp.is_none())?;
+            wps[n] = Some(Watchpoint { addr, len, kind });
+
+            Some(n)
+        })
+    })
+    .ok_or(DebugError::NoFreeWatchpoint)?;
+
+    load_watchpoints(true);
+
+    // Like for stepping, the OS lock blocks watchpoints until it is unlocked
+    unsafe { asm!("msr OSLAR_EL1, xzr" :::: "volatile") };
+    MDSCR_EL1.set(MDSCR_EL1.get() | MDSCR_KDE | MDSCR_MDE);
+
+    // Debug exceptions are masked since boot
+    unsafe { asm!("msr DAIFClr, #0x8" :::: "volatile") };
+
+    Ok(WpHandle(n))
+}
+
+/// Remove a watchpoint that `set_watchpoint()` returned.
+pub fn clear_watchpoint(handle: WpHandle) -> Result<()> {
+    let cleared = cpu::irq_masked(|| {
+        WATCHPOINTS.lock(|wps| match wps.get_mut(handle.0) {
+            Some(wp) if wp.is_some() => {
+                *wp = None;
+                true
+            }
+            _ => false,
+        })
+    });
+    if !cleared {
+        return Err(DebugError::InvalidHandle);
+    }
+
+    write_watchpoint(handle.0, 0, 0);
+    if !watchpoints_set() {
+        MDSCR_EL1.set(MDSCR_EL1.get() & !MDSCR_MDE);
+    }
+
+    Ok(())
+}
+
+/// The handle of watchpoint number `n`, if it is set.
+pub fn watchpoint_handle(n: usize) -> Option<WpHandle> {
+    match WATCHPOINTS.lock(|wps| wps.get(n).map(|wp| wp.is_some())) {
+        Some(true) => Some(WpHandle(n)),
+        _ => None,
+    }
+}
+
+/// All watchpoints, by their number.
+pub fn watchpoints() -> [Option<Watchpoint>; MAX_WATCHPOINTS] {
+    WATCHPOINTS.lock(|wps| *wps)
+}
+
+/// Make the context step one instruction after returning to it.
+pub fn arm_step(e: &mut ExceptionContext) {
+    unsafe { asm!("msr OSLAR_EL1, xzr" :::: "volatile") };
+    MDSCR_EL1.set(MDSCR_EL1.get() | MDSCR_KDE | MDSCR_SS);
+
+    e.spsr_el1 = (e.spsr_el1 & !SPSR_D) | SPSR_SS;
+}
+
+/// Stop stepping the context, and mask debug exceptions in it again, unless
+/// watchpoints need them.
+pub fn disarm_step(e: &mut ExceptionContext) {
+    if watchpoints_set() {
+        MDSCR_EL1.set(MDSCR_EL1.get() & !MDSCR_SS);
+        e.spsr_el1 &= !SPSR_SS;
+        return;
+    }
+
+    MDSCR_EL1.set(MDSCR_EL1.get() & !(MDSCR_KDE | MDSCR_SS));
+    e.spsr_el1 = (e.spsr_el1 & !SPSR_SS) | SPSR_D;
+}
+
+/// Called for the BRK exception class.
+pub fn handle_brk(e: &mut ExceptionContext, imm: u16) {
+    if gdbstub::handle_brk(e) {
+        return;
+    }
+
+    println!("[!] Breakpoint: brk #{:#06X}", imm);
+    println!("{}", e);
+
+    match BRK_HANDLER.lock(|h| *h) {
+        Some(handler) => {
+            handler(e);
+
+            // ELR_EL1 points at the brk itself
+            e.elr_el1 += 4;
+        }
+
+        None => {
+            println!("      No breakpoint handler registered. Halting CPU.");
+            cpu::wait_forever();
+        }
+    }
+}
+
+/// Called for the software step exception class, after each stepped
+/// instruction.
+pub fn handle_software_step(e: &mut ExceptionContext) {
+    if gdbstub::handle_step(e) {
+        return;
+    }
+
+    if STEPPING_OVER.swap(false, Ordering::Relaxed) {
+        // The watched access is done, watch for the next one
+        load_watchpoints(true);
+
+        if !STEPPING.load(Ordering::Relaxed) {
+            disarm_step(e);
+            return;
+        }
+    }
+
+    let steps = STEPS.fetch_add(1, Ordering::Relaxed) + 1;
+
+    if let Some(handler) = STEP_HANDLER.lock(|h| *h) {
+        handler(e);
+    }
+
+    if STEPPING.load(Ordering::Relaxed) && steps < MAX_STEPS {
+        // Step the next instruction, too
+        e.spsr_el1 |= SPSR_SS;
+        return;
+    }
+
+    STEPPING.store(false, Ordering::Relaxed);
+    disarm_step(e);
+}
+
+/// Called for the watchpoint exception class. The accessing instruction has
+/// not been executed yet.
+pub fn handle_watchpoint(e: &mut ExceptionContext, esr: EsrEL1) {
+    let far = FAR_EL1.get() as usize;
+    let access = if esr.iss() & ISS_WNR != 0 {
+        "Write"
+    } else {
+        "Read"
+    };
+
+    let hit = WATCHPOINTS.lock(|wps| {
+        wps.iter()
+            .enumerate()
+            .find_map(|(n, wp)| wp.filter(|wp| wp.covers(far)).map(|wp| (n, wp)))
+    });
+    match hit {
+        Some((n, wp)) => println!(
+            "[!] Watchpoint {}: {:#010X}, {} bytes",
+            n, wp.addr, wp.len as usize
+        ),
+        None => println!("[!] Watchpoint"),
+    }
+    println!("      {} of {:#010X} by the instruction at {:#010X}", access, far, e.elr_el1);
+    println!("{}", e);
+
+    // Let the access happen with the watchpoints off, and turn them back on
+    // after it
+    load_watchpoints(false);
+    STEPPING_OVER.store(true, Ordering::Relaxed);
+    arm_step(e);
+}
@@ -22,7 +22,122 @@
  * SOFTWARE.
  */
 
-use cortex_a::asm;
+use crate::{cpu, devices::hw, event, exception, memory, time, timer};
+use core::{
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+    time::Duration,
+};
+use cortex_a::{asm, barrier};
+use register::cpu::{RegisterReadOnly, RegisterReadWrite};
+
+/*
+ *
+ * Using the CPU's counter registers
+ *
+ */
+/// Wait for the given duration (ARM CPU only)
+pub fn wait(d: Duration) {
+    let start = time::Instant::now();
+
+    while start.elapsed() < d {
+        asm::nop();
+    }
+}
+
+/// Wait N microsec (ARM CPU only)
+///
+/// Compares against the full 64 bit counter, so arbitrarily long waits work.
+pub fn wait_usec(n: u64) {
+    wait(Duration::from_micros(n));
+}
+
+/// Wait N millisec (ARM CPU only)
+pub fn wait_msec(n: u64) {
+    wait(Duration::from_millis(n));
+}
+
+/// Wait N nanosec (ARM CPU only)
+///
+/// The wait is rounded up to whole ticks of the generic timer, which is 52 ns
+/// at 19.2 MHz. Reading the counter adds an overhead of a few tens of CPU
+/// cycles on top, so the shortest achievable delay is one to two ticks.
+pub fn wait_nsec(n: u32) {
+    let ticks = time::duration_to_ticks(Duration::from_nanos(u64::from(n)));
+    let start = time::Instant::now().ticks();
+
+    while time::Instant::now().ticks().wrapping_sub(start) < ticks {
+        asm::nop();
+    }
+}
+
+/// Set by the timer callback when the wait of `wait_usec_irq()` has elapsed.
+static TIMER_FIRED: AtomicBool = AtomicBool::new(false);
+
+fn wake_up() {
+    TIMER_FIRED.store(true, Ordering::Release);
+    event::notify();
+}
+
+/// Wait N microsec (ARM CPU only), sleeping in `wfe` until the timer IRQ fires.
+///
+/// Falls back to the polling `wait_usec()` as long as the exception vectors
+/// are not installed, because nobody would handle the IRQ.
+pub fn wait_usec_irq(n: u64) {
+    if !exception::vectors_installed() {
+        wait_usec(n);
+        return;
+    }
+
+    TIMER_FIRED.store(false, Ordering::Relaxed);
+
+    if timer::schedule_oneshot(Duration::from_micros(n), wake_up).is_none() {
+        wait_usec(n);
+        return;
+    }
+
+    // The callback notifies after setting the flag, so an IRQ that sneaks in
+    // between the check and the wfe can not leave us sleeping forever.
+    cpu::irq_unmasked(|| event::wait_for(|| TIMER_FIRED.load(Ordering::Acquire)));
+
+    // The one-shot callback is gone now, so the timer module already switched
+    // off the comparator if nothing else is scheduled.
+}
+
+/*
+ *
+ * Polling with a timeout
+ *
+ */
+/// Returned by `poll_timeout()` if the condition did not become true in time.
+#[derive(Debug)]
+pub struct TimeoutError;
+
+/// The deadline `from_now_us` microseconds from now, see `SysTmr::deadline()`.
+pub fn deadline(from_now_us: u64) -> hw::Deadline {
+    hw::SysTmr::new(hw::Peripheral::SysTimer).deadline(from_now_us)
+}
+
+/// Poll `cond` until it returns true, or until `deadline` passed.
+///
+/// `cond` is checked at least once, even if the deadline passed already. So a
+/// deadline can bound several polls in a row, and the ones after it passed
+/// still pick up a condition that is true by then.
+pub fn poll_timeout<F>(deadline: hw::Deadline, mut cond: F) -> Result<(), TimeoutError>
+where
+    F: FnMut() -> bool,
+{
+    loop {
+        if cond() {
+            return Ok(());
+        }
+
+        if deadline.expired() {
+            return Err(TimeoutError);
+        }
+
+        asm::nop();
+    }
+}
 
 /*
  *
@@ -30,8 +145,97 @@ use cortex_a::asm;
  *
  */
 /// Wait N CPU cycles (ARM CPU only)
+///
+/// Executes N `nop`s, so it is only a rough approximation of N cycles. See
+/// `wait_cycles_pmu()` for the accurate version.
 pub fn wait_cycles(cyc: u32) {
     for _ in 0..cyc {
         asm::nop();
     }
 }
+
+// States of the PMU cycle counter
+const PMU_UNPROBED: u8 = 0;
+const PMU_AVAILABLE: u8 = 1;
+const PMU_UNAVAILABLE: u8 = 2;
+
+static PMU_STATE: AtomicU8 = AtomicU8::new(PMU_UNPROBED);
+
+/// Enable the PMU cycle counter. Returns false if it can not be used.
+fn enable_cycle_counter() -> bool {
+    use cpu::regs::*;
+
+    const PMUVER_SHIFT: u64 = 8;
+    const PMUVER_MASK: u64 = 0xF;
+    const PMUVER_IMPDEF: u64 = 0xF;
+    const PMCR_E: u64 = 1 << 0; // enable all counters
+    const PMCR_C: u64 = 1 << 2; // reset the cycle counter
+    const PMCR_LC: u64 = 1 << 6; // 64 bit cycle counter
+    const PMCNTEN_C: u64 = 1 << 31;
+
+    // No PMU at all, or an IMPLEMENTATION DEFINED one we know nothing about
+    let pmuver = (ID_AA64DFR0_EL1.get() >> PMUVER_SHIFT) & PMUVER_MASK;
+    if pmuver == 0 || pmuver == PMUVER_IMPDEF {
+        return false;
+    }
+
+    // Count cycles in EL0 and EL1
+    PMCCFILTR_EL0.set(0);
+    PMCR_EL0.set(PMCR_EL0.get() | PMCR_E | PMCR_C | PMCR_LC);
+    PMCNTENSET_EL0.set(PMCNTEN_C);
+    unsafe { barrier::isb(barrier::SY) };
+
+    // Some emulators implement the registers, but never count
+    let start = PMCCNTR_EL0.get();
+    wait_cycles(1000);
+
+    PMCCNTR_EL0.get() != start
+}
+
+/// Read the PMU cycle counter, or None if it is not accessible.
+///
+/// Enables the counter on first use.
+pub fn read_cycles() -> Option<u64> {
+    let available = match PMU_STATE.load(Ordering::Relaxed) {
+        PMU_AVAILABLE => true,
+        PMU_UNAVAILABLE => false,
+        _ => {
+            let available = enable_cycle_counter();
+            let state = if available {
+                PMU_AVAILABLE
+            } else {
+                PMU_UNAVAILABLE
+            };
+            PMU_STATE.store(state, Ordering::Relaxed);
+
+            available
+        }
+    };
+
+    if !available {
+        return None;
+    }
+
+    unsafe { barrier::isb(barrier::SY) };
+    Some(cpu::regs::PMCCNTR_EL0.get())
+}
+
+/// Wait N CPU cycles, as counted by the PMU cycle counter (ARM CPU only)
+///
+/// Falls back to the `nop` loop of `wait_cycles()` if the PMU is not
+/// accessible.
+pub fn wait_cycles_pmu(cyc: u64) {
+    let start = match read_cycles() {
+        Some(start) => start,
+        None => {
+            for _ in 0..cyc {
+                asm::nop();
+            }
+            return;
+        }
+    };
+
+    while cpu::regs::PMCCNTR_EL0.get().wrapping_sub(start) < cyc {
+        asm::nop();
+    }
+}
@@ -22,12 +22,41 @@
  * SOFTWARE.
  */
 
+pub mod audio;
+mod clock_manager;
+pub mod dma;
+pub mod ds18b20;
+pub mod ds3231;
+mod gic400;
 mod gpio;
+mod i2c;
+mod irq_ctrl;
+mod local_ctrl;
 mod mini_uart;
-mod pl011_uart;
-mod videocore_mbox;
+pub mod onewire;
+pub mod pl011_uart;
+mod pwm;
+mod rng;
+mod spi;
+mod sys_timer;
+pub mod videocore_mbox;
+mod watchdog;
 
-pub use gpio::GPIO;
+pub use clock_manager::{ClockManager, GpClock, Mash as ClockMash, Source as ClockSource};
+pub use gpio::{
+    AltFn as GpioAltFn, AltPin, Edge as GpioEdge, Function as GpioFunction, InputPin, OutputPin,
+    Pin, Pull as GpioPull, GPIO,
+};
+pub use gic400::{GicCpuInterface, GicDistributor, SPURIOUS as GIC_SPURIOUS};
+pub use i2c::{I2c, Speed as I2cSpeed};
+pub use irq_ctrl::IrqCtrl;
+pub use local_ctrl::LocalCtrl;
 pub use mini_uart::MiniUart;
 pub use pl011_uart::PL011Uart;
+pub use raspi3_hal::mmio::Peripheral;
+pub use pwm::{Channel as PwmChannel, Mode as PwmMode, Pwm};
+pub use rng::Rng;
+pub use spi::{ChipSelect as SpiCs, Mode as SpiMode, Spi};
+pub use sys_timer::{Channel as SysTmrChannel, Deadline, SysTmr};
 pub use videocore_mbox::VideocoreMbox;
+pub use watchdog::Watchdog;
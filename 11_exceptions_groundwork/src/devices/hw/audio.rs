@@ -0,0 +1,328 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Audio output on the headphone jack, with the PWM.
+//!
+//! The left and right channel of the jack are low-pass filtered PWM outputs:
+//! PWM channel 1 on GPIO40 and channel 2 on GPIO41, both ALT0. Each sample
+//! becomes the duty of one PWM period, so the range is the PWM clock divided
+//! by the sample rate. Mono samples are written to the shared FIFO twice, once
+//! for each channel.
+//!
+//! `play()` feeds the FIFO by the CPU and returns when the sample is done.
+//! `play_async()` lets the DMA engine feed it, paced by the PWM's DREQ, and
+//! returns right away.
+
+use super::{clock_manager, dma, gpio, pwm};
+use crate::{delays, memory};
+use core::{
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use raspi3_hal::mmio::Peripheral;
+
+/// The PWM clock for audio: PLLD divided by 5
+pub const PWM_CLOCK_HZ: u32 = 100_000_000;
+const PWM_CLOCK_DIVISOR: u32 = 5;
+
+pub const MIN_SAMPLE_RATE: u32 = 4_000;
+pub const MAX_SAMPLE_RATE: u32 = 48_000;
+
+/// A 0.4 s beep at 880 Hz, unsigned 8 bit samples at `BEEP_SAMPLE_RATE`
+pub static BEEP: &[u8] = include_bytes!("../../beep.raw");
+pub const BEEP_SAMPLE_RATE: u32 = 8_000;
+
+/// Bytes per DMA control block. Lite channels move at most 64 KiB at once,
+/// and a block must end on a whole sample, i.e. two words.
+const BLOCK_BYTES: usize = 0xFFF8;
+
+#[derive(Debug)]
+pub enum AudioError {
+    /// Outside of `MIN_SAMPLE_RATE..=MAX_SAMPLE_RATE`
+    InvalidSampleRate,
+    /// Another playback is still running
+    Busy,
+    /// GPIO40 or GPIO41 is owned by someone else
+    PinInUse,
+    /// More samples than one DMA chain can move, or none at all
+    InvalidLength,
+    /// No DMA memory left for the samples
+    OutOfMemory,
+    /// The FIFO did not drain, e.g. because the PWM clock is not running
+    Timeout,
+    Pwm(pwm::PwmError),
+    Dma(dma::DmaError),
+}
+pub type Result<T> = ::core::result::Result<T, AudioError>;
+
+impl From<pwm::PwmError> for AudioError {
+    fn from(e: pwm::PwmError) -> AudioError {
+        AudioError::Pwm(e)
+    }
+}
+
+impl From<dma::DmaError> for AudioError {
+    fn from(e: dma::DmaError) -> AudioError {
+        AudioError::Dma(e)
+    }
+}
+
+/// Set while a playback owns the PWM
+static PLAYING: AtomicBool = AtomicBool::new(false);
+
+/// Set by the DMA completion IRQ of `play_async()`
+static DMA_DONE: AtomicBool = AtomicBool::new(false);
+
+fn dma_done() {
+    DMA_DONE.store(true, Ordering::Release);
+}
+
+fn pwm() -> pwm::Pwm {
+    pwm::Pwm::new(Peripheral::Pwm)
+}
+
+/// The PWM and its pins, while a playback owns them
+struct Output {
+    range: u32,
+    _pins: [gpio::AltPin; 2],
+}
+
+impl Output {
+    /// Claim the PWM, and set it up for `sample_rate` on the headphone jack.
+    fn claim(sample_rate: u32) -> Result<Output> {
+        if sample_rate < MIN_SAMPLE_RATE || sample_rate > MAX_SAMPLE_RATE {
+            return Err(AudioError::InvalidSampleRate);
+        }
+        if PLAYING.swap(true, Ordering::Acquire) {
+            return Err(AudioError::Busy);
+        }
+
+        // Only used to take the two pins, which then guard themselves
+        let gpio = unsafe { gpio::GPIO::steal() };
+        let pins = match (gpio.take_pin(40), gpio.take_pin(41)) {
+            (Some(left), Some(right)) => [
+                left.into_alt(gpio::AltFn::Alt0),
+                right.into_alt(gpio::AltFn::Alt0),
+            ],
+            _ => {
+                PLAYING.store(false, Ordering::Release);
+                return Err(AudioError::PinInUse);
+            }
+        };
+
+        // From here on, dropping the output releases the PWM and the pins
+        // again.
+        let output = Output {
+            range: PWM_CLOCK_HZ / sample_rate,
+            _pins: pins,
+        };
+
+        let cm = clock_manager::ClockManager::new(Peripheral::ClockManager);
+        pwm().init_fifo(&cm, clock_manager::Source::PllD, PWM_CLOCK_DIVISOR, output.range)?;
+
+        Ok(output)
+    }
+
+    /// The duty for an unsigned 8 bit sample
+    fn duty(&self, sample: u8) -> u32 {
+        u32::from(sample) * self.range / 256
+    }
+}
+
+impl Drop for Output {
+    fn drop(&mut self) {
+        pwm().stop_fifo();
+        PLAYING.store(false, Ordering::Release);
+    }
+}
+
+/// Play unsigned 8 bit mono `samples`, and return when they are done.
+pub fn play(samples: &[u8], sample_rate: u32) -> Result<()> {
+    let output = Output::claim(sample_rate)?;
+
+    play_pio(sample_rate, samples.len(), |i| output.duty(samples[i]))
+}
+
+/// Play signed 16 bit mono `samples`, and return when they are done.
+///
+/// The samples are made unsigned and scaled to the PWM range, which at
+/// 100 MHz and 44.1 kHz is 2267 steps, or about 11 bits.
+#[allow(dead_code)]
+pub fn play_i16(samples: &[i16], sample_rate: u32) -> Result<()> {
+    let output = Output::claim(sample_rate)?;
+    let range = output.range;
+
+    play_pio(sample_rate, samples.len(), |i| {
+        ((i32::from(samples[i]) + 32768) as u32 * range) >> 16
+    })
+}
+
+/// Write the duty of sample `i` for both channels, for all `len` samples.
+///
+/// The channels start once the FIFO is full, so that the first samples do not
+/// underrun it. At the end, the FIFO has to drain before the PWM is stopped.
+/// The PWM must have been claimed with `Output::claim()`.
+fn play_pio<F>(sample_rate: u32, len: usize, duty: F) -> Result<()>
+where
+    F: Fn(usize) -> u32,
+{
+    let pwm = pwm();
+    let sample_us = u64::from(1_000_000 / sample_rate) + 1;
+    let mut started = false;
+
+    for i in 0..len {
+        let value = duty(i);
+
+        for _ in 0..2 {
+            if pwm.fifo_full() {
+                if !started {
+                    pwm.start_fifo();
+                    started = true;
+                }
+
+                delays::poll_timeout(delays::deadline(sample_us * 2), || !pwm.fifo_full())
+                    .map_err(|_| AudioError::Timeout)?;
+            }
+            pwm.push_fifo(value);
+        }
+    }
+
+    if !started {
+        pwm.start_fifo();
+    }
+
+    delays::poll_timeout(delays::deadline(sample_us * 16), || pwm.fifo_empty())
+        .map_err(|_| AudioError::Timeout)?;
+
+    // The last value is still in the channels' period
+    delays::wait_usec(sample_us);
+
+    Ok(())
+}
+
+/// A playback by DMA. Dropping it stops the playback.
+pub struct Playback {
+    channel: dma::Channel,
+    buffer: Option<memory::DmaRegion>,
+    sample_rate: u32,
+    len: usize,
+    _output: Output,
+}
+
+/// Start playing unsigned 8 bit mono `samples`, and return right away.
+///
+/// The samples are turned into PWM values in a buffer of DMA memory first, so
+/// `samples` need not live on. Lite DMA channels limit a playback to
+/// `dma::MAX_CHAIN` times 8191 samples, which is about 8 s at 8 kHz.
+pub fn play_async(samples: &[u8], sample_rate: u32) -> Result<Playback> {
+    let words = samples.len() * 2;
+    let bytes = words * 4;
+    let blocks = (bytes + BLOCK_BYTES - 1) / BLOCK_BYTES;
+    if blocks == 0 || blocks > dma::MAX_CHAIN {
+        return Err(AudioError::InvalidLength);
+    }
+
+    let output = Output::claim(sample_rate)?;
+    let mut channel = dma::Channel::claim()?;
+    channel.set_callback(Some(dma_done))?;
+    let buffer = memory::dma_alloc(bytes, 8).ok_or(AudioError::OutOfMemory)?;
+
+    let start = buffer.virt_addr();
+    for (i, &sample) in samples.iter().enumerate() {
+        let value = output.duty(sample);
+        let pair = (start + 8 * i) as *mut u32;
+
+        unsafe {
+            ptr::write_volatile(pair, value);
+            ptr::write_volatile(pair.add(1), value);
+        }
+    }
+
+    // From here on, dropping the playback frees the buffer.
+    let mut playback = Playback {
+        channel,
+        buffer: Some(buffer),
+        sample_rate,
+        len: samples.len(),
+        _output: output,
+    };
+
+    let pwm = pwm();
+    let fifo = pwm.fifo_phys_addr();
+
+    let mut chain = [dma::ControlBlock::new(0, 0, 0); dma::MAX_CHAIN];
+    for (i, cb) in chain.iter_mut().take(blocks).enumerate() {
+        let offset = i * BLOCK_BYTES;
+        let len = BLOCK_BYTES.min(bytes - offset);
+
+        *cb = dma::ControlBlock::new(0, start + offset, len)
+            .dst_peripheral(fifo)
+            .dreq(dma::Dreq::Pwm);
+    }
+
+    DMA_DONE.store(false, Ordering::Release);
+    playback.channel.start_chain(&chain[..blocks])?;
+
+    pwm.enable_fifo_dma();
+    pwm.start_fifo();
+
+    Ok(playback)
+}
+
+impl Playback {
+    /// The completion flag: Whether the DMA engine wrote the last sample to
+    /// the FIFO. At most a few samples are left to play then.
+    pub fn is_done(&self) -> bool {
+        DMA_DONE.load(Ordering::Acquire)
+    }
+
+    /// Wait until the playback is done.
+    pub fn wait(mut self) -> Result<()> {
+        // The duration of the samples, plus a second of slack
+        let duration_us = self.len as u64 * 1_000_000 / u64::from(self.sample_rate);
+        self.channel.wait(duration_us + 1_000_000)?;
+
+        let sample_us = u64::from(1_000_000 / self.sample_rate) + 1;
+        let pwm = pwm();
+        delays::poll_timeout(delays::deadline(sample_us * 16), || pwm.fifo_empty())
+            .map_err(|_| AudioError::Timeout)?;
+        delays::wait_usec(sample_us);
+
+        Ok(())
+    }
+}
+
+impl Drop for Playback {
+    fn drop(&mut self) {
+        // Stop the PWM's DMA requests first, then the channel, before the
+        // buffer is given back.
+        pwm().stop_fifo();
+        let _ = self.channel.set_callback(None);
+        self.channel.abort();
+
+        if let Some(buffer) = self.buffer.take() {
+            memory::dma_free(buffer);
+        }
+    }
+}
@@ -0,0 +1,322 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use super::gpio;
+use crate::delays;
+use crate::{static_assert_offset, static_assert_size};
+use core::ops;
+use raspi3_hal::mmio::{self, Peripheral};
+use register::{mmio::ReadWrite, register_bitfields};
+
+// Clock manager registers, as far as they are used in this kernel.
+//
+// The clock manager is not documented in the BCM2837 peripherals datasheet,
+// apart from the general purpose clocks. The PWM clock works the same way.
+register_bitfields! {
+    u32,
+
+    /// Clock control
+    CM_CTL [
+        /// Must be written as 0x5A, otherwise the write is ignored
+        PASSWD OFFSET(24) NUMBITS(8) [
+            Password = 0x5A
+        ],
+
+        /// MASH noise-shaping filter. Zero means an integer divider.
+        MASH OFFSET(9) NUMBITS(2) [],
+
+        /// The clock generator is running. Do not change the source or the
+        /// divisor while set.
+        BUSY OFFSET(7) NUMBITS(1) [],
+
+        /// Stop and reset the clock generator immediately
+        KILL OFFSET(5) NUMBITS(1) [],
+
+        /// Start or stop the clock generator
+        ENAB OFFSET(4) NUMBITS(1) [],
+
+        /// Clock source
+        SRC OFFSET(0) NUMBITS(4) [
+            Gnd = 0,
+            Oscillator = 1, // 19.2 MHz
+            PllC = 5,       // 1000 MHz, follows core_freq
+            PllD = 6        // 500 MHz
+        ]
+    ],
+
+    /// Clock divisor
+    CM_DIV [
+        /// Must be written as 0x5A, otherwise the write is ignored
+        PASSWD OFFSET(24) NUMBITS(8) [
+            Password = 0x5A
+        ],
+
+        /// Integer part of the divisor
+        DIVI OFFSET(12) NUMBITS(12) [],
+
+        /// Fractional part of the divisor, only used with MASH
+        DIVF OFFSET(0) NUMBITS(12) []
+    ]
+}
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct RegisterBlock {
+    __reserved_0: [u32; 28],                     // 0x00
+    CM_GP0CTL: ReadWrite<u32, CM_CTL::Register>, // 0x70
+    CM_GP0DIV: ReadWrite<u32, CM_DIV::Register>, // 0x74
+    CM_GP1CTL: ReadWrite<u32, CM_CTL::Register>, // 0x78
+    CM_GP1DIV: ReadWrite<u32, CM_DIV::Register>, // 0x7C
+    CM_GP2CTL: ReadWrite<u32, CM_CTL::Register>, // 0x80
+    CM_GP2DIV: ReadWrite<u32, CM_DIV::Register>, // 0x84
+    __reserved_1: [u32; 6],                      // 0x88
+    CM_PWMCTL: ReadWrite<u32, CM_CTL::Register>, // 0xA0
+    CM_PWMDIV: ReadWrite<u32, CM_DIV::Register>, // 0xA4
+}
+
+static_assert_offset!(RegisterBlock, __reserved_0, 0x00);
+static_assert_offset!(RegisterBlock, CM_GP0CTL, 0x70);
+static_assert_offset!(RegisterBlock, CM_GP0DIV, 0x74);
+static_assert_offset!(RegisterBlock, CM_GP1CTL, 0x78);
+static_assert_offset!(RegisterBlock, CM_GP1DIV, 0x7C);
+static_assert_offset!(RegisterBlock, CM_GP2CTL, 0x80);
+static_assert_offset!(RegisterBlock, CM_GP2DIV, 0x84);
+static_assert_offset!(RegisterBlock, __reserved_1, 0x88);
+static_assert_offset!(RegisterBlock, CM_PWMCTL, 0xA0);
+static_assert_offset!(RegisterBlock, CM_PWMDIV, 0xA4);
+static_assert_size!(RegisterBlock, 0xA8);
+
+#[derive(Debug)]
+pub enum ClockManagerError {
+    /// The divisor, or the one needed for the requested frequency, is out of
+    /// range for the chosen MASH filter.
+    InvalidDivisor,
+    Timeout,
+}
+pub type Result<T> = ::core::result::Result<T, ClockManagerError>;
+
+/// Frequency of the crystal oscillator that is used as clock source
+pub const OSCILLATOR_HZ: u32 = 19_200_000;
+
+/// Maximum time the clock generator may take to stop or start
+const BUSY_TIMEOUT_US: u64 = 10_000;
+
+/// The general purpose clocks
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum GpClock {
+    /// On GPIO4
+    Gp0,
+    /// On GPIO5
+    Gp1,
+    /// On GPIO6
+    Gp2,
+}
+
+/// Clock sources of the general purpose clocks
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Source {
+    /// The 19.2 MHz crystal
+    Oscillator,
+    /// 1 GHz, but it changes with the `core_freq` setting in config.txt.
+    PllC,
+    /// 500 MHz
+    PllD,
+}
+
+impl Source {
+    pub fn hz(self) -> u32 {
+        match self {
+            Source::Oscillator => OSCILLATOR_HZ,
+            Source::PllC => 1_000_000_000,
+            Source::PllD => 500_000_000,
+        }
+    }
+
+    fn field(self) -> register::FieldValue<u32, CM_CTL::Register> {
+        match self {
+            Source::Oscillator => CM_CTL::SRC::Oscillator,
+            Source::PllC => CM_CTL::SRC::PllC,
+            Source::PllD => CM_CTL::SRC::PllD,
+        }
+    }
+}
+
+/// MASH noise-shaping filters for fractional divisors
+///
+/// An integer divisor gives a clean clock. The filters alternate between
+/// neighbouring integer divisors so that the average matches the fractional
+/// divisor, at the cost of jitter that grows with the filter order.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Mash {
+    Integer = 0,
+    Stage1 = 1,
+    Stage2 = 2,
+    Stage3 = 3,
+}
+
+impl Mash {
+    /// The smallest integer part of the divisor that the filter supports
+    fn min_divi(self) -> u32 {
+        match self {
+            Mash::Integer => 1,
+            Mash::Stage1 => 2,
+            Mash::Stage2 => 3,
+            Mash::Stage3 => 5,
+        }
+    }
+}
+
+/// Public interface to the clock manager
+pub struct ClockManager {
+    peripheral: Peripheral,
+}
+
+impl ops::Deref for ClockManager {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl ClockManager {
+    pub fn new(peripheral: Peripheral) -> ClockManager {
+        ClockManager { peripheral }
+    }
+
+    /// Returns a pointer to the register block
+    fn ptr(&self) -> *const RegisterBlock {
+        mmio::base(self.peripheral) as *const _
+    }
+
+    /// Run the PWM clock from the oscillator, divided by `divisor`.
+    ///
+    /// The divisor must be in 2..=4095. The PWM peripheral must be stopped
+    /// while the clock is changed.
+    pub fn set_pwm_clock(&self, divisor: u32) -> Result<()> {
+        self.set_pwm_clock_from(Source::Oscillator, divisor)
+    }
+
+    /// Run the PWM clock from `source`, divided by `divisor`.
+    ///
+    /// Like `set_pwm_clock()`, for clocks faster than the oscillator.
+    pub fn set_pwm_clock_from(&self, source: Source, divisor: u32) -> Result<()> {
+        if divisor < 2 || divisor > 4095 {
+            return Err(ClockManagerError::InvalidDivisor);
+        }
+
+        program(
+            &self.CM_PWMCTL,
+            &self.CM_PWMDIV,
+            source.field(),
+            Mash::Integer,
+            divisor,
+            0,
+        )
+    }
+
+    /// Output `source` divided down to `target_hz` on the GPIO pin of
+    /// `clock`, and return the frequency that is actually achieved.
+    ///
+    /// With `Mash::Integer`, the divisor is rounded to the nearest integer.
+    /// Otherwise, it has 12 fractional bits, and the returned frequency is
+    /// the average one.
+    pub fn configure_gp_clock(
+        &self,
+        gpio: &gpio::GPIO,
+        clock: GpClock,
+        source: Source,
+        mash: Mash,
+        target_hz: u32,
+    ) -> Result<u32> {
+        if target_hz == 0 {
+            return Err(ClockManagerError::InvalidDivisor);
+        }
+
+        // The divisor in 12.12 fixed point, rounded to nearest
+        let src_hz = u64::from(source.hz());
+        let target_hz = u64::from(target_hz);
+        let mut div = (src_hz * 4096 + target_hz / 2) / target_hz;
+        if mash == Mash::Integer {
+            div = (div + 2048) & !0xFFF;
+        }
+
+        let divi = (div >> 12) as u32;
+        let divf = (div & 0xFFF) as u32;
+        if div > 0x00FF_FFFF || divi < mash.min_divi() {
+            return Err(ClockManagerError::InvalidDivisor);
+        }
+
+        let (ctl, div_reg, pin) = match clock {
+            GpClock::Gp0 => (&self.CM_GP0CTL, &self.CM_GP0DIV, 4),
+            GpClock::Gp1 => (&self.CM_GP1CTL, &self.CM_GP1DIV, 5),
+            GpClock::Gp2 => (&self.CM_GP2CTL, &self.CM_GP2DIV, 6),
+        };
+
+        program(ctl, div_reg, source.field(), mash, divi, divf)?;
+        gpio.set_function(pin, gpio::Function::Alt0);
+
+        Ok(((src_hz * 4096 + div / 2) / div) as u32)
+    }
+}
+
+/// Stop a clock generator, wait until it stopped, change its source and
+/// divisor, and start it again.
+///
+/// Changing either while BUSY is set glitches the clock or locks the
+/// generator up.
+fn program(
+    ctl: &ReadWrite<u32, CM_CTL::Register>,
+    div: &ReadWrite<u32, CM_DIV::Register>,
+    src: register::FieldValue<u32, CM_CTL::Register>,
+    mash: Mash,
+    divi: u32,
+    divf: u32,
+) -> Result<()> {
+    let wait_busy = |busy: bool| {
+        let deadline = delays::deadline(BUSY_TIMEOUT_US);
+        delays::poll_timeout(deadline, || ctl.is_set(CM_CTL::BUSY) == busy).is_ok()
+    };
+
+    // Stop the clock generator and wait until it actually stopped
+    ctl.write(CM_CTL::PASSWD::Password + src);
+    if !wait_busy(false) {
+        // Stuck, reset it the hard way
+        ctl.write(CM_CTL::PASSWD::Password + CM_CTL::KILL::SET);
+        if !wait_busy(false) {
+            return Err(ClockManagerError::Timeout);
+        }
+    }
+
+    let settings = CM_CTL::PASSWD::Password + CM_CTL::MASH.val(mash as u32) + src;
+    div.write(CM_DIV::PASSWD::Password + CM_DIV::DIVI.val(divi) + CM_DIV::DIVF.val(divf));
+    ctl.write(settings);
+    ctl.write(settings + CM_CTL::ENAB::SET);
+
+    if !wait_busy(true) {
+        return Err(ClockManagerError::Timeout);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,911 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! The DMA engine.
+//!
+//! Channels 0 to 14 share one register block, 0x100 bytes apart. Channels 7 to
+//! 14 are "lite" channels, which have no 2D mode and move at most 64 KiB per
+//! control block. Channel 15 lives elsewhere and is not supported.
+//!
+//! A transfer is described by a chain of control blocks in memory, which the
+//! engine reads on its own. Each claimed `Channel` keeps room for its chain in
+//! the non-cacheable DMA pool, so callers build `ControlBlock`s with plain
+//! virtual addresses, and `start()` translates them to bus addresses and does
+//! the cache maintenance for the buffers.
+//!
+//! Completion is signaled by polling with `wait()`, by a callback from the
+//! channel's IRQ, or to `async` code by the future of `transfer()`.
+
+use crate::{
+    cache, delays, interrupt,
+    memory::{self, map},
+    static_assert_offset, static_assert_size,
+    sync::SpinLock,
+};
+use core::{
+    future::Future,
+    ops,
+    pin::Pin,
+    ptr,
+    sync::atomic::{AtomicU32, Ordering},
+    task::{Context, Poll, Waker},
+};
+use cortex_a::barrier;
+use raspi3_hal::mmio;
+use register::{
+    mmio::{ReadOnly, ReadWrite},
+    register_bitfields,
+};
+
+// DMA registers.
+//
+// Descriptions taken from
+// https://github.com/raspberrypi/documentation/files/1888662/BCM2837-ARM-Peripherals.-.Revised.-.V2-1.pdf
+register_bitfields! {
+    u32,
+
+    /// Control and Status
+    CS [
+        /// Reset the channel
+        RESET OFFSET(31) NUMBITS(1) [],
+
+        /// Abort the current control block, and load the next one
+        ABORT OFFSET(30) NUMBITS(1) [],
+
+        WAIT_FOR_OUTSTANDING_WRITES OFFSET(28) NUMBITS(1) [],
+
+        /// AXI priority for panicking peripherals
+        PANIC_PRIORITY OFFSET(20) NUMBITS(4) [],
+
+        /// AXI priority
+        PRIORITY OFFSET(16) NUMBITS(4) [],
+
+        /// An error was flagged in the DEBUG register
+        ERROR OFFSET(8) NUMBITS(1) [],
+
+        /// Interrupt status. Write 1 to clear.
+        INT OFFSET(2) NUMBITS(1) [],
+
+        /// Set when the transfer of the last control block completed. Write 1
+        /// to clear.
+        END OFFSET(1) NUMBITS(1) [],
+
+        /// Activate the channel. Cleared by the hardware when the chain is done.
+        ACTIVE OFFSET(0) NUMBITS(1) []
+    ],
+
+    /// Transfer Information, as stored in a control block
+    TI [
+        /// Don't do wide writes as 2 beat bursts
+        NO_WIDE_BURSTS OFFSET(26) NUMBITS(1) [],
+
+        /// Peripheral that paces the transfer with its DREQ
+        PERMAP OFFSET(16) NUMBITS(5) [],
+
+        BURST_LENGTH OFFSET(12) NUMBITS(4) [],
+
+        /// Pace the reads with the DREQ
+        SRC_DREQ OFFSET(10) NUMBITS(1) [],
+
+        /// 128 bit reads, instead of 32 bit
+        SRC_WIDTH OFFSET(9) NUMBITS(1) [],
+
+        /// Increment the source address after each read
+        SRC_INC OFFSET(8) NUMBITS(1) [],
+
+        /// Pace the writes with the DREQ
+        DEST_DREQ OFFSET(6) NUMBITS(1) [],
+
+        /// 128 bit writes, instead of 32 bit
+        DEST_WIDTH OFFSET(5) NUMBITS(1) [],
+
+        /// Increment the destination address after each write
+        DEST_INC OFFSET(4) NUMBITS(1) [],
+
+        /// Wait for the write response of each write
+        WAIT_RESP OFFSET(3) NUMBITS(1) [],
+
+        /// Treat TXFR_LEN as YLENGTH rows of XLENGTH bytes
+        TDMODE OFFSET(1) NUMBITS(1) [],
+
+        /// Raise an interrupt once the control block is done
+        INTEN OFFSET(0) NUMBITS(1) []
+    ],
+
+    /// Debug. The error bits are cleared by writing 1.
+    DEBUG [
+        /// The channel is a lite one
+        LITE OFFSET(28) NUMBITS(1) [],
+
+        READ_ERROR OFFSET(2) NUMBITS(1) [],
+
+        FIFO_ERROR OFFSET(1) NUMBITS(1) [],
+
+        READ_LAST_NOT_SET_ERROR OFFSET(0) NUMBITS(1) []
+    ]
+}
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct ChannelRegisters {
+    CS: ReadWrite<u32, CS::Register>,       // 0x00
+    CONBLK_AD: ReadWrite<u32>,              // 0x04
+    TI: ReadOnly<u32, TI::Register>,        // 0x08
+    SOURCE_AD: ReadOnly<u32>,               // 0x0C
+    DEST_AD: ReadOnly<u32>,                 // 0x10
+    TXFR_LEN: ReadOnly<u32>,                // 0x14
+    STRIDE: ReadOnly<u32>,                  // 0x18
+    NEXTCONBK: ReadOnly<u32>,               // 0x1C
+    DEBUG: ReadWrite<u32, DEBUG::Register>, // 0x20
+    __reserved_0: [u32; 55],                // 0x24
+}
+
+static_assert_offset!(ChannelRegisters, CS, 0x00);
+static_assert_offset!(ChannelRegisters, CONBLK_AD, 0x04);
+static_assert_offset!(ChannelRegisters, TI, 0x08);
+static_assert_offset!(ChannelRegisters, SOURCE_AD, 0x0C);
+static_assert_offset!(ChannelRegisters, DEST_AD, 0x10);
+static_assert_offset!(ChannelRegisters, TXFR_LEN, 0x14);
+static_assert_offset!(ChannelRegisters, STRIDE, 0x18);
+static_assert_offset!(ChannelRegisters, NEXTCONBK, 0x1C);
+static_assert_offset!(ChannelRegisters, DEBUG, 0x20);
+static_assert_offset!(ChannelRegisters, __reserved_0, 0x24);
+static_assert_size!(ChannelRegisters, 0x100);
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct RegisterBlock {
+    CHANNELS: [ChannelRegisters; NUM_CHANNELS], // 0x000
+    __reserved_0: [u32; 56],                    // 0xF00
+    INT_STATUS: ReadOnly<u32>,                  // 0xFE0
+    __reserved_1: [u32; 3],                     // 0xFE4
+    ENABLE: ReadWrite<u32>,                     // 0xFF0
+}
+
+static_assert_offset!(RegisterBlock, CHANNELS, 0x000);
+static_assert_offset!(RegisterBlock, __reserved_0, 0xF00);
+static_assert_offset!(RegisterBlock, INT_STATUS, 0xFE0);
+static_assert_offset!(RegisterBlock, __reserved_1, 0xFE4);
+static_assert_offset!(RegisterBlock, ENABLE, 0xFF0);
+static_assert_size!(RegisterBlock, 0xFF4);
+
+/// Channels 0 to 14, see the module documentation.
+pub const NUM_CHANNELS: usize = 15;
+
+/// The first lite channel
+const FIRST_LITE_CHANNEL: usize = 7;
+
+/// Control blocks per chain that a `Channel` has room for.
+pub const MAX_CHAIN: usize = 8;
+
+/// Size of a control block in memory. They must be aligned to it as well.
+const CB_SIZE: usize = 32;
+
+/// The uncached alias of the first GiB of SDRAM on the bus.
+const BUS_SDRAM_UNCACHED: u32 = 0xC000_0000;
+
+/// Where the peripherals appear on the bus.
+const BUS_PERIPHERALS: u32 = 0x7E00_0000;
+
+/// Transfers below this size are done by the CPU in `dma_copy()` and
+/// `dma_fill()`, setting up the engine is not worth it.
+const MIN_DMA_LEN: usize = 1024;
+
+/// How long `dma_copy()` and `dma_fill()` wait for a transfer.
+const TIMEOUT_US: u64 = 100_000;
+
+#[derive(Debug)]
+pub enum DmaError {
+    /// All channels that the firmware leaves to us are claimed, or `init()`
+    /// was not called.
+    NoChannel,
+    /// The channel is still busy with the previous chain.
+    Busy,
+    EmptyChain,
+    TooManyBlocks,
+    /// Zero, or more than the channel can move in one control block.
+    InvalidLength,
+    /// 2D mode on a lite channel
+    NotSupported,
+    /// The engine flagged an error. Carries the DEBUG register.
+    Transfer(u32),
+    Timeout,
+    /// No IRQ handler could be installed for the channel.
+    Irq,
+}
+pub type Result<T> = ::core::result::Result<T, DmaError>;
+
+/// Peripherals that can pace a transfer, as numbered in the PERMAP field.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Dreq {
+    Pwm = 5,
+    SpiTx = 6,
+    SpiRx = 7,
+    Emmc = 11,
+    UartTx = 12,
+    SdHost = 13,
+    UartRx = 14,
+}
+
+/// One side of a transfer.
+#[derive(Copy, Clone, Debug)]
+enum End {
+    /// A virtual address of RAM, incremented unless `fixed`
+    Memory { addr: usize, fixed: bool },
+    /// The physical address of a peripheral register
+    Peripheral(usize),
+}
+
+impl End {
+    fn bus_addr(&self) -> u32 {
+        match *self {
+            End::Memory { addr, .. } => memory::virt_to_phys(addr) as u32 | BUS_SDRAM_UNCACHED,
+            End::Peripheral(phys) => (phys - map::physical::MMIO_BASE) as u32 + BUS_PERIPHERALS,
+        }
+    }
+
+    fn increments(&self) -> bool {
+        match *self {
+            End::Memory { fixed, .. } => !fixed,
+            End::Peripheral(_) => false,
+        }
+    }
+
+    fn is_peripheral(&self) -> bool {
+        match self {
+            End::Peripheral(_) => true,
+            _ => false,
+        }
+    }
+
+    /// The memory that a transfer of `rows` rows of `len` bytes, `stride`
+    /// bytes apart, touches on this side. None for peripherals.
+    fn extent(&self, len: usize, rows: usize, stride: i16) -> Option<(usize, usize)> {
+        match *self {
+            End::Memory { addr, fixed: true } => Some((addr, 4)),
+            End::Memory { addr, fixed: false } => {
+                let last = addr as isize + (rows as isize - 1) * (len as isize + stride as isize);
+                let start = (addr as isize).min(last) as usize;
+                let end = (addr as isize).max(last) as usize + len;
+
+                Some((start, end - start))
+            }
+            End::Peripheral(_) => None,
+        }
+    }
+}
+
+/// A transfer, as the builder for the control block that the engine reads.
+///
+/// ```
+/// let cb = ControlBlock::new(dst, src, 640 * 4)
+///     .rows(480, 0, 0)
+///     .interrupt();
+/// ```
+#[derive(Copy, Clone, Debug)]
+pub struct ControlBlock {
+    dst: End,
+    src: End,
+    /// Bytes, per row in 2D mode
+    len: usize,
+    rows: usize,
+    src_stride: i16,
+    dst_stride: i16,
+    dreq: Option<Dreq>,
+    interrupt: bool,
+}
+
+impl ControlBlock {
+    /// Copy `len` bytes from `src` to `dst`, both virtual addresses of RAM.
+    pub fn new(dst: usize, src: usize, len: usize) -> ControlBlock {
+        ControlBlock {
+            dst: End::Memory {
+                addr: dst,
+                fixed: false,
+            },
+            src: End::Memory {
+                addr: src,
+                fixed: false,
+            },
+            len,
+            rows: 1,
+            src_stride: 0,
+            dst_stride: 0,
+            dreq: None,
+            interrupt: false,
+        }
+    }
+
+    /// Read from the peripheral register at the physical address `phys`.
+    #[allow(dead_code)]
+    pub fn src_peripheral(mut self, phys: usize) -> ControlBlock {
+        self.src = End::Peripheral(phys);
+        self
+    }
+
+    /// Write to the peripheral register at the physical address `phys`.
+    pub fn dst_peripheral(mut self, phys: usize) -> ControlBlock {
+        self.dst = End::Peripheral(phys);
+        self
+    }
+
+    /// Read the same word of RAM over and over, e.g. to fill memory.
+    pub fn src_fixed(mut self) -> ControlBlock {
+        if let End::Memory { ref mut fixed, .. } = self.src {
+            *fixed = true;
+        }
+        self
+    }
+
+    /// 2D mode: Move `rows` rows of `len` bytes, and skip `src_stride` and
+    /// `dst_stride` bytes after each row. Full channels only.
+    #[allow(dead_code)]
+    pub fn rows(mut self, rows: usize, src_stride: i16, dst_stride: i16) -> ControlBlock {
+        self.rows = rows;
+        self.src_stride = src_stride;
+        self.dst_stride = dst_stride;
+        self
+    }
+
+    /// Pace the peripheral side of the transfer with the DREQ of `dreq`.
+    pub fn dreq(mut self, dreq: Dreq) -> ControlBlock {
+        self.dreq = Some(dreq);
+        self
+    }
+
+    /// Raise the channel's IRQ once this block is done.
+    ///
+    /// `start()` does this for the last block by itself if the channel has a
+    /// completion callback.
+    #[allow(dead_code)]
+    pub fn interrupt(mut self) -> ControlBlock {
+        self.interrupt = true;
+        self
+    }
+
+    fn two_d(&self) -> bool {
+        self.rows > 1
+    }
+
+    fn check(&self, lite: bool) -> Result<()> {
+        if self.len == 0 || self.rows == 0 {
+            return Err(DmaError::InvalidLength);
+        }
+
+        if self.two_d() {
+            if lite {
+                return Err(DmaError::NotSupported);
+            }
+
+            // XLENGTH has 16 bits, YLENGTH 14
+            if self.len > 0xFFFF || self.rows > 0x4000 {
+                return Err(DmaError::InvalidLength);
+            }
+        } else if self.len > if lite { 0xFFFF } else { 0x3FFF_FFFF } {
+            return Err(DmaError::InvalidLength);
+        }
+
+        Ok(())
+    }
+
+    fn ti(&self) -> u32 {
+        // Bursts only for memory to memory, peripherals take single words
+        let burst = if self.dreq.is_some() { 0 } else { 4 };
+        let mut ti = TI::WAIT_RESP::SET + TI::BURST_LENGTH.val(burst);
+
+        if self.interrupt {
+            ti += TI::INTEN::SET;
+        }
+        if self.two_d() {
+            ti += TI::TDMODE::SET;
+        }
+        if self.src.increments() {
+            ti += TI::SRC_INC::SET;
+        }
+        if self.dst.increments() {
+            ti += TI::DEST_INC::SET;
+        }
+        if let Some(dreq) = self.dreq {
+            ti += TI::PERMAP.val(dreq as u32);
+
+            if self.src.is_peripheral() {
+                ti += TI::SRC_DREQ::SET;
+            }
+            if self.dst.is_peripheral() {
+                ti += TI::DEST_DREQ::SET;
+            }
+        }
+
+        ti.value
+    }
+
+    fn txfr_len(&self) -> u32 {
+        if self.two_d() {
+            ((self.rows as u32 - 1) << 16) | self.len as u32
+        } else {
+            self.len as u32
+        }
+    }
+
+    fn stride(&self) -> u32 {
+        (u32::from(self.dst_stride as u16) << 16) | u32::from(self.src_stride as u16)
+    }
+
+    fn src_extent(&self) -> Option<(usize, usize)> {
+        self.src.extent(self.len, self.rows, self.src_stride)
+    }
+
+    fn dst_extent(&self) -> Option<(usize, usize)> {
+        self.dst.extent(self.len, self.rows, self.dst_stride)
+    }
+}
+
+/// What the IRQ handler needs to know about a channel.
+#[derive(Copy, Clone)]
+struct ChannelState {
+    callback: Option<fn()>,
+    /// RAM that the running chain writes, to be invalidated once it is done
+    dst: [Option<(usize, usize)>; MAX_CHAIN],
+}
+
+const IDLE_STATE: ChannelState = ChannelState {
+    callback: None,
+    dst: [None; MAX_CHAIN],
+};
+
+static STATE: SpinLock<[ChannelState; NUM_CHANNELS]> = SpinLock::new([IDLE_STATE; NUM_CHANNELS]);
+
+/// The task waiting in `transfer()` on each channel. Wakers are not `Copy`, so
+/// they are kept apart from `STATE`.
+static WAKERS: SpinLock<[Option<Waker>; NUM_CHANNELS]> = SpinLock::new([
+    None, None, None, None, None, None, None, None, None, None, None, None, None, None, None,
+]);
+
+/// Channels that the firmware leaves to the ARM, set by `init()`.
+static USABLE: AtomicU32 = AtomicU32::new(0);
+
+/// Channels that are claimed right now.
+static CLAIMED: AtomicU32 = AtomicU32::new(0);
+
+fn dma() -> &'static RegisterBlock {
+    unsafe { &*(mmio::base(mmio::Peripheral::Dma) as *const RegisterBlock) }
+}
+
+/// Ask the firmware which channels are ours, and return them as a mask.
+pub fn init(v_mbox: &mut super::VideocoreMbox) -> super::videocore_mbox::Result<u32> {
+    let mask = super::videocore_mbox::dma_channels(v_mbox)? & ((1 << NUM_CHANNELS) - 1);
+    USABLE.store(mask, Ordering::Relaxed);
+
+    Ok(mask)
+}
+
+/// Invalidate what the chain of `nr` wrote, so that the CPU does not read
+/// stale lines that it pulled in while the chain was running.
+fn finish(nr: usize) {
+    let dst = STATE.lock_irqsave(|state| {
+        let dst = state[nr].dst;
+        state[nr].dst = [None; MAX_CHAIN];
+
+        dst
+    });
+
+    for &(addr, len) in dst.iter().flatten() {
+        cache::invalidate_dcache_range(addr, len);
+    }
+}
+
+/// Serves the IRQs of all channels, which may share a line.
+fn irq_handler() {
+    let dma = dma();
+    let pending = dma.INT_STATUS.get();
+
+    for nr in (0..NUM_CHANNELS).filter(|nr| pending & (1 << nr) != 0) {
+        dma.CHANNELS[nr].CS.write(CS::INT::SET);
+        finish(nr);
+
+        if let Some(callback) = STATE.lock_irqsave(|state| state[nr].callback) {
+            callback();
+        }
+
+        if let Some(waker) = WAKERS.lock_irqsave(|wakers| wakers[nr].take()) {
+            waker.wake();
+        }
+    }
+}
+
+fn irq_of(nr: usize) -> interrupt::Irq {
+    use interrupt::Irq;
+
+    match nr {
+        0 => Irq::Dma0,
+        1 => Irq::Dma1,
+        2 => Irq::Dma2,
+        3 => Irq::Dma3,
+        4 => Irq::Dma4,
+        5 => Irq::Dma5,
+        6 => Irq::Dma6,
+        7 => Irq::Dma7,
+        8 => Irq::Dma8,
+        9 => Irq::Dma9,
+        10 => Irq::Dma10,
+        _ => Irq::Dma11To14,
+    }
+}
+
+/// A claimed DMA channel. Released again when dropped.
+pub struct Channel {
+    nr: usize,
+    /// Room for `MAX_CHAIN` control blocks, and one more slot for the word
+    /// that `dma_fill()` reads.
+    region: memory::DmaRegion,
+}
+
+impl ops::Deref for Channel {
+    type Target = ChannelRegisters;
+
+    fn deref(&self) -> &Self::Target {
+        &dma().CHANNELS[self.nr]
+    }
+}
+
+impl Channel {
+    /// Claim the lowest free channel that the firmware left to us.
+    pub fn claim() -> Result<Channel> {
+        let usable = USABLE.load(Ordering::Relaxed);
+
+        let nr = loop {
+            let claimed = CLAIMED.load(Ordering::Relaxed);
+            let free = usable & !claimed;
+            if free == 0 {
+                return Err(DmaError::NoChannel);
+            }
+
+            let nr = free.trailing_zeros() as usize;
+            let new = claimed | (1 << nr);
+            if CLAIMED
+                .compare_exchange(claimed, new, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+            {
+                break nr;
+            }
+        };
+
+        let region = match memory::dma_alloc((MAX_CHAIN + 1) * CB_SIZE, CB_SIZE) {
+            Some(region) => region,
+            None => {
+                CLAIMED.fetch_and(!(1 << nr), Ordering::Release);
+                return Err(DmaError::NoChannel);
+            }
+        };
+
+        let channel = Channel { nr, region };
+
+        let dma = dma();
+        dma.ENABLE.set(dma.ENABLE.get() | (1 << nr));
+        channel.CS.write(CS::RESET::SET);
+        channel.DEBUG.write(
+            DEBUG::READ_ERROR::SET + DEBUG::FIFO_ERROR::SET + DEBUG::READ_LAST_NOT_SET_ERROR::SET,
+        );
+
+        Ok(channel)
+    }
+
+    /// The channel number
+    pub fn nr(&self) -> usize {
+        self.nr
+    }
+
+    pub fn is_lite(&self) -> bool {
+        self.nr >= FIRST_LITE_CHANNEL
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.CS.is_set(CS::ACTIVE)
+    }
+
+    /// Call `callback` from the IRQ handler whenever a chain of this channel
+    /// completed, or stop doing so with `None`.
+    pub fn set_callback(&mut self, callback: Option<fn()>) -> Result<()> {
+        if callback.is_some() {
+            self.enable_irq()?;
+        }
+
+        STATE.lock_irqsave(|state| state[self.nr].callback = callback);
+
+        Ok(())
+    }
+
+    fn enable_irq(&self) -> Result<()> {
+        let irq = irq_of(self.nr);
+
+        // Channels 11 to 14 share their IRQ, so it may be registered already.
+        match interrupt::register_handler(irq, irq_handler) {
+            Ok(()) | Err(interrupt::InterruptError::AlreadyRegistered) => (),
+            Err(_) => return Err(DmaError::Irq),
+        }
+        interrupt::enable(irq);
+
+        Ok(())
+    }
+
+    /// Start the transfer of a single control block.
+    pub fn start(&mut self, cb: &ControlBlock) -> Result<()> {
+        self.start_chain(core::slice::from_ref(cb))
+    }
+
+    /// Start the transfer of `blocks`, one after another.
+    ///
+    /// The blocks are copied, so they need not live on. The buffers must,
+    /// until the chain is done.
+    pub fn start_chain(&mut self, blocks: &[ControlBlock]) -> Result<()> {
+        if blocks.is_empty() {
+            return Err(DmaError::EmptyChain);
+        }
+        if blocks.len() > MAX_CHAIN {
+            return Err(DmaError::TooManyBlocks);
+        }
+        if self.is_busy() {
+            return Err(DmaError::Busy);
+        }
+        for cb in blocks {
+            cb.check(self.is_lite())?;
+        }
+
+        let has_callback = STATE.lock_irqsave(|state| state[self.nr].callback.is_some())
+            || WAKERS.lock_irqsave(|wakers| wakers[self.nr].is_some());
+        let mut dst = [None; MAX_CHAIN];
+
+        for (i, cb) in blocks.iter().enumerate() {
+            let last = i == blocks.len() - 1;
+
+            // Dirty lines of the destination could be evicted on top of what
+            // the engine wrote, so they go out before the transfer as well.
+            if let Some((addr, len)) = cb.src_extent() {
+                cache::clean_dcache_range(addr, len);
+            }
+            if let Some((addr, len)) = cb.dst_extent() {
+                cache::clean_invalidate_dcache_range(addr, len);
+            }
+            dst[i] = cb.dst_extent();
+
+            let mut ti = cb.ti();
+            if last && has_callback {
+                ti |= TI::INTEN::SET.value;
+            }
+
+            let next = if last {
+                0
+            } else {
+                self.region.bus_addr() + ((i + 1) * CB_SIZE) as u32
+            };
+
+            let words = [
+                ti,
+                cb.src.bus_addr(),
+                cb.dst.bus_addr(),
+                cb.txfr_len(),
+                cb.stride(),
+                next,
+                0,
+                0,
+            ];
+
+            let slot = (self.region.virt_addr() + i * CB_SIZE) as *mut u32;
+            for (j, &word) in words.iter().enumerate() {
+                unsafe { ptr::write_volatile(slot.add(j), word) };
+            }
+        }
+
+        STATE.lock_irqsave(|state| state[self.nr].dst = dst);
+
+        // The control blocks are in non-cacheable memory, but the writes must
+        // have arrived before the engine is told to read them.
+        unsafe { barrier::dsb(barrier::SY) };
+
+        self.CONBLK_AD.set(self.region.bus_addr());
+        self.CS.write(
+            CS::END::SET
+                + CS::INT::SET
+                + CS::WAIT_FOR_OUTSTANDING_WRITES::SET
+                + CS::PANIC_PRIORITY.val(15)
+                + CS::PRIORITY.val(8)
+                + CS::ACTIVE::SET,
+        );
+
+        Ok(())
+    }
+
+    /// Wait until the chain is done, or `timeout_us` passed. A chain that
+    /// timed out is aborted.
+    pub fn wait(&mut self, timeout_us: u64) -> Result<()> {
+        if delays::poll_timeout(delays::deadline(timeout_us), || !self.is_busy()).is_err() {
+            self.abort();
+
+            return Err(DmaError::Timeout);
+        }
+
+        self.CS.write(CS::END::SET);
+        finish(self.nr);
+
+        if self.CS.is_set(CS::ERROR) {
+            let debug = self.DEBUG.get();
+            self.DEBUG.set(debug);
+
+            return Err(DmaError::Transfer(debug));
+        }
+
+        Ok(())
+    }
+
+    /// Stop the chain right away, wherever it is.
+    pub fn abort(&mut self) {
+        self.CS.write(CS::RESET::SET);
+        finish(self.nr);
+    }
+
+    /// The word that `dma_fill()` reads from
+    fn fill_word(&self) -> usize {
+        self.region.virt_addr() + MAX_CHAIN * CB_SIZE
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        self.CS.write(CS::RESET::SET);
+        finish(self.nr);
+        STATE.lock_irqsave(|state| state[self.nr] = IDLE_STATE);
+
+        let dma = dma();
+        dma.ENABLE.set(dma.ENABLE.get() & !(1 << self.nr));
+
+        memory::dma_free(unsafe { ptr::read(&self.region) });
+        CLAIMED.fetch_and(!(1 << self.nr), Ordering::Release);
+    }
+}
+
+/// Run `blocks` on `channel` in `async` code.
+///
+/// The chain is started on the first poll, and the task is woken by the IRQ of
+/// the channel once it is done. Dropping the future before aborts the chain.
+#[allow(dead_code)]
+pub fn transfer<'a>(channel: &'a mut Channel, blocks: &'a [ControlBlock]) -> Transfer<'a> {
+    Transfer {
+        channel,
+        blocks,
+        started: false,
+        done: false,
+    }
+}
+
+/// The future of `transfer()`.
+pub struct Transfer<'a> {
+    channel: &'a mut Channel,
+    blocks: &'a [ControlBlock],
+    started: bool,
+    done: bool,
+}
+
+impl<'a> Future for Transfer<'a> {
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<()>> {
+        let this = &mut *self;
+        let nr = this.channel.nr;
+
+        // Before the chain is started or checked, so that its IRQ is not missed
+        WAKERS.lock_irqsave(|wakers| wakers[nr] = Some(cx.waker().clone()));
+
+        if !this.started {
+            let blocks = this.blocks;
+            let started = this
+                .channel
+                .enable_irq()
+                .and_then(|_| this.channel.start_chain(blocks));
+
+            if let Err(e) = started {
+                WAKERS.lock_irqsave(|wakers| wakers[nr] = None);
+                this.done = true;
+
+                return Poll::Ready(Err(e));
+            }
+
+            this.started = true;
+            return Poll::Pending;
+        }
+
+        if this.channel.is_busy() {
+            return Poll::Pending;
+        }
+
+        WAKERS.lock_irqsave(|wakers| wakers[nr] = None);
+        this.done = true;
+
+        Poll::Ready(this.channel.wait(0))
+    }
+}
+
+impl<'a> Drop for Transfer<'a> {
+    fn drop(&mut self) {
+        let nr = self.channel.nr;
+        WAKERS.lock_irqsave(|wakers| wakers[nr] = None);
+
+        // The buffers may not live on
+        if self.started && !self.done {
+            self.channel.abort();
+        }
+    }
+}
+
+/// Whether `dma_copy()` and `dma_fill()` may hand `a` and `len` to the engine.
+fn dma_worthwhile(a: usize, len: usize) -> bool {
+    len >= MIN_DMA_LEN && a % 4 == 0 && len % 4 == 0
+}
+
+/// Copy `len` bytes from `src` to `dst` with a DMA channel, or with the CPU if
+/// none is free, the buffers overlap, or the copy is small or unaligned.
+///
+/// # Safety
+///
+/// Same as for `core::ptr::copy_nonoverlapping()`.
+pub unsafe fn dma_copy(dst: *mut u8, src: *const u8, len: usize) {
+    let (d, s) = (dst as usize, src as usize);
+    let overlap = d < s + len && s < d + len;
+
+    if !overlap && dma_worthwhile(d, len) && s % 4 == 0 {
+        if let Ok(mut channel) = Channel::claim() {
+            let cb = ControlBlock::new(d, s, len);
+
+            if channel.start(&cb).and_then(|_| channel.wait(TIMEOUT_US)).is_ok() {
+                return;
+            }
+        }
+    }
+
+    ptr::copy(src, dst, len);
+}
+
+/// Set `len` bytes at `dst` to `val`, with a DMA channel if possible. See
+/// `dma_copy()`.
+///
+/// # Safety
+///
+/// Same as for `core::ptr::write_bytes()`.
+pub unsafe fn dma_fill(dst: *mut u8, val: u8, len: usize) {
+    let d = dst as usize;
+
+    if dma_worthwhile(d, len) {
+        if let Ok(mut channel) = Channel::claim() {
+            let word = channel.fill_word();
+            ptr::write_volatile(word as *mut u32, u32::from_ne_bytes([val; 4]));
+
+            let cb = ControlBlock::new(d, word, len).src_fixed();
+
+            if channel.start(&cb).and_then(|_| channel.wait(TIMEOUT_US)).is_ok() {
+                return;
+            }
+        }
+    }
+
+    ptr::write_bytes(dst, val, len);
+}
@@ -0,0 +1,71 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! DS18B20 temperature sensors on a 1-Wire bus.
+
+use super::onewire::{self, OwError, Result, Rom};
+use crate::delays;
+
+/// The family code in the ROM code of a DS18B20
+pub const FAMILY: u8 = 0x28;
+
+const CONVERT_T: u8 = 0x44;
+const READ_SCRATCHPAD: u8 = 0xBE;
+
+/// A conversion takes up to 750 ms at the default 12 bit resolution.
+const CONVERSION_TIMEOUT_US: u64 = 1_000_000;
+
+/// Start a conversion on the sensor with `rom`, wait for it, and return the
+/// temperature in millidegrees Celsius.
+///
+/// The sensor must have its own supply. In parasite power mode, it draws its
+/// power from the bus during the conversion, which needs a strong pull-up that
+/// this driver does not provide.
+pub fn read_temp(bus: &onewire::Bus, rom: &Rom) -> Result<i32> {
+    bus.select(rom)?;
+    bus.write_byte(CONVERT_T);
+
+    // The sensor answers read slots with 0 while it is converting.
+    delays::poll_timeout(delays::deadline(CONVERSION_TIMEOUT_US), || bus.read_bit())
+        .map_err(|_| OwError::Timeout)?;
+
+    bus.select(rom)?;
+    bus.write_byte(READ_SCRATCHPAD);
+
+    let mut scratchpad = [0u8; 9];
+    for byte in scratchpad.iter_mut() {
+        *byte = bus.read_byte();
+    }
+
+    // A bus that nobody drives reads as all ones and fails the CRC, but all
+    // zeros would pass it.
+    if onewire::crc8(&scratchpad) != 0 || scratchpad.iter().all(|&b| b == 0) {
+        return Err(OwError::CrcMismatch);
+    }
+
+    // Two's complement in 1/16 degrees
+    let raw = i32::from(i16::from_le_bytes([scratchpad[0], scratchpad[1]]));
+
+    Ok(raw * 1000 / 16)
+}
@@ -0,0 +1,155 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! DS3231 real-time clock on the I2C bus.
+//!
+//! The time registers hold BCD, with the hours in 24 hour mode, and a century
+//! bit in the month register, which covers the years 2000 to 2199. The
+//! oscillator stop flag is set when the clock stopped, e.g. because the
+//! backup battery died. From then on, the time is garbage until it is set
+//! again.
+
+use super::i2c::{I2c, I2cError};
+use crate::walltime::DateTime;
+
+/// The fixed I2C address of the DS3231
+pub const ADDR: u8 = 0x68;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_STATUS: u8 = 0x0F;
+
+/// Hours register bit for the 12 hour mode
+const HOURS_12H: u8 = 1 << 6;
+/// Month register bit for the years from 2100 on
+const MONTH_CENTURY: u8 = 1 << 7;
+/// Status register: Oscillator Stop Flag
+const STATUS_OSF: u8 = 1 << 7;
+
+#[derive(Debug)]
+pub enum Ds3231Error {
+    I2c(I2cError),
+    /// The oscillator stopped since the time was last set, the time is
+    /// invalid.
+    TimeInvalid,
+    /// The registers hold something that is not a date.
+    BadValue,
+    /// The date is out of the range of the DS3231.
+    OutOfRange,
+    /// No DS3231 was found at boot.
+    NotFound,
+}
+pub type Result<T> = ::core::result::Result<T, Ds3231Error>;
+
+impl From<I2cError> for Ds3231Error {
+    fn from(e: I2cError) -> Self {
+        Ds3231Error::I2c(e)
+    }
+}
+
+fn from_bcd(bcd: u8) -> u8 {
+    (bcd >> 4) * 10 + (bcd & 0xF)
+}
+
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+/// Read the date and time.
+///
+/// Fails with `TimeInvalid` if the oscillator stopped since the last `set()`.
+pub fn read(i2c: &I2c) -> Result<DateTime> {
+    let mut status = [0u8; 1];
+    i2c.write_read(ADDR, &[REG_STATUS], &mut status)?;
+    if status[0] & STATUS_OSF != 0 {
+        return Err(Ds3231Error::TimeInvalid);
+    }
+
+    let mut regs = [0u8; 7];
+    i2c.write_read(ADDR, &[REG_SECONDS], &mut regs)?;
+
+    let hour = if regs[2] & HOURS_12H != 0 {
+        // Bit 5 is PM, 12 AM is midnight
+        let h12 = from_bcd(regs[2] & 0x1F) % 12;
+        if regs[2] & (1 << 5) != 0 {
+            h12 + 12
+        } else {
+            h12
+        }
+    } else {
+        from_bcd(regs[2] & 0x3F)
+    };
+
+    let century = if regs[5] & MONTH_CENTURY != 0 { 2100 } else { 2000 };
+
+    let dt = DateTime {
+        year: century + u16::from(from_bcd(regs[6])),
+        month: from_bcd(regs[5] & 0x1F),
+        day: from_bcd(regs[4] & 0x3F),
+        hour,
+        minute: from_bcd(regs[1] & 0x7F),
+        second: from_bcd(regs[0] & 0x7F),
+    };
+
+    if !dt.is_valid() {
+        return Err(Ds3231Error::BadValue);
+    }
+
+    Ok(dt)
+}
+
+/// Set the date and time, in 24 hour mode, and clear the oscillator stop
+/// flag.
+pub fn set(i2c: &I2c, dt: &DateTime) -> Result<()> {
+    if !dt.is_valid() || dt.year < 2000 || dt.year > 2199 {
+        return Err(Ds3231Error::OutOfRange);
+    }
+
+    let (century, year) = if dt.year >= 2100 {
+        (MONTH_CENTURY, dt.year - 2100)
+    } else {
+        (0, dt.year - 2000)
+    };
+
+    i2c.write(
+        ADDR,
+        &[
+            REG_SECONDS,
+            to_bcd(dt.second),
+            to_bcd(dt.minute),
+            to_bcd(dt.hour),
+            // The DS3231 counts the weekdays from 1
+            dt.weekday() + 1,
+            to_bcd(dt.day),
+            century | to_bcd(dt.month),
+            to_bcd(year as u8),
+        ],
+    )?;
+
+    // Clear OSF, and keep the other flags
+    let mut status = [0u8; 1];
+    i2c.write_read(ADDR, &[REG_STATUS], &mut status)?;
+    i2c.write(ADDR, &[REG_STATUS, status[0] & !STATUS_OSF])?;
+
+    Ok(())
+}
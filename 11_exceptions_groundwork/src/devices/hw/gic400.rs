@@ -0,0 +1,244 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use crate::{static_assert_offset, static_assert_size};
+use core::ops;
+use raspi3_hal::mmio::{self, Peripheral};
+use register::mmio::{ReadOnly, ReadWrite, WriteOnly};
+
+// The GIC-400 interrupt controller of the BCM2711 (Pi 4). It replaces the
+// BCM interrupt controller and the IRQ routing of the ARM local peripherals.
+//
+// The distributor (GICD) prioritizes the sources and forwards them to the CPU
+// interfaces (GICC), one per core, where the cores acknowledge and complete
+// them. Interrupt IDs (INTIDs) 0 to 15 are software generated (SGIs), 16 to 31
+// are private to each core (PPIs), and SPIs start at 32.
+//
+// Descriptions taken from the ARM CoreLink GIC-400 TRM (DDI 0471B) and the
+// ARM GIC architecture specification v2 (IHI 0048B).
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct DistributorRegisterBlock {
+    CTLR: ReadWrite<u32>,              // 0x000
+    TYPER: ReadOnly<u32>,              // 0x004
+    IIDR: ReadOnly<u32>,               // 0x008
+    __reserved_0: [u32; 29],           // 0x00C
+    IGROUPR: [ReadWrite<u32>; 32],     // 0x080
+    ISENABLER: [ReadWrite<u32>; 32],   // 0x100
+    ICENABLER: [ReadWrite<u32>; 32],   // 0x180
+    ISPENDR: [ReadWrite<u32>; 32],     // 0x200
+    ICPENDR: [ReadWrite<u32>; 32],     // 0x280
+    ISACTIVER: [ReadWrite<u32>; 32],   // 0x300
+    ICACTIVER: [ReadWrite<u32>; 32],   // 0x380
+    IPRIORITYR: [ReadWrite<u32>; 255], // 0x400
+    __reserved_1: u32,                 // 0x7FC
+    ITARGETSR: [ReadWrite<u32>; 255],  // 0x800
+    __reserved_2: u32,                 // 0xBFC
+    ICFGR: [ReadWrite<u32>; 64],       // 0xC00
+    __reserved_3: [u32; 128],          // 0xD00
+    SGIR: WriteOnly<u32>,              // 0xF00
+}
+
+static_assert_offset!(DistributorRegisterBlock, CTLR, 0x000);
+static_assert_offset!(DistributorRegisterBlock, TYPER, 0x004);
+static_assert_offset!(DistributorRegisterBlock, IIDR, 0x008);
+static_assert_offset!(DistributorRegisterBlock, __reserved_0, 0x00C);
+static_assert_offset!(DistributorRegisterBlock, IGROUPR, 0x080);
+static_assert_offset!(DistributorRegisterBlock, ISENABLER, 0x100);
+static_assert_offset!(DistributorRegisterBlock, ICENABLER, 0x180);
+static_assert_offset!(DistributorRegisterBlock, ISPENDR, 0x200);
+static_assert_offset!(DistributorRegisterBlock, ICPENDR, 0x280);
+static_assert_offset!(DistributorRegisterBlock, ISACTIVER, 0x300);
+static_assert_offset!(DistributorRegisterBlock, ICACTIVER, 0x380);
+static_assert_offset!(DistributorRegisterBlock, IPRIORITYR, 0x400);
+static_assert_offset!(DistributorRegisterBlock, __reserved_1, 0x7FC);
+static_assert_offset!(DistributorRegisterBlock, ITARGETSR, 0x800);
+static_assert_offset!(DistributorRegisterBlock, __reserved_2, 0xBFC);
+static_assert_offset!(DistributorRegisterBlock, ICFGR, 0xC00);
+static_assert_offset!(DistributorRegisterBlock, __reserved_3, 0xD00);
+static_assert_offset!(DistributorRegisterBlock, SGIR, 0xF00);
+static_assert_size!(DistributorRegisterBlock, 0xF04);
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct CpuInterfaceRegisterBlock {
+    CTLR: ReadWrite<u32>, // 0x00
+    PMR: ReadWrite<u32>,  // 0x04
+    BPR: ReadWrite<u32>,  // 0x08
+    IAR: ReadOnly<u32>,   // 0x0C
+    EOIR: WriteOnly<u32>, // 0x10
+}
+
+static_assert_offset!(CpuInterfaceRegisterBlock, CTLR, 0x00);
+static_assert_offset!(CpuInterfaceRegisterBlock, PMR, 0x04);
+static_assert_offset!(CpuInterfaceRegisterBlock, BPR, 0x08);
+static_assert_offset!(CpuInterfaceRegisterBlock, IAR, 0x0C);
+static_assert_offset!(CpuInterfaceRegisterBlock, EOIR, 0x10);
+static_assert_size!(CpuInterfaceRegisterBlock, 0x14);
+
+// GICD_CTLR and GICC_CTLR: Forward interrupts. The firmware hands over in the
+// non-secure world with all sources in group 1, which this bit enables there.
+const CTLR_ENABLE: u32 = 1 << 0;
+
+/// Priority of all sources. Lower is more urgent, and the GIC-400 implements
+/// the upper 4 bits only.
+const DEFAULT_PRIORITY: u32 = 0xA0;
+
+/// Priority mask of the CPU interfaces, letting everything above it through
+const PRIORITY_MASK: u32 = 0xF0;
+
+/// The INTID that IAR reads while nothing is pending
+pub const SPURIOUS: u32 = 1023;
+
+/// The first shared peripheral interrupt
+const FIRST_SPI: usize = 32;
+
+/// Public interface to the distributor
+pub struct GicDistributor {
+    peripheral: Peripheral,
+}
+
+impl ops::Deref for GicDistributor {
+    type Target = DistributorRegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl GicDistributor {
+    pub fn new(peripheral: Peripheral) -> GicDistributor {
+        GicDistributor { peripheral }
+    }
+
+    /// Returns a pointer to the register block
+    fn ptr(&self) -> *const DistributorRegisterBlock {
+        mmio::base(self.peripheral) as *const _
+    }
+
+    /// The number of INTIDs that the distributor implements, a multiple of 32.
+    pub fn num_intids(&self) -> usize {
+        ((self.TYPER.get() & 0x1F) as usize + 1) * 32
+    }
+
+    /// Bring the distributor into a known state and switch it on.
+    ///
+    /// All SPIs are disabled, level triggered, and forwarded to core 0 with
+    /// the same priority. Call once, on the boot core, before any `enable()`.
+    pub fn init(&self) {
+        self.CTLR.set(0);
+
+        let num_intids = self.num_intids();
+        for reg in FIRST_SPI / 32..num_intids / 32 {
+            self.ICENABLER[reg].set(!0);
+            self.ICPENDR[reg].set(!0);
+            self.ICACTIVER[reg].set(!0);
+        }
+
+        let priorities = DEFAULT_PRIORITY * 0x0101_0101;
+        for reg in FIRST_SPI / 4..num_intids / 4 {
+            self.IPRIORITYR[reg].set(priorities);
+            self.ITARGETSR[reg].set(0x0101_0101);
+        }
+
+        for reg in FIRST_SPI / 16..num_intids / 16 {
+            self.ICFGR[reg].set(0);
+        }
+
+        self.CTLR.set(CTLR_ENABLE);
+    }
+
+    /// Set up the SGIs and PPIs of the executing core, which are banked per
+    /// core, with the default priority.
+    pub fn init_banked(&self) {
+        let priorities = DEFAULT_PRIORITY * 0x0101_0101;
+        for reg in 0..FIRST_SPI / 4 {
+            self.IPRIORITYR[reg].set(priorities);
+        }
+    }
+
+    /// Enable INTID `intid`. The set-enable registers are write-1-to-set, so
+    /// other sources are not affected.
+    ///
+    /// SGIs and PPIs are enabled on the executing core only.
+    pub fn enable(&self, intid: usize) {
+        self.ISENABLER[intid / 32].set(1 << (intid % 32));
+    }
+
+    /// Disable INTID `intid`.
+    pub fn disable(&self, intid: usize) {
+        self.ICENABLER[intid / 32].set(1 << (intid % 32));
+    }
+
+    /// Raise SGI `intid` (0..16) on all cores in `core_mask`.
+    pub fn send_sgi(&self, intid: usize, core_mask: u32) {
+        self.SGIR.set(((core_mask & 0xFF) << 16) | (intid as u32 & 0xF));
+    }
+}
+
+/// Public interface to the CPU interface of the executing core
+///
+/// All cores see their own CPU interface at the same address.
+pub struct GicCpuInterface {
+    peripheral: Peripheral,
+}
+
+impl ops::Deref for GicCpuInterface {
+    type Target = CpuInterfaceRegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl GicCpuInterface {
+    pub fn new(peripheral: Peripheral) -> GicCpuInterface {
+        GicCpuInterface { peripheral }
+    }
+
+    /// Returns a pointer to the register block
+    fn ptr(&self) -> *const CpuInterfaceRegisterBlock {
+        mmio::base(self.peripheral) as *const _
+    }
+
+    /// Let all priorities through, and switch the interface on. Call on each
+    /// core.
+    pub fn init(&self) {
+        self.PMR.set(PRIORITY_MASK);
+        self.BPR.set(0);
+        self.CTLR.set(CTLR_ENABLE);
+    }
+
+    /// Acknowledge the most urgent pending interrupt. Returns the raw IAR
+    /// value, which goes back to `end_of_interrupt()`, and holds the INTID in
+    /// bits 0 to 9. That is `SPURIOUS` if nothing is pending.
+    pub fn acknowledge(&self) -> u32 {
+        self.IAR.get()
+    }
+
+    /// Signal that the interrupt acknowledged as `iar` was handled.
+    pub fn end_of_interrupt(&self, iar: u32) {
+        self.EOIR.set(iar);
+    }
+}
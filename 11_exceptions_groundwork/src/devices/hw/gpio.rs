@@ -22,82 +22,249 @@
  * SOFTWARE.
  */
 
-use core::ops;
-use register::{mmio::ReadWrite, register_bitfields};
+use super::SysTmr;
+use crate::{
+    delays, interrupt, static_assert_offset, static_assert_size, sync::SpinLock, time, workqueue,
+};
+use core::{
+    ops,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+use raspi3_hal::mmio::{self, Peripheral};
+use register::{
+    mmio::{ReadOnly, ReadWrite, WriteOnly},
+    register_bitfields, Field,
+};
+
+/// Number of GPIO pins on the BCM2837
+pub const NUM_PINS: usize = 54;
+
+#[derive(Debug)]
+pub enum GpioError {
+    InvalidPin,
+    InterruptError,
+}
+pub type Result<T> = ::core::result::Result<T, GpioError>;
 
-// Descriptions taken from
-// https://github.com/raspberrypi/documentation/files/1888662/BCM2837-ARM-Peripherals.-.Revised.-.V2-1.pdf
 register_bitfields! {
     u32,
 
-    /// GPIO Function Select 1
-    GPFSEL1 [
-        /// Pin 15
-        FSEL15 OFFSET(15) NUMBITS(3) [
-            Input = 0b000,
-            Output = 0b001,
-            RXD0 = 0b100, // UART0     - Alternate function 0
-            RXD1 = 0b010  // Mini UART - Alternate function 5
-
-        ],
-
-        /// Pin 14
-        FSEL14 OFFSET(12) NUMBITS(3) [
-            Input = 0b000,
-            Output = 0b001,
-            TXD0 = 0b100, // UART0     - Alternate function 0
-            TXD1 = 0b010  // Mini UART - Alternate function 5
-        ]
-    ],
-
-    /// GPIO Pull-up/down Clock Register 0
-    GPPUDCLK0 [
-        /// Pin 15
-        PUDCLK15 OFFSET(15) NUMBITS(1) [
-            NoEffect = 0,
-            AssertClock = 1
-        ],
-
-        /// Pin 14
-        PUDCLK14 OFFSET(14) NUMBITS(1) [
-            NoEffect = 0,
-            AssertClock = 1
-        ]
+    /// GPIO Function Select, for ten pins each. The values are `Function`s.
+    GPFSEL [
+        FSEL0 OFFSET(0) NUMBITS(3) [],
+        FSEL1 OFFSET(3) NUMBITS(3) [],
+        FSEL2 OFFSET(6) NUMBITS(3) [],
+        FSEL3 OFFSET(9) NUMBITS(3) [],
+        FSEL4 OFFSET(12) NUMBITS(3) [],
+        FSEL5 OFFSET(15) NUMBITS(3) [],
+        FSEL6 OFFSET(18) NUMBITS(3) [],
+        FSEL7 OFFSET(21) NUMBITS(3) [],
+        FSEL8 OFFSET(24) NUMBITS(3) [],
+        FSEL9 OFFSET(27) NUMBITS(3) []
     ]
 }
 
+/// The function select field of each pin in its GPFSELn register
+const FSEL: [Field<u32, GPFSEL::Register>; 10] = [
+    GPFSEL::FSEL0,
+    GPFSEL::FSEL1,
+    GPFSEL::FSEL2,
+    GPFSEL::FSEL3,
+    GPFSEL::FSEL4,
+    GPFSEL::FSEL5,
+    GPFSEL::FSEL6,
+    GPFSEL::FSEL7,
+    GPFSEL::FSEL8,
+    GPFSEL::FSEL9,
+];
+
+/// Pin function, as encoded in the GPFSELn registers
+///
+/// Note that the alternate functions are not numbered in order.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Function {
+    Input = 0b000,
+    Output = 0b001,
+    Alt0 = 0b100,
+    Alt1 = 0b101,
+    Alt2 = 0b110,
+    Alt3 = 0b111,
+    Alt4 = 0b011,
+    Alt5 = 0b010,
+}
+
+/// Pull-up/down control, as encoded in GPPUD
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Pull {
+    Off = 0b00,
+    Down = 0b01,
+    Up = 0b10,
+}
+
+/// Edges that trigger an edge callback, see `GPIO::on_edge()`
+///
+/// The synchronous variants sample the pin with the system clock and filter
+/// out glitches. The asynchronous variants are not sampled and therefore also
+/// catch pulses that are shorter than a clock cycle.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Edge {
+    Rising,
+    Falling,
+    Both,
+    AsyncRising,
+    AsyncFalling,
+    AsyncBoth,
+}
+
+#[derive(Copy, Clone)]
+struct EdgeHandler {
+    callback: fn(usize),
+    debounce_us: u32,
+    last_us: u64,
+}
+
+/// Shared with the IRQ handler, which may run on any core. The edge detect
+/// enables are changed with the lock held as well, so that a pin's handler and
+/// its enables always agree.
+static EDGE_HANDLERS: SpinLock<[Option<EdgeHandler>; NUM_PINS]> = SpinLock::new([None; NUM_PINS]);
+
+/// Whether the IRQ handler is installed
+static IRQ_INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// The GPIO IRQs of the three pin banks. All of them are routed to the same
+/// handler, which checks both event status registers.
+const IRQS: [interrupt::Irq; 3] = [
+    interrupt::Irq::GpioBank0,
+    interrupt::Irq::GpioBank1,
+    interrupt::Irq::GpioBank2,
+];
+
+/// Microseconds for debouncing, from the BCM System Timer if available
+fn now_us() -> u64 {
+    match SysTmr::new(Peripheral::SysTimer).get_system_timer() {
+        0 => time::uptime(), // QEMU does not emulate the System Timer
+        t => t,
+    }
+}
+
+fn irq_handler() {
+    // Only the write-1-to-clear event status is touched, which leaves the
+    // bits of other pins alone
+    let gpio = unsafe { GPIO::steal() };
+    let now = now_us();
+
+    for (bank, eds) in gpio.GPEDS.iter().enumerate() {
+        // Event status bits are write-1-to-clear. Clear before calling the
+        // callbacks, so that edges arriving meanwhile are not lost, and so
+        // that the IRQ does not stay asserted.
+        let mut events = eds.get();
+        eds.set(events);
+
+        while events != 0 {
+            let bit = events.trailing_zeros() as usize;
+            events &= !(1 << bit);
+
+            let pin = bank * 32 + bit;
+            if pin >= NUM_PINS {
+                continue;
+            }
+
+            let callback = EDGE_HANDLERS.lock_irqsave(|h| match h[pin] {
+                Some(ref mut e) => {
+                    if e.debounce_us != 0
+                        && e.last_us != 0
+                        && now.wrapping_sub(e.last_us) < u64::from(e.debounce_us)
+                    {
+                        return None;
+                    }
+
+                    e.last_us = now;
+                    Some(e.callback)
+                }
+                None => None,
+            });
+
+            // The callback is the bottom half. It runs from the work queue,
+            // so a slow one does not hold up other IRQs.
+            if let Some(callback) = callback {
+                let _ = workqueue::queue_work(callback, pin);
+            }
+        }
+    }
+}
+
+// Descriptions taken from
+// https://github.com/raspberrypi/documentation/files/1888662/BCM2837-ARM-Peripherals.-.Revised.-.V2-1.pdf
 #[allow(non_snake_case)]
 #[repr(C)]
 pub struct RegisterBlock {
-    pub GPFSEL0: ReadWrite<u32>,                        // 0x00
-    pub GPFSEL1: ReadWrite<u32, GPFSEL1::Register>,     // 0x04
-    pub GPFSEL2: ReadWrite<u32>,                        // 0x08
-    pub GPFSEL3: ReadWrite<u32>,                        // 0x0C
-    pub GPFSEL4: ReadWrite<u32>,                        // 0x10
-    pub GPFSEL5: ReadWrite<u32>,                        // 0x14
-    __reserved_0: u32,                                  // 0x18
-    GPSET0: ReadWrite<u32>,                             // 0x1C
-    GPSET1: ReadWrite<u32>,                             // 0x20
-    __reserved_1: u32,                                  //
-    GPCLR0: ReadWrite<u32>,                             // 0x28
-    __reserved_2: [u32; 2],                             //
-    GPLEV0: ReadWrite<u32>,                             // 0x34
-    GPLEV1: ReadWrite<u32>,                             // 0x38
-    __reserved_3: u32,                                  //
-    GPEDS0: ReadWrite<u32>,                             // 0x40
-    GPEDS1: ReadWrite<u32>,                             // 0x44
-    __reserved_4: [u32; 7],                             //
-    GPHEN0: ReadWrite<u32>,                             // 0x64
-    GPHEN1: ReadWrite<u32>,                             // 0x68
-    __reserved_5: [u32; 10],                            //
-    pub GPPUD: ReadWrite<u32>,                          // 0x94
-    pub GPPUDCLK0: ReadWrite<u32, GPPUDCLK0::Register>, // 0x98
-    pub GPPUDCLK1: ReadWrite<u32>,                      // 0x9C
+    GPFSEL: [ReadWrite<u32, GPFSEL::Register>; 6], // 0x00
+    __reserved_0: u32,                             // 0x18
+    GPSET: [WriteOnly<u32>; 2],                    // 0x1C
+    __reserved_1: u32,                             // 0x24
+    GPCLR: [WriteOnly<u32>; 2],                    // 0x28
+    __reserved_2: u32,                             // 0x30
+    GPLEV: [ReadOnly<u32>; 2],                     // 0x34
+    __reserved_3: u32,                             // 0x3C
+    GPEDS: [ReadWrite<u32>; 2],                    // 0x40
+    __reserved_4: u32,                             // 0x48
+    GPREN: [ReadWrite<u32>; 2],                    // 0x4C
+    __reserved_5: u32,                             // 0x54
+    GPFEN: [ReadWrite<u32>; 2],                    // 0x58
+    __reserved_6: u32,                             // 0x60
+    GPHEN: [ReadWrite<u32>; 2],                    // 0x64
+    __reserved_7: u32,                             // 0x6C
+    GPLEN: [ReadWrite<u32>; 2],                    // 0x70
+    __reserved_8: u32,                             // 0x78
+    GPAREN: [ReadWrite<u32>; 2],                   // 0x7C
+    __reserved_9: u32,                             // 0x84
+    GPAFEN: [ReadWrite<u32>; 2],                   // 0x88
+    __reserved_10: u32,                            // 0x90
+    GPPUD: ReadWrite<u32>,                         // 0x94
+    GPPUDCLK: [ReadWrite<u32>; 2],                 // 0x98
+    __reserved_11: [u32; 17],                      // 0xA0
+    GPIO_PUP_PDN_CNTRL: [ReadWrite<u32>; 4],       // 0xE4, BCM2711 only
 }
 
+static_assert_offset!(RegisterBlock, GPFSEL, 0x00);
+static_assert_offset!(RegisterBlock, __reserved_0, 0x18);
+static_assert_offset!(RegisterBlock, GPSET, 0x1C);
+static_assert_offset!(RegisterBlock, __reserved_1, 0x24);
+static_assert_offset!(RegisterBlock, GPCLR, 0x28);
+static_assert_offset!(RegisterBlock, __reserved_2, 0x30);
+static_assert_offset!(RegisterBlock, GPLEV, 0x34);
+static_assert_offset!(RegisterBlock, __reserved_3, 0x3C);
+static_assert_offset!(RegisterBlock, GPEDS, 0x40);
+static_assert_offset!(RegisterBlock, __reserved_4, 0x48);
+static_assert_offset!(RegisterBlock, GPREN, 0x4C);
+static_assert_offset!(RegisterBlock, __reserved_5, 0x54);
+static_assert_offset!(RegisterBlock, GPFEN, 0x58);
+static_assert_offset!(RegisterBlock, __reserved_6, 0x60);
+static_assert_offset!(RegisterBlock, GPHEN, 0x64);
+static_assert_offset!(RegisterBlock, __reserved_7, 0x6C);
+static_assert_offset!(RegisterBlock, GPLEN, 0x70);
+static_assert_offset!(RegisterBlock, __reserved_8, 0x78);
+static_assert_offset!(RegisterBlock, GPAREN, 0x7C);
+static_assert_offset!(RegisterBlock, __reserved_9, 0x84);
+static_assert_offset!(RegisterBlock, GPAFEN, 0x88);
+static_assert_offset!(RegisterBlock, __reserved_10, 0x90);
+static_assert_offset!(RegisterBlock, GPPUD, 0x94);
+static_assert_offset!(RegisterBlock, GPPUDCLK, 0x98);
+static_assert_offset!(RegisterBlock, __reserved_11, 0xA0);
+static_assert_offset!(RegisterBlock, GPIO_PUP_PDN_CNTRL, 0xE4);
+static_assert_size!(RegisterBlock, 0xF4);
+
+/// Set by the first `GPIO::take()`
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
 /// Public interface to the GPIO MMIO area
+///
+/// There is one, handed out by `GPIO::take()`. Code that only needs a few
+/// pins is given those as `Pin`s instead.
+///
+/// All pin arguments must be below `NUM_PINS`.
 pub struct GPIO {
-    base_addr: usize,
+    peripheral: Peripheral,
 }
 
 impl ops::Deref for GPIO {
@@ -109,12 +276,353 @@ impl ops::Deref for GPIO {
 }
 
 impl GPIO {
-    pub fn new(base_addr: usize) -> GPIO {
-        GPIO { base_addr }
+    /// Get the GPIO. Returns `None` if it was taken before.
+    pub fn take() -> Option<GPIO> {
+        if TAKEN.swap(true, Ordering::Relaxed) {
+            return None;
+        }
+
+        Some(GPIO {
+            peripheral: Peripheral::Gpio,
+        })
+    }
+
+    /// Get the GPIO, even if it was taken before.
+    ///
+    /// # Safety
+    ///
+    /// The GPIO is likely in use elsewhere already. Only touch pins that are
+    /// owned by the caller, through a `Pin` or otherwise, and only through
+    /// registers where that can not disturb other pins.
+    pub unsafe fn steal() -> GPIO {
+        TAKEN.store(true, Ordering::Relaxed);
+
+        GPIO {
+            peripheral: Peripheral::Gpio,
+        }
     }
 
     /// Returns a pointer to the register block
     fn ptr(&self) -> *const RegisterBlock {
-        self.base_addr as *const _
+        mmio::base(self.peripheral) as *const _
+    }
+
+    /// Select the function of a pin
+    pub fn set_function(&self, pin: usize, function: Function) {
+        self.GPFSEL[pin / 10].modify(FSEL[pin % 10].val(function as u32));
+    }
+
+    /// Drive an output pin high
+    pub fn set_high(&self, pin: usize) {
+        self.GPSET[pin / 32].set(1 << (pin % 32));
+    }
+
+    /// Drive an output pin low
+    pub fn set_low(&self, pin: usize) {
+        self.GPCLR[pin / 32].set(1 << (pin % 32));
+    }
+
+    /// Read the current level of a pin
+    pub fn read(&self, pin: usize) -> bool {
+        self.GPLEV[pin / 32].get() & (1 << (pin % 32)) != 0
+    }
+
+    /// Take ownership of `pin` for the typed pin API.
+    ///
+    /// Returns `None` if the pin number is invalid, or if the pin is already
+    /// owned by another `Pin`. Ownership is given back when the pin is dropped.
+    pub fn take_pin(&self, pin: usize) -> Option<Pin> {
+        if pin >= NUM_PINS {
+            return None;
+        }
+
+        let mask = 1 << pin;
+        if PINS_TAKEN.fetch_or(mask, Ordering::Relaxed) & mask != 0 {
+            return None;
+        }
+
+        Some(Pin(PinInner { pin }))
+    }
+
+    /// Call `callback` whenever `edge` is detected on `pin`.
+    ///
+    /// Edges that occur within `debounce_us` microseconds after the last
+    /// reported one are ignored. Pass zero to report every edge.
+    ///
+    /// The IRQ handler only queues the callback, it runs from the
+    /// `workqueue` with IRQs unmasked. The pin should already be configured
+    /// as an input. IRQs must be unmasked on the core for edges to be
+    /// detected at all. A previously registered callback of `pin` is
+    /// replaced.
+    pub fn on_edge(
+        &self,
+        pin: usize,
+        edge: Edge,
+        debounce_us: u32,
+        callback: fn(usize),
+    ) -> Result<()> {
+        if pin >= NUM_PINS {
+            return Err(GpioError::InvalidPin);
+        }
+
+        self.install_irq_handler()?;
+
+        EDGE_HANDLERS.lock_irqsave(|h| {
+            h[pin] = Some(EdgeHandler {
+                callback,
+                debounce_us,
+                last_us: 0,
+            });
+
+            self.set_edge_detect(pin, Some(edge));
+        });
+
+        Ok(())
+    }
+
+    /// Stop edge detection on `pin` and remove its callback.
+    pub fn clear_edge(&self, pin: usize) {
+        if pin >= NUM_PINS {
+            return;
+        }
+
+        EDGE_HANDLERS.lock_irqsave(|h| {
+            self.set_edge_detect(pin, None);
+            h[pin] = None;
+        });
+    }
+
+    fn install_irq_handler(&self) -> Result<()> {
+        if IRQ_INSTALLED.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        for &irq in IRQS.iter() {
+            if interrupt::register_handler(irq, irq_handler).is_err() {
+                return Err(GpioError::InterruptError);
+            }
+        }
+
+        IRQ_INSTALLED.store(true, Ordering::Relaxed);
+        for &irq in IRQS.iter() {
+            interrupt::enable(irq);
+        }
+
+        Ok(())
+    }
+
+    /// Program the edge detect enable registers of `pin`. Must be called with
+    /// IRQs masked, because the registers are updated read-modify-write.
+    fn set_edge_detect(&self, pin: usize, edge: Option<Edge>) {
+        let bank = pin / 32;
+        let mask = 1 << (pin % 32);
+
+        let (rising, falling, async_rising, async_falling) = match edge {
+            Some(Edge::Rising) => (true, false, false, false),
+            Some(Edge::Falling) => (false, true, false, false),
+            Some(Edge::Both) => (true, true, false, false),
+            Some(Edge::AsyncRising) => (false, false, true, false),
+            Some(Edge::AsyncFalling) => (false, false, false, true),
+            Some(Edge::AsyncBoth) => (false, false, true, true),
+            None => (false, false, false, false),
+        };
+
+        for &(reg, enable) in &[
+            (&self.GPREN[bank], rising),
+            (&self.GPFEN[bank], falling),
+            (&self.GPAREN[bank], async_rising),
+            (&self.GPAFEN[bank], async_falling),
+        ] {
+            if enable {
+                reg.set(reg.get() | mask);
+            } else {
+                reg.set(reg.get() & !mask);
+            }
+        }
+
+        // Discard an event that may have been latched before
+        self.GPEDS[bank].set(mask);
+    }
+
+    /// Set the pull-up/down control of a pin
+    ///
+    /// See `set_pull_many()` for configuring several pins at once.
+    pub fn set_pull(&self, pin: usize, pull: Pull) {
+        self.set_pull_many(&[(pin, pull)]);
+    }
+
+    /// Set the pull-up/down control of several pins.
+    ///
+    /// Follows the sequence from the datasheet: write the control signal,
+    /// wait 150 cycles for it to settle, clock it into the pads, wait another
+    /// 150 cycles, then remove both again. Pins below 32 are clocked through
+    /// GPPUDCLK0, the others through GPPUDCLK1.
+    ///
+    /// The control signal is shared by all pins, so the sequence runs once per
+    /// distinct `Pull` value in `pins` instead of once per pin.
+    ///
+    /// The BCM2711 of the Pi 4 has neither, but two bits per pin in the
+    /// GPIO_PUP_PDN_CNTRL registers, with up and down swapped.
+    pub fn set_pull_many(&self, pins: &[(usize, Pull)]) {
+        if cfg!(feature = "rpi4") {
+            for &(pin, pull) in pins {
+                let bits = match pull {
+                    Pull::Off => 0b00,
+                    Pull::Up => 0b01,
+                    Pull::Down => 0b10,
+                };
+                let shift = (pin % 16) * 2;
+                let reg = &self.GPIO_PUP_PDN_CNTRL[pin / 16];
+
+                reg.set((reg.get() & !(0b11 << shift)) | (bits << shift));
+            }
+
+            return;
+        }
+
+        for &pull in &[Pull::Off, Pull::Down, Pull::Up] {
+            let mut clk = [0u32; 2];
+            for &(pin, _) in pins.iter().filter(|&&(_, p)| p == pull) {
+                clk[pin / 32] |= 1 << (pin % 32);
+            }
+
+            if clk == [0, 0] {
+                continue;
+            }
+
+            self.GPPUD.set(pull as u32);
+            delays::wait_cycles(150);
+
+            self.GPPUDCLK[0].set(clk[0]);
+            self.GPPUDCLK[1].set(clk[1]);
+            delays::wait_cycles(150);
+
+            self.GPPUD.set(0);
+            self.GPPUDCLK[0].set(0);
+            self.GPPUDCLK[1].set(0);
+        }
+    }
+}
+
+/// One bit per pin that is currently owned by a `Pin`, `InputPin`, `OutputPin`
+/// or `AltPin`
+static PINS_TAKEN: AtomicU64 = AtomicU64::new(0);
+
+/// Alternate functions, for `Pin::into_alt()`
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum AltFn {
+    Alt0,
+    Alt1,
+    Alt2,
+    Alt3,
+    Alt4,
+    Alt5,
+}
+
+impl From<AltFn> for Function {
+    fn from(alt: AltFn) -> Function {
+        match alt {
+            AltFn::Alt0 => Function::Alt0,
+            AltFn::Alt1 => Function::Alt1,
+            AltFn::Alt2 => Function::Alt2,
+            AltFn::Alt3 => Function::Alt3,
+            AltFn::Alt4 => Function::Alt4,
+            AltFn::Alt5 => Function::Alt5,
+        }
+    }
+}
+
+/// Owns a pin number and releases it on drop
+struct PinInner {
+    pin: usize,
+}
+
+impl PinInner {
+    fn gpio(&self) -> GPIO {
+        // The pin is ours. GPFSEL is read-modify-write, so reconfiguring
+        // pins of the same register on two cores at once would still race,
+        // just as it would through the one `GPIO`.
+        unsafe { GPIO::steal() }
+    }
+
+    fn reconfigure(self, function: Function) -> PinInner {
+        self.gpio().set_function(self.pin, function);
+        self
+    }
+}
+
+impl Drop for PinInner {
+    fn drop(&mut self) {
+        PINS_TAKEN.fetch_and(!(1 << self.pin), Ordering::Relaxed);
+    }
+}
+
+/// A pin in unknown configuration, as returned by `GPIO::take_pin()`
+///
+/// The pin numbers are checked when the pin is taken, not at compile time,
+/// because the toolchain used by this tutorial does not yet support const
+/// generics. What the types do guarantee is that a pin can only have one
+/// owner, and that only the operations of its current mode are available.
+pub struct Pin(PinInner);
+
+/// A pin configured as output
+pub struct OutputPin(PinInner);
+
+/// A pin configured as input
+pub struct InputPin(PinInner);
+
+/// A pin configured for one of its alternate functions
+pub struct AltPin(PinInner);
+
+macro_rules! impl_pin_conversions {
+    ($($t:ident),*) => {$(
+        impl $t {
+            /// The BCM GPIO number of this pin
+            pub fn number(&self) -> usize {
+                self.0.pin
+            }
+
+            pub fn into_output(self) -> OutputPin {
+                OutputPin(self.0.reconfigure(Function::Output))
+            }
+
+            pub fn into_input(self, pull: Pull) -> InputPin {
+                let inner = self.0.reconfigure(Function::Input);
+                inner.gpio().set_pull(inner.pin, pull);
+
+                InputPin(inner)
+            }
+
+            pub fn into_alt(self, alt: AltFn) -> AltPin {
+                AltPin(self.0.reconfigure(alt.into()))
+            }
+        }
+    )*};
+}
+
+impl_pin_conversions!(Pin, OutputPin, InputPin, AltPin);
+
+impl OutputPin {
+    pub fn set_high(&self) {
+        self.0.gpio().set_high(self.0.pin);
+    }
+
+    pub fn set_low(&self) {
+        self.0.gpio().set_low(self.0.pin);
+    }
+}
+
+impl InputPin {
+    pub fn is_high(&self) -> bool {
+        self.0.gpio().read(self.0.pin)
+    }
+
+    pub fn is_low(&self) -> bool {
+        !self.is_high()
+    }
+
+    /// See `GPIO::on_edge()`
+    pub fn on_edge(&self, edge: Edge, debounce_us: u32, callback: fn(usize)) -> Result<()> {
+        self.0.gpio().on_edge(self.0.pin, edge, debounce_us, callback)
     }
 }
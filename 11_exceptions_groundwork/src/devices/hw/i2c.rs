@@ -0,0 +1,394 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! I2C master on the BSC1 controller, which is wired to GPIO2 (SDA) and GPIO3
+//! (SCL) on the pin header.
+//!
+//! All transfers are polled. The controller has a 16 byte FIFO, which is
+//! refilled and drained while a longer transfer runs.
+
+use super::{gpio, sys_timer::Deadline, videocore_mbox};
+use crate::{delays, static_assert_offset, static_assert_size};
+use core::{fmt, ops};
+use raspi3_hal::mmio::{self, Peripheral};
+use register::{mmio::ReadWrite, register_bitfields};
+
+// BSC registers.
+//
+// Descriptions taken from
+// https://github.com/raspberrypi/documentation/files/1888662/BCM2837-ARM-Peripherals.-.Revised.-.V2-1.pdf
+register_bitfields! {
+    u32,
+
+    /// Control
+    C [
+        /// I2C Enable
+        I2CEN OFFSET(15) NUMBITS(1) [],
+
+        /// Start Transfer
+        ST OFFSET(7) NUMBITS(1) [],
+
+        /// FIFO Clear
+        CLEAR OFFSET(4) NUMBITS(2) [
+            Clear = 0b01
+        ],
+
+        /// Read Transfer, instead of a write
+        READ OFFSET(0) NUMBITS(1) []
+    ],
+
+    /// Status. CLKT, ERR and DONE are cleared by writing 1.
+    S [
+        /// Slave held SCL low for longer than the CLKT register allows
+        CLKT OFFSET(9) NUMBITS(1) [],
+
+        /// Slave did not acknowledge its address or a byte
+        ERR OFFSET(8) NUMBITS(1) [],
+
+        /// FIFO contains at least one byte
+        RXD OFFSET(5) NUMBITS(1) [],
+
+        /// FIFO can accept data
+        TXD OFFSET(4) NUMBITS(1) [],
+
+        /// Transfer Done
+        DONE OFFSET(1) NUMBITS(1) [],
+
+        /// Transfer Active
+        TA OFFSET(0) NUMBITS(1) []
+    ]
+}
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct RegisterBlock {
+    C: ReadWrite<u32, C::Register>, // 0x00
+    S: ReadWrite<u32, S::Register>, // 0x04
+    DLEN: ReadWrite<u32>,           // 0x08
+    A: ReadWrite<u32>,              // 0x0C
+    FIFO: ReadWrite<u32>,           // 0x10
+    DIV: ReadWrite<u32>,            // 0x14
+    DEL: ReadWrite<u32>,            // 0x18
+    CLKT: ReadWrite<u32>,           // 0x1C
+}
+
+static_assert_offset!(RegisterBlock, C, 0x00);
+static_assert_offset!(RegisterBlock, S, 0x04);
+static_assert_offset!(RegisterBlock, DLEN, 0x08);
+static_assert_offset!(RegisterBlock, A, 0x0C);
+static_assert_offset!(RegisterBlock, FIFO, 0x10);
+static_assert_offset!(RegisterBlock, DIV, 0x14);
+static_assert_offset!(RegisterBlock, DEL, 0x18);
+static_assert_offset!(RegisterBlock, CLKT, 0x1C);
+static_assert_size!(RegisterBlock, 0x20);
+
+const FIFO_SIZE: usize = 16;
+
+/// Upper limit for a single transfer, given by the DLEN register.
+const MAX_LEN: usize = 0xFFFF;
+
+/// Enough for a 64 KiB transfer at 100 kHz, with some margin.
+const TRANSFER_TIMEOUT_US: u64 = 10_000_000;
+
+#[derive(Debug)]
+pub enum I2cError {
+    /// The slave did not acknowledge its address or a byte, e.g. because
+    /// there is none at that address.
+    Nack,
+    /// The slave stretched the clock for too long.
+    ClockStretchTimeout,
+    /// The transfer did not finish in time.
+    Timeout,
+    /// Zero or too many bytes. The write part of `write_read()` must fit into
+    /// the FIFO.
+    InvalidLength,
+    /// A 7 bit address was expected.
+    InvalidAddress,
+    MailboxError,
+}
+pub type Result<T> = ::core::result::Result<T, I2cError>;
+
+/// Bus speeds
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Speed {
+    /// 100 kHz
+    Standard,
+    /// 400 kHz
+    Fast,
+}
+
+impl Speed {
+    fn hz(self) -> u32 {
+        match self {
+            Speed::Standard => 100_000,
+            Speed::Fast => 400_000,
+        }
+    }
+}
+
+/// Which of the addresses that `I2c::scan()` probed acknowledged.
+#[derive(Copy, Clone)]
+pub struct ScanResult(u128);
+
+impl ScanResult {
+    pub fn contains(&self, addr: u8) -> bool {
+        addr < 128 && self.0 & (1 << addr) != 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..128).filter(move |&addr| self.contains(addr))
+    }
+}
+
+/// Lists the addresses, e.g. `0x3c 0x68`, or `none`.
+impl fmt::Display for ScanResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 == 0 {
+            return write!(f, "none");
+        }
+
+        for (i, addr) in self.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{:#04x}", addr)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Public interface to the BSC1 I2C master
+pub struct I2c {
+    peripheral: Peripheral,
+}
+
+impl ops::Deref for I2c {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl I2c {
+    pub fn new(peripheral: Peripheral) -> I2c {
+        I2c { peripheral }
+    }
+
+    /// Returns a pointer to the register block
+    fn ptr(&self) -> *const RegisterBlock {
+        mmio::base(self.peripheral) as *const _
+    }
+
+    /// Map the controller to GPIO2/3 and set the clock divisor for `speed`.
+    ///
+    /// The BSC is clocked by the core clock, whose rate is asked from the
+    /// firmware.
+    pub fn init(
+        &self,
+        v_mbox: &mut videocore_mbox::VideocoreMbox,
+        gpio: &gpio::GPIO,
+        speed: Speed,
+    ) -> Result<()> {
+        let core_clock =
+            videocore_mbox::clock::get_rate(v_mbox, videocore_mbox::Clock::Core)
+                .map_err(|_| I2cError::MailboxError)?;
+        if core_clock == 0 {
+            return Err(I2cError::MailboxError);
+        }
+
+        gpio.set_function(2, gpio::Function::Alt0);
+        gpio.set_function(3, gpio::Function::Alt0);
+        gpio.set_pull_many(&[(2, gpio::Pull::Up), (3, gpio::Pull::Up)]);
+
+        // The hardware rounds CDIV down to an even number. Rounding up here
+        // keeps the bus at or below the requested speed.
+        let cdiv = (core_clock + speed.hz() - 1) / speed.hz();
+        self.DIV.set((cdiv + 1) & !1 & 0xFFFF);
+
+        self.C.write(C::I2CEN::SET + C::CLEAR::Clear);
+        self.clear_status();
+
+        Ok(())
+    }
+
+    fn clear_status(&self) {
+        self.S.write(S::CLKT::SET + S::ERR::SET + S::DONE::SET);
+    }
+
+    /// Set up a transfer of `len` bytes to or from `addr`, with an empty FIFO.
+    fn prepare(&self, addr: u8, len: usize) -> Result<()> {
+        if addr > 0x7F {
+            return Err(I2cError::InvalidAddress);
+        }
+        if len == 0 || len > MAX_LEN {
+            return Err(I2cError::InvalidLength);
+        }
+
+        self.C.write(C::I2CEN::SET + C::CLEAR::Clear);
+        self.clear_status();
+        self.A.set(u32::from(addr));
+        self.DLEN.set(len as u32);
+
+        Ok(())
+    }
+
+    /// The error flagged in the status register, if any.
+    fn status_error(&self) -> Result<()> {
+        if self.S.is_set(S::ERR) {
+            Err(I2cError::Nack)
+        } else if self.S.is_set(S::CLKT) {
+            Err(I2cError::ClockStretchTimeout)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Wait for DONE, feeding the FIFO from `data` in the meantime.
+    fn finish_write(&self, data: &[u8], mut sent: usize) -> Result<()> {
+        let mut result = Ok(());
+
+        let done = delays::poll_timeout(delays::deadline(TRANSFER_TIMEOUT_US), || {
+            sent += self.fill_fifo(&data[sent..]);
+
+            result = self.status_error();
+            result.is_err() || self.S.is_set(S::DONE)
+        });
+
+        self.finish(done.map_err(|_| I2cError::Timeout).and(result))
+    }
+
+    /// Wait for DONE until `deadline`, draining the FIFO into `buf` in the
+    /// meantime.
+    fn finish_read(&self, buf: &mut [u8], deadline: Deadline) -> Result<()> {
+        let mut received = 0;
+        let mut result = Ok(());
+
+        let done = delays::poll_timeout(deadline, || {
+            while received < buf.len() && self.S.is_set(S::RXD) {
+                buf[received] = self.FIFO.get() as u8;
+                received += 1;
+            }
+
+            // After a repeated start, DONE may still be set from the write, so
+            // all bytes must be in as well.
+            result = self.status_error();
+            result.is_err() || (self.S.is_set(S::DONE) && received == buf.len())
+        });
+
+        self.finish(done.map_err(|_| I2cError::Timeout).and(result))
+    }
+
+    /// Leave the controller idle again, with the status of the transfer
+    /// cleared.
+    fn finish(&self, result: Result<()>) -> Result<()> {
+        self.C.write(C::I2CEN::SET + C::CLEAR::Clear);
+        self.clear_status();
+
+        result
+    }
+
+    /// Fill the FIFO with as much of `data` as fits, and return how much that
+    /// was.
+    fn fill_fifo(&self, data: &[u8]) -> usize {
+        let mut sent = 0;
+        while sent < data.len() && self.S.is_set(S::TXD) {
+            self.FIFO.set(u32::from(data[sent]));
+            sent += 1;
+        }
+
+        sent
+    }
+
+    /// Write `data` to the slave at `addr`.
+    pub fn write(&self, addr: u8, data: &[u8]) -> Result<()> {
+        self.prepare(addr, data.len())?;
+
+        let sent = self.fill_fifo(data);
+        self.C.write(C::I2CEN::SET + C::ST::SET);
+
+        self.finish_write(data, sent)
+    }
+
+    /// Read `buf.len()` bytes from the slave at `addr`.
+    pub fn read(&self, addr: u8, buf: &mut [u8]) -> Result<()> {
+        self.prepare(addr, buf.len())?;
+        self.C.write(C::I2CEN::SET + C::ST::SET + C::READ::SET);
+
+        self.finish_read(buf, delays::deadline(TRANSFER_TIMEOUT_US))
+    }
+
+    /// Write `data`, then read into `buf` after a repeated start instead of a
+    /// stop, e.g. to read a register of the slave. `data` must fit into the
+    /// FIFO.
+    ///
+    /// The BSC has no explicit repeated start. Starting the read while the
+    /// write is still active makes it send one once the write is done, though.
+    pub fn write_read(&self, addr: u8, data: &[u8], buf: &mut [u8]) -> Result<()> {
+        if data.len() > FIFO_SIZE || buf.is_empty() || buf.len() > MAX_LEN {
+            return Err(I2cError::InvalidLength);
+        }
+
+        self.prepare(addr, data.len())?;
+        self.fill_fifo(data);
+        self.C.write(C::I2CEN::SET + C::ST::SET);
+
+        // Both halves together share one timeout
+        let deadline = delays::deadline(TRANSFER_TIMEOUT_US);
+
+        // Wait for the write to become active, but not for it to end
+        let mut result = Ok(());
+        let active = delays::poll_timeout(deadline, || {
+            result = self.status_error();
+            result.is_err() || self.S.is_set(S::TA) || self.S.is_set(S::DONE)
+        });
+        if let Err(e) = active.map_err(|_| I2cError::Timeout).and(result) {
+            return self.finish(Err(e));
+        }
+
+        self.DLEN.set(buf.len() as u32);
+        self.C.write(C::I2CEN::SET + C::ST::SET + C::READ::SET);
+
+        self.finish_read(buf, deadline)
+    }
+
+    /// Probe the addresses 0x08 to 0x77 with a one byte read, and return the
+    /// ones that acknowledged.
+    ///
+    /// A read is harmless for most devices, unlike a write, which could change
+    /// the register pointer of an EEPROM or the like.
+    pub fn scan(&self) -> ScanResult {
+        let mut found = 0u128;
+        let mut byte = [0u8];
+
+        for addr in 0x08..=0x77 {
+            if self.read(addr, &mut byte).is_ok() {
+                found |= 1 << addr;
+            }
+        }
+
+        ScanResult(found)
+    }
+}
@@ -0,0 +1,156 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use crate::{static_assert_offset, static_assert_size};
+use core::ops;
+use raspi3_hal::mmio::{self, Peripheral};
+use register::mmio::{ReadOnly, ReadWrite, WriteOnly};
+
+// The BCM2837 interrupt controller for the peripheral (GPU) IRQs.
+//
+// Descriptions taken from
+// https://github.com/raspberrypi/documentation/files/1888662/BCM2837-ARM-Peripherals.-.Revised.-.V2-1.pdf
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct RegisterBlock {
+    IRQ_BASIC_PENDING: ReadOnly<u32>,   // 0x00
+    IRQ_PENDING_1: ReadOnly<u32>,       // 0x04
+    IRQ_PENDING_2: ReadOnly<u32>,       // 0x08
+    FIQ_CONTROL: ReadWrite<u32>,        // 0x0C
+    ENABLE_IRQS_1: WriteOnly<u32>,      // 0x10
+    ENABLE_IRQS_2: WriteOnly<u32>,      // 0x14
+    ENABLE_BASIC_IRQS: WriteOnly<u32>,  // 0x18
+    DISABLE_IRQS_1: WriteOnly<u32>,     // 0x1C
+    DISABLE_IRQS_2: WriteOnly<u32>,     // 0x20
+    DISABLE_BASIC_IRQS: WriteOnly<u32>, // 0x24
+}
+
+static_assert_offset!(RegisterBlock, IRQ_BASIC_PENDING, 0x00);
+static_assert_offset!(RegisterBlock, IRQ_PENDING_1, 0x04);
+static_assert_offset!(RegisterBlock, IRQ_PENDING_2, 0x08);
+static_assert_offset!(RegisterBlock, FIQ_CONTROL, 0x0C);
+static_assert_offset!(RegisterBlock, ENABLE_IRQS_1, 0x10);
+static_assert_offset!(RegisterBlock, ENABLE_IRQS_2, 0x14);
+static_assert_offset!(RegisterBlock, ENABLE_BASIC_IRQS, 0x18);
+static_assert_offset!(RegisterBlock, DISABLE_IRQS_1, 0x1C);
+static_assert_offset!(RegisterBlock, DISABLE_IRQS_2, 0x20);
+static_assert_offset!(RegisterBlock, DISABLE_BASIC_IRQS, 0x24);
+static_assert_size!(RegisterBlock, 0x28);
+
+// Bits of IRQ_BASIC_PENDING saying that IRQ_PENDING_1/2 have bits set
+const BASIC_PENDING_1: u32 = 1 << 8;
+const BASIC_PENDING_2: u32 = 1 << 9;
+
+// FIQ_CONTROL: Routes the selected source to the FIQ instead of the IRQ
+const FIQ_ENABLE: u32 = 1 << 7;
+
+// Bits 10 to 20 of IRQ_BASIC_PENDING mirror these GPU IRQs
+const BASIC_MIRRORED_SHIFT: usize = 10;
+const BASIC_MIRRORED_IRQS: [u32; 11] = [7, 9, 10, 18, 19, 53, 54, 55, 56, 57, 62];
+
+/// Public interface to the interrupt controller
+pub struct IrqCtrl {
+    peripheral: Peripheral,
+}
+
+impl ops::Deref for IrqCtrl {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl IrqCtrl {
+    pub fn new(peripheral: Peripheral) -> IrqCtrl {
+        IrqCtrl { peripheral }
+    }
+
+    /// Returns a pointer to the register block
+    fn ptr(&self) -> *const RegisterBlock {
+        mmio::base(self.peripheral) as *const _
+    }
+
+    /// Enable GPU IRQ number `irq` (0..64).
+    ///
+    /// The enable registers are write-1-to-set, so other sources are not
+    /// affected.
+    pub fn enable(&self, irq: usize) {
+        if irq < 32 {
+            self.ENABLE_IRQS_1.set(1 << irq);
+        } else {
+            self.ENABLE_IRQS_2.set(1 << (irq - 32));
+        }
+    }
+
+    /// Disable GPU IRQ number `irq` (0..64).
+    pub fn disable(&self, irq: usize) {
+        if irq < 32 {
+            self.DISABLE_IRQS_1.set(1 << irq);
+        } else {
+            self.DISABLE_IRQS_2.set(1 << (irq - 32));
+        }
+    }
+
+    /// Route GPU IRQ number `irq` (0..64) to the FIQ.
+    ///
+    /// There is only one FIQ source, so this replaces any previous one. The
+    /// source should be disabled as IRQ, or it is signalled as both.
+    pub fn set_fiq(&self, irq: usize) {
+        self.FIQ_CONTROL.set(FIQ_ENABLE | irq as u32);
+    }
+
+    /// Stop routing any source to the FIQ.
+    pub fn clear_fiq(&self) {
+        self.FIQ_CONTROL.set(0);
+    }
+
+    /// All pending GPU IRQs, with bit N set if IRQ number N is pending.
+    ///
+    /// Starts with the basic pending register, and reads the other two only if
+    /// it says that they have bits set.
+    pub fn pending(&self) -> u64 {
+        let basic = self.IRQ_BASIC_PENDING.get();
+        let mut pending = 0;
+
+        if basic & BASIC_PENDING_1 != 0 {
+            pending |= u64::from(self.IRQ_PENDING_1.get());
+        }
+
+        if basic & BASIC_PENDING_2 != 0 {
+            pending |= u64::from(self.IRQ_PENDING_2.get()) << 32;
+        }
+
+        // Some GPU IRQs are mirrored into the basic pending register. These
+        // do not set the "pending 1/2" bits above, so they would be missed
+        // without decoding them here.
+        for (bit, &irq) in BASIC_MIRRORED_IRQS.iter().enumerate() {
+            if basic & (1 << (BASIC_MIRRORED_SHIFT + bit)) != 0 {
+                pending |= 1 << irq;
+            }
+        }
+
+        pending
+    }
+}
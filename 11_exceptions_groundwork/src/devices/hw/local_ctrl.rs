@@ -0,0 +1,162 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use crate::{static_assert_offset, static_assert_size};
+use core::ops;
+use raspi3_hal::mmio::{self, Peripheral};
+use register::{mmio::*, register_bitfields};
+
+// ARM local peripherals. These are not part of the BCM2837 peripheral MMIO
+// range, but live in a separate block at 0x4000_0000.
+//
+// Descriptions taken from
+// https://www.raspberrypi.org/documentation/hardware/raspberrypi/bcm2836/QA7_rev3.4.pdf
+register_bitfields! {
+    u32,
+
+    /// Core timers interrupt control
+    TIMER_INT_CNTL [
+        /// Non-secure physical timer IRQ control. If set, the FIQ bit for
+        /// this timer must be cleared.
+        CNTPNSIRQ_IRQ OFFSET(1) NUMBITS(1) [
+            Disabled = 0,
+            Enabled = 1
+        ]
+    ],
+
+    /// Core mailboxes interrupt control
+    MAILBOX_INT_CNTL [
+        /// Mailbox 0 IRQ control. If set, the FIQ bit for this mailbox must
+        /// be cleared.
+        MAILBOX0_IRQ OFFSET(0) NUMBITS(1) [
+            Disabled = 0,
+            Enabled = 1
+        ]
+    ],
+
+    /// Core interrupt source
+    IRQ_SOURCE [
+        /// Non-secure physical timer interrupt pending
+        CNTPNSIRQ OFFSET(1) NUMBITS(1) [],
+
+        /// Mailbox 0 interrupt pending
+        MAILBOX0 OFFSET(4) NUMBITS(1) [],
+
+        /// GPU interrupt pending. Only one core receives it, core 0 by default.
+        GPU OFFSET(8) NUMBITS(1) []
+    ]
+}
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct RegisterBlock {
+    __reserved_0: [u32; 16],                                                // 0x00
+    CORE_TIMER_INT_CNTL: [ReadWrite<u32, TIMER_INT_CNTL::Register>; 4],     // 0x40
+    CORE_MAILBOX_INT_CNTL: [ReadWrite<u32, MAILBOX_INT_CNTL::Register>; 4], // 0x50
+    CORE_IRQ_SOURCE: [ReadOnly<u32, IRQ_SOURCE::Register>; 4],              // 0x60
+    __reserved_1: [u32; 4],                                                 // 0x70
+    /// Write-set, four mailboxes per core
+    CORE_MAILBOX_SET: [[WriteOnly<u32>; 4]; 4],                             // 0x80
+    /// Read, and write-1-to-clear
+    CORE_MAILBOX_CLR: [[ReadWrite<u32>; 4]; 4],                             // 0xC0
+}
+
+static_assert_offset!(RegisterBlock, __reserved_0, 0x00);
+static_assert_offset!(RegisterBlock, CORE_TIMER_INT_CNTL, 0x40);
+static_assert_offset!(RegisterBlock, CORE_MAILBOX_INT_CNTL, 0x50);
+static_assert_offset!(RegisterBlock, CORE_IRQ_SOURCE, 0x60);
+static_assert_offset!(RegisterBlock, __reserved_1, 0x70);
+static_assert_offset!(RegisterBlock, CORE_MAILBOX_SET, 0x80);
+static_assert_offset!(RegisterBlock, CORE_MAILBOX_CLR, 0xC0);
+static_assert_size!(RegisterBlock, 0x100);
+
+/// Public interface to the ARM local peripherals
+pub struct LocalCtrl {
+    peripheral: Peripheral,
+}
+
+impl ops::Deref for LocalCtrl {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl LocalCtrl {
+    pub fn new(peripheral: Peripheral) -> LocalCtrl {
+        LocalCtrl { peripheral }
+    }
+
+    /// Returns a pointer to the register block
+    fn ptr(&self) -> *const RegisterBlock {
+        mmio::base(self.peripheral) as *const _
+    }
+
+    /// Route the non-secure physical timer (CNTP) of `core` to its IRQ line.
+    pub fn enable_cntpns_irq(&self, core: usize) {
+        self.CORE_TIMER_INT_CNTL[core].modify(TIMER_INT_CNTL::CNTPNSIRQ_IRQ::Enabled);
+    }
+
+    /// Stop routing the non-secure physical timer of `core`.
+    pub fn disable_cntpns_irq(&self, core: usize) {
+        self.CORE_TIMER_INT_CNTL[core].modify(TIMER_INT_CNTL::CNTPNSIRQ_IRQ::Disabled);
+    }
+
+    /// Check if the non-secure physical timer IRQ is pending on `core`.
+    pub fn cntpns_irq_pending(&self, core: usize) -> bool {
+        self.CORE_IRQ_SOURCE[core].is_set(IRQ_SOURCE::CNTPNSIRQ)
+    }
+
+    /// Route mailbox 0 of `core` to its IRQ line.
+    pub fn enable_mailbox0_irq(&self, core: usize) {
+        self.CORE_MAILBOX_INT_CNTL[core].modify(MAILBOX_INT_CNTL::MAILBOX0_IRQ::Enabled);
+    }
+
+    /// Check if the mailbox 0 IRQ is pending on `core`.
+    pub fn mailbox0_irq_pending(&self, core: usize) -> bool {
+        self.CORE_IRQ_SOURCE[core].is_set(IRQ_SOURCE::MAILBOX0)
+    }
+
+    /// Set `bits` in mailbox 0 of `core`. The IRQ is pending for as long as
+    /// any bit is set.
+    pub fn mailbox0_set(&self, core: usize, bits: u32) {
+        self.CORE_MAILBOX_SET[core][0].set(bits);
+    }
+
+    /// The bits currently set in mailbox 0 of `core`.
+    pub fn mailbox0_read(&self, core: usize) -> u32 {
+        self.CORE_MAILBOX_CLR[core][0].get()
+    }
+
+    /// Clear `bits` in mailbox 0 of `core`.
+    pub fn mailbox0_clear(&self, core: usize, bits: u32) {
+        self.CORE_MAILBOX_CLR[core][0].set(bits);
+    }
+
+    /// Check if a peripheral (GPU) IRQ is pending on `core`.
+    pub fn gpu_irq_pending(&self, core: usize) -> bool {
+        self.CORE_IRQ_SOURCE[core].is_set(IRQ_SOURCE::GPU)
+    }
+}
@@ -23,9 +23,13 @@
  */
 
 use super::gpio;
+use crate::board;
+use crate::delays;
 use crate::devices::virt::ConsoleOps;
-use core::ops;
+use crate::{static_assert_offset, static_assert_size};
+use core::{fmt, ops};
 use cortex_a::asm;
+use raspi3_hal::mmio::{self, Peripheral};
 use register::{mmio::*, register_bitfields};
 
 /// Auxilary mini UART registers
@@ -122,8 +126,31 @@ pub struct RegisterBlock {
     AUX_MU_BAUD: WriteOnly<u32, AUX_MU_BAUD::Register>, // 0x68
 }
 
+static_assert_offset!(RegisterBlock, __reserved_0, 0x00);
+static_assert_offset!(RegisterBlock, AUX_ENABLES, 0x04);
+static_assert_offset!(RegisterBlock, __reserved_1, 0x08);
+static_assert_offset!(RegisterBlock, AUX_MU_IO, 0x40);
+static_assert_offset!(RegisterBlock, AUX_MU_IER, 0x44);
+static_assert_offset!(RegisterBlock, AUX_MU_IIR, 0x48);
+static_assert_offset!(RegisterBlock, AUX_MU_LCR, 0x4C);
+static_assert_offset!(RegisterBlock, AUX_MU_MCR, 0x50);
+static_assert_offset!(RegisterBlock, AUX_MU_LSR, 0x54);
+static_assert_offset!(RegisterBlock, __reserved_2, 0x58);
+static_assert_offset!(RegisterBlock, AUX_MU_CNTL, 0x60);
+static_assert_offset!(RegisterBlock, __reserved_3, 0x64);
+static_assert_offset!(RegisterBlock, AUX_MU_BAUD, 0x68);
+static_assert_size!(RegisterBlock, 0x6C);
+
+// Sending a character at 115200 baud takes less than 100 us, so a TX FIFO that
+// stays full this long indicates a stuck UART.
+const TX_TIMEOUT_US: u64 = 10_000;
+
+// The baud rate is the core clock / (8 * (BAUD_REG + 1)), so 270 on the Pi 3,
+// and 541 on the Pi 4.
+const BAUD_REG: u32 = board::CORE_CLOCK_HZ / (8 * 115_200) - 1;
+
 pub struct MiniUart {
-    base_addr: usize,
+    peripheral: Peripheral,
 }
 
 /// Deref to RegisterBlock
@@ -145,13 +172,13 @@ impl ops::Deref for MiniUart {
 }
 
 impl MiniUart {
-    pub fn new(base_addr: usize) -> MiniUart {
-        MiniUart { base_addr }
+    pub fn new(peripheral: Peripheral) -> MiniUart {
+        MiniUart { peripheral }
     }
 
     /// Returns a pointer to the register block
     fn ptr(&self) -> *const RegisterBlock {
-        self.base_addr as *const _
+        mmio::base(self.peripheral) as *const _
     }
 
     ///Set baud rate and characteristics (115200 8N1) and map to GPIO
@@ -164,24 +191,12 @@ impl MiniUart {
         self.AUX_MU_MCR.set(0);
         self.AUX_MU_IER.set(0);
         self.AUX_MU_IIR.write(AUX_MU_IIR::FIFO_CLEAR::All);
-        self.AUX_MU_BAUD.write(AUX_MU_BAUD::RATE.val(270)); // 115200 baud
+        self.AUX_MU_BAUD.write(AUX_MU_BAUD::RATE.val(BAUD_REG)); // 115200 baud
 
         // map UART1 to GPIO pins
-        gpio.GPFSEL1
-            .modify(gpio::GPFSEL1::FSEL14::TXD1 + gpio::GPFSEL1::FSEL15::RXD1);
-
-        gpio.GPPUD.set(0); // enable pins 14 and 15
-        for _ in 0..150 {
-            asm::nop();
-        }
-
-        gpio.GPPUDCLK0
-            .write(gpio::GPPUDCLK0::PUDCLK14::AssertClock + gpio::GPPUDCLK0::PUDCLK15::AssertClock);
-        for _ in 0..150 {
-            asm::nop();
-        }
-
-        gpio.GPPUDCLK0.set(0);
+        gpio.set_function(14, gpio::Function::Alt5);
+        gpio.set_function(15, gpio::Function::Alt5);
+        gpio.set_pull_many(&[(14, gpio::Pull::Off), (15, gpio::Pull::Off)]);
 
         self.AUX_MU_CNTL
             .write(AUX_MU_CNTL::RX_EN::Enabled + AUX_MU_CNTL::TX_EN::Enabled);
@@ -190,6 +205,13 @@ impl MiniUart {
         self.AUX_MU_IIR.write(AUX_MU_IIR::FIFO_CLEAR::All);
     }
 
+    /// Whether there is a mini UART at the base address, i.e. whether the
+    /// enable bit that `init()` set reads back. Reads from a wrong address
+    /// come back as zero, or as garbage at best.
+    pub fn responds(&self) -> bool {
+        self.AUX_ENABLES.read(AUX_ENABLES::MINI_UART_ENABLE) == 1
+    }
+
     pub fn wait_tx_fifo_empty(&self) {
         loop {
             if self.AUX_MU_LSR.is_set(AUX_MU_LSR::TX_IDLE) {
@@ -199,6 +221,35 @@ impl MiniUart {
             asm::nop();
         }
     }
+
+    /// Send a character, giving up if the TX FIFO does not drain in time
+    pub fn send(&self, c: char) -> Result<(), delays::TimeoutError> {
+        // wait until we can send
+        let deadline = delays::deadline(TX_TIMEOUT_US);
+        delays::poll_timeout(deadline, || self.AUX_MU_LSR.is_set(AUX_MU_LSR::TX_EMPTY))?;
+
+        // write the character to the buffer
+        self.AUX_MU_IO.set(c as u32);
+
+        Ok(())
+    }
+
+    /// Receive a character, giving up if nothing arrives within `timeout_us`
+    pub fn recv(&self, timeout_us: u64) -> Result<char, delays::TimeoutError> {
+        // wait until something is in the buffer
+        let deadline = delays::deadline(timeout_us);
+        delays::poll_timeout(deadline, || self.AUX_MU_LSR.is_set(AUX_MU_LSR::DATA_READY))?;
+
+        // read it and return
+        let mut ret = self.AUX_MU_IO.get() as u8 as char;
+
+        // convert carrige return to newline
+        if ret == '\r' {
+            ret = '\n'
+        }
+
+        Ok(ret)
+    }
 }
 
 impl Drop for MiniUart {
@@ -210,18 +261,11 @@ impl Drop for MiniUart {
 
 impl ConsoleOps for MiniUart {
     /// Send a character
+    ///
+    /// The character is dropped if the UART is stuck, instead of hanging the
+    /// whole kernel in a print.
     fn putc(&self, c: char) {
-        // wait until we can send
-        loop {
-            if self.AUX_MU_LSR.is_set(AUX_MU_LSR::TX_EMPTY) {
-                break;
-            }
-
-            asm::nop();
-        }
-
-        // write the character to the buffer
-        self.AUX_MU_IO.set(c as u32);
+        let _ = self.send(c);
     }
 
     /// Display a string
@@ -237,25 +281,18 @@ impl ConsoleOps for MiniUart {
     }
 
     /// Receive a character
+    ///
+    /// Waits for as long as it takes somebody to type something.
     fn getc(&self) -> char {
-        // wait until something is in the buffer
         loop {
-            if self.AUX_MU_LSR.is_set(AUX_MU_LSR::DATA_READY) {
-                break;
+            if let Ok(c) = self.recv(u64::max_value()) {
+                return c;
             }
-
-            asm::nop();
-        }
-
-        // read it and return
-        let mut ret = self.AUX_MU_IO.get() as u8 as char;
-
-        // convert carrige return to newline
-        if ret == '\r' {
-            ret = '\n'
         }
+    }
 
-        ret
+    fn try_getc(&self) -> Option<char> {
+        self.recv(0).ok()
     }
 
     /// Wait until the TX FIFO is empty, aka all characters have been put on the
@@ -264,3 +301,11 @@ impl ConsoleOps for MiniUart {
         self.wait_tx_fifo_empty();
     }
 }
+
+impl fmt::Write for MiniUart {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.puts(s);
+
+        Ok(())
+    }
+}
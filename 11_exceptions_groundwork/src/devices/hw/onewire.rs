@@ -0,0 +1,280 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Bit-banged 1-Wire master on a single GPIO pin.
+//!
+//! The bus is open drain with a pull-up. The pin's output latch is kept low,
+//! and pulling the bus low or releasing it is done by switching the pin
+//! between output and input. Every time slot is started by the master pulling
+//! the bus low, and the slaves answer within microseconds, so each slot runs
+//! with IRQs masked. The reset pulse itself may be stretched, which the
+//! slaves tolerate, but its presence window is masked as well.
+
+use super::gpio;
+use crate::{cpu, delays};
+use core::fmt;
+
+pub const READ_ROM: u8 = 0x33;
+pub const MATCH_ROM: u8 = 0x55;
+pub const SKIP_ROM: u8 = 0xCC;
+pub const SEARCH_ROM: u8 = 0xF0;
+
+#[derive(Debug)]
+pub enum OwError {
+    /// No slave answered the reset pulse
+    NoPresence,
+    /// The ROM search read a 1 for both a bit and its complement, a slave
+    /// left in the middle of it
+    SearchFailed,
+    /// A ROM code or scratchpad did not match its CRC
+    CrcMismatch,
+    /// A slave did not finish an operation in time
+    Timeout,
+}
+pub type Result<T> = ::core::result::Result<T, OwError>;
+
+/// The 64 bit ROM code of a slave: family code, 48 bit serial number and CRC,
+/// in the order they are sent on the bus
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub struct Rom(pub [u8; 8]);
+
+impl Rom {
+    pub fn family(&self) -> u8 {
+        self.0[0]
+    }
+}
+
+impl fmt::Display for Rom {
+    /// Formatted like the Linux w1 subsystem does: family, then the serial
+    /// number, most significant byte first
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}-", self.0[0])?;
+        for byte in self.0[1..7].iter().rev() {
+            write!(f, "{:02x}", byte)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Dallas/Maxim CRC-8, polynomial x^8 + x^5 + x^4 + 1, LSB first
+///
+/// Over data that ends with its own CRC, the result is zero.
+pub fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+
+    for &byte in data {
+        let mut byte = byte;
+
+        for _ in 0..8 {
+            let mix = (crc ^ byte) & 1;
+            crc >>= 1;
+            if mix != 0 {
+                crc ^= 0x8C;
+            }
+            byte >>= 1;
+        }
+    }
+
+    crc
+}
+
+/// A 1-Wire bus, owning its pin
+pub struct Bus {
+    gpio: gpio::GPIO,
+    pin: gpio::Pin,
+}
+
+impl Bus {
+    /// Release the bus and enable the pull-up of `pin`.
+    ///
+    /// The internal pull-up is around 50 kOhm, which is too weak for more
+    /// than a slave or two on a short cable. Add an external 4.7 kOhm one to
+    /// 3.3 V for anything else.
+    pub fn new(pin: gpio::Pin) -> Bus {
+        // The bus owns `pin` and touches nothing else. The typed pin API can
+        // not switch between input and output without giving up the pin, so
+        // this goes through the registers.
+        let gpio = unsafe { gpio::GPIO::steal() };
+        let nr = pin.number();
+
+        gpio.set_function(nr, gpio::Function::Input);
+        gpio.set_pull(nr, gpio::Pull::Up);
+        gpio.set_low(nr);
+
+        Bus { gpio, pin }
+    }
+
+    fn drive_low(&self) {
+        self.gpio.set_function(self.pin.number(), gpio::Function::Output);
+    }
+
+    fn release(&self) {
+        self.gpio.set_function(self.pin.number(), gpio::Function::Input);
+    }
+
+    fn is_high(&self) -> bool {
+        self.gpio.read(self.pin.number())
+    }
+
+    /// Send a reset pulse, and check that at least one slave answered it
+    /// with a presence pulse.
+    pub fn reset(&self) -> Result<()> {
+        self.drive_low();
+        delays::wait_usec(480);
+
+        let present = cpu::irq_masked(|| {
+            self.release();
+            delays::wait_usec(70);
+            !self.is_high()
+        });
+
+        // The rest of the 480 us recovery time, after which the slaves have
+        // released the bus again
+        delays::wait_usec(410);
+
+        if !present {
+            return Err(OwError::NoPresence);
+        }
+        Ok(())
+    }
+
+    /// Send a single bit: A short low pulse for 1, a long one for 0.
+    pub fn write_bit(&self, bit: bool) {
+        let (low_us, high_us) = if bit { (6, 64) } else { (60, 10) };
+
+        cpu::irq_masked(|| {
+            self.drive_low();
+            delays::wait_usec(low_us);
+            self.release();
+        });
+        delays::wait_usec(high_us);
+    }
+
+    /// Receive a single bit: Start the slot, and sample the bus before the
+    /// slave releases it again, 15 us after the start.
+    pub fn read_bit(&self) -> bool {
+        let bit = cpu::irq_masked(|| {
+            self.drive_low();
+            delays::wait_usec(6);
+            self.release();
+            delays::wait_nsec(9_000);
+            self.is_high()
+        });
+        delays::wait_usec(55);
+
+        bit
+    }
+
+    /// Send a byte, LSB first.
+    pub fn write_byte(&self, byte: u8) {
+        for i in 0..8 {
+            self.write_bit(byte & (1 << i) != 0);
+        }
+    }
+
+    /// Receive a byte, LSB first.
+    pub fn read_byte(&self) -> u8 {
+        (0..8).fold(0, |byte, i| byte | (u8::from(self.read_bit()) << i))
+    }
+
+    /// Reset the bus, and address the slave with `rom` for the following
+    /// function command.
+    pub fn select(&self, rom: &Rom) -> Result<()> {
+        self.reset()?;
+        self.write_byte(MATCH_ROM);
+        for &byte in rom.0.iter() {
+            self.write_byte(byte);
+        }
+
+        Ok(())
+    }
+
+    /// Find the ROM codes of the slaves on the bus, and store up to
+    /// `roms.len()` of them. Returns how many were found.
+    ///
+    /// Each pass walks the ROM codes of all slaves bit by bit. For each bit,
+    /// the slaves send it and its complement, which reads 0 for both if they
+    /// disagree. The master then sends the direction it takes, and only the
+    /// slaves that have that bit stay in the search. Taking the 1 branch at
+    /// the last disagreement that took the 0 branch before finds the next
+    /// slave in the following pass.
+    pub fn search(&self, roms: &mut [Rom]) -> Result<usize> {
+        let mut found = 0;
+        let mut last_discrepancy = 0;
+        let mut last_rom = [0u8; 8];
+
+        while found < roms.len() {
+            self.reset()?;
+            self.write_byte(SEARCH_ROM);
+
+            let mut rom = [0u8; 8];
+            let mut last_zero = 0;
+
+            // Bits are numbered from 1, so that 0 can mean "no discrepancy"
+            for bit in 1..=64 {
+                let (byte, mask) = ((bit - 1) / 8, 1 << ((bit - 1) % 8));
+                let id = self.read_bit();
+                let complement = self.read_bit();
+
+                let direction = match (id, complement) {
+                    (true, true) => return Err(OwError::SearchFailed),
+                    (false, false) => {
+                        let direction = if bit < last_discrepancy {
+                            last_rom[byte] & mask != 0
+                        } else {
+                            bit == last_discrepancy
+                        };
+
+                        if !direction {
+                            last_zero = bit;
+                        }
+                        direction
+                    }
+                    (id, _) => id,
+                };
+
+                if direction {
+                    rom[byte] |= mask;
+                }
+                self.write_bit(direction);
+            }
+
+            if crc8(&rom) != 0 {
+                return Err(OwError::CrcMismatch);
+            }
+
+            roms[found] = Rom(rom);
+            found += 1;
+
+            if last_zero == 0 {
+                break;
+            }
+            last_discrepancy = last_zero;
+            last_rom = rom;
+        }
+
+        Ok(found)
+    }
+}
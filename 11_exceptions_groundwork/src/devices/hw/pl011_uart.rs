@@ -24,13 +24,22 @@
 
 use super::gpio;
 use super::videocore_mbox;
-use crate::delays;
 use crate::devices::virt::ConsoleOps;
+use crate::{
+    cpu, delays, event, interrupt, memory, ring_buffer::RingBuffer, static_assert_offset,
+    static_assert_size, sync::SpinLock,
+};
 use core::{
+    cell::Cell,
+    fmt,
+    future::Future,
     ops,
-    sync::atomic::{compiler_fence, Ordering},
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    task::{Context, Poll, Waker},
 };
 use cortex_a::asm;
+use raspi3_hal::mmio::{self, Peripheral};
 use register::{mmio::*, register_bitfields};
 
 // PL011 UART registers.
@@ -54,7 +63,13 @@ register_bitfields! {
         /// FIFO is disabled, this bit is set when the receive holding
         /// register is empty. If the FIFO is enabled, the RXFE bit is
         /// set when the receive FIFO is empty.
-        RXFE OFFSET(4) NUMBITS(1) []
+        RXFE OFFSET(4) NUMBITS(1) [],
+
+        /// UART busy. If this bit is set to 1, the UART is busy
+        /// transmitting data. This bit remains set until the complete
+        /// byte, including all the stop bits, has been sent from the
+        /// shift register.
+        BUSY OFFSET(3) NUMBITS(1) []
     ],
 
     /// Integer Baud rate divisor
@@ -78,11 +93,41 @@ register_bitfields! {
             SixBit = 0b01,
             SevenBit = 0b10,
             EightBit = 0b11
+        ],
+
+        /// Enable FIFOs. If this bit is set to 1, transmit and receive
+        /// FIFO buffers are enabled (FIFO mode).
+        FEN  OFFSET(4) NUMBITS(1) [
+            FifosDisabled = 0,
+            FifosEnabled = 1
         ]
     ],
 
     /// Control Register
     CR [
+        /// CTS hardware flow control enable. If this bit is set to 1,
+        /// data is only transmitted when the nUARTCTS signal is
+        /// asserted.
+        CTSEN  OFFSET(15) NUMBITS(1) [
+            Disabled = 0,
+            Enabled = 1
+        ],
+
+        /// RTS hardware flow control enable. If this bit is set to 1,
+        /// data is only requested when there is space in the receive
+        /// FIFO for it to be received.
+        RTSEN  OFFSET(14) NUMBITS(1) [
+            Disabled = 0,
+            Enabled = 1
+        ],
+
+        /// Loopback enable. If this bit is set to 1, the UARTTXD path
+        /// is fed through to the UARTRXD path.
+        LBE    OFFSET(7) NUMBITS(1) [
+            Disabled = 0,
+            Enabled = 1
+        ],
+
         /// Receive enable. If this bit is set to 1, the receive
         /// section of the UART is enabled. Data reception occurs for
         /// UART signals. When the UART is disabled in the middle of
@@ -113,10 +158,40 @@ register_bitfields! {
         ]
     ],
 
+    /// Interrupt Mask Set Clear Register
+    IMSC [
+        /// Receive timeout interrupt mask. Fires if the RX FIFO is not empty,
+        /// but no more data arrived for 32 bit periods.
+        RTIM OFFSET(6) NUMBITS(1) [
+            Disabled = 0,
+            Enabled = 1
+        ],
+
+        /// Transmit interrupt mask. Fires if the TX FIFO drained down to the
+        /// trigger level.
+        TXIM OFFSET(5) NUMBITS(1) [
+            Disabled = 0,
+            Enabled = 1
+        ],
+
+        /// Receive interrupt mask. Fires if the RX FIFO reaches the trigger
+        /// level.
+        RXIM OFFSET(4) NUMBITS(1) [
+            Disabled = 0,
+            Enabled = 1
+        ]
+    ],
+
     /// Interupt Clear Register
     ICR [
         /// Meta field for all pending interrupts
-        ALL OFFSET(0) NUMBITS(11) []
+        ALL OFFSET(0) NUMBITS(11) [],
+
+        /// Receive timeout interrupt clear
+        RTIC OFFSET(6) NUMBITS(1) [],
+
+        /// Receive interrupt clear
+        RXIC OFFSET(4) NUMBITS(1) []
     ]
 }
 
@@ -131,17 +206,215 @@ pub struct RegisterBlock {
     FBRD: WriteOnly<u32, FBRD::Register>, // 0x28
     LCRH: WriteOnly<u32, LCRH::Register>, // 0x2C
     CR: WriteOnly<u32, CR::Register>,     // 0x30
-    __reserved_2: [u32; 4],               // 0x34
+    __reserved_2: u32,                    // 0x34
+    IMSC: ReadWrite<u32, IMSC::Register>, // 0x38
+    __reserved_3: [u32; 2],               // 0x3C
     ICR: WriteOnly<u32, ICR::Register>,   // 0x44
 }
 
+static_assert_offset!(RegisterBlock, DR, 0x00);
+static_assert_offset!(RegisterBlock, __reserved_0, 0x04);
+static_assert_offset!(RegisterBlock, FR, 0x18);
+static_assert_offset!(RegisterBlock, __reserved_1, 0x1C);
+static_assert_offset!(RegisterBlock, IBRD, 0x24);
+static_assert_offset!(RegisterBlock, FBRD, 0x28);
+static_assert_offset!(RegisterBlock, LCRH, 0x2C);
+static_assert_offset!(RegisterBlock, CR, 0x30);
+static_assert_offset!(RegisterBlock, __reserved_2, 0x34);
+static_assert_offset!(RegisterBlock, IMSC, 0x38);
+static_assert_offset!(RegisterBlock, __reserved_3, 0x3C);
+static_assert_offset!(RegisterBlock, ICR, 0x44);
+static_assert_size!(RegisterBlock, 0x48);
+
 pub enum PL011UartError {
     MailboxError,
+    InterruptError,
+    UnsupportedBaudRate,
 }
 pub type Result<T> = ::core::result::Result<T, PL011UartError>;
 
+#[derive(Debug)]
+pub enum SelfTestError {
+    /// Not a single byte came back.
+    NothingReceived,
+    /// The byte at `offset` came back wrong, or not at all.
+    Corrupted { offset: usize },
+}
+
+// Sending a character at 115200 baud takes less than 100 us, so a TX FIFO that
+// stays full this long indicates a stuck UART.
+const TX_TIMEOUT_US: u64 = 10_000;
+
+// UART clock requested from the firmware. High enough for 921600 baud, which
+// needs at least 16 * 921600 Hz.
+const UART_CLOCK_HZ: u32 = 48_000_000;
+
+/// Compute the IBRD and FBRD values for `baud` at a UART clock of `clock` Hz.
+///
+/// See `raspi3_hal::pl011_uart::baud_divisors()`, which has the host tests.
+pub fn baud_divisors(clock: u32, baud: u32) -> Result<(u32, u32)> {
+    raspi3_hal::pl011_uart::baud_divisors(clock, baud).ok_or(PL011UartError::UnsupportedBaudRate)
+}
+
+/// Received bytes, filled by the IRQ or FIQ handler once `enable_rx_irq()` or
+/// `enable_rx_fiq()` was called.
+///
+/// The handler is the only producer, so it pushes without a lock. An FIQ can
+/// interrupt `lock_irqsave()`, so the FIQ handler could not take one anyway.
+/// The readers on all cores take `RX_POP` for each pop.
+static RX_BUFFER: RingBuffer = RingBuffer::new();
+
+/// Makes the readers of `RX_BUFFER` one consumer. Take it with
+/// `lock_irqsave()`.
+static RX_POP: SpinLock<()> = SpinLock::new(());
+
+/// Bytes waiting to be sent, drained by the IRQ handler once `enable_tx_irq()`
+/// was called.
+///
+/// Locked because any core may print, while the IRQ handler runs on core 0.
+/// Always take it with `lock_irqsave()`.
+static TX_BUFFER: SpinLock<RingBuffer> = SpinLock::new(RingBuffer::new());
+
+/// The `async` reader waiting for the next received byte, see `read_byte()`.
+/// Only the IRQ handler wakes it, see `RX_FIQ`.
+static RX_WAKER: SpinLock<Option<Waker>> = SpinLock::new(None);
+
+/// Physical base address of the UART that is served by the IRQ handler, zero
+/// if none.
+static IRQ_BASE: AtomicUsize = AtomicUsize::new(0);
+static RX_IRQ: AtomicBool = AtomicBool::new(false);
+/// Set if RX is served by the FIQ handler. It must not take locks, so instead
+/// of being woken, `async` readers are polled again right away.
+static RX_FIQ: AtomicBool = AtomicBool::new(false);
+static TX_IRQ: AtomicBool = AtomicBool::new(false);
+
+/// Set if RTS/CTS flow control was requested in `init()`.
+static FLOW_CONTROL: AtomicBool = AtomicBool::new(false);
+
+/// Set while the RX IRQ is masked because `RX_BUFFER` passed the high-water
+/// mark. The RX FIFO then fills up, and the UART deasserts RTS.
+static RX_THROTTLED: AtomicBool = AtomicBool::new(false);
+
+// Fill levels of RX_BUFFER for throttling the sender with flow control.
+const RX_HIGH_WATER: usize = 192;
+const RX_LOW_WATER: usize = 64;
+
+/// The registers of the UART in `IRQ_BASE`, if any.
+///
+/// `IRQ_BASE` holds the physical address of the owner's peripheral, so it
+/// goes through `memory::map_mmio()` like `mmio::base()` does.
+fn irq_uart() -> Option<&'static RegisterBlock> {
+    let base_addr = IRQ_BASE.load(Ordering::Relaxed);
+    if base_addr == 0 {
+        return None;
+    }
+
+    Some(unsafe { &*(memory::map_mmio(base_addr) as *const RegisterBlock) })
+}
+
+fn irq_handler() {
+    let uart = match irq_uart() {
+        Some(uart) => uart,
+        None => return,
+    };
+
+    if RX_IRQ.load(Ordering::Relaxed) {
+        drain_rx_fifo(uart);
+    }
+
+    if TX_IRQ.load(Ordering::Relaxed) {
+        fill_tx_fifo(uart);
+    }
+}
+
+/// Handler for the UART routed to the FIQ with `enable_rx_fiq()`. Only the RX
+/// side is served, the TX IRQ is off in this mode.
+fn fiq_handler() {
+    if let Some(uart) = irq_uart() {
+        drain_rx_fifo(uart);
+    }
+}
+
+/// Move bytes from the RX FIFO into `RX_BUFFER`, and wake up `getc()` and
+/// `read_byte()`.
+fn drain_rx_fifo(uart: &RegisterBlock) {
+    let flow_control = FLOW_CONTROL.load(Ordering::Relaxed);
+
+    while !uart.FR.is_set(FR::RXFE) {
+        if flow_control && RX_BUFFER.len() >= RX_HIGH_WATER {
+            // Leave the rest in the FIFO, so that the UART deasserts RTS
+            // once it is full. `unthrottle_rx()` takes it from there.
+            uart.IMSC.modify(IMSC::RXIM::Disabled + IMSC::RTIM::Disabled);
+            RX_THROTTLED.store(true, Ordering::Relaxed);
+            break;
+        }
+
+        let byte = uart.DR.get() as u8;
+        RX_BUFFER.push(byte);
+    }
+
+    uart.ICR.write(ICR::RXIC::SET + ICR::RTIC::SET);
+    event::notify();
+
+    if RX_FIQ.load(Ordering::Relaxed) {
+        return;
+    }
+
+    if let Some(waker) = RX_WAKER.lock_irqsave(|w| w.take()) {
+        waker.wake();
+    }
+}
+
+/// Move bytes from `TX_BUFFER` into the TX FIFO.
+///
+/// If the FIFO runs full, the TX IRQ is unmasked to continue once it drained.
+/// Must only be called from the IRQ handler or with IRQs masked, because it is
+/// the consumer side of `TX_BUFFER`.
+fn fill_tx_fifo(uart: &RegisterBlock) {
+    while !uart.FR.is_set(FR::TXFF) {
+        match TX_BUFFER.lock_irqsave(|b| b.pop()) {
+            Some(byte) => uart.DR.set(u32::from(byte)),
+            None => {
+                uart.IMSC.modify(IMSC::TXIM::Disabled);
+                return;
+            }
+        }
+    }
+
+    uart.IMSC.modify(IMSC::TXIM::Enabled);
+}
+
+fn rx_irq_enabled() -> bool {
+    RX_IRQ.load(Ordering::Relaxed)
+}
+
+/// Pop a byte from `RX_BUFFER`, and take the RX IRQ back up once the buffer
+/// drained below the low-water mark.
+fn rx_buffer_pop(uart: &RegisterBlock) -> Option<u8> {
+    let (byte, len) = RX_POP.lock_irqsave(|_| (RX_BUFFER.pop(), RX_BUFFER.len()));
+
+    if RX_THROTTLED.load(Ordering::Relaxed) && len < RX_LOW_WATER {
+        cpu::irq_masked(|| {
+            RX_THROTTLED.store(false, Ordering::Relaxed);
+            uart.IMSC.modify(IMSC::RXIM::Enabled + IMSC::RTIM::Enabled);
+        });
+    }
+
+    byte
+}
+
+/// Transmit through `TX_BUFFER` only if the IRQ handler can drain it.
+///
+/// With IRQs masked, e.g. while panicking or inside an exception handler,
+/// characters are sent synchronously instead.
+fn tx_buffered() -> bool {
+    TX_IRQ.load(Ordering::Relaxed) && !cpu::local_irq_masked()
+}
+
 pub struct PL011Uart {
-    base_addr: usize,
+    peripheral: Peripheral,
+    /// UART clock in Hz as reported by the firmware, zero before `init()`.
+    clock: Cell<u32>,
 }
 
 impl ops::Deref for PL011Uart {
@@ -153,72 +426,465 @@ impl ops::Deref for PL011Uart {
 }
 
 impl PL011Uart {
-    pub fn new(base_addr: usize) -> PL011Uart {
-        PL011Uart { base_addr }
+    pub fn new(peripheral: Peripheral) -> PL011Uart {
+        PL011Uart {
+            peripheral,
+            clock: Cell::new(0),
+        }
     }
 
     /// Returns a pointer to the register block
     fn ptr(&self) -> *const RegisterBlock {
-        self.base_addr as *const _
+        mmio::base(self.peripheral) as *const _
     }
 
-    ///Set baud rate and characteristics (115200 8N1) and map to GPIO
+    /// Set baud rate and characteristics (8N1) and map to GPIO
+    ///
+    /// Rates from 9600 up to 921600 baud are supported.
+    ///
+    /// With `flow_control`, CTS and RTS are mapped to GPIO16 and GPIO17 as
+    /// well. Only use it if the USB-serial adapter actually has them wired: FTDI
+    /// FT232R and CP2102 boards that break out all modem lines do, while the
+    /// common 4-wire cables (PL2303 and clones) only have TX, RX and ground. A
+    /// floating CTS input stalls all transmission.
     pub fn init(
         &self,
         v_mbox: &mut videocore_mbox::VideocoreMbox,
         gpio: &gpio::GPIO,
+        baud: u32,
+        flow_control: bool,
     ) -> Result<()> {
         // turn off UART0
         self.CR.set(0);
 
-        // set up clock for consistent divisor values
-        v_mbox.buffer[0] = 9 * 4;
-        v_mbox.buffer[1] = videocore_mbox::REQUEST;
-        v_mbox.buffer[2] = videocore_mbox::tag::SETCLKRATE;
-        v_mbox.buffer[3] = 12;
-        v_mbox.buffer[4] = 8;
-        v_mbox.buffer[5] = videocore_mbox::clock::UART; // UART clock
-        v_mbox.buffer[6] = 4_000_000; // 4Mhz
-        v_mbox.buffer[7] = 0; // skip turbo setting
-        v_mbox.buffer[8] = videocore_mbox::tag::LAST;
-
-        // Insert a compiler fence that ensures that all stores to the
-        // mbox buffer are finished before the GPU is signaled (which
-        // is done by a store operation as well).
-        compiler_fence(Ordering::Release);
-
-        if v_mbox.call(videocore_mbox::channel::PROP).is_err() {
-            return Err(PL011UartError::MailboxError); // Abort if UART clocks couldn't be set
-        };
+        // set up clock for consistent divisor values. The firmware might not
+        // grant the exact rate, so ask for the one that is actually in effect
+        // in the same call.
+        let resp = videocore_mbox::PropertyMessage::new()
+            .with(videocore_mbox::Tag::SetClockRate {
+                clock: videocore_mbox::Clock::Uart,
+                rate: UART_CLOCK_HZ,
+                skip_turbo: false,
+            })
+            .with(videocore_mbox::Tag::GetClockRate {
+                clock: videocore_mbox::Clock::Uart,
+            })
+            .call(v_mbox);
+
+        match resp.ok().and_then(|r| r.get(1)) {
+            Some(videocore_mbox::Response::ClockRate { rate, .. }) => self.clock.set(rate),
+            _ => return Err(PL011UartError::MailboxError), // Abort if UART clocks couldn't be set
+        }
+        let (ibrd, fbrd) = baud_divisors(self.clock.get(), baud)?;
 
         // map UART0 to GPIO pins
-        gpio.GPFSEL1
-            .modify(gpio::GPFSEL1::FSEL14::TXD0 + gpio::GPFSEL1::FSEL15::RXD0);
+        gpio.set_function(14, gpio::Function::Alt0);
+        gpio.set_function(15, gpio::Function::Alt0);
+        gpio.set_pull_many(&[(14, gpio::Pull::Off), (15, gpio::Pull::Off)]);
+
+        if flow_control {
+            // map CTS0 and RTS0 to GPIO pins
+            gpio.set_function(16, gpio::Function::Alt3);
+            gpio.set_function(17, gpio::Function::Alt3);
+            gpio.set_pull_many(&[(16, gpio::Pull::Off), (17, gpio::Pull::Off)]);
+        }
+        FLOW_CONTROL.store(flow_control, Ordering::Relaxed);
 
-        gpio.GPPUD.set(0); // enable pins 14 and 15
-        delays::wait_cycles(150);
+        self.ICR.write(ICR::ALL::CLEAR);
+        self.IBRD.write(IBRD::IBRD.val(ibrd));
+        self.FBRD.write(FBRD::FBRD.val(fbrd));
+        self.LCRH.write(LCRH::WLEN::EightBit + LCRH::FEN::FifosEnabled); // 8N1
 
-        gpio.GPPUDCLK0.modify(
-            gpio::GPPUDCLK0::PUDCLK14::AssertClock + gpio::GPPUDCLK0::PUDCLK15::AssertClock,
-        );
-        delays::wait_cycles(150);
+        self.enable();
 
-        gpio.GPPUDCLK0.set(0);
+        Ok(())
+    }
 
-        self.ICR.write(ICR::ALL::CLEAR);
-        self.IBRD.write(IBRD::IBRD.val(2)); // Results in 115200 baud
-        self.FBRD.write(FBRD::FBRD.val(0xB));
-        self.LCRH.write(LCRH::WLEN::EightBit); // 8N1
+    /// Check the UART with its internal loopback, no host needed.
+    ///
+    /// Sends a pseudo-random pattern to itself and verifies that it comes back
+    /// unchanged. Normal operation is restored afterwards. Older QEMU versions
+    /// do not emulate the loopback, so expect `NothingReceived` there.
+    pub fn selftest(&self) -> ::core::result::Result<(), SelfTestError> {
+        const PATTERN_LEN: usize = 512;
+        const BYTE_TIMEOUT_US: u64 = 1000;
+
+        // Finish pending output, and keep the IRQ handler off the RX FIFO
+        self.flush();
+
+        cpu::irq_masked(|| {
+            self.CR.write(
+                CR::UARTEN::Enabled + CR::TXE::Enabled + CR::RXE::Enabled + CR::LBE::Enabled,
+            );
+
+            // Discard stale input
+            while self.try_getc_fifo().is_some() {}
+
+            // xorshift32
+            let mut x: u32 = 0x2545_F491;
+            let mut result = Ok(());
+
+            for offset in 0..PATTERN_LEN {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                let sent = x as u8;
+
+                let deadline = delays::deadline(TX_TIMEOUT_US);
+                if delays::poll_timeout(deadline, || !self.FR.is_set(FR::TXFF)).is_err() {
+                    result = Err(SelfTestError::Corrupted { offset });
+                    break;
+                }
+                self.DR.set(u32::from(sent));
+
+                let mut received = None;
+                let arrived = delays::poll_timeout(delays::deadline(BYTE_TIMEOUT_US), || {
+                    received = self.try_getc_fifo();
+                    received.is_some()
+                });
+
+                match (arrived, received) {
+                    (Ok(()), Some(byte)) if byte == u32::from(sent) => (),
+                    (Err(_), _) if offset == 0 => {
+                        result = Err(SelfTestError::NothingReceived);
+                        break;
+                    }
+                    _ => {
+                        result = Err(SelfTestError::Corrupted { offset });
+                        break;
+                    }
+                }
+            }
 
-        self.CR
-            .write(CR::UARTEN::Enabled + CR::TXE::Enabled + CR::RXE::Enabled);
+            self.enable();
+
+            result
+        })
+    }
+
+    /// Read DR, including its error flags, if the RX FIFO is not empty.
+    fn try_getc_fifo(&self) -> Option<u32> {
+        if self.FR.is_set(FR::RXFE) {
+            return None;
+        }
+
+        Some(self.DR.get())
+    }
+
+    /// Turn on the UART, with flow control if requested in `init()`.
+    fn enable(&self) {
+        let cr = CR::UARTEN::Enabled + CR::TXE::Enabled + CR::RXE::Enabled;
+
+        if self.flow_control_enabled() {
+            self.CR.write(cr + CR::RTSEN::Enabled + CR::CTSEN::Enabled);
+        } else {
+            self.CR.write(cr);
+        }
+    }
+
+    /// Check if RTS/CTS flow control is active.
+    pub fn flow_control_enabled(&self) -> bool {
+        FLOW_CONTROL.load(Ordering::Relaxed)
+    }
+
+    /// Switch to a different baud rate after `init()`.
+    ///
+    /// Everything that is still in the TX buffer and FIFO is sent with the old
+    /// rate first. On error, the old rate stays in effect.
+    pub fn set_baud(&self, baud: u32) -> Result<()> {
+        let (ibrd, fbrd) = baud_divisors(self.clock.get(), baud)?;
+
+        self.flush();
+        self.CR.set(0);
+
+        // the divisors are only latched by a write to LCRH
+        self.IBRD.write(IBRD::IBRD.val(ibrd));
+        self.FBRD.write(FBRD::FBRD.val(fbrd));
+        self.LCRH.write(LCRH::WLEN::EightBit + LCRH::FEN::FifosEnabled); // 8N1
+
+        self.enable();
 
         Ok(())
     }
+
+    /// Send a character, giving up if the TX FIFO does not drain in time
+    pub fn send(&self, c: char) -> ::core::result::Result<(), delays::TimeoutError> {
+        // wait until we can send
+        delays::poll_timeout(delays::deadline(TX_TIMEOUT_US), || !self.FR.is_set(FR::TXFF))?;
+
+        // write the character to the buffer
+        self.DR.set(c as u32);
+
+        Ok(())
+    }
+
+    /// Register the IRQ handler and unmask the UART in the interrupt
+    /// controller.
+    fn install_irq_handler(&self) -> Result<()> {
+        if IRQ_BASE.load(Ordering::Relaxed) == self.peripheral.phys() {
+            return Ok(());
+        }
+
+        if interrupt::register_handler(interrupt::Irq::Pl011Uart, irq_handler).is_err() {
+            return Err(PL011UartError::InterruptError);
+        }
+
+        IRQ_BASE.store(self.peripheral.phys(), Ordering::Relaxed);
+        interrupt::enable(interrupt::Irq::Pl011Uart);
+
+        Ok(())
+    }
+
+    /// Receive RX data by IRQ instead of polling.
+    ///
+    /// Incoming bytes are drained into a 256 byte ring buffer, so that nothing
+    /// is lost while the kernel is busy with something else, as long as IRQs
+    /// are unmasked. If the buffer is full, newly received bytes are dropped.
+    /// See `rx_dropped()`.
+    pub fn enable_rx_irq(&self) -> Result<()> {
+        self.install_irq_handler()?;
+
+        RX_IRQ.store(true, Ordering::Relaxed);
+        self.IMSC.modify(IMSC::RXIM::Enabled + IMSC::RTIM::Enabled);
+
+        Ok(())
+    }
+
+    /// Receive RX data by FIQ, for the lowest latency.
+    ///
+    /// Like `enable_rx_irq()`, but the FIQ handler keeps draining the RX FIFO
+    /// while the kernel runs with IRQs masked, e.g. inside other IRQ handlers.
+    /// The UART has only one interrupt line, so sending falls back to polling,
+    /// and the IRQ path of the UART is disabled.
+    pub fn enable_rx_fiq(&self) -> Result<()> {
+        // Finish buffered output while the TX IRQ can still drain it
+        self.drain_tx_buffer();
+        cpu::irq_masked(|| {
+            TX_IRQ.store(false, Ordering::Relaxed);
+            self.IMSC.modify(IMSC::TXIM::Disabled);
+        });
+
+        IRQ_BASE.store(self.peripheral.phys(), Ordering::Relaxed);
+        if interrupt::route_fiq(interrupt::Irq::Pl011Uart, fiq_handler).is_err() {
+            return Err(PL011UartError::InterruptError);
+        }
+
+        RX_FIQ.store(true, Ordering::Relaxed);
+        RX_IRQ.store(true, Ordering::Relaxed);
+        self.IMSC.modify(IMSC::RXIM::Enabled + IMSC::RTIM::Enabled);
+
+        Ok(())
+    }
+
+    /// Send TX data by IRQ instead of polling.
+    ///
+    /// Afterwards, `putc()` and `puts()` only copy into a 256 byte ring buffer
+    /// and return, while the IRQ handler feeds the TX FIFO in the background.
+    /// They only block if the ring buffer is full.
+    ///
+    /// Not available while the UART is routed to the FIQ.
+    pub fn enable_tx_irq(&self) -> Result<()> {
+        if interrupt::fiq_source() == Some(interrupt::Irq::Pl011Uart) {
+            return Err(PL011UartError::InterruptError);
+        }
+
+        self.install_irq_handler()?;
+
+        TX_IRQ.store(true, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    /// Queue as many of `bytes` as fit without waiting, and return how many
+    /// that were.
+    ///
+    /// Without the TX IRQ enabled, only the free space of the TX FIFO is used.
+    pub fn send_nonblocking(&self, bytes: &[u8]) -> usize {
+        if !TX_IRQ.load(Ordering::Relaxed) {
+            self.drain_tx_buffer();
+
+            let mut sent = 0;
+            for &byte in bytes {
+                if self.FR.is_set(FR::TXFF) {
+                    break;
+                }
+
+                self.DR.set(u32::from(byte));
+                sent += 1;
+            }
+
+            return sent;
+        }
+
+        let queued = TX_BUFFER.lock_irqsave(|b| {
+            let mut queued = 0;
+            for &byte in bytes {
+                if b.is_full() {
+                    break;
+                }
+
+                b.push(byte);
+                queued += 1;
+            }
+
+            queued
+        });
+
+        cpu::irq_masked(|| fill_tx_fifo(self));
+
+        queued
+    }
+
+    /// Synchronously send whatever is left in the TX ring buffer.
+    fn drain_tx_buffer(&self) {
+        // The lock is only held for each pop, not while the byte is sent.
+        while let Some(byte) = TX_BUFFER.lock_irqsave(|b| b.pop()) {
+            let _ = self.send(byte as char);
+        }
+    }
+
+    /// Number of received bytes that were dropped because the RX ring buffer
+    /// was full.
+    pub fn rx_dropped() -> usize {
+        RX_BUFFER.dropped()
+    }
+
+    /// Whether received bytes are taken by the IRQ handler.
+    pub fn rx_irq_enabled(&self) -> bool {
+        rx_irq_enabled()
+    }
+
+    /// Receive a byte if one is available, without waiting.
+    pub fn try_getc(&self) -> Option<u8> {
+        if rx_irq_enabled() {
+            return rx_buffer_pop(self);
+        }
+
+        if self.FR.is_set(FR::RXFE) {
+            return None;
+        }
+
+        Some(self.DR.get() as u8)
+    }
+
+    /// Receive a byte, or None if nothing arrives within `timeout_us`
+    pub fn getc_timeout(&self, timeout_us: u64) -> Option<u8> {
+        let mut byte = None;
+
+        // wait until something is in the buffer
+        delays::poll_timeout(delays::deadline(timeout_us), || {
+            byte = self.try_getc();
+            byte.is_some()
+        })
+        .ok()?;
+
+        byte
+    }
+
+    /// Fill `buf`, giving up once `timeout_us` passed in total
+    ///
+    /// Returns how many bytes were actually received.
+    pub fn recv_exact_timeout(&self, buf: &mut [u8], timeout_us: u64) -> usize {
+        let mut received = 0;
+
+        let _ = delays::poll_timeout(delays::deadline(timeout_us), || {
+            while received < buf.len() {
+                match self.try_getc() {
+                    Some(byte) => {
+                        buf[received] = byte;
+                        received += 1;
+                    }
+                    None => return false,
+                }
+            }
+
+            true
+        });
+
+        received
+    }
+
+    /// Receive a character, giving up if nothing arrives within `timeout_us`
+    pub fn recv(&self, timeout_us: u64) -> ::core::result::Result<char, delays::TimeoutError> {
+        self.getc_timeout(timeout_us)
+            .map(to_char)
+            .ok_or(delays::TimeoutError)
+    }
+
+    /// Receive a byte in `async` code.
+    ///
+    /// The task is woken by the RX IRQ, see `enable_rx_irq()`. Without it, the
+    /// task keeps polling. There is only one waiting reader, a second one takes
+    /// over the wake up.
+    #[allow(dead_code)]
+    pub fn read_byte(&self) -> ReadByte<'_> {
+        ReadByte { uart: self }
+    }
+
+    /// Like `poll_read_byte()`, but with CR turned into LF like `getc()`.
+    pub fn poll_getc(&self, cx: &mut Context) -> Poll<char> {
+        self.poll_read_byte(cx).map(to_char)
+    }
+
+    /// The poll of `read_byte()`, for callers that can not hold on to the
+    /// UART across polls, like the console.
+    pub fn poll_read_byte(&self, cx: &mut Context) -> Poll<u8> {
+        if let Some(byte) = self.try_getc() {
+            return Poll::Ready(byte);
+        }
+
+        if !rx_irq_enabled() || RX_FIQ.load(Ordering::Relaxed) {
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+
+        RX_WAKER.lock_irqsave(|w| *w = Some(cx.waker().clone()));
+
+        // The byte may have arrived before the waker was in place
+        match self.try_getc() {
+            Some(byte) => Poll::Ready(byte),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// The future of `PL011Uart::read_byte()`.
+pub struct ReadByte<'a> {
+    uart: &'a PL011Uart,
+}
+
+impl<'a> Future for ReadByte<'a> {
+    type Output = u8;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<u8> {
+        self.uart.poll_read_byte(cx)
+    }
+}
+
+/// Convert a received byte, turning carrige return into newline.
+fn to_char(byte: u8) -> char {
+    match byte as char {
+        '\r' => '\n',
+        c => c,
+    }
 }
 
 impl Drop for PL011Uart {
     fn drop(&mut self) {
+        if IRQ_BASE.load(Ordering::Relaxed) == self.peripheral.phys() {
+            self.drain_tx_buffer();
+
+            interrupt::disable(interrupt::Irq::Pl011Uart);
+            interrupt::unroute_fiq(interrupt::Irq::Pl011Uart);
+            interrupt::unregister_handler(interrupt::Irq::Pl011Uart);
+            self.IMSC.set(0);
+
+            RX_FIQ.store(false, Ordering::Relaxed);
+            RX_IRQ.store(false, Ordering::Relaxed);
+            TX_IRQ.store(false, Ordering::Relaxed);
+            IRQ_BASE.store(0, Ordering::Relaxed);
+        }
+
         self.CR
             .write(CR::UARTEN::Disabled + CR::TXE::Disabled + CR::RXE::Disabled);
     }
@@ -226,18 +892,24 @@ impl Drop for PL011Uart {
 
 impl ConsoleOps for PL011Uart {
     /// Send a character
+    ///
+    /// The character is dropped if the UART is stuck, instead of hanging the
+    /// whole kernel in a print.
     fn putc(&self, c: char) {
-        // wait until we can send
-        loop {
-            if !self.FR.is_set(FR::TXFF) {
-                break;
-            }
+        if !tx_buffered() {
+            // Keep the order with characters that are still buffered
+            self.drain_tx_buffer();
 
+            let _ = self.send(c);
+            return;
+        }
+
+        while !TX_BUFFER.lock_irqsave(|b| !b.is_full() && b.push(c as u8)) {
+            cpu::irq_masked(|| fill_tx_fifo(self));
             asm::nop();
         }
 
-        // write the character to the buffer
-        self.DR.set(c as u32);
+        cpu::irq_masked(|| fill_tx_fifo(self));
     }
 
     /// Display a string
@@ -253,24 +925,48 @@ impl ConsoleOps for PL011Uart {
     }
 
     /// Receive a character
+    ///
+    /// Waits for as long as it takes somebody to type something. With the RX
+    /// IRQ enabled, the core sleeps in `wfe` in the meantime.
     fn getc(&self) -> char {
-        // wait until something is in the buffer
-        loop {
-            if !self.FR.is_set(FR::RXFE) {
-                break;
+        if !rx_irq_enabled() {
+            loop {
+                if let Ok(c) = self.recv(u64::max_value()) {
+                    return c;
+                }
             }
-
-            asm::nop();
         }
 
-        // read it and return
-        let mut ret = self.DR.get() as u8 as char;
+        // The RX IRQ handler notifies after filling RX_BUFFER, so an IRQ that
+        // sneaks in between the check and the wfe can not leave us sleeping.
+        let mut byte = None;
+        cpu::irq_unmasked(|| {
+            event::wait_for(|| {
+                byte = cpu::irq_masked(|| rx_buffer_pop(self));
+                byte.is_some()
+            })
+        });
+
+        to_char(byte.unwrap())
+    }
 
-        // convert carrige return to newline
-        if ret == '\r' {
-            ret = '\n'
-        }
+    fn try_getc(&self) -> Option<char> {
+        cpu::irq_masked(|| PL011Uart::try_getc(self)).map(to_char)
+    }
 
-        ret
+    /// Wait until the TX ring buffer and FIFO are empty, and the last character
+    /// left the line.
+    fn flush(&self) {
+        self.drain_tx_buffer();
+
+        let _ = delays::poll_timeout(delays::deadline(TX_TIMEOUT_US), || !self.FR.is_set(FR::BUSY));
+    }
+}
+
+impl fmt::Write for PL011Uart {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.puts(s);
+
+        Ok(())
     }
 }
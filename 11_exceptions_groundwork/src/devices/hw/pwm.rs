@@ -0,0 +1,334 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use super::{clock_manager, gpio};
+use crate::{static_assert_offset, static_assert_size};
+use core::ops;
+use raspi3_hal::mmio::{self, Peripheral};
+use register::{mmio::ReadWrite, register_bitfields};
+
+// PWM registers.
+//
+// Descriptions taken from
+// https://github.com/raspberrypi/documentation/files/1888662/BCM2837-ARM-Peripherals.-.Revised.-.V2-1.pdf
+register_bitfields! {
+    u32,
+
+    /// PWM Control
+    CTL [
+        /// Channel 2 M/S Enable
+        MSEN2 OFFSET(15) NUMBITS(1) [],
+
+        /// Channel 2 Polarity
+        POLA2 OFFSET(12) NUMBITS(1) [],
+
+        /// Channel 2 Mode. Zero means PWM mode, one serializer mode.
+        MODE2 OFFSET(9) NUMBITS(1) [],
+
+        /// Channel 2 Enable
+        PWEN2 OFFSET(8) NUMBITS(1) [],
+
+        /// Channel 1 M/S Enable
+        MSEN1 OFFSET(7) NUMBITS(1) [],
+
+        /// Channel 2 Use FIFO
+        USEF2 OFFSET(13) NUMBITS(1) [],
+
+        /// Clear FIFO
+        CLRF1 OFFSET(6) NUMBITS(1) [],
+
+        /// Channel 1 Use FIFO
+        USEF1 OFFSET(5) NUMBITS(1) [],
+
+        /// Channel 1 Polarity
+        POLA1 OFFSET(4) NUMBITS(1) [],
+
+        /// Channel 1 Mode. Zero means PWM mode, one serializer mode.
+        MODE1 OFFSET(1) NUMBITS(1) [],
+
+        /// Channel 1 Enable
+        PWEN1 OFFSET(0) NUMBITS(1) []
+    ],
+
+    /// PWM Status. The error flags are cleared by writing one.
+    STA [
+        /// Bus Error
+        BERR OFFSET(8) NUMBITS(1) [],
+
+        /// FIFO Read Error, read while empty
+        RERR1 OFFSET(3) NUMBITS(1) [],
+
+        /// FIFO Write Error, written while full
+        WERR1 OFFSET(2) NUMBITS(1) [],
+
+        /// FIFO Empty
+        EMPT1 OFFSET(1) NUMBITS(1) [],
+
+        /// FIFO Full
+        FULL1 OFFSET(0) NUMBITS(1) []
+    ],
+
+    /// PWM DMA Configuration
+    DMAC [
+        /// DMA Enable
+        ENAB OFFSET(31) NUMBITS(1) [],
+
+        /// The panic signal is raised below this many FIFO entries
+        PANIC OFFSET(8) NUMBITS(8) [],
+
+        /// The DREQ signal is raised below this many FIFO entries
+        DREQ OFFSET(0) NUMBITS(8) []
+    ]
+}
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct RegisterBlock {
+    CTL: ReadWrite<u32, CTL::Register>,   // 0x00
+    STA: ReadWrite<u32, STA::Register>,   // 0x04
+    DMAC: ReadWrite<u32, DMAC::Register>, // 0x08
+    __reserved_0: u32,                    // 0x0C
+    RNG1: ReadWrite<u32>,                 // 0x10
+    DAT1: ReadWrite<u32>,                 // 0x14
+    FIF1: ReadWrite<u32>,                 // 0x18
+    __reserved_1: u32,                    // 0x1C
+    RNG2: ReadWrite<u32>,                 // 0x20
+    DAT2: ReadWrite<u32>,                 // 0x24
+}
+
+static_assert_offset!(RegisterBlock, CTL, 0x00);
+static_assert_offset!(RegisterBlock, STA, 0x04);
+static_assert_offset!(RegisterBlock, DMAC, 0x08);
+static_assert_offset!(RegisterBlock, __reserved_0, 0x0C);
+static_assert_offset!(RegisterBlock, RNG1, 0x10);
+static_assert_offset!(RegisterBlock, DAT1, 0x14);
+static_assert_offset!(RegisterBlock, FIF1, 0x18);
+static_assert_offset!(RegisterBlock, __reserved_1, 0x1C);
+static_assert_offset!(RegisterBlock, RNG2, 0x20);
+static_assert_offset!(RegisterBlock, DAT2, 0x24);
+static_assert_size!(RegisterBlock, 0x28);
+
+#[derive(Debug)]
+pub enum PwmError {
+    InvalidRange,
+    InvalidDivisor,
+    ClockTimeout,
+}
+pub type Result<T> = ::core::result::Result<T, PwmError>;
+
+impl From<clock_manager::ClockManagerError> for PwmError {
+    fn from(e: clock_manager::ClockManagerError) -> PwmError {
+        match e {
+            clock_manager::ClockManagerError::InvalidDivisor => PwmError::InvalidDivisor,
+            clock_manager::ClockManagerError::Timeout => PwmError::ClockTimeout,
+        }
+    }
+}
+
+/// Offset of the FIFO register, for DMA
+const FIF1_OFFSET: usize = 0x18;
+
+/// PWM channel 1 is output on GPIO18, channel 2 on GPIO19
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Channel {
+    One,
+    Two,
+}
+
+/// Output algorithm
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Mode {
+    /// The output is high for `duty` clock cycles out of every `range` cycles.
+    /// Good for servos and anything that expects a fixed period.
+    MarkSpace,
+
+    /// The high cycles are spread as evenly as possible over the range, which
+    /// gives a much higher output frequency. Good for LEDs and audio, which
+    /// are low-pass filtered anyways.
+    Balanced,
+}
+
+/// Public interface to the PWM peripheral
+pub struct Pwm {
+    peripheral: Peripheral,
+}
+
+impl ops::Deref for Pwm {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl Pwm {
+    pub fn new(peripheral: Peripheral) -> Pwm {
+        Pwm { peripheral }
+    }
+
+    /// Returns a pointer to the register block
+    fn ptr(&self) -> *const RegisterBlock {
+        mmio::base(self.peripheral) as *const _
+    }
+
+    /// Set up `channel` with a period of `range` PWM clock cycles, and map it
+    /// to its GPIO pin.
+    ///
+    /// The PWM clock is the 19.2 MHz oscillator divided by `clock_divisor`. It
+    /// is shared by both channels, so setting up the second channel with a
+    /// different divisor changes the frequency of the first one as well.
+    ///
+    /// The channel starts with a duty of zero and needs to be enabled with
+    /// `enable()`.
+    pub fn init(
+        &self,
+        cm: &clock_manager::ClockManager,
+        gpio: &gpio::GPIO,
+        channel: Channel,
+        range: u32,
+        clock_divisor: u32,
+        mode: Mode,
+    ) -> Result<()> {
+        if range == 0 {
+            return Err(PwmError::InvalidRange);
+        }
+
+        // The clock must not be changed while any channel is running
+        let ctl = self.CTL.get();
+        self.CTL.set(0);
+
+        let clock = cm.set_pwm_clock(clock_divisor);
+        self.CTL.set(ctl);
+        clock?;
+
+        let ms = match mode {
+            Mode::MarkSpace => 1,
+            Mode::Balanced => 0,
+        };
+
+        match channel {
+            Channel::One => {
+                gpio.set_function(18, gpio::Function::Alt5);
+                self.RNG1.set(range);
+                self.DAT1.set(0);
+                self.CTL.modify(CTL::MSEN1.val(ms) + CTL::MODE1::CLEAR + CTL::POLA1::CLEAR);
+            }
+            Channel::Two => {
+                gpio.set_function(19, gpio::Function::Alt5);
+                self.RNG2.set(range);
+                self.DAT2.set(0);
+                self.CTL.modify(CTL::MSEN2.val(ms) + CTL::MODE2::CLEAR + CTL::POLA2::CLEAR);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Output high for `value` out of `range` clock cycles. Values above the
+    /// range mean always high.
+    pub fn set_duty(&self, channel: Channel, value: u32) {
+        match channel {
+            Channel::One => self.DAT1.set(value),
+            Channel::Two => self.DAT2.set(value),
+        }
+    }
+
+    pub fn enable(&self, channel: Channel) {
+        match channel {
+            Channel::One => self.CTL.modify(CTL::PWEN1::SET),
+            Channel::Two => self.CTL.modify(CTL::PWEN2::SET),
+        }
+    }
+
+    pub fn disable(&self, channel: Channel) {
+        match channel {
+            Channel::One => self.CTL.modify(CTL::PWEN1::CLEAR),
+            Channel::Two => self.CTL.modify(CTL::PWEN2::CLEAR),
+        }
+    }
+
+    /// Set up both channels to take their values from the FIFO, with a period
+    /// of `range` cycles of `source` divided by `clock_divisor`.
+    ///
+    /// The channels share the FIFO and take turns, so words go to channel 1
+    /// and 2 alternately. They stay disabled until `start_fifo()`, and the
+    /// caller maps them to pins.
+    pub fn init_fifo(
+        &self,
+        cm: &clock_manager::ClockManager,
+        source: clock_manager::Source,
+        clock_divisor: u32,
+        range: u32,
+    ) -> Result<()> {
+        if range == 0 {
+            return Err(PwmError::InvalidRange);
+        }
+
+        self.CTL.set(0);
+        self.DMAC.set(0);
+        cm.set_pwm_clock_from(source, clock_divisor)?;
+
+        self.RNG1.set(range);
+        self.RNG2.set(range);
+        self.CTL.write(CTL::CLRF1::SET);
+        self.STA.write(STA::BERR::SET + STA::RERR1::SET + STA::WERR1::SET);
+
+        Ok(())
+    }
+
+    /// Enable both channels in balanced FIFO mode.
+    pub fn start_fifo(&self) {
+        self.CTL.write(CTL::USEF1::SET + CTL::PWEN1::SET + CTL::USEF2::SET + CTL::PWEN2::SET);
+    }
+
+    /// Disable both channels and the DMA requests, and drop what is left in
+    /// the FIFO.
+    pub fn stop_fifo(&self) {
+        self.DMAC.set(0);
+        self.CTL.write(CTL::CLRF1::SET);
+    }
+
+    pub fn fifo_full(&self) -> bool {
+        self.STA.is_set(STA::FULL1)
+    }
+
+    pub fn fifo_empty(&self) -> bool {
+        self.STA.is_set(STA::EMPT1)
+    }
+
+    /// Write a value to the FIFO. Check `fifo_full()` first.
+    pub fn push_fifo(&self, value: u32) {
+        self.FIF1.set(value);
+    }
+
+    /// Let the FIFO request DMA transfers whenever it runs low.
+    pub fn enable_fifo_dma(&self) {
+        self.DMAC.write(DMAC::ENAB::SET + DMAC::PANIC.val(7) + DMAC::DREQ.val(7));
+    }
+
+    /// The physical address of the FIFO register, for DMA
+    pub fn fifo_phys_addr(&self) -> usize {
+        self.peripheral.phys() + FIF1_OFFSET
+    }
+}
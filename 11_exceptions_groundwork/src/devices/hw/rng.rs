@@ -0,0 +1,143 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+
+use crate::{delays, static_assert_offset, static_assert_size};
+use core::ops;
+use raspi3_hal::mmio::{self, Peripheral};
+use register::{
+    mmio::{ReadOnly, ReadWrite},
+    register_bitfields,
+};
+
+/*
+ *
+ * The hardware random number generator of the BCM2837
+ *
+ * The Pi 4 has a different one (RNG200) at the same address, which this
+ * driver does not know.
+ *
+ */
+register_bitfields! {
+    u32,
+
+    /// Control
+    RNG_CTRL [
+        /// Generator enable
+        RBGEN OFFSET(0) NUMBITS(1) []
+    ],
+
+    /// Status
+    RNG_STATUS [
+        /// Words waiting in the FIFO
+        READY OFFSET(24) NUMBITS(8) [],
+
+        /// Bits that are thrown away after enabling the generator
+        WARMUP OFFSET(0) NUMBITS(20) []
+    ],
+
+    /// Interrupt mask
+    RNG_INT_MASK [
+        INT_OFF OFFSET(0) NUMBITS(1) []
+    ]
+}
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct RegisterBlock {
+    RNG_CTRL: ReadWrite<u32, RNG_CTRL::Register>,         // 0x00
+    RNG_STATUS: ReadWrite<u32, RNG_STATUS::Register>,     // 0x04
+    RNG_DATA: ReadOnly<u32>,                              // 0x08
+    __reserved_0: u32,                                    // 0x0C, FIFO threshold
+    RNG_INT_MASK: ReadWrite<u32, RNG_INT_MASK::Register>, // 0x10
+}
+
+static_assert_offset!(RegisterBlock, RNG_CTRL, 0x00);
+static_assert_offset!(RegisterBlock, RNG_STATUS, 0x04);
+static_assert_offset!(RegisterBlock, RNG_DATA, 0x08);
+static_assert_offset!(RegisterBlock, __reserved_0, 0x0C);
+static_assert_offset!(RegisterBlock, RNG_INT_MASK, 0x10);
+static_assert_size!(RegisterBlock, 0x14);
+
+/// What Linux throws away, the first output is poor before that
+const WARMUP_COUNT: u32 = 0x4_0000;
+
+/// The FIFO refills in microseconds, after the warm-up in milliseconds
+const READ_TIMEOUT_US: u64 = 100_000;
+
+#[derive(Debug)]
+pub enum RngError {
+    /// No random number came, e.g. because the generator does not exist
+    Timeout,
+}
+
+pub type Result<T> = ::core::result::Result<T, RngError>;
+
+/// Public interface to the RNG
+pub struct Rng {
+    peripheral: Peripheral,
+}
+
+impl ops::Deref for Rng {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl Rng {
+    pub fn new(peripheral: Peripheral) -> Rng {
+        Rng { peripheral }
+    }
+
+    /// Returns a pointer to the register block
+    fn ptr(&self) -> *const RegisterBlock {
+        mmio::base(self.peripheral) as *const _
+    }
+
+    /// Start the generator, if the firmware did not already.
+    pub fn init(&self) {
+        if self.RNG_CTRL.is_set(RNG_CTRL::RBGEN) {
+            return;
+        }
+
+        self.RNG_INT_MASK.write(RNG_INT_MASK::INT_OFF::SET);
+        self.RNG_STATUS.write(RNG_STATUS::WARMUP.val(WARMUP_COUNT));
+        self.RNG_CTRL.write(RNG_CTRL::RBGEN::SET);
+    }
+
+    /// Wait for the next 32 random bits.
+    pub fn read_u32(&self) -> Result<u32> {
+        let deadline = delays::deadline(READ_TIMEOUT_US);
+        delays::poll_timeout(deadline, || self.RNG_STATUS.read(RNG_STATUS::READY) != 0)
+            .map_err(|_| RngError::Timeout)?;
+
+        Ok(self.RNG_DATA.get())
+    }
+
+    pub fn read_u64(&self) -> Result<u64> {
+        Ok(u64::from(self.read_u32()?) << 32 | u64::from(self.read_u32()?))
+    }
+}
@@ -0,0 +1,315 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! SPI master on the SPI0 controller, on GPIO7 to GPIO11 of the pin header:
+//! CE1, CE0, MISO, MOSI and SCLK.
+//!
+//! All transfers are polled and full duplex. The controller has 64 byte FIFOs
+//! in each direction. Whatever is sent, the same number of bytes comes back,
+//! so the TX FIFO is only fed as long as the RX FIFO has room for the answer,
+//! instead of writing everything first and draining afterwards, which stalls
+//! as soon as the RX FIFO is full.
+
+use super::{gpio, videocore_mbox};
+use crate::{delays, static_assert_offset, static_assert_size};
+use core::ops;
+use raspi3_hal::mmio::{self, Peripheral};
+use register::{mmio::ReadWrite, register_bitfields};
+
+// SPI registers.
+//
+// Descriptions taken from
+// https://github.com/raspberrypi/documentation/files/1888662/BCM2837-ARM-Peripherals.-.Revised.-.V2-1.pdf
+register_bitfields! {
+    u32,
+
+    /// Control and Status
+    CS [
+        /// RX FIFO contains data
+        RXD OFFSET(17) NUMBITS(1) [],
+
+        /// TX FIFO can accept data
+        TXD OFFSET(18) NUMBITS(1) [],
+
+        /// Transfer Done, the TX FIFO is empty
+        DONE OFFSET(16) NUMBITS(1) [],
+
+        /// Transfer Active. Chip select is asserted while set.
+        TA OFFSET(7) NUMBITS(1) [],
+
+        /// FIFO Clear
+        CLEAR OFFSET(4) NUMBITS(2) [
+            Both = 0b11
+        ],
+
+        /// Clock Polarity
+        CPOL OFFSET(3) NUMBITS(1) [],
+
+        /// Clock Phase
+        CPHA OFFSET(2) NUMBITS(1) [],
+
+        /// Chip Select
+        CS OFFSET(0) NUMBITS(2) [
+            Cs0 = 0b00,
+            Cs1 = 0b01,
+            // No line is asserted
+            None = 0b11
+        ]
+    ]
+}
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct RegisterBlock {
+    CS: ReadWrite<u32, CS::Register>, // 0x00
+    FIFO: ReadWrite<u32>,             // 0x04
+    CLK: ReadWrite<u32>,              // 0x08
+    DLEN: ReadWrite<u32>,             // 0x0C
+    LTOH: ReadWrite<u32>,             // 0x10
+    DC: ReadWrite<u32>,               // 0x14
+}
+
+static_assert_offset!(RegisterBlock, CS, 0x00);
+static_assert_offset!(RegisterBlock, FIFO, 0x04);
+static_assert_offset!(RegisterBlock, CLK, 0x08);
+static_assert_offset!(RegisterBlock, DLEN, 0x0C);
+static_assert_offset!(RegisterBlock, LTOH, 0x10);
+static_assert_offset!(RegisterBlock, DC, 0x14);
+static_assert_size!(RegisterBlock, 0x18);
+
+/// A transfer must move at least one byte per this many microseconds, or it
+/// is given up.
+const BYTE_TIMEOUT_US: u64 = 100_000;
+
+const FIFO_SIZE: usize = 64;
+
+#[derive(Debug)]
+pub enum SpiError {
+    /// The controller stopped moving bytes.
+    Timeout,
+    /// The requested clock is above half the core clock, or zero.
+    InvalidClock,
+    MailboxError,
+}
+pub type Result<T> = ::core::result::Result<T, SpiError>;
+
+/// Clock polarity and phase, as the usual mode numbers
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Mode {
+    /// CPOL 0, CPHA 0
+    Mode0,
+    /// CPOL 0, CPHA 1
+    Mode1,
+    /// CPOL 1, CPHA 0
+    Mode2,
+    /// CPOL 1, CPHA 1
+    Mode3,
+}
+
+/// The chip select line that is asserted during transfers
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ChipSelect {
+    /// GPIO8
+    Cs0,
+    /// GPIO7
+    Cs1,
+    /// Neither, for a chip select that is driven as a GPIO by the caller
+    None,
+}
+
+/// Public interface to the SPI0 master
+pub struct Spi {
+    peripheral: Peripheral,
+}
+
+impl ops::Deref for Spi {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl Spi {
+    pub fn new(peripheral: Peripheral) -> Spi {
+        Spi { peripheral }
+    }
+
+    /// Returns a pointer to the register block
+    fn ptr(&self) -> *const RegisterBlock {
+        mmio::base(self.peripheral) as *const _
+    }
+
+    /// Map the controller to GPIO7 to GPIO11, and set it up for `mode` and
+    /// `cs` at no more than `hz`.
+    ///
+    /// The SPI clock is the core clock, as reported by the firmware, divided by
+    /// an even number. Returns the clock that is actually used.
+    pub fn init(
+        &self,
+        v_mbox: &mut videocore_mbox::VideocoreMbox,
+        gpio: &gpio::GPIO,
+        hz: u32,
+        mode: Mode,
+        cs: ChipSelect,
+    ) -> Result<u32> {
+        let core_clock =
+            videocore_mbox::clock::get_rate(v_mbox, videocore_mbox::Clock::Core)
+                .map_err(|_| SpiError::MailboxError)?;
+        if core_clock == 0 {
+            return Err(SpiError::MailboxError);
+        }
+        if hz == 0 || hz > core_clock / 2 {
+            return Err(SpiError::InvalidClock);
+        }
+
+        for pin in 7..=11 {
+            gpio.set_function(pin, gpio::Function::Alt0);
+        }
+        gpio.set_pull_many(&[
+            (7, gpio::Pull::Off),
+            (8, gpio::Pull::Off),
+            (9, gpio::Pull::Off),
+            (10, gpio::Pull::Off),
+            (11, gpio::Pull::Off),
+        ]);
+
+        // Round the divisor up to the next even number, so that the clock is
+        // never faster than requested. Zero would mean 65536.
+        let cdiv = ((core_clock + hz - 1) / hz + 1) & !1;
+        let cdiv = cdiv.min(0xFFFE);
+        self.CLK.set(cdiv);
+
+        let (cpol, cpha) = match mode {
+            Mode::Mode0 => (0, 0),
+            Mode::Mode1 => (0, 1),
+            Mode::Mode2 => (1, 0),
+            Mode::Mode3 => (1, 1),
+        };
+        let cs = match cs {
+            ChipSelect::Cs0 => CS::CS::Cs0,
+            ChipSelect::Cs1 => CS::CS::Cs1,
+            ChipSelect::None => CS::CS::None,
+        };
+        self.CS
+            .write(CS::CLEAR::Both + CS::CPOL.val(cpol) + CS::CPHA.val(cpha) + cs);
+
+        Ok(core_clock / cdiv)
+    }
+
+    /// Move `buf.len()` bytes in both directions.
+    fn run(&self, mut buf: Buffer) -> Result<()> {
+        let len = buf.len();
+        self.CS.modify(CS::CLEAR::Both + CS::TA::SET);
+
+        let mut sent = 0;
+        let mut received = 0;
+        let mut result = Ok(());
+
+        while received < len {
+            let progress = delays::poll_timeout(delays::deadline(BYTE_TIMEOUT_US), || {
+                let mut moved = false;
+
+                // Never more than a FIFO's worth ahead of the RX side, so that
+                // the answers always fit into the RX FIFO.
+                while sent < len && sent - received < FIFO_SIZE && self.CS.is_set(CS::TXD) {
+                    self.FIFO.set(u32::from(buf.tx(sent)));
+                    sent += 1;
+                    moved = true;
+                }
+
+                while received < sent && self.CS.is_set(CS::RXD) {
+                    buf.rx(received, self.FIFO.get() as u8);
+                    received += 1;
+                    moved = true;
+                }
+
+                moved
+            });
+
+            if progress.is_err() {
+                result = Err(SpiError::Timeout);
+                break;
+            }
+        }
+
+        if result.is_ok() {
+            let deadline = delays::deadline(BYTE_TIMEOUT_US);
+            let _ = delays::poll_timeout(deadline, || self.CS.is_set(CS::DONE));
+        }
+        self.CS.modify(CS::TA::CLEAR);
+
+        result
+    }
+
+    /// Full duplex, in place: Send `buf`, and replace it with what came back.
+    pub fn transfer(&self, buf: &mut [u8]) -> Result<()> {
+        self.run(Buffer::InPlace(buf))
+    }
+
+    /// Send `data`, and ignore what comes back.
+    #[allow(dead_code)]
+    pub fn write(&self, data: &[u8]) -> Result<()> {
+        self.run(Buffer::Write(data))
+    }
+
+    /// Receive into `buf`, sending zeros.
+    #[allow(dead_code)]
+    pub fn read(&self, buf: &mut [u8]) -> Result<()> {
+        self.run(Buffer::Read(buf))
+    }
+}
+
+/// Where the bytes of a transfer come from and go to
+enum Buffer<'a> {
+    InPlace(&'a mut [u8]),
+    Write(&'a [u8]),
+    Read(&'a mut [u8]),
+}
+
+impl Buffer<'_> {
+    fn len(&self) -> usize {
+        match self {
+            Buffer::InPlace(buf) | Buffer::Read(buf) => buf.len(),
+            Buffer::Write(data) => data.len(),
+        }
+    }
+
+    /// Byte `i` is always sent before byte `i` is received, so in place
+    /// transfers never overwrite a byte that is still to be sent.
+    fn tx(&self, i: usize) -> u8 {
+        match self {
+            Buffer::InPlace(buf) => buf[i],
+            Buffer::Write(data) => data[i],
+            Buffer::Read(_) => 0,
+        }
+    }
+
+    fn rx(&mut self, i: usize, byte: u8) {
+        match self {
+            Buffer::InPlace(buf) | Buffer::Read(buf) => buf[i] = byte,
+            Buffer::Write(_) => (),
+        }
+    }
+}
@@ -0,0 +1,369 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use crate::{
+    interrupt,
+    static_assert_offset, static_assert_size,
+    sync::SpinLock,
+    time,
+};
+use core::ops;
+use raspi3_hal::mmio::{self, Peripheral};
+use register::mmio::{ReadOnly, ReadWrite};
+
+/*
+ *
+ * Using the RPi3 SoC's system timer peripheral
+ *
+ */
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct RegisterBlock {
+    CS: ReadWrite<u32>,     // 0x00
+    CLO: ReadOnly<u32>,     // 0x04
+    CHI: ReadOnly<u32>,     // 0x08
+    C: [ReadWrite<u32>; 4], // 0x0C
+}
+
+static_assert_offset!(RegisterBlock, CS, 0x00);
+static_assert_offset!(RegisterBlock, CLO, 0x04);
+static_assert_offset!(RegisterBlock, CHI, 0x08);
+static_assert_offset!(RegisterBlock, C, 0x0C);
+static_assert_size!(RegisterBlock, 0x1C);
+
+#[derive(Debug)]
+pub enum SysTmrError {
+    /// The interrupt controller refused the handler
+    InterruptError,
+}
+pub type Result<T> = ::core::result::Result<T, SysTmrError>;
+
+/// The compare channels that are free for the ARM. The GPU uses 0 and 2.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Channel {
+    One = 1,
+    Three = 3,
+}
+
+impl Channel {
+    fn irq(self) -> interrupt::Irq {
+        match self {
+            Channel::One => interrupt::Irq::SysTimer1,
+            Channel::Three => interrupt::Irq::SysTimer3,
+        }
+    }
+
+    fn slot(self) -> usize {
+        match self {
+            Channel::One => 0,
+            Channel::Three => 1,
+        }
+    }
+}
+
+const CHANNELS: [Channel; 2] = [Channel::One, Channel::Three];
+
+/// How far in the future the compare value must at least be when arming, so
+/// that the counter does not pass it before the write took effect
+const MIN_DELTA_US: u64 = 2;
+
+/// The farthest that a deadline can be ahead. Wrapping arithmetic can only
+/// tell which of two counter values is the later one while they are less than
+/// half of the counter's range apart.
+const MAX_DEADLINE_US: u64 = i64::max_value() as u64;
+
+/// A point in time by which something must be done, see `SysTmr::deadline()`.
+///
+/// Unlike a timeout, a deadline can be passed on through all the steps of an
+/// operation, so that it bounds the operation as a whole, however often the
+/// steps wait or retry in between.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Deadline {
+    at_us: u64,
+    clock: Clock,
+}
+
+/// The microsecond counter that a deadline is measured on
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Clock {
+    SysTmr,
+    /// QEMU does not emulate the System Timer, see `time::uptime()`
+    Uptime,
+}
+
+impl Clock {
+    /// The clock that deadlines are measured on, with its counter value: The
+    /// System Timer, or the ARM generic timer where the System Timer reads
+    /// zero.
+    fn read(sys_tmr: &SysTmr) -> (Clock, u64) {
+        let now = sys_tmr.get_system_timer();
+        if now != 0 {
+            (Clock::SysTmr, now)
+        } else {
+            (Clock::Uptime, time::uptime())
+        }
+    }
+}
+
+impl Deadline {
+    fn on(clock: Clock, now_us: u64, from_now_us: u64) -> Deadline {
+        Deadline {
+            at_us: now_us.wrapping_add(from_now_us.min(MAX_DEADLINE_US)),
+            clock,
+        }
+    }
+
+    /// The deadline `from_now_us` after the counter value `now_us`, without
+    /// using that value of the counter.
+    ///
+    /// The counter is the one `SysTmr::deadline()` picks, so both kinds of
+    /// deadline can be compared.
+    pub fn after(now_us: u64, from_now_us: u64) -> Deadline {
+        let (clock, _) = Clock::read(&SysTmr::new(Peripheral::SysTimer));
+
+        Deadline::on(clock, now_us, from_now_us)
+    }
+
+    fn now_us(&self) -> u64 {
+        match self.clock {
+            Clock::SysTmr => SysTmr::new(Peripheral::SysTimer).get_system_timer(),
+            Clock::Uptime => time::uptime(),
+        }
+    }
+
+    /// Whether the deadline has passed
+    pub fn expired(&self) -> bool {
+        self.expired_at(self.now_us())
+    }
+
+    /// Microseconds until the deadline, zero once it has passed
+    #[allow(dead_code)]
+    pub fn remaining_us(&self) -> u64 {
+        self.remaining_us_at(self.now_us())
+    }
+
+    /// Whether the deadline has passed at the counter value `now_us`
+    ///
+    /// Computed with wrapping arithmetic, so the result stays correct if the
+    /// counter rolls over in between.
+    pub fn expired_at(&self, now_us: u64) -> bool {
+        now_us.wrapping_sub(self.at_us) as i64 >= 0
+    }
+
+    /// Microseconds from the counter value `now_us` until the deadline
+    pub fn remaining_us_at(&self, now_us: u64) -> u64 {
+        if self.expired_at(now_us) {
+            0
+        } else {
+            self.at_us.wrapping_sub(now_us)
+        }
+    }
+
+    /// The earlier of both deadlines, e.g. for a step that has a limit of its
+    /// own within the deadline of the whole operation
+    ///
+    /// Both must count on the same clock, which all deadlines made on one
+    /// board or emulator do.
+    pub fn min(self, other: Deadline) -> Deadline {
+        debug_assert_eq!(self.clock, other.clock, "deadlines on different clocks");
+
+        if other.expired_at(self.at_us) {
+            other
+        } else {
+            self
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Alarm {
+    deadline_us: u64,
+    callback: fn(),
+}
+
+static ALARMS: SpinLock<[Option<Alarm>; 2]> = SpinLock::new([None; 2]);
+
+/// Public interface to the BCM System Timer
+pub struct SysTmr {
+    peripheral: Peripheral,
+}
+
+impl ops::Deref for SysTmr {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl SysTmr {
+    pub fn new(peripheral: Peripheral) -> SysTmr {
+        SysTmr { peripheral }
+    }
+
+    /// Returns a pointer to the register block
+    fn ptr(&self) -> *const RegisterBlock {
+        mmio::base(self.peripheral) as *const _
+    }
+
+    /// Get System Timer's counter
+    ///
+    /// The counter ticks at 1 MHz. Reads constant zero on QEMU, which does not
+    /// emulate the System Timer.
+    pub fn get_system_timer(&self) -> u64 {
+        // Since it is MMIO, we must emit two separate 32 bit reads
+        let mut hi = self.CHI.get();
+        let mut lo = self.CLO.get();
+
+        // We have to repeat if high word changed during read. This
+        // will emit a clippy warning that needs be ignored, or you
+        // lose an MMIO read.
+        if hi != self.CHI.get() {
+            hi = self.CHI.get();
+            lo = self.CLO.get();
+        }
+
+        // Compose long int value
+        (u64::from(hi) << 32) | u64::from(lo)
+    }
+
+    /// The deadline `from_now_us` microseconds from now.
+    ///
+    /// Deadlines further ahead than 2^63 us are cut to that, which is
+    /// practically never. On QEMU, where the counter reads zero, the deadline
+    /// is measured on the ARM generic timer instead.
+    pub fn deadline(&self, from_now_us: u64) -> Deadline {
+        let (clock, now) = Clock::read(self);
+
+        Deadline::on(clock, now, from_now_us)
+    }
+
+    /// Let `channel` match when the lower 32 bits of the counter reach those
+    /// of `deadline_us`.
+    ///
+    /// A match sets the channel's bit in CS, and raises its IRQ until it is
+    /// acknowledged with `ack()`.
+    pub fn set_compare(&self, channel: Channel, deadline_us: u64) {
+        self.C[channel as usize].set(deadline_us as u32);
+    }
+
+    /// Whether `channel` matched since it was last acknowledged
+    pub fn matched(&self, channel: Channel) -> bool {
+        self.CS.get() & (1 << channel as u32) != 0
+    }
+
+    /// Acknowledge a match of `channel`, which also clears its IRQ
+    pub fn ack(&self, channel: Channel) {
+        self.CS.set(1 << channel as u32);
+    }
+
+    /// Call `callback` once from IRQ context when the counter reaches
+    /// `deadline_us`, or right after arming if it already passed.
+    ///
+    /// An alarm that is already set on `channel` is replaced. IRQs must be
+    /// unmasked on the core for the callback to actually run.
+    pub fn set_alarm(&self, channel: Channel, deadline_us: u64, callback: fn()) -> Result<()> {
+        // Both channels share the handler, so it may be registered already.
+        match interrupt::register_handler(channel.irq(), irq_handler) {
+            Ok(()) | Err(interrupt::InterruptError::AlreadyRegistered) => (),
+            Err(_) => return Err(SysTmrError::InterruptError),
+        }
+
+        ALARMS.lock_irqsave(|alarms| {
+            alarms[channel.slot()] = Some(Alarm {
+                deadline_us,
+                callback,
+            });
+            self.arm(channel, deadline_us);
+        });
+        interrupt::enable(channel.irq());
+
+        Ok(())
+    }
+
+    /// Remove the alarm of `channel` before it fired.
+    #[allow(dead_code)]
+    pub fn cancel_alarm(&self, channel: Channel) {
+        interrupt::disable(channel.irq());
+        ALARMS.lock_irqsave(|alarms| alarms[channel.slot()] = None);
+        self.ack(channel);
+    }
+
+    /// Program the compare register of `channel` for `deadline_us`.
+    ///
+    /// The register only holds the lower 32 bits, so a deadline that is more
+    /// than 2^32 us (71 minutes) away matches early, once per wrap. The IRQ
+    /// handler checks the full deadline and arms again in that case.
+    ///
+    /// The match only happens when the counter equals the compare value. If
+    /// it passed the value already, the next match would be one wrap later.
+    /// Deadlines that are too close or in the past are therefore moved just
+    /// ahead of the counter, and the write is checked against the counter.
+    fn arm(&self, channel: Channel, deadline_us: u64) {
+        self.ack(channel);
+
+        loop {
+            let target = deadline_us.max(self.get_system_timer() + MIN_DELTA_US);
+            self.set_compare(channel, target);
+
+            if self.get_system_timer() < target {
+                break;
+            }
+        }
+    }
+}
+
+/// Fire the alarms of all matched channels whose deadline passed, and arm
+/// the others again.
+fn irq_handler() {
+    let sys_tmr = SysTmr::new(Peripheral::SysTimer);
+
+    for &channel in CHANNELS.iter() {
+        if !sys_tmr.matched(channel) {
+            continue;
+        }
+        sys_tmr.ack(channel);
+
+        let callback = ALARMS.lock_irqsave(|alarms| {
+            let slot = &mut alarms[channel.slot()];
+
+            match *slot {
+                Some(alarm) if sys_tmr.get_system_timer() >= alarm.deadline_us => {
+                    *slot = None;
+                    Some(alarm.callback)
+                }
+                Some(alarm) => {
+                    sys_tmr.arm(channel, alarm.deadline_us);
+                    None
+                }
+                None => None,
+            }
+        });
+
+        // Called without the lock held, so that it can set the next alarm
+        if let Some(callback) = callback {
+            callback();
+        }
+    }
+}
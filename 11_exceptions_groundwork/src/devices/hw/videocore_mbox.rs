@@ -22,149 +22,568 @@
  * SOFTWARE.
  */
 
-use core::ops;
-use cortex_a::asm;
-use register::{
-    mmio::{ReadOnly, WriteOnly},
-    register_bitfields,
+//! The mailbox protocol and the property messages live in
+//! `raspi3_hal::videocore_mbox`, where they have host tests. This module adds
+//! the DMA buffer, the caches and the timeouts of the kernel, and the calls
+//! that are built on top.
+
+use crate::cache;
+use crate::delays;
+use crate::memory;
+use core::{
+    fmt, ptr, slice,
+    sync::atomic::{compiler_fence, Ordering},
+};
+use cortex_a::barrier;
+use raspi3_hal::videocore_mbox;
+
+pub use raspi3_hal::videocore_mbox::{
+    channel, response, tag, tag_code, ArmRegion, Clock, Mailbox, MemoryRegion, PropertyMessage,
+    Response, Responses, Result, Tag, VcRegion, VideocoreMboxError, REQUEST,
 };
 
-register_bitfields! {
-    u32,
+// The address for buffer needs to be 16-byte aligned so that the Videcore can
+// handle it properly.
+const MBOX_ALIGNMENT: usize = 16;
+// Big enough for the command line, which the firmware extends with quite a
+// few options of its own.
+const MBOX_SIZE: usize = 256;
+
+/// How long to wait for the Videocore before giving up on a call
+pub const DEFAULT_TIMEOUT_US: u64 = 500_000;
 
-    STATUS [
-        FULL  OFFSET(31) NUMBITS(1) [],
-        EMPTY OFFSET(30) NUMBITS(1) []
-    ]
+// Public interface to the mailbox
+pub struct VideocoreMbox<'a> {
+    /// The memory of `region`
+    pub buffer: &'a mut [u32],
+    region: memory::DmaRegion,
+    timeout_us: u64,
 }
 
-#[allow(non_snake_case)]
-#[repr(C)]
-pub struct RegisterBlock {
-    READ: ReadOnly<u32>,                     // 0x00
-    __reserved_0: [u32; 5],                  // 0x04
-    STATUS: ReadOnly<u32, STATUS::Register>, // 0x18
-    __reserved_1: u32,                       // 0x1C
-    WRITE: WriteOnly<u32>,                   // 0x20
+impl<'a> VideocoreMbox<'a> {
+    pub fn new() -> ::core::result::Result<VideocoreMbox<'a>, ()> {
+        let region = memory::dma_alloc(MBOX_SIZE * 4, MBOX_ALIGNMENT).ok_or(())?;
+        let buffer =
+            unsafe { slice::from_raw_parts_mut(region.virt_addr() as *mut u32, MBOX_SIZE) };
+
+        Ok(VideocoreMbox {
+            buffer,
+            region,
+            timeout_us: DEFAULT_TIMEOUT_US,
+        })
+    }
+
+    /// Set how long `call()` waits for the Videocore
+    pub fn set_timeout(&mut self, timeout_us: u64) {
+        self.timeout_us = timeout_us;
+    }
+
+    /// Make a mailbox call. Returns Err(VideocoreMboxError) on failure, Ok(()) success
+    pub fn call(&mut self, channel: u32) -> Result<()> {
+        let buf_virt = self.buffer.as_ptr() as usize;
+        let buf_len = self.buffer.len() * 4;
+
+        // the Videocore is handed the bus address of the buffer
+        let buf_ptr = self.region.bus_addr();
+        debug_assert_eq!(buf_ptr & 0xF, 0, "mailbox buffer is not 16-byte aligned");
+
+        // make sure that all stores to the buffer have reached memory before
+        // the Videocore is signaled
+        cache::clean_dcache_range(buf_virt, buf_len);
+
+        // The timeout bounds the whole call, not each of the waits
+        let deadline = delays::deadline(self.timeout_us);
+
+        videocore_mbox::send(channel, buf_ptr, || deadline.expired())?;
+        videocore_mbox::receive(channel, buf_ptr, || deadline.expired())?;
+
+        // do not read the buffer before the response arrived, and not from
+        // stale cache lines
+        unsafe { barrier::dmb(barrier::SY) };
+        cache::invalidate_dcache_range(buf_virt, buf_len);
+
+        // is it a valid successful response?
+        match self.buffer[1] {
+            response::SUCCESS => Ok(()),
+            code => Err(VideocoreMboxError::ResponseError(code)),
+        }
+    }
 }
 
-// Custom errors
-pub enum VideocoreMboxError {
-    ResponseError,
-    UnknownError,
+impl<'a> Mailbox for VideocoreMbox<'a> {
+    fn buffer(&mut self) -> &mut [u32] {
+        self.buffer
+    }
+
+    fn call(&mut self, channel: u32) -> Result<()> {
+        VideocoreMbox::call(self, channel)
+    }
 }
-pub type Result<T> = ::core::result::Result<T, VideocoreMboxError>;
 
-// Channels
-pub mod channel {
-    pub const PROP: u32 = 8;
+impl<'a> Drop for VideocoreMbox<'a> {
+    /// Give the buffer back to the DMA pool.
+    fn drop(&mut self) {
+        // The region is not touched anymore, and has no drop glue of its own.
+        memory::dma_free(unsafe { ptr::read(&self.region) });
+    }
 }
 
-// Tags
-pub mod tag {
-    pub const SETCLKRATE: u32 = 0x38002;
-    pub const LAST: u32 = 0;
+/// Which board the kernel is running on, see `board_info()`
+#[derive(Copy, Clone, Debug)]
+pub struct BoardInfo {
+    pub revision: u32,
+    pub serial: u64,
+    pub mac_address: [u8; 6],
+    /// Memory that belongs to the ARM cores
+    pub arm_memory: MemoryRegion,
+    /// Memory that the firmware keeps for the Videocore
+    pub vc_memory: MemoryRegion,
 }
 
-// Clocks
-pub mod clock {
-    pub const UART: u32 = 0x0_0000_0002;
+impl BoardInfo {
+    /// Revision codes with bit 23 set encode type, memory size, etc. in
+    /// bitfields. Older boards use a plain number.
+    fn new_style(&self) -> bool {
+        self.revision & (1 << 23) != 0
+    }
+
+    /// The board model, decoded from the revision code
+    pub fn model(&self) -> &'static str {
+        if !self.new_style() {
+            return "Raspberry Pi 1 (old-style revision)";
+        }
+
+        match (self.revision >> 4) & 0xFF {
+            0x00 => "Raspberry Pi 1 Model A",
+            0x01 => "Raspberry Pi 1 Model B",
+            0x02 => "Raspberry Pi 1 Model A+",
+            0x03 => "Raspberry Pi 1 Model B+",
+            0x04 => "Raspberry Pi 2 Model B",
+            0x06 => "Compute Module 1",
+            0x08 => "Raspberry Pi 3 Model B",
+            0x09 => "Raspberry Pi Zero",
+            0x0A => "Compute Module 3",
+            0x0C => "Raspberry Pi Zero W",
+            0x0D => "Raspberry Pi 3 Model B+",
+            0x0E => "Raspberry Pi 3 Model A+",
+            0x10 => "Compute Module 3+",
+            _ => "unknown Raspberry Pi",
+        }
+    }
+
+    /// The total RAM in MiB, decoded from the revision code
+    pub fn ram_mib(&self) -> Option<u32> {
+        if !self.new_style() {
+            return None;
+        }
+
+        match (self.revision >> 20) & 0x7 {
+            n @ 0..=4 => Some(256 << n),
+            _ => None,
+        }
+    }
 }
 
-// Responses
-mod response {
-    pub const SUCCESS: u32 = 0x8000_0000;
-    pub const ERROR: u32 = 0x8000_0001; // error parsing request buffer (partial response)
+impl fmt::Display for BoardInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let m = &self.mac_address;
+
+        write!(f, "{} (rev {:#010x}", self.model(), self.revision)?;
+        if let Some(ram) = self.ram_mib() {
+            write!(f, ", {} MiB", ram)?;
+        }
+        write!(
+            f,
+            "), serial {:016x}, MAC {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            self.serial, m[0], m[1], m[2], m[3], m[4], m[5]
+        )
+    }
 }
 
-pub const REQUEST: u32 = 0;
+/// Ask the firmware which board the kernel is running on
+pub fn board_info(v_mbox: &mut VideocoreMbox) -> Result<BoardInfo> {
+    let resp = PropertyMessage::new()
+        .with(Tag::GetBoardRevision)
+        .with(Tag::GetBoardSerial)
+        .with(Tag::GetBoardMacAddress)
+        .with(Tag::GetArmMemory)
+        .with(Tag::GetVcMemory)
+        .call(v_mbox)?;
 
-// The address for buffer needs to be 16-byte aligned so that the Videcore can
-// handle it properly.
-const MBOX_ALIGNMENT: usize = 16;
-const MBOX_SIZE: usize = 36;
+    match (resp.get(0), resp.get(1), resp.get(2), resp.get(3), resp.get(4)) {
+        (
+            Some(Response::BoardRevision(revision)),
+            Some(Response::BoardSerial(serial)),
+            Some(Response::MacAddress(mac_address)),
+            Some(Response::Memory(arm_memory)),
+            Some(Response::Memory(vc_memory)),
+        ) => Ok(BoardInfo {
+            revision,
+            serial,
+            mac_address,
+            arm_memory,
+            vc_memory,
+        }),
+        _ => Err(VideocoreMboxError::UnknownError),
+    }
+}
 
-// Public interface to the mailbox
-pub struct VideocoreMbox<'a> {
-    pub buffer: &'a mut [u32],
-    base_addr: usize,
+/// Ask the firmware how the SDRAM is split between the ARM cores and the
+/// Videocore
+pub fn memory_split(v_mbox: &mut VideocoreMbox) -> Result<(ArmRegion, VcRegion)> {
+    let resp = PropertyMessage::new()
+        .with(Tag::GetArmMemory)
+        .with(Tag::GetVcMemory)
+        .call(v_mbox)?;
+
+    match (resp.get(0), resp.get(1)) {
+        (Some(Response::Memory(arm)), Some(Response::Memory(vc))) => Ok((arm, vc)),
+        _ => Err(VideocoreMboxError::UnknownError),
+    }
 }
 
-/// Deref to RegisterBlock
-///
-/// Allows writing
-/// ```
-/// self.STATUS.read()
-/// ```
-/// instead of something along the lines of
-/// ```
-/// unsafe { (*Mbox::ptr()).STATUS.read() }
-/// ```
-impl<'a> ops::Deref for VideocoreMbox<'a> {
-    type Target = RegisterBlock;
-
-    fn deref(&self) -> &Self::Target {
-        unsafe { &*self.ptr() }
+/// Ask the firmware which DMA channels it leaves to the ARM, as a mask with
+/// bit n for channel n
+pub fn dma_channels(v_mbox: &mut VideocoreMbox) -> Result<u32> {
+    match PropertyMessage::new().with(Tag::GetDmaChannels).call(v_mbox)?.get(0) {
+        Some(Response::DmaChannels(mask)) => Ok(mask),
+        _ => Err(VideocoreMboxError::UnknownError),
     }
 }
 
-impl<'a> VideocoreMbox<'a> {
-    pub fn new(base_addr: usize) -> ::core::result::Result<VideocoreMbox<'a>, ()> {
-        let ret = crate::DMA_ALLOCATOR.lock(|d| d.alloc_slice_zeroed(MBOX_SIZE, MBOX_ALIGNMENT));
+/// Query and change clock rates
+pub mod clock {
+    use super::{Clock, PropertyMessage, Response, Result, Tag, VideocoreMbox, VideocoreMboxError};
 
-        if ret.is_err() {
-            return Err(());
+    fn rate(v_mbox: &mut VideocoreMbox, tag: Tag) -> Result<u32> {
+        match PropertyMessage::new().with(tag).call(v_mbox)?.get(0) {
+            Some(Response::ClockRate { rate, .. }) => Ok(rate),
+            _ => Err(VideocoreMboxError::UnknownError),
         }
+    }
 
-        Ok(VideocoreMbox {
-            base_addr,
-            buffer: ret.unwrap(),
-        })
+    /// The current rate of `clock` in Hz. Zero if the clock does not exist.
+    pub fn get_rate(v_mbox: &mut VideocoreMbox, clock: Clock) -> Result<u32> {
+        rate(v_mbox, Tag::GetClockRate { clock })
     }
 
-    /// Returns a pointer to the register block
-    fn ptr(&self) -> *const RegisterBlock {
-        self.base_addr as *const _
+    /// The maximum rate of `clock` in Hz
+    pub fn get_max_rate(v_mbox: &mut VideocoreMbox, clock: Clock) -> Result<u32> {
+        rate(v_mbox, Tag::GetMaxClockRate { clock })
     }
 
-    /// Make a mailbox call. Returns Err(MboxError) on failure, Ok(()) success
-    pub fn call(&self, channel: u32) -> Result<()> {
-        // wait until we can write to the mailbox
-        loop {
-            if !self.STATUS.is_set(STATUS::FULL) {
-                break;
-            }
+    /// The minimum rate of `clock` in Hz
+    pub fn get_min_rate(v_mbox: &mut VideocoreMbox, clock: Clock) -> Result<u32> {
+        rate(v_mbox, Tag::GetMinClockRate { clock })
+    }
 
-            asm::nop();
+    /// Set `clock` to `hz` and return the rate that the firmware granted.
+    ///
+    /// Rates outside of what the firmware reports as minimum and maximum are
+    /// rejected. By default, changing the ARM clock also changes the turbo
+    /// settings of the firmware. `skip_turbo` prevents that.
+    pub fn set_rate(
+        v_mbox: &mut VideocoreMbox,
+        clock: Clock,
+        hz: u32,
+        skip_turbo: bool,
+    ) -> Result<u32> {
+        let resp = PropertyMessage::new()
+            .with(Tag::GetMinClockRate { clock })
+            .with(Tag::GetMaxClockRate { clock })
+            .call(v_mbox)?;
+
+        let rate_of = |n| match resp.get(n) {
+            Some(Response::ClockRate { rate, .. }) => Ok(rate),
+            _ => Err(VideocoreMboxError::UnknownError),
+        };
+
+        if hz < rate_of(0)? || hz > rate_of(1)? {
+            return Err(VideocoreMboxError::RateOutOfRange);
         }
 
-        let buf_ptr = self.buffer.as_ptr() as u32;
+        rate(
+            v_mbox,
+            Tag::SetClockRate {
+                clock,
+                rate: hz,
+                skip_turbo,
+            },
+        )
+    }
+}
+
+/// SoC temperature and throttling
+pub mod thermal {
+    use super::{PropertyMessage, Response, Result, Tag, VideocoreMbox, VideocoreMboxError};
 
-        // write the address of our message to the mailbox with channel identifier
-        self.WRITE.set((buf_ptr & !0xF) | (channel & 0xF));
+    /// The throttling state of the firmware, as reported by `GET_THROTTLED`.
+    ///
+    /// The first group of flags describes the current state, the `*_occurred`
+    /// flags are sticky since boot.
+    #[derive(Copy, Clone, Debug)]
+    pub struct ThrottleFlags(pub u32);
 
-        // now wait for the response
-        loop {
-            // is there a response?
-            loop {
-                if !self.STATUS.is_set(STATUS::EMPTY) {
-                    break;
-                }
+    impl ThrottleFlags {
+        const UNDER_VOLTAGE: u32 = 1 << 0;
+        const FREQUENCY_CAPPED: u32 = 1 << 1;
+        const THROTTLED: u32 = 1 << 2;
+        const SOFT_TEMP_LIMIT: u32 = 1 << 3;
+        const OCCURRED_SHIFT: u32 = 16;
 
-                asm::nop();
-            }
+        fn is_set(self, flag: u32) -> bool {
+            self.0 & flag != 0
+        }
 
-            let resp: u32 = self.READ.get();
+        pub fn under_voltage(self) -> bool {
+            self.is_set(Self::UNDER_VOLTAGE)
+        }
+
+        pub fn frequency_capped(self) -> bool {
+            self.is_set(Self::FREQUENCY_CAPPED)
+        }
+
+        pub fn throttled(self) -> bool {
+            self.is_set(Self::THROTTLED)
+        }
+
+        pub fn soft_temp_limit(self) -> bool {
+            self.is_set(Self::SOFT_TEMP_LIMIT)
+        }
+
+        pub fn under_voltage_occurred(self) -> bool {
+            self.is_set(Self::UNDER_VOLTAGE << Self::OCCURRED_SHIFT)
+        }
+
+        pub fn frequency_capped_occurred(self) -> bool {
+            self.is_set(Self::FREQUENCY_CAPPED << Self::OCCURRED_SHIFT)
+        }
+
+        pub fn throttled_occurred(self) -> bool {
+            self.is_set(Self::THROTTLED << Self::OCCURRED_SHIFT)
+        }
+
+        pub fn soft_temp_limit_occurred(self) -> bool {
+            self.is_set(Self::SOFT_TEMP_LIMIT << Self::OCCURRED_SHIFT)
+        }
+    }
+
+    fn single(v_mbox: &mut VideocoreMbox, tag: Tag) -> Result<Response> {
+        PropertyMessage::new()
+            .with(tag)
+            .call(v_mbox)?
+            .get(0)
+            .ok_or(VideocoreMboxError::UnknownError)
+    }
+
+    /// The current SoC temperature in thousandths of a degree Celsius
+    pub fn temperature_millicelsius(v_mbox: &mut VideocoreMbox) -> Result<u32> {
+        match single(v_mbox, Tag::GetTemperature)? {
+            Response::Temperature(t) => Ok(t),
+            _ => Err(VideocoreMboxError::UnknownError),
+        }
+    }
+
+    /// The temperature at which the firmware starts throttling, in
+    /// thousandths of a degree Celsius
+    pub fn max_temperature_millicelsius(v_mbox: &mut VideocoreMbox) -> Result<u32> {
+        match single(v_mbox, Tag::GetMaxTemperature)? {
+            Response::Temperature(t) => Ok(t),
+            _ => Err(VideocoreMboxError::UnknownError),
+        }
+    }
+
+    pub fn throttle_status(v_mbox: &mut VideocoreMbox) -> Result<ThrottleFlags> {
+        match single(v_mbox, Tag::GetThrottled)? {
+            Response::Throttled(flags) => Ok(ThrottleFlags(flags)),
+            _ => Err(VideocoreMboxError::UnknownError),
+        }
+    }
+}
+
+/// Copy the command line, the contents of cmdline.txt plus the options that the
+/// firmware adds, to `buf`.
+///
+/// The tag has a variable length. Whatever does not fit into the mailbox
+/// buffer or `buf` is cut off, and then so is the argument that the cut went
+/// through, so that no half `key=value` pair is returned.
+pub fn command_line<'b>(v_mbox: &mut VideocoreMbox, buf: &'b mut [u8]) -> Result<&'b str> {
+    // Message header, tag header and end tag take six words
+    let value_words = v_mbox.buffer.len() - 6;
+
+    let msg = &mut *v_mbox.buffer;
+    msg[0] = (msg.len() * 4) as u32;
+    msg[1] = REQUEST;
+    msg[2] = tag::GETCMDLINE;
+    msg[3] = (value_words * 4) as u32;
+    msg[4] = tag_code::REQUEST;
+    for v in msg[5..5 + value_words].iter_mut() {
+        *v = 0;
+    }
+    msg[5 + value_words] = tag::LAST;
+
+    compiler_fence(Ordering::Release);
+    v_mbox.call(channel::PROP)?;
+
+    let msg = &*v_mbox.buffer;
+    let code = msg[4];
+    if msg[2] != tag::GETCMDLINE || code & tag_code::RESPONSE == 0 {
+        return Err(VideocoreMboxError::TagRejected(tag::GETCMDLINE));
+    }
+
+    // The response length is that of the whole command line, even if the
+    // firmware wrote only part of it.
+    let full_len = (code & !tag_code::RESPONSE) as usize;
+    let len = full_len.min(value_words * 4).min(buf.len());
+
+    // The string is packed into the little endian words of the value buffer
+    for (i, b) in buf[..len].iter_mut().enumerate() {
+        *b = (msg[5 + i / 4] >> (8 * (i % 4))) as u8;
+    }
+
+    let mut line = &buf[..len];
+    if let Some(nul) = line.iter().position(|&b| b == 0) {
+        line = &line[..nul];
+    } else if len < full_len {
+        let cut = line.iter().rposition(|b| b.is_ascii_whitespace()).unwrap_or(0);
+        line = &line[..cut];
+    }
+
+    match core::str::from_utf8(line) {
+        Ok(s) => Ok(s.trim_end()),
+        Err(e) => Ok(core::str::from_utf8(&line[..e.valid_up_to()]).unwrap_or("")),
+    }
+}
+
+pub mod edid {
+    use super::{channel, tag, tag_code, VideocoreMbox, VideocoreMboxError, REQUEST};
+    use core::{
+        fmt,
+        sync::atomic::{compiler_fence, Ordering},
+    };
+
+    pub const BLOCK_LEN: usize = 128;
+
+    /// What a display without EDID, e.g. on QEMU, gets
+    pub const FALLBACK_MODE: Mode = Mode {
+        width: 1024,
+        height: 768,
+    };
+
+    const HEADER: [u8; 8] = [0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00];
+
+    /// Offset of the first detailed timing descriptor, the preferred mode
+    const PREFERRED_TIMING: usize = 54;
+
+    #[derive(Debug)]
+    pub enum EdidError {
+        Mailbox(VideocoreMboxError),
+        /// The firmware has no EDID, e.g. because no display is attached
+        NoEdid,
+        /// The bytes of block `n` do not sum up to zero
+        BadChecksum(u32),
+        /// Block 0 does not start with the EDID header
+        BadHeader,
+        /// The first descriptor is not a detailed timing
+        NoPreferredTiming,
+    }
+
+    pub type Result<T> = ::core::result::Result<T, EdidError>;
+
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub struct Mode {
+        pub width: u32,
+        pub height: u32,
+    }
+
+    impl fmt::Display for Mode {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "{}x{}", self.width, self.height)
+        }
+    }
+
+    /// Where `display_mode()` got the mode from
+    #[derive(Debug)]
+    pub enum Source {
+        Edid,
+        /// Why the EDID could not be used
+        Fallback(EdidError),
+    }
+
+    /// Read EDID block `n` of the attached display, with a verified checksum.
+    pub fn edid_block(v_mbox: &mut VideocoreMbox, n: u32) -> Result<[u8; BLOCK_LEN]> {
+        // Block number and status, then the block
+        const VALUE_WORDS: usize = 2 + BLOCK_LEN / 4;
+
+        let msg = &mut *v_mbox.buffer;
+        msg[0] = ((6 + VALUE_WORDS) * 4) as u32;
+        msg[1] = REQUEST;
+        msg[2] = tag::GETEDIDBLOCK;
+        msg[3] = (VALUE_WORDS * 4) as u32;
+        msg[4] = tag_code::REQUEST;
+        msg[5] = n;
+        for v in msg[6..5 + VALUE_WORDS].iter_mut() {
+            *v = 0;
+        }
+        msg[5 + VALUE_WORDS] = tag::LAST;
+
+        compiler_fence(Ordering::Release);
+        v_mbox.call(channel::PROP).map_err(EdidError::Mailbox)?;
+
+        let msg = &*v_mbox.buffer;
+        if msg[2] != tag::GETEDIDBLOCK || msg[4] & tag_code::RESPONSE == 0 {
+            return Err(EdidError::Mailbox(VideocoreMboxError::TagRejected(
+                tag::GETEDIDBLOCK,
+            )));
+        }
+        if msg[6] != 0 {
+            return Err(EdidError::NoEdid);
+        }
+
+        // Packed into the little endian words of the value buffer
+        let mut block = [0u8; BLOCK_LEN];
+        for (i, b) in block.iter_mut().enumerate() {
+            *b = (msg[7 + i / 4] >> (8 * (i % 4))) as u8;
+        }
+
+        if !checksum_ok(&block) {
+            return Err(EdidError::BadChecksum(n));
+        }
+
+        Ok(block)
+    }
+
+    /// Whether all bytes of `block`, including its last, sum up to zero.
+    pub fn checksum_ok(block: &[u8; BLOCK_LEN]) -> bool {
+        block.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+    }
+
+    /// The resolution of the preferred timing in EDID block 0.
+    pub fn preferred_mode(block: &[u8; BLOCK_LEN]) -> Result<Mode> {
+        if block[..8] != HEADER {
+            return Err(EdidError::BadHeader);
+        }
+
+        let d = &block[PREFERRED_TIMING..PREFERRED_TIMING + 18];
+
+        // Other descriptors have a pixel clock of zero
+        if d[0] == 0 && d[1] == 0 {
+            return Err(EdidError::NoPreferredTiming);
+        }
+
+        // The upper nibbles of bytes 4 and 7 hold bits 8 to 11
+        Ok(Mode {
+            width: u32::from(d[2]) | u32::from(d[4] >> 4) << 8,
+            height: u32::from(d[5]) | u32::from(d[7] >> 4) << 8,
+        })
+    }
 
-            // is it a response to our message?
-            if ((resp & 0xF) == channel) && ((resp & !0xF) == buf_ptr) {
-                // is it a valid successful response?
-                return match self.buffer[1] {
-                    response::SUCCESS => Ok(()),
-                    response::ERROR => Err(VideocoreMboxError::ResponseError),
-                    _ => Err(VideocoreMboxError::UnknownError),
-                };
-            }
+    /// The preferred mode of the display, or `FALLBACK_MODE` without a
+    /// usable EDID.
+    pub fn display_mode(v_mbox: &mut VideocoreMbox) -> (Mode, Source) {
+        match edid_block(v_mbox, 0).and_then(|b| preferred_mode(&b)) {
+            Ok(mode) => (mode, Source::Edid),
+            Err(e) => (FALLBACK_MODE, Source::Fallback(e)),
         }
     }
 }
@@ -0,0 +1,114 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use crate::{cpu, static_assert_offset, static_assert_size};
+use core::ops;
+use raspi3_hal::mmio::{self, Peripheral};
+use register::{mmio::ReadWrite, register_bitfields};
+
+/*
+ *
+ * The watchdog of the power management block, used to reset the board
+ *
+ */
+register_bitfields! {
+    u32,
+
+    /// Reset Control
+    RSTC [
+        /// Writes are ignored without it
+        PASSWD OFFSET(24) NUMBITS(8) [
+            Password = 0x5A
+        ],
+
+        /// What happens when the watchdog expires
+        WRCFG OFFSET(4) NUMBITS(2) [
+            Clear = 0b00,
+            FullReset = 0b10
+        ]
+    ],
+
+    /// Watchdog
+    WDOG [
+        PASSWD OFFSET(24) NUMBITS(8) [
+            Password = 0x5A
+        ],
+
+        /// Ticks of about 16 us before the watchdog expires
+        TIME OFFSET(0) NUMBITS(20) []
+    ]
+}
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct RegisterBlock {
+    __reserved_0: [u32; 7],               // 0x00
+    RSTC: ReadWrite<u32, RSTC::Register>, // 0x1C
+    __reserved_1: u32,                    // 0x20, RSTS
+    WDOG: ReadWrite<u32, WDOG::Register>, // 0x24
+}
+
+static_assert_offset!(RegisterBlock, __reserved_0, 0x00);
+static_assert_offset!(RegisterBlock, RSTC, 0x1C);
+static_assert_offset!(RegisterBlock, __reserved_1, 0x20);
+static_assert_offset!(RegisterBlock, WDOG, 0x24);
+static_assert_size!(RegisterBlock, 0x28);
+
+/// How many ticks `reset()` leaves for outstanding writes, e.g. to the UART
+const RESET_TICKS: u32 = 10;
+
+/// Public interface to the watchdog
+pub struct Watchdog {
+    peripheral: Peripheral,
+}
+
+impl ops::Deref for Watchdog {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl Watchdog {
+    pub fn new(peripheral: Peripheral) -> Watchdog {
+        Watchdog { peripheral }
+    }
+
+    /// Returns a pointer to the register block
+    fn ptr(&self) -> *const RegisterBlock {
+        mmio::base(self.peripheral) as *const _
+    }
+
+    /// Reset the whole board, by letting the watchdog expire right away.
+    ///
+    /// The firmware boots from the partition in RSTS, which is left alone, so
+    /// the board comes up from the same one as this time.
+    pub fn reset(&self) -> ! {
+        self.WDOG.write(WDOG::PASSWD::Password + WDOG::TIME.val(RESET_TICKS));
+        self.RSTC.modify(RSTC::PASSWD::Password + RSTC::WRCFG::FullReset);
+
+        cpu::wait_forever()
+    }
+}
@@ -22,6 +22,6 @@
  * SOFTWARE.
  */
 
-mod console;
+pub mod console;
 
-pub use console::{Console, ConsoleOps};
+pub use console::{Console, ConsoleOps, Output};
@@ -22,27 +22,209 @@
  * SOFTWARE.
  */
 
-use crate::devices::hw;
-use core::fmt;
+use crate::{cpu, devices::hw};
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
 
 /// A trait that must be implemented by devices that are candidates for the
 /// global console.
+///
+/// `fmt::Write` is required as well, so that any console device can be used
+/// with `write!` directly.
 #[allow(unused_variables)]
-pub trait ConsoleOps: Drop {
+pub trait ConsoleOps: Drop + fmt::Write {
     fn putc(&self, c: char) {}
     fn puts(&self, string: &str) {}
     fn getc(&self) -> char {
         ' '
     }
+
+    /// Receive a character if one is available, without waiting.
+    fn try_getc(&self) -> Option<char> {
+        None
+    }
     fn flush(&self) {}
+
+    /// Whether the device can take output right now. Registered sinks that
+    /// are not ready are skipped.
+    fn is_ready(&self) -> bool {
+        true
+    }
+
+    /// Display a binary value in hexadecimal
+    fn hex(&self, d: u32) {
+        let mut n;
+
+        for i in 0..8 {
+            // get highest tetrad
+            n = d.wrapping_shr(28 - i * 4) & 0xF;
+
+            // 0-9 => '0'-'9', 10-15 => 'A'-'F'
+            // Add proper offset for ASCII table
+            if n > 9 {
+                n += 0x37;
+            } else {
+                n += 0x30;
+            }
+
+            self.putc(n as u8 as char);
+        }
+    }
+
+    /// Dump `len` bytes of memory starting at `addr`, 16 bytes per line
+    ///
+    /// ```text
+    /// 00080000: 52 42 49 4E  36 34 0D 0A  00 00 00 00  FF FF FF FF  |RBIN64..........|
+    /// ```
+    /// Lines show the address, the bytes in groups of four and an ASCII column
+    /// with non-printable bytes as dots. Memory is read by aligned 32 bit
+    /// volatile loads, so MMIO regions can be dumped as well. `addr` is rounded
+    /// down and `len` up to whole lines, and `len` is capped at
+    /// `DUMP_MAX_LEN`.
+    fn dump(&self, addr: usize, len: usize) {
+        const LINE: usize = 16;
+
+        let start = addr & !(LINE - 1);
+        let end = addr.saturating_add(len.min(DUMP_MAX_LEN));
+
+        let mut line = start;
+        while line < end {
+            let mut bytes = [0u8; LINE];
+            for (i, word) in bytes.chunks_mut(4).enumerate() {
+                let value = unsafe { core::ptr::read_volatile((line + i * 4) as *const u32) };
+                word.copy_from_slice(&value.to_le_bytes());
+            }
+
+            put_hex(self, line as u64, 8);
+            self.puts(": ");
+
+            for (i, byte) in bytes.iter().enumerate() {
+                put_hex(self, u64::from(*byte), 2);
+                self.putc(' ');
+
+                if i % 4 == 3 {
+                    self.putc(' ');
+                }
+            }
+
+            self.putc('|');
+            for &byte in bytes.iter() {
+                match byte {
+                    0x20..=0x7E => self.putc(byte as char),
+                    _ => self.putc('.'),
+                }
+            }
+            self.puts("|\n");
+
+            line += LINE;
+        }
+
+        if len > DUMP_MAX_LEN {
+            self.puts("[...] truncated\n");
+        }
+    }
+
+    /// Read a line of input into `buf`, with basic line editing.
+    ///
+    /// Printable characters are echoed. Backspace and DEL erase the last
+    /// character, Ctrl-U erases the whole line. The line ends on CR or LF,
+    /// which is not part of the returned string. Once `buf` is full, further
+    /// characters are dropped and the bell is rung instead.
+    fn getline<'a>(&self, buf: &'a mut [u8]) -> &'a str {
+        let mut len = 0;
+
+        // getc() already turns CR into LF
+        while !edit_line(self, buf, &mut len, self.getc()) {}
+
+        line_str(buf, len)
+    }
+}
+
+/// One character of the line editing of `ConsoleOps::getline()`, for readers
+/// that get their characters elsewhere, like `async` code. `len` is the length
+/// of the line so far. Returns true once the line is complete.
+pub fn edit_line<C>(con: &C, buf: &mut [u8], len: &mut usize, c: char) -> bool
+where
+    C: ConsoleOps + ?Sized,
+{
+    const BACKSPACE: char = '\x08';
+    const BELL: char = '\x07';
+    const CTRL_U: char = '\x15';
+    const DEL: char = '\x7F';
+
+    match c {
+        '\n' => return true,
+
+        BACKSPACE | DEL => {
+            if *len > 0 {
+                *len -= 1;
+                con.puts("\x08 \x08");
+            }
+        }
+
+        CTRL_U => {
+            while *len > 0 {
+                *len -= 1;
+                con.puts("\x08 \x08");
+            }
+        }
+
+        c @ ' '..='~' => {
+            if *len < buf.len() {
+                buf[*len] = c as u8;
+                *len += 1;
+                con.putc(c);
+            } else {
+                con.putc(BELL);
+            }
+        }
+
+        _ => (),
+    }
+
+    false
+}
+
+/// The line that `edit_line()` put into `buf`.
+pub fn line_str(buf: &[u8], len: usize) -> &str {
+    // Only printable ASCII made it into the buffer
+    core::str::from_utf8(&buf[..len]).unwrap_or("")
+}
+
+/// Upper limit for `ConsoleOps::dump()`, so that a typo does not flood the
+/// console for minutes.
+pub const DUMP_MAX_LEN: usize = 4096;
+
+/// Display the lowest `digits` hex digits of `value`.
+fn put_hex<C: ConsoleOps + ?Sized>(c: &C, value: u64, digits: usize) {
+    for i in (0..digits).rev() {
+        let n = ((value >> (i * 4)) & 0xF) as u8;
+
+        c.putc(match n {
+            0..=9 => (b'0' + n) as char,
+            _ => (b'A' + n - 10) as char,
+        });
+    }
 }
 
 /// A dummy console that just ignores its inputs.
+///
+/// Stored in the global console until a real device is brought up, so that
+/// early output is silently discarded.
 pub struct NullConsole;
 impl Drop for NullConsole {
     fn drop(&mut self) {}
 }
 impl ConsoleOps for NullConsole {}
+impl fmt::Write for NullConsole {
+    fn write_str(&mut self, _s: &str) -> fmt::Result {
+        Ok(())
+    }
+}
 
 /// Possible outputs which the console can store.
 pub enum Output {
@@ -63,14 +245,130 @@ impl From<hw::PL011Uart> for Output {
     }
 }
 
+/// How many additional outputs can be registered with the console.
+pub const MAX_SINKS: usize = 4;
+
+#[derive(Debug)]
+pub enum ConsoleError {
+    TooManySinks,
+}
+
+/// Register an additional output with the global console.
+///
+/// From then on, everything that is printed is mirrored to `sink`, including
+/// panic messages. IRQs are masked while the console is modified, so that an
+/// IRQ handler that prints never sees it half-updated.
+pub fn register(sink: &'static dyn ConsoleOps) -> Result<(), ConsoleError> {
+    crate::CONSOLE.lock_irqsave(|c| c.add_sink(sink))
+}
+
+/// The global console, taking its lock for every single call.
+///
+/// For code that runs for a long time on the console, like the shell. Holding
+/// the lock while waiting for input would keep every other core from
+/// printing.
+pub struct GlobalConsole;
+
+impl Drop for GlobalConsole {
+    fn drop(&mut self) {}
+}
+
+impl ConsoleOps for GlobalConsole {
+    fn putc(&self, c: char) {
+        crate::CONSOLE.lock(|con| con.putc(c));
+    }
+
+    fn puts(&self, string: &str) {
+        crate::CONSOLE.lock(|con| con.puts(string));
+    }
+
+    /// Poll for a character. If the RX IRQ can wake us up, the core sleeps in
+    /// `wfe` in between.
+    fn getc(&self) -> char {
+        loop {
+            let (c, rx_irq) = crate::CONSOLE.lock(|con| (con.try_getc(), con.rx_irq_enabled()));
+            if let Some(c) = c {
+                return c;
+            }
+
+            if rx_irq && !cpu::local_irq_masked() {
+                crate::task::idle();
+            }
+        }
+    }
+
+    fn try_getc(&self) -> Option<char> {
+        crate::CONSOLE.lock(|con| con.try_getc())
+    }
+
+    fn flush(&self) {
+        crate::CONSOLE.lock(|con| con.flush());
+    }
+}
+
+impl GlobalConsole {
+    /// Receive a character in `async` code, see `Console::poll_getc()`.
+    pub fn read_char(&self) -> ReadChar {
+        ReadChar
+    }
+}
+
+/// The future of `GlobalConsole::read_char()`.
+pub struct ReadChar;
+
+impl Future for ReadChar {
+    type Output = char;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<char> {
+        crate::CONSOLE.lock(|con| con.poll_getc(cx))
+    }
+}
+
+impl fmt::Write for GlobalConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.puts(s);
+
+        Ok(())
+    }
+}
+
 pub struct Console {
     output: Output,
+    sinks: [Option<&'static dyn ConsoleOps>; MAX_SINKS],
 }
 
 impl Console {
     pub const fn new() -> Console {
         Console {
             output: Output::None(NullConsole {}),
+            sinks: [None; MAX_SINKS],
+        }
+    }
+
+    /// Call `f` for the current output and every registered sink that is
+    /// ready.
+    fn for_each_output<F>(&self, f: F)
+    where
+        F: Fn(&dyn ConsoleOps),
+    {
+        f(self.current_ptr());
+
+        for sink in self.sinks.iter().filter_map(|s| *s) {
+            if sink.is_ready() {
+                f(sink);
+            }
+        }
+    }
+
+    /// Mirror all output to `sink` as well. Input is only ever taken from the
+    /// current output.
+    pub fn add_sink(&mut self, sink: &'static dyn ConsoleOps) -> Result<(), ConsoleError> {
+        match self.sinks.iter_mut().find(|s| s.is_none()) {
+            Some(slot) => {
+                *slot = Some(sink);
+                Ok(())
+            }
+            None => Err(ConsoleError::TooManySinks),
         }
     }
 
@@ -83,6 +381,34 @@ impl Console {
         }
     }
 
+    /// Whether the current output receives by IRQ, which notifies waiters.
+    fn rx_irq_enabled(&self) -> bool {
+        match &self.output {
+            Output::PL011Uart(i) => i.rx_irq_enabled(),
+            _ => false,
+        }
+    }
+
+    /// The poll of an `async` `getc()`. The PL011 UART wakes the task once a
+    /// character arrived, the other outputs are polled over and over again.
+    pub fn poll_getc(&self, cx: &mut Context) -> Poll<char> {
+        match &self.output {
+            Output::PL011Uart(i) => i.poll_getc(cx),
+            _ => match self.try_getc() {
+                Some(c) => Poll::Ready(c),
+                None => {
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                }
+            },
+        }
+    }
+
+    /// The current output.
+    pub fn output(&self) -> &Output {
+        &self.output
+    }
+
     /// Overwrite the current output. The old output will go out of scope and
     /// it's Drop function will be called.
     pub fn replace_with(&mut self, x: Output) {
@@ -90,22 +416,6 @@ impl Console {
 
         self.output = x;
     }
-
-    /// A command prompt. Currently does nothing.
-    pub fn command_prompt(&self) -> ! {
-        self.puts("\n$> ");
-
-        let mut input;
-        loop {
-            input = self.getc();
-
-            if input == '\n' {
-                self.puts("\n$> ")
-            } else {
-                self.putc(input);
-            }
-        }
-    }
 }
 
 impl Drop for Console {
@@ -113,21 +423,27 @@ impl Drop for Console {
 }
 
 /// Dispatch the respective function to the currently stored output device.
+///
+/// Output goes to the registered sinks as well.
 impl ConsoleOps for Console {
     fn putc(&self, c: char) {
-        self.current_ptr().putc(c);
+        self.for_each_output(|o| o.putc(c));
     }
 
     fn puts(&self, string: &str) {
-        self.current_ptr().puts(string);
+        self.for_each_output(|o| o.puts(string));
     }
 
     fn getc(&self) -> char {
         self.current_ptr().getc()
     }
 
+    fn try_getc(&self) -> Option<char> {
+        self.current_ptr().try_getc()
+    }
+
     fn flush(&self) {
-        self.current_ptr().flush()
+        self.for_each_output(|o| o.flush());
     }
 }
 
@@ -137,7 +453,7 @@ impl ConsoleOps for Console {
 /// See src/macros.rs.
 impl fmt::Write for Console {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        self.current_ptr().puts(s);
+        self.puts(s);
 
         Ok(())
     }
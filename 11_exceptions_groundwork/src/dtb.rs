@@ -0,0 +1,355 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! A minimal reader for the flattened device tree that the firmware passes.
+//!
+//! Only what the kernel needs is extracted: the `reg` entries of `/memory`,
+//! and `bootargs` and `stdout-path` of `/chosen`. The blob is used in place,
+//! so it must not be overwritten while the kernel runs.
+//!
+//! Firmwares that pass no device tree, or a broken one, are common, so
+//! everything here returns an `Option` and the kernel falls back to the
+//! mailbox.
+
+use crate::{devices::hw::videocore_mbox::MemoryRegion, memory};
+use core::str;
+
+const FDT_MAGIC: u32 = 0xD00D_FEED;
+const HEADER_SIZE: usize = 40;
+
+/// Oldest version of the format that has all header fields used here
+const MIN_VERSION: u32 = 17;
+
+/// Anything bigger is more likely garbage than a device tree
+const MAX_TOTAL_SIZE: usize = 1 << 20;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+/// Defaults of the root node's `#address-cells` and `#size-cells`
+const DEFAULT_ADDRESS_CELLS: u32 = 2;
+const DEFAULT_SIZE_CELLS: u32 = 1;
+
+#[derive(Debug)]
+pub enum DtbError {
+    /// The firmware passed no device tree.
+    NotPassed,
+    /// The blob is misaligned or not in RAM.
+    BadAddress,
+    BadMagic,
+    /// The header's sizes and offsets do not fit together.
+    BadHeader,
+    /// The structure block is cut short or contains unknown tokens.
+    BadStructure,
+}
+pub type Result<T> = ::core::result::Result<T, DtbError>;
+
+fn be32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let b = bytes.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+/// A nul terminated string at the start of `bytes`.
+fn c_str(bytes: &[u8]) -> Option<&str> {
+    let len = bytes.iter().position(|&b| b == 0)?;
+    str::from_utf8(&bytes[..len]).ok()
+}
+
+/// One token of the structure block
+enum Token<'a> {
+    BeginNode(&'a str),
+    EndNode,
+    Prop(&'a str, &'a [u8]),
+}
+
+/// Iterates over the structure block. Stops at `FDT_END`, and at anything it
+/// does not understand.
+struct Tokens {
+    structs: &'static [u8],
+    strings: &'static [u8],
+    offset: usize,
+}
+
+impl Iterator for Tokens {
+    type Item = Token<'static>;
+
+    fn next(&mut self) -> Option<Token<'static>> {
+        loop {
+            let token = be32(self.structs, self.offset)?;
+            self.offset += 4;
+
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = c_str(self.structs.get(self.offset..)?)?;
+                    self.offset = align4(self.offset + name.len() + 1);
+
+                    return Some(Token::BeginNode(name));
+                }
+
+                FDT_END_NODE => return Some(Token::EndNode),
+
+                FDT_PROP => {
+                    let len = be32(self.structs, self.offset)? as usize;
+                    let name_offset = be32(self.structs, self.offset + 4)? as usize;
+                    let start = self.offset + 8;
+
+                    let value = self.structs.get(start..start + len)?;
+                    let name = c_str(self.strings.get(name_offset..)?)?;
+                    self.offset = align4(start + len);
+
+                    return Some(Token::Prop(name, value));
+                }
+
+                FDT_NOP => continue,
+
+                _ => return None,
+            }
+        }
+    }
+}
+
+/// Whether the node `name` is meant by the path component `component`. The
+/// unit address may be left out, so "memory" matches "memory@0".
+fn node_matches(name: &str, component: &str) -> bool {
+    name == component
+        || (name.starts_with(component) && name.as_bytes().get(component.len()) == Some(&b'@'))
+}
+
+/// A validated device tree blob
+pub struct Fdt {
+    structs: &'static [u8],
+    strings: &'static [u8],
+}
+
+impl Fdt {
+    /// Validate the blob at the physical address `phys_addr`.
+    ///
+    /// # Safety
+    ///
+    /// Reads up to `MAX_TOTAL_SIZE` bytes at the address, which must be mapped
+    /// if it is in RAM.
+    pub unsafe fn from_phys(phys_addr: usize) -> Result<Fdt> {
+        let ram_end = memory::mmio_base::base();
+        if phys_addr % 8 != 0 || phys_addr >= ram_end - HEADER_SIZE {
+            return Err(DtbError::BadAddress);
+        }
+
+        let virt_addr = if memory::mmu::mmu_enabled() {
+            memory::phys_to_virt(phys_addr)
+        } else {
+            phys_addr
+        };
+        let header = core::slice::from_raw_parts(virt_addr as *const u8, HEADER_SIZE);
+
+        let field = |n: usize| be32(header, 4 * n).unwrap() as usize;
+        if field(0) as u32 != FDT_MAGIC {
+            return Err(DtbError::BadMagic);
+        }
+
+        let total_size = field(1);
+        let (struct_offset, strings_offset) = (field(2), field(3));
+        let (version, last_comp_version) = (field(5) as u32, field(6) as u32);
+        let (strings_size, struct_size) = (field(8), field(9));
+
+        let fits = |offset: usize, size: usize| {
+            offset >= HEADER_SIZE && offset.checked_add(size).map_or(false, |end| end <= total_size)
+        };
+        if version < MIN_VERSION
+            || last_comp_version > MIN_VERSION
+            || total_size > MAX_TOTAL_SIZE
+            || total_size > ram_end - phys_addr
+            || !fits(struct_offset, struct_size)
+            || !fits(strings_offset, strings_size)
+        {
+            return Err(DtbError::BadHeader);
+        }
+
+        let blob = core::slice::from_raw_parts(virt_addr as *const u8, total_size);
+        let fdt = Fdt {
+            structs: &blob[struct_offset..struct_offset + struct_size],
+            strings: &blob[strings_offset..strings_offset + strings_size],
+        };
+        fdt.validate_structure()?;
+
+        Ok(fdt)
+    }
+
+    /// Walk the whole structure block once, so that later lookups can not
+    /// silently stop half way.
+    fn validate_structure(&self) -> Result<()> {
+        let mut tokens = self.tokens();
+        let mut depth = 0usize;
+
+        for token in &mut tokens {
+            match token {
+                Token::BeginNode(_) => depth += 1,
+                Token::EndNode if depth > 0 => depth -= 1,
+                Token::EndNode => return Err(DtbError::BadStructure),
+                Token::Prop(..) if depth > 0 => (),
+                Token::Prop(..) => return Err(DtbError::BadStructure),
+            }
+        }
+
+        // The iterator stops on FDT_END as well as on garbage
+        let last = tokens.offset.checked_sub(4).and_then(|o| be32(self.structs, o));
+        if depth != 0 || last != Some(FDT_END) {
+            return Err(DtbError::BadStructure);
+        }
+
+        Ok(())
+    }
+
+    fn tokens(&self) -> Tokens {
+        Tokens {
+            structs: self.structs,
+            strings: self.strings,
+            offset: 0,
+        }
+    }
+
+    /// The value of property `name` of the first node at `path`, e.g.
+    /// `&["chosen"]`. An empty path is the root node.
+    pub fn property(&self, path: &[&str], name: &str) -> Option<&'static [u8]> {
+        // Nesting depth, with the root node at 1, and the number of path
+        // components that the current node and its parents matched.
+        let mut depth = 0;
+        let mut matched = 0;
+
+        for token in self.tokens() {
+            match token {
+                Token::BeginNode(node) => {
+                    if depth > 0
+                        && depth == matched + 1
+                        && matched < path.len()
+                        && node_matches(node, path[matched])
+                    {
+                        matched += 1;
+                    }
+                    depth += 1;
+                }
+
+                Token::EndNode => {
+                    depth -= 1;
+                    matched = matched.min(depth.saturating_sub(1));
+                }
+
+                Token::Prop(prop, value) => {
+                    if depth == matched + 1 && matched == path.len() && prop == name {
+                        return Some(value);
+                    }
+                }
+            }
+        }
+
+        None
+    }
+
+    /// A string property, without its nul terminator
+    pub fn str_property(&self, path: &[&str], name: &str) -> Option<&'static str> {
+        c_str(self.property(path, name)?)
+    }
+
+    fn root_cells(&self, name: &str, default: u32) -> u32 {
+        self.property(&[], name)
+            .and_then(|v| be32(v, 0))
+            .unwrap_or(default)
+    }
+
+    /// The `reg` entries of `/memory`
+    pub fn memory_regions(&self) -> MemoryRegions {
+        MemoryRegions {
+            reg: self.property(&["memory"], "reg").unwrap_or(&[]),
+            address_cells: self.root_cells("#address-cells", DEFAULT_ADDRESS_CELLS),
+            size_cells: self.root_cells("#size-cells", DEFAULT_SIZE_CELLS),
+            offset: 0,
+        }
+    }
+}
+
+/// Iterator over the `reg` entries of `/memory`
+pub struct MemoryRegions {
+    reg: &'static [u8],
+    address_cells: u32,
+    size_cells: u32,
+    offset: usize,
+}
+
+impl MemoryRegions {
+    /// Read a number of `cells` 32-bit big endian cells.
+    fn read(&mut self, cells: u32) -> Option<usize> {
+        let mut value = 0usize;
+
+        for _ in 0..cells {
+            value = (value << 32) | be32(self.reg, self.offset)? as usize;
+            self.offset += 4;
+        }
+
+        Some(value)
+    }
+}
+
+impl Iterator for MemoryRegions {
+    type Item = MemoryRegion;
+
+    fn next(&mut self) -> Option<MemoryRegion> {
+        // More than two cells do not fit a usize
+        if self.address_cells > 2 || self.size_cells > 2 {
+            return None;
+        }
+
+        let base = self.read(self.address_cells)?;
+        let size = self.read(self.size_cells)?;
+
+        Some(MemoryRegion { base, size })
+    }
+}
+
+/// The device tree that the firmware passed, if there is a valid one.
+pub fn fdt() -> Result<Fdt> {
+    let addr = raspi3_boot::boot_dtb().ok_or(DtbError::NotPassed)?;
+
+    unsafe { Fdt::from_phys(addr) }
+}
+
+/// The RAM as described by the device tree. None without a valid one.
+pub fn memory_regions() -> Option<MemoryRegions> {
+    fdt().ok().map(|fdt| fdt.memory_regions())
+}
+
+/// `bootargs` of `/chosen`, which the firmware fills from cmdline.txt.
+pub fn bootargs() -> Option<&'static str> {
+    fdt().ok()?.str_property(&["chosen"], "bootargs")
+}
+
+/// `stdout-path` of `/chosen`, the console that the firmware suggests.
+pub fn stdout_path() -> Option<&'static str> {
+    fdt().ok()?.str_property(&["chosen"], "stdout-path")
+}
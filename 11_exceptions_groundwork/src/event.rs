@@ -0,0 +1,57 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Sleeping in `wfe` until a condition holds.
+//!
+//! The side that makes the condition true calls `notify()`, which sends an
+//! event with `sev`. Events are latched in the core's event register, so a
+//! `notify()` that happens between checking the condition and executing `wfe`
+//! is not lost: the `wfe` returns right away and the condition is checked
+//! again.
+
+use crate::cpu;
+use cortex_a::{asm, barrier};
+
+/// Sleep in `wfe` until `cond` returns true.
+///
+/// If the notifier is an IRQ handler, IRQs must be unmasked while waiting, or
+/// the handler never gets to run.
+pub fn wait_for<F>(mut cond: F)
+where
+    F: FnMut() -> bool,
+{
+    while !cond() {
+        asm::wfe();
+    }
+}
+
+/// Wake up all cores that sleep in `wait_for()`.
+///
+/// Call it after the stores that make the condition true. The `dsb` makes
+/// sure that these stores are visible before the event, so that a woken core
+/// does not read the old values and go back to sleep.
+pub fn notify() {
+    unsafe { barrier::dsb(barrier::SY) };
+    cpu::sev();
+}
@@ -22,62 +22,441 @@
  * SOFTWARE.
  */
 
-use crate::println;
+use crate::{
+    backtrace, cpu, debug, gdbstub, interrupt, memory, println, smp, stack_guard, sync,
+    syscall::{self, Caller},
+    user,
+};
+use core::{
+    cell::Cell,
+    fmt,
+    ops::RangeInclusive,
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+};
 use cortex_a::{barrier, regs::*};
 
 global_asm!(include_str!("vectors.S"));
 
+/// Set once VBAR_EL1 points to our exception vectors.
+static VECTORS_INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// Addresses that the code run by `expect_fault()` may fault on.
+static EXPECTED_FAULT: sync::NullLock<Option<RangeInclusive<usize>>> = sync::NullLock::new(None);
+
+/// Set by the handler if the expected fault happened.
+static EXPECTED_FAULT_HIT: AtomicBool = AtomicBool::new(false);
+
+/// How many exceptions each core is in the middle of handling, more than one
+/// if they nest.
+static DEPTH: smp::PerCore<Cell<usize>> =
+    smp::PerCore::new([Cell::new(0), Cell::new(0), Cell::new(0), Cell::new(0)]);
+
+/// Counts a handler in `DEPTH` for as long as it lives.
+struct Nesting;
+
+impl Nesting {
+    fn enter() -> Nesting {
+        DEPTH.with(|depth| depth.set(depth.get() + 1));
+
+        Nesting
+    }
+}
+
+impl Drop for Nesting {
+    fn drop(&mut self) {
+        DEPTH.with(|depth| depth.set(depth.get() - 1));
+    }
+}
+
+/// The number of exceptions that the executing core is handling right now.
+pub fn depth() -> usize {
+    DEPTH.with(|depth| depth.get())
+}
+
+/// Size of the frame that `SAVE_CONTEXT_CALL_HANDLER_AND_RESTORE` pushes.
+const FRAME_SIZE: usize = 16 * 17;
+
+/// Lives in the lowest word of the exception stack. If it changed, a handler
+/// overflowed the stack.
+const STACK_CANARY: u64 = 0xDEAD_BEEF_CAFE_F00D;
+
+// The exception stack, which SP_EL1 points to. Reserved in the linker script.
+extern "C" {
+    static mut __exception_stack_start: u64;
+    static __exception_stack_end: u64;
+}
+
+/// Called from `__restore_context` before each return from an exception.
+#[no_mangle]
+unsafe extern "C" fn check_exception_stack_canary() {
+    if ptr::read_volatile(&__exception_stack_start) != STACK_CANARY {
+        eprintln!("[!] Exception stack overflow. Halting CPU.");
+
+        cpu::wait_forever();
+    }
+}
+
+/// The context of the outermost exception.
+///
+/// Exceptions from kernel code are taken with an empty exception stack, so its
+/// frame lies right at the top.
+unsafe fn outermost_context() -> &'static ExceptionContext {
+    let top = &__exception_stack_end as *const _ as usize;
+
+    &*((top - FRAME_SIZE) as *const ExceptionContext)
+}
+
+/// Run `f`, which is expected to cause an abort on an address in `range`, e.g.
+/// to verify that the MMU protects it. Returns true if the fault happened.
+///
+/// Instead of being reported, an expected data abort skips the faulting
+/// instruction. This is only safe for single register loads and stores without
+/// writeback, so other instructions fault as usual. A skipped load reads zero.
+///
+/// An expected instruction abort is only recovered from if it happened right
+/// at the target of a call, which then returns to the caller at once.
+pub fn expect_fault<F>(range: RangeInclusive<usize>, f: F) -> bool
+where
+    F: FnOnce(),
+{
+    EXPECTED_FAULT_HIT.store(false, Ordering::Relaxed);
+    EXPECTED_FAULT.lock(|w| *w = Some(range));
+
+    f();
+
+    EXPECTED_FAULT.lock(|w| *w = None);
+    EXPECTED_FAULT_HIT.load(Ordering::Relaxed)
+}
+
+/// Skip the faulting instruction if the abort was expected by `expect_fault()`.
+fn skip_expected_fault(e: &mut ExceptionContext, esr: EsrEL1) -> bool {
+    if !esr.is_abort() || !esr.far_valid() {
+        return false;
+    }
+
+    let far = cpu::regs::FAR_EL1.get() as usize;
+    if !EXPECTED_FAULT.lock(|w| w.as_ref().map_or(false, |r| r.contains(&far))) {
+        return false;
+    }
+
+    if !esr.is_data_abort() {
+        // Faulted on fetching the first instruction of the called code, so
+        // the link register still holds the return address.
+        if e.elr_el1 as usize != far {
+            return false;
+        }
+
+        e.elr_el1 = e.gpr.x[30];
+        EXPECTED_FAULT_HIT.store(true, Ordering::Relaxed);
+
+        return true;
+    }
+
+    let insn = unsafe { ptr::read_volatile(e.elr_el1 as *const u32) };
+
+    // Load/store register with unsigned immediate, register offset, or
+    // unscaled immediate. The pre- and post-indexed variants write back to
+    // the base register, which would be skipped as well.
+    let single_no_writeback = insn & 0x3B00_0000 == 0x3900_0000
+        || insn & 0x3B20_0C00 == 0x3820_0800
+        || insn & 0x3B20_0C00 == 0x3800_0000;
+    if !single_no_writeback {
+        return false;
+    }
+
+    // A general purpose register (V = 0) target of a load (opc != 0)
+    let rt = (insn & 0x1F) as usize;
+    if insn & (1 << 26) == 0 && (insn >> 22) & 0x3 != 0 && rt != 31 {
+        e.gpr.x[rt] = 0;
+    }
+
+    e.elr_el1 += 4;
+    EXPECTED_FAULT_HIT.store(true, Ordering::Relaxed);
+
+    true
+}
+
 pub unsafe fn set_vbar_el1_checked(vec_base_addr: u64) -> bool {
     if vec_base_addr.trailing_zeros() < 11 {
         false
     } else {
+        ptr::write_volatile(&mut __exception_stack_start, STACK_CANARY);
+
         cortex_a::regs::VBAR_EL1.set(vec_base_addr);
 
         // Force VBAR update to complete before next instruction.
         barrier::isb(barrier::SY);
 
+        VECTORS_INSTALLED.store(true, Ordering::Release);
+
         true
     }
 }
 
+/// Returns true if exceptions, and therefore IRQs, can be handled.
+pub fn vectors_installed() -> bool {
+    VECTORS_INSTALLED.load(Ordering::Acquire)
+}
+
 #[repr(C)]
 pub struct GPR {
-    x: [u64; 31],
+    pub x: [u64; 31],
 }
 
+/// The context of the interrupted code, as saved by the vector stubs in
+/// `vectors.S`. Changes are written back to the registers before `eret`.
 #[repr(C)]
 pub struct ExceptionContext {
     // General Purpose Registers
-    gpr: GPR,
-    spsr_el1: u64,
-    elr_el1: u64,
+    pub gpr: GPR,
+    pub spsr_el1: u64,
+    pub elr_el1: u64,
+}
+
+/// Prints all general purpose registers, four per line
+impl fmt::Display for ExceptionContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, x) in self.gpr.x.iter().enumerate() {
+            if i % 4 == 0 {
+                write!(f, "      ")?;
+            }
+
+            write!(f, "x{:<2} {:#018X}", i, x)?;
+
+            if i % 4 == 3 || i == self.gpr.x.len() - 1 {
+                writeln!(f)?;
+            } else {
+                write!(f, "  ")?;
+            }
+        }
+
+        write!(f, "      ELR_EL1: {:#010X}", self.elr_el1)
+    }
+}
+
+/// The Exception Syndrome Register, which says why a synchronous exception or
+/// an SError was taken.
+#[derive(Copy, Clone)]
+pub struct EsrEL1(pub u64);
+
+impl EsrEL1 {
+    /// The syndrome of the exception that is currently handled
+    pub fn read() -> EsrEL1 {
+        EsrEL1(cpu::regs::ESR_EL1.get())
+    }
+
+    /// Exception Class
+    pub fn ec(self) -> u32 {
+        ((self.0 >> 26) & 0x3F) as u32
+    }
+
+    /// Instruction Specific Syndrome
+    pub fn iss(self) -> u32 {
+        (self.0 & 0x1FF_FFFF) as u32
+    }
+
+    /// The exception class as a name
+    pub fn ec_name(self) -> &'static str {
+        match self.ec() {
+            0x00 => "Unknown reason",
+            0x01 => "Trapped WFI/WFE",
+            0x07 => "Trapped SIMD/FP access",
+            0x0E => "Illegal execution state",
+            0x15 => "SVC instruction",
+            0x16 => "HVC instruction",
+            0x17 => "SMC instruction",
+            0x18 => "Trapped MSR/MRS/system instruction",
+            0x20 => "Instruction Abort, lower EL",
+            0x21 => "Instruction Abort, current EL",
+            0x22 => "PC alignment fault",
+            0x24 => "Data Abort, lower EL",
+            0x25 => "Data Abort, current EL",
+            0x26 => "SP alignment fault",
+            0x2C => "Trapped FP exception",
+            0x2F => "SError interrupt",
+            0x30 | 0x31 => "Breakpoint",
+            0x32 | 0x33 => "Software step",
+            0x34 | 0x35 => "Watchpoint",
+            0x3C => "BRK instruction",
+            _ => "Other",
+        }
+    }
+
+    /// Whether the exception was taken because of an `svc` instruction
+    pub fn is_svc(self) -> bool {
+        self.ec() == 0x15
+    }
+
+    /// Whether the exception was taken because of a `brk` instruction
+    pub fn is_brk(self) -> bool {
+        self.ec() == 0x3C
+    }
+
+    /// Whether the exception is a software step in the current EL
+    pub fn is_software_step(self) -> bool {
+        self.ec() == 0x33
+    }
+
+    /// Whether the exception is a watchpoint hit in the current EL
+    pub fn is_watchpoint(self) -> bool {
+        self.ec() == 0x35
+    }
+
+    /// The immediate of the `svc` or `brk` instruction that caused the
+    /// exception
+    pub fn imm16(self) -> u16 {
+        (self.iss() & 0xFFFF) as u16
+    }
+
+    fn is_data_abort(self) -> bool {
+        self.ec() == 0x24 || self.ec() == 0x25
+    }
+
+    /// Whether the exception is an instruction or a data abort, which have a
+    /// fault status
+    pub fn is_abort(self) -> bool {
+        self.is_data_abort() || self.ec() == 0x20 || self.ec() == 0x21
+    }
+
+    /// Whether FAR_EL1 holds the faulting address
+    pub fn far_valid(self) -> bool {
+        const FNV: u32 = 1 << 10;
+
+        match self.ec() {
+            0x20 | 0x21 | 0x24 | 0x25 => self.iss() & FNV == 0,
+            0x22 | 0x34 | 0x35 => true,
+            _ => false,
+        }
+    }
+
+    /// The Data or Instruction Fault Status Code of an abort, decoded
+    pub fn fault_status(self) -> (&'static str, Option<u32>) {
+        let fsc = self.iss() & 0x3F;
+        let level = Some(fsc & 0x3);
+
+        match fsc >> 2 {
+            0b0000 => ("Address size fault", level),
+            0b0001 => ("Translation fault", level),
+            0b0010 => ("Access flag fault", level),
+            0b0011 => ("Permission fault", level),
+            _ => match fsc {
+                0b01_0000 => ("Synchronous external abort", None),
+                0b10_0001 => ("Alignment fault", None),
+                0b11_0000 => ("TLB conflict abort", None),
+                _ => ("Other fault", None),
+            },
+        }
+    }
+}
+
+/// Prints the decoded syndrome, one field per line
+impl fmt::Display for EsrEL1 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const WNR: u32 = 1 << 6;
+
+        writeln!(f, "      ESR_EL1: {:#010X}", self.0)?;
+        write!(f, "            Exception Class: {} ({:#04X})", self.ec_name(), self.ec())?;
+
+        if self.is_abort() {
+            let fsc = self.iss() & 0x3F;
+
+            match self.fault_status() {
+                (name, Some(level)) => write!(
+                    f,
+                    "\n            Fault status:    {}, level {} ({:#04X})",
+                    name, level, fsc
+                )?,
+                (name, None) => {
+                    write!(f, "\n            Fault status:    {} ({:#04X})", name, fsc)?
+                }
+            }
+        }
+
+        if self.is_data_abort() {
+            let access = if self.iss() & WNR != 0 { "write" } else { "read" };
+            write!(f, "\n            Access:          {}", access)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A saved program status, as found in SPSR_EL1.
+#[derive(Copy, Clone)]
+pub struct SpsrEL1(pub u64);
+
+/// Prints the condition flags, the masked exceptions and the mode, one per line
+impl fmt::Display for SpsrEL1 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bit = |n: u32, c: char| if self.0 & (1 << n) != 0 { c } else { '-' };
+
+        writeln!(f, "      SPSR_EL1: {:#010X}", self.0)?;
+        writeln!(
+            f,
+            "            Flags:  {}{}{}{}",
+            bit(31, 'N'),
+            bit(30, 'Z'),
+            bit(29, 'C'),
+            bit(28, 'V')
+        )?;
+        writeln!(
+            f,
+            "            Masked: {}{}{}{}",
+            bit(9, 'D'),
+            bit(8, 'A'),
+            bit(7, 'I'),
+            bit(6, 'F')
+        )?;
+
+        if self.0 & (1 << 4) != 0 {
+            return write!(f, "            Mode:   AArch32");
+        }
+
+        let el = (self.0 >> 2) & 0x3;
+        let sp = if self.0 & 1 != 0 { 'h' } else { 't' };
+        write!(f, "            Mode:   EL{}{}", el, sp)
+    }
 }
 
 /// The default exception, invoked for every exception type unless the handler
 /// is overwritten.
 #[no_mangle]
 unsafe extern "C" fn default_exception_handler() {
-    println!("Unexpected exception. Halting CPU.");
+    eprintln!("Unexpected exception. Halting CPU.");
 
-    loop {
-        cortex_a::asm::wfe()
-    }
+    crate::cpu::wait_forever();
+}
+
+/// Run the system call of an `svc` exception.
+///
+/// The arguments are read from the saved x0-x3 and the result is written to
+/// the saved x0, so that it is in place after `eret`. ELR_EL1 already points
+/// at the instruction after the `svc`.
+fn handle_svc(e: &mut ExceptionContext, esr: EsrEL1, caller: Caller) {
+    let args = [e.gpr.x[0], e.gpr.x[1], e.gpr.x[2], e.gpr.x[3]];
+
+    e.gpr.x[0] = syscall::dispatch(esr.imm16(), &args, caller) as u64;
 }
 
 // To implement an exception handler, overwrite it by defining the respective
 // function below.
 // Don't forget the #[no_mangle] attribute.
 //
+// Kernel code runs on SP_EL0, so its exceptions arrive in the current_el0_*
+// handlers. The current_elx_* handlers are entered while an exception handler
+// is running on SP_EL1, that is, for nested exceptions.
+//
 // unsafe extern "C" fn current_el0_synchronous(e: &mut ExceptionContext);
 // unsafe extern "C" fn current_el0_irq(e: &mut ExceptionContext);
+// unsafe extern "C" fn current_el0_fiq();
 // unsafe extern "C" fn current_el0_serror(e: &mut ExceptionContext);
 
 // unsafe extern "C" fn current_elx_synchronous(e: &mut ExceptionContext);
 // unsafe extern "C" fn current_elx_irq(e: &mut ExceptionContext);
+// unsafe extern "C" fn current_elx_fiq();
 // unsafe extern "C" fn current_elx_serror(e: &mut ExceptionContext);
 
-// unsafe extern "C" fn lower_aarch64_synchronous(e: &mut ExceptionContext);
-// unsafe extern "C" fn lower_aarch64_irq(e: &mut ExceptionContext);
 // unsafe extern "C" fn lower_aarch64_serror(e: &mut ExceptionContext);
 
 // unsafe extern "C" fn lower_aarch32_synchronous(e: &mut ExceptionContext);
@@ -85,9 +464,57 @@ unsafe extern "C" fn default_exception_handler() {
 // unsafe extern "C" fn lower_aarch32_serror(e: &mut ExceptionContext);
 
 #[no_mangle]
-unsafe extern "C" fn current_elx_synchronous(e: &mut ExceptionContext) {
+unsafe extern "C" fn current_el0_synchronous(e: &mut ExceptionContext) {
+    let _nesting = Nesting::enter();
+    let esr = EsrEL1::read();
+
+    if esr.is_svc() {
+        handle_svc(e, esr, Caller::Kernel);
+        return;
+    }
+
+    if esr.is_brk() {
+        debug::handle_brk(e, esr.imm16());
+        return;
+    }
+
+    if esr.is_software_step() {
+        debug::handle_software_step(e);
+        return;
+    }
+
+    if esr.is_watchpoint() {
+        debug::handle_watchpoint(e, esr);
+        return;
+    }
+
+    if skip_expected_fault(e, esr) {
+        return;
+    }
+
+    if esr.far_valid() {
+        let far = cpu::regs::FAR_EL1.get() as usize;
+
+        if let Some(core) = stack_guard::guard_owner(far) {
+            eprintln!("[!] Stack overflow on core {}.", core);
+            eprintln!("      FAR_EL1: {:#010X} (guard page)", far);
+            eprintln!("      ELR_EL1: {:#010X}", e.elr_el1);
+            eprintln!("      Halting CPU.");
+
+            cpu::wait_forever();
+        }
+    }
+
     println!("[!] A synchronous exception happened.");
+    println!("{}", esr);
+    if esr.far_valid() {
+        let far = cpu::regs::FAR_EL1.get();
+
+        println!("      FAR_EL1: {:#010X} ({})", far, memory::region_name(far as usize));
+    }
+    println!("{}", SpsrEL1(e.spsr_el1));
     println!("      ELR_EL1: {:#010X}", e.elr_el1);
+    backtrace::print(e.gpr.x[29] as usize);
     println!(
         "      Incrementing ELR_EL1 by 4 now to continue with the first \
          instruction after the exception!"
@@ -98,3 +525,91 @@ unsafe extern "C" fn current_elx_synchronous(e: &mut ExceptionContext) {
     println!("      ELR_EL1 modified: {:#010X}", e.elr_el1);
     println!("      Returning from exception...\n");
 }
+
+/// A synchronous exception inside an exception handler.
+///
+/// The outer handler may have been interrupted halfway through changing some
+/// state, so there is no safe way back. Report both exceptions and park, unless
+/// the fault was expected by `expect_fault()`.
+#[no_mangle]
+unsafe extern "C" fn current_elx_synchronous(e: &mut ExceptionContext) {
+    let _nesting = Nesting::enter();
+    let esr = EsrEL1::read();
+
+    // Unless the handler asked for it, e.g. the GDB stub reading memory
+    if skip_expected_fault(e, esr) {
+        return;
+    }
+
+    eprintln!("[!] Nested exception, taken while handling another exception.");
+    eprintln!("{}", esr);
+    if esr.far_valid() {
+        let far = cpu::regs::FAR_EL1.get();
+
+        eprintln!("      FAR_EL1: {:#010X} ({})", far, memory::region_name(far as usize));
+    }
+    eprintln!("      ELR_EL1 (nested): {:#010X}", e.elr_el1);
+    eprintln!("      ELR_EL1 (outer):  {:#010X}", outermost_context().elr_el1);
+    eprintln!("      Nesting depth: {}", depth());
+    eprintln!("      Halting CPU.");
+
+    cpu::wait_forever();
+}
+
+/// A system call or a fault of the user program, see `user`.
+#[no_mangle]
+unsafe extern "C" fn lower_aarch64_synchronous(e: &mut ExceptionContext) {
+    let _nesting = Nesting::enter();
+    let esr = EsrEL1::read();
+
+    if esr.is_svc() && esr.imm16() != syscall::nr::EXIT {
+        handle_svc(e, esr, Caller::User);
+        return;
+    }
+
+    // Everything else ends the program, and returns to the kernel
+    user::handle_exception(e, esr);
+}
+
+/// An IRQ while the user program runs. The kernel tick and the timers keep
+/// going.
+#[no_mangle]
+unsafe extern "C" fn lower_aarch64_irq(_e: &mut ExceptionContext) {
+    let _nesting = Nesting::enter();
+    irq_handler();
+}
+
+/// Only saves the caller-saved registers, see `vectors.S`.
+#[no_mangle]
+unsafe extern "C" fn current_el0_fiq() {
+    let _nesting = Nesting::enter();
+    interrupt::dispatch_fiq();
+}
+
+/// A FIQ while an IRQ handler runs.
+#[no_mangle]
+unsafe extern "C" fn current_elx_fiq() {
+    let _nesting = Nesting::enter();
+    interrupt::dispatch_fiq();
+}
+
+fn irq_handler() {
+    interrupt::handle_irq();
+}
+
+#[no_mangle]
+unsafe extern "C" fn current_el0_irq(e: &mut ExceptionContext) {
+    let _nesting = Nesting::enter();
+    irq_handler();
+
+    // Any IRQ, like the kernel tick, gives GDB a chance to interrupt
+    gdbstub::poll_interrupt(e);
+}
+
+/// An IRQ while an exception handler runs with IRQs unmasked, e.g. the
+/// `sleep_us` system call.
+#[no_mangle]
+unsafe extern "C" fn current_elx_irq(_e: &mut ExceptionContext) {
+    let _nesting = Nesting::enter();
+    irq_handler();
+}
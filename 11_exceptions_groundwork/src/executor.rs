@@ -0,0 +1,226 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+
+//! A minimal executor for `async` code, on the boot core.
+//!
+//! Futures are spawned into a fixed arena of `MAX_TASKS` slots, boxed and
+//! pinned on the heap. Each slot has a ready bit in `READY`. The `Waker` that
+//! a task gets carries nothing but its slot number, so waking it only sets the
+//! bit, which is cheap and safe from IRQ handlers.
+//!
+//! `run()` polls the tasks whose bit is set, and otherwise sleeps in `wfi`
+//! until an IRQ handler wakes one, e.g. for received UART bytes, an expired
+//! `timer::sleep_us()`, or a finished DMA chain. The deferred work of the
+//! `workqueue` and the other kernel tasks run as well while the executor has
+//! nothing to do.
+//!
+//! Without a heap, `block_on()` still runs a single future on the stack.
+//!
+//! Wakers may be called from any core, but a wake up from another core is
+//! only seen with the next IRQ of the boot core, the kernel tick at the latest.
+
+use crate::{cpu, memory, sync::NullLock, task, workqueue};
+use alloc::boxed::Box;
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU32, Ordering},
+    task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
+};
+use cortex_a::asm;
+
+/// How many tasks can be spawned at the same time
+pub const MAX_TASKS: usize = 8;
+
+#[derive(Debug)]
+pub enum ExecutorError {
+    TooManyTasks,
+    /// The heap is not set up, so there is no place for the task
+    NoHeap,
+}
+
+pub type Result<T> = ::core::result::Result<T, ExecutorError>;
+
+type Task = Pin<Box<dyn Future<Output = ()>>>;
+
+enum Slot {
+    Free,
+    /// Taken out of the arena while it is polled, so that it can spawn
+    Polled,
+    Task(Task),
+}
+
+/// Only used on the boot core, and never from IRQ handlers.
+static TASKS: NullLock<[Slot; MAX_TASKS]> = NullLock::new([
+    Slot::Free,
+    Slot::Free,
+    Slot::Free,
+    Slot::Free,
+    Slot::Free,
+    Slot::Free,
+    Slot::Free,
+    Slot::Free,
+]);
+
+/// One bit per slot, set by its waker
+static READY: AtomicU32 = AtomicU32::new(0);
+
+/// The bit for the future of `block_on()`
+const BLOCK_ON: usize = 31;
+
+static VTABLE: RawWakerVTable =
+    RawWakerVTable::new(waker_clone, waker_wake, waker_wake, waker_drop);
+
+fn raw_waker(slot: usize) -> RawWaker {
+    RawWaker::new(slot as *const (), &VTABLE)
+}
+
+unsafe fn waker_clone(data: *const ()) -> RawWaker {
+    raw_waker(data as usize)
+}
+
+/// Both `wake` and `wake_by_ref`, there is nothing to release.
+unsafe fn waker_wake(data: *const ()) {
+    READY.fetch_or(1 << (data as usize), Ordering::Release);
+}
+
+unsafe fn waker_drop(_data: *const ()) {}
+
+fn is_free(slot: &Slot) -> bool {
+    match slot {
+        Slot::Free => true,
+        _ => false,
+    }
+}
+
+/// Add `future` to the tasks. It is polled the first time by `run()`.
+pub fn spawn<F>(future: F) -> Result<()>
+where
+    F: Future<Output = ()> + 'static,
+{
+    if memory::heap::usage().0 == 0 {
+        return Err(ExecutorError::NoHeap);
+    }
+
+    let nr = TASKS.lock(|tasks| {
+        let nr = tasks.iter().position(is_free)?;
+        tasks[nr] = Slot::Task(Box::pin(future));
+
+        Some(nr)
+    });
+
+    match nr {
+        Some(nr) => {
+            READY.fetch_or(1 << nr, Ordering::Release);
+            Ok(())
+        }
+
+        None => Err(ExecutorError::TooManyTasks),
+    }
+}
+
+/// Poll the task in `nr` once, and free its slot if it completed.
+fn poll(nr: usize) {
+    let task = TASKS.lock(|tasks| match core::mem::replace(&mut tasks[nr], Slot::Polled) {
+        Slot::Task(task) => Some(task),
+        other => {
+            tasks[nr] = other;
+            None
+        }
+    });
+
+    let mut task = match task {
+        Some(task) => task,
+        None => return,
+    };
+
+    let waker = unsafe { Waker::from_raw(raw_waker(nr)) };
+    let mut cx = Context::from_waker(&waker);
+
+    let slot = match task.as_mut().poll(&mut cx) {
+        Poll::Ready(()) => Slot::Free,
+        Poll::Pending => Slot::Task(task),
+    };
+
+    TASKS.lock(|tasks| tasks[nr] = slot);
+}
+
+/// Nothing of `mask` is ready, so give the rest of the kernel a turn, and
+/// sleep until the next IRQ. Like the scheduler, the check is done with IRQs
+/// masked, so that a wake up can not sneak in between it and the `wfi`.
+fn idle(mask: u32) {
+    task::yield_now();
+    workqueue::run_pending();
+
+    let daif = cpu::local_irq_save();
+    if READY.load(Ordering::Acquire) & mask == 0 && workqueue::is_empty() {
+        asm::wfi();
+    }
+    cpu::local_irq_enable();
+    cpu::local_irq_restore(daif);
+}
+
+/// Run the tasks until all of them completed.
+///
+/// IRQs must be unmasked, or nothing ever wakes a waiting task.
+pub fn run() {
+    loop {
+        if TASKS.lock(|tasks| tasks.iter().all(is_free)) {
+            return;
+        }
+
+        let ready = READY.swap(0, Ordering::Acquire);
+        if ready == 0 {
+            idle(!0);
+            continue;
+        }
+
+        for nr in (0..MAX_TASKS).filter(|nr| ready & (1 << nr) != 0) {
+            poll(nr);
+        }
+    }
+}
+
+/// Run `future` to completion on the stack, without the heap. Spawned tasks do
+/// not run in the meantime.
+pub fn block_on<F: Future>(mut future: F) -> F::Output {
+    // The binding is shadowed, so the future can not be moved anymore
+    let mut future = unsafe { Pin::new_unchecked(&mut future) };
+
+    let waker = unsafe { Waker::from_raw(raw_waker(BLOCK_ON)) };
+    let mut cx = Context::from_waker(&waker);
+
+    loop {
+        READY.fetch_and(!(1 << BLOCK_ON), Ordering::Acquire);
+
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+
+        while READY.load(Ordering::Acquire) & (1 << BLOCK_ON) == 0 {
+            idle(1 << BLOCK_ON);
+        }
+    }
+}
@@ -0,0 +1,617 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+
+//! A minimal GDB stub, speaking the remote serial protocol on the mini UART.
+//!
+//! The PL011 stays free for the console. After `init()`, the stub takes over
+//! all `brk` exceptions and software steps of the boot core:
+//!
+//! - `handle_brk()` stops at a breakpoint that GDB set, or at a `brk` in the
+//!   code like the one of `wait_for_gdb()`, which is skipped when resuming.
+//! - `poll_interrupt()` stops when GDB sends a break character (Ctrl-C). It is
+//!   called at the end of each IRQ, so the kernel tick checks for it.
+//!
+//! While stopped, the stub serves GDB's requests until it resumes us:
+//!
+//! - `?` and `qSupported` for the handshake,
+//! - `g`/`G` for the general purpose registers, SP, PC and CPSR,
+//! - `m`/`M` for memory, where faults are caught with `expect_fault()` and
+//!   read-only code pages are made writable for the time of the write,
+//! - `Z0`/`z0` for software breakpoints, by patching in `brk` instructions,
+//! - `c` and `s` to continue or step one instruction, with MDSCR_EL1 stepping.
+//!
+//! Anything else gets the empty reply, which tells GDB that it is not
+//! supported. The other cores keep running while the boot core is stopped.
+
+use crate::{
+    cache, cpu, debug,
+    devices::hw,
+    exception::{self, ExceptionContext},
+    memory::mmu,
+    sync::NullLock,
+};
+use core::ptr;
+use cortex_a::regs::*;
+
+/// Largest packet that is sent or received, including the framing. Told to
+/// GDB in hex in the reply to `qSupported`.
+const PACKET_LEN: usize = 0x400;
+
+/// How many software breakpoints can be set at the same time
+const MAX_BREAKPOINTS: usize = 16;
+
+/// How long to wait for GDB to acknowledge a packet, and how often to send it
+const ACK_TIMEOUT_US: u64 = 1_000_000;
+const SEND_RETRIES: u32 = 3;
+
+/// `brk #0`
+const BRK_INSN: u32 = 0xD420_0000;
+
+/// The interrupt character of GDB, Ctrl-C
+const BREAK_CHAR: char = '\x03';
+
+// SPSR_EL1: The IRQ mask
+const SPSR_I: u64 = 1 << 7;
+
+// Signals in stop replies
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+#[derive(Copy, Clone)]
+struct Breakpoint {
+    addr: usize,
+    /// The instruction that the `brk` replaced
+    insn: u32,
+}
+
+/// What to do after handling a packet
+enum Action {
+    Stay,
+    Resume,
+    Detach,
+}
+
+struct Stub {
+    uart: hw::MiniUart,
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+    /// GDB waits for a stop reply, after a `c` or `s`
+    running: bool,
+    /// Stepping one instruction, and whether IRQs were masked before it
+    stepping: Option<bool>,
+    /// The signal of the last stop, for `?`
+    signal: u8,
+}
+
+static STUB: NullLock<Option<Stub>> = NullLock::new(None);
+
+/// Start serving GDB on `uart`, which `init()` was called on already.
+pub fn init(uart: hw::MiniUart) {
+    let stub = Stub {
+        uart,
+        breakpoints: [None; MAX_BREAKPOINTS],
+        running: false,
+        stepping: None,
+        signal: SIGTRAP,
+    };
+
+    cpu::irq_masked(|| STUB.lock(|s| *s = Some(stub)));
+}
+
+/// Whether `init()` ran.
+pub fn is_active() -> bool {
+    STUB.lock(|s| s.is_some())
+}
+
+/// Stop right here, and wait until GDB connects and resumes us.
+pub fn wait_for_gdb() {
+    if is_active() {
+        unsafe { asm!("brk #0" :::: "volatile") };
+    }
+}
+
+/// Called for `brk` exceptions. Returns false if the stub is not in charge.
+pub fn handle_brk(e: &mut ExceptionContext) -> bool {
+    if cpu::core_id() != 0 {
+        return false;
+    }
+
+    STUB.lock(|s| match s {
+        Some(stub) => {
+            // Not ours, so it must be skipped, or it would stop us right away
+            // again.
+            let addr = e.elr_el1 as usize;
+            if !stub.breakpoints.iter().flatten().any(|b| b.addr == addr) {
+                e.elr_el1 += 4;
+            }
+
+            stub.stop(e, SIGTRAP);
+            true
+        }
+
+        None => false,
+    })
+}
+
+/// Called for software step exceptions. Returns false if the stub did not ask
+/// for the step.
+pub fn handle_step(e: &mut ExceptionContext) -> bool {
+    if cpu::core_id() != 0 {
+        return false;
+    }
+
+    STUB.lock(|s| match s {
+        Some(stub) => match stub.stepping.take() {
+            Some(irq_masked) => {
+                debug::disarm_step(e);
+                if !irq_masked {
+                    e.spsr_el1 &= !SPSR_I;
+                }
+
+                stub.stop(e, SIGTRAP);
+                true
+            }
+
+            None => false,
+        },
+
+        None => false,
+    })
+}
+
+/// Stop if GDB sent a break character. Called at the end of IRQs.
+pub fn poll_interrupt(e: &mut ExceptionContext) {
+    if cpu::core_id() != 0 {
+        return;
+    }
+
+    STUB.lock(|s| {
+        if let Some(stub) = s {
+            if let Ok(BREAK_CHAR) = stub.uart.recv(0) {
+                stub.stop(e, SIGINT);
+            }
+        }
+    });
+}
+
+/// Append-only buffer for the payload of a reply
+struct Reply {
+    buf: [u8; PACKET_LEN],
+    len: usize,
+}
+
+impl Reply {
+    fn new() -> Reply {
+        Reply {
+            buf: [0; PACKET_LEN],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, b: u8) {
+        // Leave room for the framing
+        if self.len < PACKET_LEN - 4 {
+            self.buf[self.len] = b;
+            self.len += 1;
+        }
+    }
+
+    fn push_hex(&mut self, byte: u8) {
+        const DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+        self.push(DIGITS[(byte >> 4) as usize]);
+        self.push(DIGITS[(byte & 0xF) as usize]);
+    }
+
+    /// A register value, in target byte order, which is little endian
+    fn push_reg(&mut self, value: u64, bytes: usize) {
+        (0..bytes).for_each(|i| self.push_hex((value >> (8 * i)) as u8));
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// A hex number, most significant digit first
+fn parse_hex(s: &[u8]) -> Option<u64> {
+    if s.is_empty() || s.len() > 16 {
+        return None;
+    }
+
+    s.iter()
+        .try_fold(0, |acc, &c| hex_digit(c).map(|d| (acc << 4) | u64::from(d)))
+}
+
+/// Hex encoded bytes, in memory order
+fn hex_bytes(s: &[u8]) -> impl Iterator<Item = Option<u8>> + '_ {
+    s.chunks(2).map(|pair| match pair {
+        [hi, lo] => Some(hex_digit(*hi)? << 4 | hex_digit(*lo)?),
+        _ => None,
+    })
+}
+
+/// Split `addr,len` into its numbers
+fn parse_addr_len(s: &[u8]) -> Option<(usize, usize)> {
+    let mut parts = s.splitn(2, |&c| c == b',');
+    let addr = parse_hex(parts.next()?)? as usize;
+    let len = parse_hex(parts.next()?)? as usize;
+
+    Some((addr, len))
+}
+
+fn read_byte(addr: usize) -> Option<u8> {
+    let mut byte = 0;
+    let faulted = exception::expect_fault(addr..=addr, || {
+        byte = unsafe { ptr::read_volatile(addr as *const u8) };
+    });
+
+    if faulted {
+        None
+    } else {
+        Some(byte)
+    }
+}
+
+/// Write a byte, also into read-only code. The caller does the cache
+/// maintenance.
+fn write_byte(addr: usize, byte: u8) -> bool {
+    let written = unsafe {
+        mmu::with_page_writable(addr, || {
+            !exception::expect_fault(addr..=addr, || {
+                ptr::write_volatile(addr as *mut u8, byte);
+            })
+        })
+    };
+
+    written.unwrap_or(false)
+}
+
+fn read_u32(addr: usize) -> Option<u32> {
+    (0..4).try_fold(0, |acc, i| {
+        read_byte(addr + i).map(|b| acc | u32::from(b) << (8 * i))
+    })
+}
+
+/// Write an instruction, and make sure that it is the one fetched next.
+fn write_insn(addr: usize, insn: u32) -> bool {
+    let written = (0..4).all(|i| write_byte(addr + i, (insn >> (8 * i)) as u8));
+
+    cache::clean_dcache_range(addr, 4);
+    cache::invalidate_icache_all();
+
+    written
+}
+
+impl Stub {
+    fn send_byte(&self, b: u8) {
+        let _ = self.uart.send(b as char);
+    }
+
+    /// Receive a byte, waiting for as long as it takes
+    fn recv_byte(&self) -> u8 {
+        loop {
+            // recv() turns carriage returns into newlines, but they can only
+            // show up in binary data, which is not supported anyways.
+            if let Ok(c) = self.uart.recv(u64::max_value()) {
+                return c as u8;
+            }
+        }
+    }
+
+    /// Send `$payload#checksum` until GDB acknowledges it.
+    fn send_packet(&self, payload: &[u8]) {
+        let checksum = payload.iter().fold(0u8, |acc, &b| acc.wrapping_add(b));
+
+        let mut framing = Reply::new();
+        framing.push_hex(checksum);
+
+        for _ in 0..SEND_RETRIES {
+            self.send_byte(b'$');
+            payload.iter().for_each(|&b| self.send_byte(b));
+            self.send_byte(b'#');
+            framing.as_bytes().iter().for_each(|&b| self.send_byte(b));
+
+            match self.uart.recv(ACK_TIMEOUT_US) {
+                Ok('+') => return,
+                _ => continue,
+            }
+        }
+    }
+
+    /// Receive the next packet into `buf`, and acknowledge it. Returns the
+    /// length of its payload.
+    fn recv_packet(&self, buf: &mut [u8; PACKET_LEN]) -> usize {
+        loop {
+            // Skip acks and break characters between packets
+            while self.recv_byte() != b'$' {}
+
+            let mut len = 0;
+            let mut sum = 0u8;
+            loop {
+                let b = self.recv_byte();
+                if b == b'#' {
+                    break;
+                }
+
+                if len < buf.len() {
+                    buf[len] = b;
+                    len += 1;
+                }
+                sum = sum.wrapping_add(b);
+            }
+
+            let checksum = [self.recv_byte(), self.recv_byte()];
+            if parse_hex(&checksum) == Some(u64::from(sum)) {
+                self.send_byte(b'+');
+                return len;
+            }
+
+            self.send_byte(b'-');
+        }
+    }
+
+    fn send_ok(&self) {
+        self.send_packet(b"OK");
+    }
+
+    /// `E` followed by an errno, GDB does not interpret it
+    fn send_error(&self, errno: u8) {
+        let mut reply = Reply::new();
+        reply.push(b'E');
+        reply.push_hex(errno);
+
+        self.send_packet(reply.as_bytes());
+    }
+
+    fn send_stop_reply(&self) {
+        let mut reply = Reply::new();
+        reply.push(b'S');
+        reply.push_hex(self.signal);
+
+        self.send_packet(reply.as_bytes());
+    }
+
+    /// Stop execution of the context and serve GDB until it resumes it.
+    fn stop(&mut self, e: &mut ExceptionContext, signal: u8) {
+        self.signal = signal;
+
+        // Unless GDB is waiting for it, it asks with `?` when it connects
+        if self.running {
+            self.running = false;
+            self.send_stop_reply();
+        }
+
+        let mut buf = [0; PACKET_LEN];
+        loop {
+            let len = self.recv_packet(&mut buf);
+
+            match self.handle_packet(e, &buf[..len]) {
+                Action::Stay => (),
+                Action::Resume => {
+                    self.running = true;
+                    return;
+                }
+                Action::Detach => {
+                    self.remove_all_breakpoints();
+                    return;
+                }
+            }
+        }
+    }
+
+    fn handle_packet(&mut self, e: &mut ExceptionContext, packet: &[u8]) -> Action {
+        let (&cmd, args) = match packet.split_first() {
+            Some(split) => split,
+            None => {
+                self.send_packet(b"");
+                return Action::Stay;
+            }
+        };
+
+        match cmd {
+            b'?' => self.send_stop_reply(),
+            b'g' => self.read_registers(e),
+            b'G' => self.write_registers(e, args),
+            b'm' => self.read_memory(args),
+            b'M' => self.write_memory(args),
+            b'Z' | b'z' => self.breakpoint(cmd == b'Z', args),
+            b'c' | b's' => {
+                if !args.is_empty() {
+                    match parse_hex(args) {
+                        Some(addr) => e.elr_el1 = addr,
+                        None => {
+                            self.send_error(22);
+                            return Action::Stay;
+                        }
+                    }
+                }
+
+                if cmd == b's' {
+                    self.stepping = Some(e.spsr_el1 & SPSR_I != 0);
+
+                    // Step the instruction, not the IRQ handler
+                    e.spsr_el1 |= SPSR_I;
+                    debug::arm_step(e);
+                }
+
+                return Action::Resume;
+            }
+            b'D' => {
+                self.send_ok();
+                return Action::Detach;
+            }
+            b'k' => return Action::Detach,
+            b'H' => self.send_ok(),
+            b'q' if args.starts_with(b"Supported") => {
+                self.send_packet(b"PacketSize=400");
+            }
+            b'q' if args == b"Attached" => self.send_packet(b"1"),
+            _ => self.send_packet(b""),
+        }
+
+        Action::Stay
+    }
+
+    /// x0 to x30, SP, PC, and the 32 bit CPSR
+    fn read_registers(&self, e: &ExceptionContext) {
+        let mut reply = Reply::new();
+
+        e.gpr.x.iter().for_each(|&x| reply.push_reg(x, 8));
+        reply.push_reg(SP_EL0.get(), 8);
+        reply.push_reg(e.elr_el1, 8);
+        reply.push_reg(e.spsr_el1, 4);
+
+        self.send_packet(reply.as_bytes());
+    }
+
+    fn write_registers(&self, e: &mut ExceptionContext, args: &[u8]) {
+        let mut bytes = hex_bytes(args);
+        let mut next_reg = |len: usize| -> Option<u64> {
+            (0..len).try_fold(0, |acc, i| Some(acc | u64::from(bytes.next()??) << (8 * i)))
+        };
+
+        let mut regs = [0; 34];
+        for (i, reg) in regs.iter_mut().enumerate() {
+            match next_reg(if i == 33 { 4 } else { 8 }) {
+                Some(value) => *reg = value,
+                None => return self.send_error(22),
+            }
+        }
+
+        e.gpr.x.copy_from_slice(&regs[..31]);
+        SP_EL0.set(regs[31]);
+        e.elr_el1 = regs[32];
+        e.spsr_el1 = regs[33];
+
+        self.send_ok();
+    }
+
+    fn read_memory(&self, args: &[u8]) {
+        let (addr, len) = match parse_addr_len(args) {
+            Some(addr_len) => addr_len,
+            None => return self.send_error(22),
+        };
+
+        let mut reply = Reply::new();
+        for a in addr..addr.saturating_add(len.min(PACKET_LEN / 2 - 4)) {
+            match read_byte(a) {
+                Some(byte) => reply.push_hex(byte),
+
+                // A partial read is fine, unless nothing could be read
+                None if reply.len > 0 => break,
+                None => return self.send_error(14),
+            }
+        }
+
+        self.send_packet(reply.as_bytes());
+    }
+
+    fn write_memory(&self, args: &[u8]) {
+        let mut parts = args.splitn(2, |&c| c == b':');
+        let (addr, len) = match parts.next().and_then(parse_addr_len) {
+            Some(addr_len) => addr_len,
+            None => return self.send_error(22),
+        };
+        let data = parts.next().unwrap_or(&[]);
+
+        if data.len() != 2 * len {
+            return self.send_error(22);
+        }
+
+        for (i, byte) in hex_bytes(data).enumerate() {
+            match byte {
+                Some(byte) if write_byte(addr + i, byte) => (),
+                _ => return self.send_error(14),
+            }
+        }
+
+        // The bytes may have been code
+        cache::clean_dcache_range(addr, len);
+        cache::invalidate_icache_all();
+
+        self.send_ok();
+    }
+
+    /// `Z0,addr,kind` and `z0,addr,kind`. Other types are not supported.
+    fn breakpoint(&mut self, insert: bool, args: &[u8]) {
+        if !args.starts_with(b"0,") {
+            return self.send_packet(b"");
+        }
+
+        let addr = match parse_addr_len(&args[2..]) {
+            Some((addr, _kind)) => addr,
+            None => return self.send_error(22),
+        };
+
+        let existing = self.breakpoints.iter().position(|b| match b {
+            Some(b) => b.addr == addr,
+            None => false,
+        });
+
+        if insert {
+            if existing.is_some() {
+                return self.send_ok();
+            }
+
+            let slot = match self.breakpoints.iter().position(Option::is_none) {
+                Some(slot) => slot,
+                None => return self.send_error(28),
+            };
+
+            match read_u32(addr) {
+                Some(insn) if write_insn(addr, BRK_INSN) => {
+                    self.breakpoints[slot] = Some(Breakpoint { addr, insn });
+                    self.send_ok();
+                }
+                _ => self.send_error(14),
+            }
+        } else {
+            if let Some(slot) = existing {
+                if let Some(b) = self.breakpoints[slot].take() {
+                    write_insn(b.addr, b.insn);
+                }
+            }
+
+            self.send_ok();
+        }
+    }
+
+    fn remove_all_breakpoints(&mut self) {
+        for b in self.breakpoints.iter_mut() {
+            if let Some(b) = b.take() {
+                write_insn(b.addr, b.insn);
+            }
+        }
+    }
+}
@@ -0,0 +1,332 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Peripheral IRQs of the BCM2837 interrupt controller, or of the GIC-400 on
+//! the Pi 4.
+//!
+//! Drivers register a handler for their IRQ number, and `dispatch()`, called
+//! from the IRQ exception vector, invokes the handlers of all pending sources.
+//!
+//! Exactly one source can instead be routed to the FIQ with `route_fiq()`, for
+//! a handler that must not wait behind sections with IRQs masked. Its handler
+//! is called directly from the FIQ vector, which saves only the caller-saved
+//! registers, so it must be short and must not cause exceptions.
+//!
+//! With the `rpi4` feature, the GIC-400 takes the place of the BCM controller
+//! and of the IRQ routing in the ARM local peripherals. The Videocore IRQs
+//! keep their numbers, as SPIs from `VC_IRQ_INTID` on, so drivers do not
+//! notice. The core timer is PPI `CORE_TIMER_INTID`, and IPIs are SGIs. There
+//! is no FIQ on this path, as the firmware puts all sources in group 1.
+
+use crate::{cpu, devices::hw, memory, smp, sync, timer};
+
+/// Peripheral IRQ numbers, as listed in the BCM2837 peripherals datasheet.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Irq {
+    /// System Timer compare channel 1
+    SysTimer1 = 1,
+    /// System Timer compare channel 3
+    SysTimer3 = 3,
+    Dma0 = 16,
+    Dma1 = 17,
+    Dma2 = 18,
+    Dma3 = 19,
+    Dma4 = 20,
+    Dma5 = 21,
+    Dma6 = 22,
+    Dma7 = 23,
+    Dma8 = 24,
+    Dma9 = 25,
+    Dma10 = 26,
+    /// Shared by DMA channels 11 to 14
+    Dma11To14 = 27,
+    GpioBank0 = 49,
+    GpioBank1 = 50,
+    GpioBank2 = 51,
+    Pl011Uart = 57,
+}
+
+#[derive(Debug)]
+pub enum InterruptError {
+    AlreadyRegistered,
+    FiqInUse,
+    /// The interrupt controller of this board has no FIQ path.
+    NoFiq,
+}
+pub type Result<T> = ::core::result::Result<T, InterruptError>;
+
+const NUM_IRQS: usize = 64;
+
+static HANDLERS: sync::NullLock<[Option<fn()>; NUM_IRQS]> =
+    sync::NullLock::new([None; NUM_IRQS]);
+
+/// The source routed to the FIQ, and its handler.
+static FIQ: sync::NullLock<Option<(Irq, fn())>> = sync::NullLock::new(None);
+
+/// The INTID of Videocore IRQ 0 on the GIC-400
+#[cfg(feature = "rpi4")]
+const VC_IRQ_INTID: usize = 96;
+
+/// The INTID of the non-secure physical timer (CNTPNSIRQ), a PPI
+#[cfg(feature = "rpi4")]
+const CORE_TIMER_INTID: usize = 30;
+
+/// The SGI that carries the IPIs of `smp::ipi`
+#[cfg(feature = "rpi4")]
+const IPI_INTID: usize = 0;
+
+fn irq_ctrl() -> hw::IrqCtrl {
+    hw::IrqCtrl::new(hw::Peripheral::IrqCtrl)
+}
+
+#[cfg(not(feature = "rpi4"))]
+fn local_ctrl() -> hw::LocalCtrl {
+    hw::LocalCtrl::new(hw::Peripheral::LocalCtrl)
+}
+
+#[cfg(feature = "rpi4")]
+fn gicd() -> hw::GicDistributor {
+    hw::GicDistributor::new(hw::Peripheral::Gicd)
+}
+
+#[cfg(feature = "rpi4")]
+fn gicc() -> hw::GicCpuInterface {
+    hw::GicCpuInterface::new(hw::Peripheral::Gicc)
+}
+
+/// Bring up the interrupt controller. Call on the boot core, before any IRQ
+/// is enabled.
+pub fn init() {
+    #[cfg(feature = "rpi4")]
+    gicd().init();
+
+    init_core();
+}
+
+/// Prepare the interrupt controller for IRQs on the executing core. `init()`
+/// does this for the boot core, the others call it themselves.
+pub fn init_core() {
+    #[cfg(feature = "rpi4")]
+    {
+        gicd().init_banked();
+        gicc().init();
+    }
+}
+
+/// Let the core timer IRQ of the executing core through.
+pub fn enable_core_timer() {
+    #[cfg(not(feature = "rpi4"))]
+    local_ctrl().enable_cntpns_irq(cpu::core_id());
+
+    #[cfg(feature = "rpi4")]
+    gicd().enable(CORE_TIMER_INTID);
+}
+
+/// Stop the core timer IRQ of the executing core.
+pub fn disable_core_timer() {
+    #[cfg(not(feature = "rpi4"))]
+    local_ctrl().disable_cntpns_irq(cpu::core_id());
+
+    #[cfg(feature = "rpi4")]
+    gicd().disable(CORE_TIMER_INTID);
+}
+
+/// Let IPIs into the executing core on the GIC, see `smp::ipi`.
+#[cfg(feature = "rpi4")]
+pub fn enable_ipi() {
+    gicd().enable(IPI_INTID);
+}
+
+/// Interrupt `core` with the IPI SGI.
+#[cfg(feature = "rpi4")]
+pub fn send_ipi(core: usize) {
+    gicd().send_sgi(IPI_INTID, 1 << core);
+}
+
+/// Register `handler` to be called whenever `irq` is pending.
+///
+/// The IRQ still needs to be unmasked with `enable()`.
+pub fn register_handler(irq: Irq, handler: fn()) -> Result<()> {
+    cpu::irq_masked(|| {
+        HANDLERS.lock(|h| {
+            let slot = &mut h[irq as usize];
+            if slot.is_some() {
+                return Err(InterruptError::AlreadyRegistered);
+            }
+
+            *slot = Some(handler);
+            Ok(())
+        })
+    })
+}
+
+/// Remove the handler of `irq`. Disable the IRQ first.
+pub fn unregister_handler(irq: Irq) {
+    cpu::irq_masked(|| HANDLERS.lock(|h| h[irq as usize] = None));
+}
+
+/// Unmask `irq` in the interrupt controller.
+pub fn enable(irq: Irq) {
+    #[cfg(not(feature = "rpi4"))]
+    irq_ctrl().enable(irq as usize);
+
+    #[cfg(feature = "rpi4")]
+    gicd().enable(VC_IRQ_INTID + irq as usize);
+}
+
+/// Mask `irq` in the interrupt controller.
+pub fn disable(irq: Irq) {
+    #[cfg(not(feature = "rpi4"))]
+    irq_ctrl().disable(irq as usize);
+
+    #[cfg(feature = "rpi4")]
+    gicd().disable(VC_IRQ_INTID + irq as usize);
+}
+
+/// Route `irq` to the FIQ, and call `handler` from the FIQ vector for it.
+///
+/// The IRQ path of the source is disabled, so that it is not handled twice.
+/// Fails if a different source is routed to the FIQ already.
+pub fn route_fiq(irq: Irq, handler: fn()) -> Result<()> {
+    if cfg!(feature = "rpi4") {
+        return Err(InterruptError::NoFiq);
+    }
+
+    cpu::irq_masked(|| {
+        FIQ.lock(|fiq| {
+            match *fiq {
+                Some((routed, _)) if routed != irq => return Err(InterruptError::FiqInUse),
+                _ => *fiq = Some((irq, handler)),
+            }
+
+            let irq_ctrl = irq_ctrl();
+            irq_ctrl.disable(irq as usize);
+            irq_ctrl.set_fiq(irq as usize);
+            cpu::local_fiq_enable();
+
+            Ok(())
+        })
+    })
+}
+
+/// Stop routing `irq` to the FIQ. Re-enable it with `enable()` for the IRQ
+/// path.
+pub fn unroute_fiq(irq: Irq) {
+    cpu::irq_masked(|| {
+        FIQ.lock(|fiq| match *fiq {
+            Some((routed, _)) if routed == irq => {
+                cpu::local_fiq_disable();
+                irq_ctrl().clear_fiq();
+                *fiq = None;
+            }
+            _ => (),
+        })
+    })
+}
+
+/// The source that is currently routed to the FIQ, if any.
+pub fn fiq_source() -> Option<Irq> {
+    FIQ.lock(|fiq| fiq.map(|(irq, _)| irq))
+}
+
+/// Call the handler of the FIQ source. Called from the FIQ vector.
+pub fn dispatch_fiq() {
+    if let Some((_, handler)) = FIQ.lock(|fiq| *fiq) {
+        handler();
+    }
+}
+
+/// Handle an IRQ exception: Find out what is pending on the executing core,
+/// and call the respective handlers.
+#[cfg(not(feature = "rpi4"))]
+pub fn handle_irq() {
+    let local_ctrl = local_ctrl();
+    let core = cpu::core_id();
+
+    if local_ctrl.cntpns_irq_pending(core) {
+        timer::irq_handler();
+    }
+
+    if local_ctrl.gpu_irq_pending(core) {
+        dispatch();
+    }
+
+    if local_ctrl.mailbox0_irq_pending(core) {
+        smp::ipi::irq_handler();
+    }
+}
+
+/// Handle an IRQ exception: Acknowledge interrupts at the GIC until none is
+/// left, and call the respective handlers.
+///
+/// Videocore IRQs without a registered handler, and unexpected INTIDs, are
+/// disabled, so that they can not keep the core stuck in the IRQ vector.
+#[cfg(feature = "rpi4")]
+pub fn handle_irq() {
+    let gicc = gicc();
+
+    loop {
+        let iar = gicc.acknowledge();
+        let intid = (iar & 0x3FF) as usize;
+
+        match intid {
+            _ if intid == hw::GIC_SPURIOUS as usize => break,
+            CORE_TIMER_INTID => timer::irq_handler(),
+            IPI_INTID => smp::ipi::irq_handler(),
+            _ if (VC_IRQ_INTID..VC_IRQ_INTID + NUM_IRQS).contains(&intid) => {
+                match HANDLERS.lock(|h| h[intid - VC_IRQ_INTID]) {
+                    Some(handler) => handler(),
+                    None => gicd().disable(intid),
+                }
+            }
+            _ => gicd().disable(intid),
+        }
+
+        gicc.end_of_interrupt(iar);
+    }
+}
+
+/// Call the handlers of all pending peripheral IRQs.
+///
+/// Sources that are pending without a registered handler are disabled, so that
+/// they can not keep the core stuck in the IRQ vector.
+#[cfg(not(feature = "rpi4"))]
+pub fn dispatch() {
+    let irq_ctrl = irq_ctrl();
+    let mut pending = irq_ctrl.pending();
+
+    if let Some(irq) = fiq_source() {
+        pending &= !(1 << irq as usize);
+    }
+
+    while pending != 0 {
+        let irq = pending.trailing_zeros() as usize;
+        pending &= !(1 << irq);
+
+        match HANDLERS.lock(|h| h[irq]) {
+            Some(handler) => handler(),
+            None => irq_ctrl.disable(irq),
+        }
+    }
+}
@@ -0,0 +1,186 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! The green activity LED.
+//!
+//! Where the LED is connected depends on the board:
+//!
+//! - Pi 3B: Behind the GPIO expander of the Videocore, which is only reachable
+//!   through the `SET_GPIO_STATE` mailbox property (expander pin 130).
+//! - Pi 3B+ and 3A+: GPIO29.
+//! - Pi 4B: GPIO42.
+//! - Older boards: GPIO47, active low on the Zero.
+//!
+//! `ActLed::init()` asks the firmware for the board revision and picks the
+//! right path. Until then, all functions are no-ops.
+
+use crate::{
+    board, debug, delays,
+    devices::hw::{
+        self,
+        videocore_mbox::{PropertyMessage, Tag},
+    },
+    memory, sync, warn,
+};
+
+#[derive(Debug)]
+pub enum ActLedError {
+    MailboxError,
+    /// The LED's GPIO pin is owned by someone else
+    PinInUse(usize),
+}
+pub type Result<T> = ::core::result::Result<T, ActLedError>;
+
+/// Pin number of the ACT LED on the GPIO expander of the Pi 3B
+const EXP_GPIO_ACT_LED: u32 = 130;
+
+enum Backend {
+    Gpio {
+        pin: hw::OutputPin,
+        active_low: bool,
+    },
+    Mbox(hw::VideocoreMbox<'static>),
+}
+
+static BACKEND: sync::NullLock<Option<Backend>> = sync::NullLock::new(None);
+
+pub struct ActLed;
+
+impl ActLed {
+    /// Detect the board and take over the LED.
+    ///
+    /// `v_mbox` is kept for the Pi 3B, so pass a mailbox of its own. On the
+    /// other boards, the LED's pin is taken from `gpio` and kept.
+    pub fn init(mut v_mbox: hw::VideocoreMbox<'static>, gpio: &hw::GPIO) -> Result<()> {
+        let revision = board::revision(&mut v_mbox).ok_or(ActLedError::MailboxError)?;
+
+        // Having the revision at hand anyway, double-check the peripheral base
+        let old_base = memory::mmio_base::base();
+        match memory::mmio_base::refine(revision) {
+            Ok(base) if base != old_base => {
+                // Nothing may be printed before the new window is mapped, the
+                // console's UART already moved there.
+                let reloaded = unsafe { memory::mmu::reload() };
+
+                // The mini UART was set up at the old base
+                let mini_uart = hw::MiniUart::new(hw::Peripheral::MiniUart);
+                mini_uart.init(gpio);
+                assert!(
+                    reloaded.is_ok() && mini_uart.responds(),
+                    "No mini UART at peripheral base {:#010X}",
+                    base
+                );
+                // Dropping it would disable the console's UART again
+                core::mem::forget(mini_uart);
+
+                warn!(
+                    "Board revision {:#08X} moved the peripherals from {:#010X} to {:#010X}.",
+                    revision, old_base, base
+                );
+            }
+            Ok(base) => debug!("Board revision {:#08X}, peripherals at {:#010X}.", revision, base),
+            Err(e) => warn!("Peripheral base not confirmed by the board revision: {:?}", e),
+        }
+
+        // New-style revision codes have bit 23 set and the board type in bits
+        // 4 to 11. All old-style codes are boards with the LED on GPIO47.
+        let board_type = if revision & (1 << 23) != 0 {
+            Some((revision >> 4) & 0xFF)
+        } else {
+            None
+        };
+
+        let (pin, active_low) = match board_type {
+            Some(0x08) => (None, false),
+            Some(0x0D) | Some(0x0E) => (Some(29), false),
+            Some(0x11) => (Some(42), false),
+            Some(0x09) | Some(0x0C) => (Some(47), true),
+            _ => (Some(47), false),
+        };
+
+        let backend = match pin {
+            None => Backend::Mbox(v_mbox),
+            Some(nr) => Backend::Gpio {
+                pin: gpio
+                    .take_pin(nr)
+                    .ok_or(ActLedError::PinInUse(nr))?
+                    .into_output(),
+                active_low,
+            },
+        };
+
+        BACKEND.lock(|b| *b = Some(backend));
+        ActLed::off();
+
+        Ok(())
+    }
+
+    /// Whether `init()` succeeded
+    pub fn is_available() -> bool {
+        BACKEND.lock(|b| b.is_some())
+    }
+
+    fn set(on: bool) {
+        BACKEND.lock(|b| match b {
+            Some(Backend::Gpio { pin, active_low }) => {
+                if on != *active_low {
+                    pin.set_high();
+                } else {
+                    pin.set_low();
+                }
+            }
+
+            Some(Backend::Mbox(v_mbox)) => {
+                let tag = Tag::SetGpioState {
+                    pin: EXP_GPIO_ACT_LED,
+                    state: on,
+                };
+
+                // Nothing sensible to do on failure, the LED is best effort
+                let _ = PropertyMessage::new().with(tag).call(v_mbox);
+            }
+
+            None => (),
+        })
+    }
+
+    pub fn on() {
+        ActLed::set(true);
+    }
+
+    pub fn off() {
+        ActLed::set(false);
+    }
+
+    /// Blink `times` times, each with `period_us` microseconds for one on/off
+    /// cycle. Busy-waits, so it also works with IRQs masked.
+    pub fn blink(times: u32, period_us: u64) {
+        for _ in 0..times {
+            ActLed::on();
+            delays::wait_usec(period_us / 2);
+            ActLed::off();
+            delays::wait_usec(period_us / 2);
+        }
+    }
+}
@@ -0,0 +1,232 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Leveled log output with timestamps.
+//!
+//! `error!`, `warn!`, `info!`, `debug!` and `trace!` print a line prefixed with
+//! the uptime and the module it came from:
+//!
+//! ```text
+//! [   1.234] DEBUG memory::mmu: TTBR1_EL1 at 0x00088000.
+//! ```
+//!
+//! Once an RTC provides the wall-clock time, see `walltime`, that replaces the
+//! uptime:
+//!
+//! ```text
+//! [2019-03-14 15:09:26.535] INFO  walltime: ...
+//! ```
+//!
+//! A message is printed if its level is at most the level of its module. That
+//! is the global level, unless the command line sets a level for the module
+//! or one of its parents:
+//!
+//! ```text
+//! loglevel=warn,memory::mmu=trace,devices=debug
+//! ```
+//!
+//! With `console=binlog`, records go out as binary frames instead, see
+//! `binlog`.
+//!
+//! Additionally, `STATIC_MAX_LEVEL` cuts off levels at compile time, so that
+//! `trace!` calls do not even make it into release builds.
+
+use crate::{binlog, cmdline, time, walltime};
+use core::{
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// Something failed, always printed unless the level is `Off`
+    Error = 1,
+    /// Something unexpected that the kernel could handle
+    Warn,
+    /// Progress of the boot, the default
+    Info,
+    /// Details for debugging a driver
+    Debug,
+    /// Very verbose, e.g. every step of a loop
+    Trace,
+}
+
+impl Level {
+    fn from_usize(l: usize) -> Option<Level> {
+        match l {
+            1 => Some(Level::Error),
+            2 => Some(Level::Warn),
+            3 => Some(Level::Info),
+            4 => Some(Level::Debug),
+            5 => Some(Level::Trace),
+            _ => None,
+        }
+    }
+
+    /// Parse a level name from the command line. `quiet` is the same as
+    /// `error`.
+    pub fn parse(s: &str) -> Option<Level> {
+        match s {
+            "quiet" | "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        })
+    }
+}
+
+/// Everything above is compiled out.
+#[cfg(debug_assertions)]
+pub const STATIC_MAX_LEVEL: Level = Level::Trace;
+#[cfg(not(debug_assertions))]
+pub const STATIC_MAX_LEVEL: Level = Level::Debug;
+
+/// The global level.
+static LEVEL: AtomicUsize = AtomicUsize::new(Level::Info as usize);
+
+/// The most verbose level of the global one and those of the modules, so that
+/// most disabled messages are rejected without looking at the modules.
+static MAX_LEVEL: AtomicUsize = AtomicUsize::new(Level::Info as usize);
+
+/// The global level.
+pub fn level() -> Level {
+    Level::from_usize(LEVEL.load(Ordering::Relaxed)).unwrap_or(Level::Info)
+}
+
+/// Change the global level. The levels of modules from the command line stay.
+#[allow(dead_code)]
+pub fn set_level(level: Level) {
+    LEVEL.store(level as usize, Ordering::Relaxed);
+
+    let max = module_levels().map(|(_, l)| l).fold(level, core::cmp::max);
+    MAX_LEVEL.store(max as usize, Ordering::Relaxed);
+}
+
+/// Take the levels from the `loglevel=` option, after `cmdline::init()`.
+pub fn init() {
+    let global = cmdline::value("loglevel")
+        .and_then(|v| v.split(',').next())
+        .and_then(Level::parse)
+        .unwrap_or(Level::Info);
+
+    set_level(global);
+}
+
+/// The `module=level` entries of the `loglevel=` option. Entries that do not
+/// parse are ignored.
+fn module_levels() -> impl Iterator<Item = (&'static str, Level)> {
+    cmdline::value("loglevel")
+        .unwrap_or("")
+        .split(',')
+        .skip(1)
+        .filter_map(|entry| {
+            let mut parts = entry.splitn(2, '=');
+            let module = parts.next()?;
+            let level = Level::parse(parts.next()?)?;
+
+            Some((module, level))
+        })
+}
+
+/// `module_path!()` without the name of the crate, which every path starts
+/// with. The crate root itself keeps its name.
+pub fn strip_crate(module: &str) -> &str {
+    module.splitn(2, "::").nth(1).unwrap_or(module)
+}
+
+/// Whether `module` is `parent` or lies below it.
+pub fn is_within(module: &str, parent: &str) -> bool {
+    module.starts_with(parent)
+        && (module.len() == parent.len() || module[parent.len()..].starts_with("::"))
+}
+
+/// The level of a module: The one of its longest parent on the command line,
+/// or the global level.
+fn level_of(module: &str) -> Level {
+    module_levels()
+        .filter(|(parent, _)| is_within(module, parent))
+        .max_by_key(|(parent, _)| parent.len())
+        .map_or_else(level, |(_, l)| l)
+}
+
+/// Whether a message of `level` from `module` would be printed.
+pub fn enabled(level: Level, module: &str) -> bool {
+    if level > STATIC_MAX_LEVEL || level as usize > MAX_LEVEL.load(Ordering::Relaxed) {
+        return false;
+    }
+
+    level <= level_of(strip_crate(module))
+}
+
+#[doc(hidden)]
+pub fn _log(level: Level, module: &str, args: fmt::Arguments) {
+    if binlog::is_enabled() && binlog::_log(level, module, args) {
+        return;
+    }
+
+    // Errors are flushed, in case the kernel halts right afterwards
+    let print: fn(fmt::Arguments) = if level == Level::Error {
+        crate::macros::_eprint
+    } else {
+        crate::macros::_print
+    };
+
+    match walltime::now_ms() {
+        Some(ms) => print(format_args!(
+            "[{}.{:03}] {:<5} {}: {}\n",
+            walltime::DateTime::from_unix(ms / 1000),
+            ms % 1000,
+            level,
+            strip_crate(module),
+            args
+        )),
+
+        None => {
+            let us = time::uptime();
+
+            print(format_args!(
+                "[{:4}.{:03}] {:<5} {}: {}\n",
+                us / 1_000_000,
+                (us / 1000) % 1000,
+                level,
+                strip_crate(module),
+                args
+            ))
+        }
+    }
+}
@@ -22,7 +22,10 @@
  * SOFTWARE.
  */
 
-use core::fmt;
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 // https://doc.rust-lang.org/src/std/macros.rs.html
 #[macro_export]
@@ -39,11 +42,252 @@ macro_rules! println {
     })
 }
 
+/// Like `print!`, for errors. The output is flushed before it returns, so that
+/// it is out even if the kernel stops right afterwards.
+#[macro_export]
+macro_rules! eprint {
+    ($($arg:tt)*) => ($crate::macros::_eprint(format_args!($($arg)*)));
+}
+
+/// Like `println!`, for errors. See `eprint!`.
+#[macro_export]
+macro_rules! eprintln {
+    () => (eprint!("\n"));
+    ($($arg:tt)*) => ({
+        $crate::macros::_eprint(format_args_nl!($($arg)*));
+    })
+}
+
+/// Print a line with the uptime and the module path in front, if `level` is
+/// enabled for the calling module, see the `log` module.
+///
+/// ```
+/// log!(log::Level::Debug, "{} tables in use", nr);
+/// ```
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)+) => ({
+        let level = $level;
+
+        // The first comparison is constant, so disabled levels compile to
+        // nothing in release builds.
+        if level <= $crate::log::STATIC_MAX_LEVEL && $crate::log::enabled(level, module_path!()) {
+            $crate::log::_log(level, module_path!(), format_args!($($arg)+));
+        }
+    })
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => (log!($crate::log::Level::Error, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => (log!($crate::log::Level::Warn, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => (log!($crate::log::Level::Info, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => (log!($crate::log::Level::Debug, $($arg)+));
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)+) => (log!($crate::log::Level::Trace, $($arg)+));
+}
+
+/// Like `assert!`, for the test kernel: Print the condition and where it
+/// failed, then end the QEMU run with a failure, see `qemu::qemu_exit()`.
+#[macro_export]
+macro_rules! assert_or_exit {
+    ($cond:expr) => ({
+        if !$cond {
+            eprintln!(
+                "\n[T] {}:{}: assertion failed: {}",
+                file!(),
+                line!(),
+                stringify!($cond)
+            );
+            $crate::qemu::qemu_exit(false);
+        }
+    })
+}
+
+/// Like `assert_eq!`, for the test kernel. Prints both values on failure.
+#[macro_export]
+macro_rules! assert_eq_or_exit {
+    ($left:expr, $right:expr) => ({
+        match (&$left, &$right) {
+            (left, right) => {
+                if !(*left == *right) {
+                    eprintln!(
+                        "\n[T] {}:{}: assertion failed: `{} == {}`",
+                        file!(),
+                        line!(),
+                        stringify!($left),
+                        stringify!($right)
+                    );
+                    eprintln!("      left: {:?}\n     right: {:?}", left, right);
+                    $crate::qemu::qemu_exit(false);
+                }
+            }
+        }
+    })
+}
+
+/// Evaluate an expression, print how many microseconds it took, and return its
+/// value.
+///
+/// ```
+/// let ret = measure!(v_mbox.call(channel::PROP));
+/// ```
+/// prints `[t] v_mbox.call(channel::PROP): 42 us`.
+#[macro_export]
+macro_rules! measure {
+    ($e:expr) => {{
+        let (ret, us) = $crate::time::measure(|| $e);
+        println!("[t] {}: {} us", stringify!($e), us);
+
+        ret
+    }};
+}
+
+/// Issue a system call with up to four arguments and evaluate to its `i64`
+/// return value.
+///
+/// The number must be a constant, because it is encoded as the immediate of
+/// the `svc` instruction.
+///
+/// ```
+/// let ret = syscall!(syscall::nr::SLEEP_US, 1000);
+/// ```
+#[macro_export]
+macro_rules! syscall {
+    ($nr:expr) => {
+        syscall!($nr, 0, 0, 0, 0)
+    };
+    ($nr:expr, $a0:expr) => {
+        syscall!($nr, $a0, 0, 0, 0)
+    };
+    ($nr:expr, $a0:expr, $a1:expr) => {
+        syscall!($nr, $a0, $a1, 0, 0)
+    };
+    ($nr:expr, $a0:expr, $a1:expr, $a2:expr) => {
+        syscall!($nr, $a0, $a1, $a2, 0)
+    };
+    ($nr:expr, $a0:expr, $a1:expr, $a2:expr, $a3:expr) => {{
+        let ret: i64;
+        unsafe {
+            asm!("svc $5"
+                 : "={x0}"(ret)
+                 : "{x0}"($a0 as u64), "{x1}"($a1 as u64), "{x2}"($a2 as u64),
+                   "{x3}"($a3 as u64), "i"($nr)
+                 : "memory"
+                 : "volatile");
+        }
+
+        ret
+    }};
+}
+
+/// The byte offset of `$field` in the struct `$type`, usable in constants.
+#[macro_export]
+macro_rules! offset_of {
+    ($type:ident, $field:ident) => {
+        // Only address arithmetic, no value of the type is needed. The base is
+        // aligned and non-null, so that the field's address is well-formed.
+        unsafe {
+            (&(*(::core::mem::align_of::<$type>() as *const $type)).$field as *const _ as usize)
+                - ::core::mem::align_of::<$type>()
+        }
+    };
+}
+
+/// Fail the build unless `$field` of the `#[repr(C)]` struct `$type` starts
+/// at byte offset `$offset`.
+///
+/// Expands to an associated constant whose type only matches its value if the
+/// offset is right, so a wrong one is a type error about an array with 0
+/// instead of 1 elements. That needs no panics in constants, which the
+/// toolchain does not have yet.
+///
+/// ```
+/// static_assert_offset!(RegisterBlock, STATUS, 0x18);
+/// ```
+#[macro_export]
+macro_rules! static_assert_offset {
+    ($type:ident, $field:ident, $offset:expr) => {
+        #[allow(dead_code, non_upper_case_globals)]
+        impl $type {
+            const $field: [(); 1] = [(); ($crate::offset_of!($type, $field) == $offset) as usize];
+        }
+    };
+}
+
+/// Fail the build unless the struct `$type` is `$size` bytes large, like
+/// `static_assert_offset!`.
+#[macro_export]
+macro_rules! static_assert_size {
+    ($type:ident, $size:expr) => {
+        #[allow(dead_code)]
+        impl $type {
+            const __SIZE: [(); 1] = [(); (::core::mem::size_of::<$type>() == $size) as usize];
+        }
+    };
+}
+
+/// Set by the panic handler, see `enter_panic_mode()`.
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// From now on, print without taking the console lock at all.
+///
+/// A panic may strike while another core holds the lock and never releases
+/// it, or while the lock is in a state that the panicking core can not tell.
+/// Output of several cores may interleave afterwards, but the panic message
+/// gets out. With IRQs masked, the UART drivers bypass their buffers as well.
+pub fn enter_panic_mode() {
+    PANICKING.store(true, Ordering::Relaxed);
+}
+
+/// Call `f` with the global console.
+pub(crate) fn with_console<F>(f: F)
+where
+    F: FnOnce(&mut crate::devices::virt::Console),
+{
+    let console = &crate::CONSOLE;
+
+    // An exception handler or a panic that interrupted a print on this core
+    // would wait for itself forever. Print right through the lock instead,
+    // the console is only read, and the UART buffers have locks of their own.
+    if PANICKING.load(Ordering::Relaxed)
+        || (crate::cpu::local_irq_masked() && console.held_by_current_core())
+    {
+        return unsafe { console.lock_unchecked(f) };
+    }
+
+    console.lock(f)
+}
+
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
 
-    crate::CONSOLE.lock(|c| {
+    with_console(|c| c.write_fmt(args).unwrap());
+}
+
+#[doc(hidden)]
+pub fn _eprint(args: fmt::Arguments) {
+    use crate::devices::virt::ConsoleOps;
+    use core::fmt::Write;
+
+    with_console(|c| {
         c.write_fmt(args).unwrap();
-    })
+        c.flush();
+    });
 }
@@ -24,33 +24,174 @@
 
 #![no_std]
 #![no_main]
-#![feature(allocator_api)]
+#![feature(alloc_error_handler)]
+#![feature(asm)]
+#![feature(async_await)]
 #![feature(const_fn)]
+#![feature(const_raw_ptr_deref)]
+#![feature(const_raw_ptr_to_usize_cast)]
 #![feature(custom_attribute)]
 #![feature(format_args_nl)]
 #![feature(global_asm)]
 #![feature(label_break_value)]
+#![feature(panic_info_message)]
 #![feature(range_contains)]
 
+extern crate alloc;
+
+mod backtrace;
+mod banner;
+mod binlog;
+mod board;
+mod cache;
+mod cmdline;
+mod cpu;
+mod debug;
 mod delays;
 mod devices;
+mod dtb;
+mod event;
 mod exception;
+mod executor;
+mod gdbstub;
+mod interrupt;
+mod led;
+mod log;
 mod macros;
 mod memory;
+mod perf;
+mod qemu;
+mod rand;
+mod ring_buffer;
+mod semihosting;
+mod shell;
+mod smp;
+mod stack_guard;
 mod sync;
+mod syscall;
+mod task;
+mod test_kernel;
+mod thermal;
+mod tick;
+mod time;
+mod timer;
+mod user;
+mod walltime;
+mod workqueue;
+
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+/// Sleep for 5 minutes during boot to verify that long delays do not return
+/// early. Off by default, because it makes booting rather boring.
+const LONG_DELAY_TEST: bool = false;
+
+/// How many temperature readings to print during boot, two seconds apart.
+const THERMAL_SAMPLES: u32 = 3;
+
+/// Let the thermal watchdog lower the ARM clock while the SoC is hot.
+const THERMAL_THROTTLE: bool = true;
+
+/// Receive on the PL011 UART by FIQ instead of IRQ, so that RX keeps up with
+/// high baud rates while IRQs are masked. Sending is polled in this mode.
+const UART_RX_FIQ: bool = false;
+
+/// GPIO of the pushbutton demo. Wire the button to GND, the pull-up is
+/// enabled by the kernel.
+const BUTTON_PIN: usize = 21;
+
+/// GPIO of the 1-Wire bus with DS18B20 temperature sensors. Connect their data
+/// lines to it, with a 4.7 kOhm pull-up to 3.3 V.
+const ONEWIRE_PIN: usize = 17;
+
+/// How many readings of each DS18B20 to print during boot, one second apart.
+const DS18B20_SAMPLES: u32 = 5;
 
 /// The global console. Output of the print! and println! macros.
-static CONSOLE: sync::NullLock<devices::virt::Console> =
-    sync::NullLock::new(devices::virt::Console::new());
-
-/// The global allocator for DMA-able memory. That is, memory which is tagged
-/// non-cacheable in the page tables.
-static DMA_ALLOCATOR: sync::NullLock<memory::BumpAllocator> =
-    sync::NullLock::new(memory::BumpAllocator::new(
-        memory::map::virt::DMA_HEAP_START as usize,
-        memory::map::virt::DMA_HEAP_END as usize,
-        "Global DMA Allocator",
-    ));
+static CONSOLE: sync::SpinLock<devices::virt::Console> =
+    sync::SpinLock::new(devices::virt::Console::new());
+
+/// Reset the board by the watchdog after a panic, instead of halting. Off by
+/// default, so that the panic message stays on the screen.
+const PANIC_RESETS: bool = false;
+
+/// Number of words above the stack pointer that a panic prints.
+const PANIC_STACK_WORDS: usize = 16;
+
+/// Report a panic with its location, a snapshot of SP, LR and the top of the
+/// stack, and a backtrace, then blink SOS on the ACT LED and halt, or reset the board if
+/// `PANIC_RESETS`.
+///
+/// The report goes right through the console lock to the UART unbuffered, see
+/// `macros::enter_panic_mode()`. Before a UART took over the console, it is
+/// dropped, but the LED still blinks.
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    // Before anything else gets called and overwrites them
+    let lr = cpu::link_register();
+    let sp = cpu::stack_pointer();
+    let fp = cpu::frame_pointer();
+
+    cpu::local_irq_disable();
+    macros::enter_panic_mode();
+
+    eprintln!("\n[!] Kernel panic on core {}!", cpu::core_id());
+    if let Some(msg) = info.message() {
+        eprintln!("      Message:  {}", msg);
+    }
+    if let Some(loc) = info.location() {
+        eprintln!("      Location: {}:{}:{}", loc.file(), loc.line(), loc.column());
+    }
+    eprintln!("      SP: {:#018X}  LR: {:#018X}", sp, lr);
+
+    // Only print words that are mapped, so that a panic near the end of the
+    // address space does not fault on top of it.
+    for i in (0..PANIC_STACK_WORDS).step_by(2) {
+        let addr = sp + i * 8;
+        if addr < memory::map::START || addr + 15 > memory::map::END {
+            break;
+        }
+
+        let words = unsafe { core::slice::from_raw_parts(addr as *const u64, 2) };
+        eprintln!("      {:#018X}: {:#018X} {:#018X}", addr, words[0], words[1]);
+    }
+
+    backtrace::print(fp);
+
+    if cfg!(feature = "test_kernel") {
+        qemu::qemu_exit(false);
+    }
+
+    loop {
+        // ... --- ...
+        led::ActLed::blink(3, 300_000);
+        led::ActLed::blink(3, 900_000);
+        led::ActLed::blink(3, 300_000);
+
+        if PANIC_RESETS {
+            devices::hw::Watchdog::new(devices::hw::Peripheral::Pm).reset();
+        }
+
+        delays::wait_usec(1_500_000);
+    }
+}
+
+#[alloc_error_handler]
+fn alloc_error(layout: core::alloc::Layout) -> ! {
+    let (total, free, blocks) = memory::heap::usage();
+    eprintln!(
+        "\n[!] Out of memory: {} bytes, aligned to {}. {}/{} bytes free in {} blocks.",
+        layout.size(),
+        layout.align(),
+        free,
+        total,
+        blocks
+    );
+
+    panic!("Out of memory");
+}
 
 fn kernel_entry() -> ! {
     use devices::hw;
@@ -60,16 +201,37 @@ fn kernel_entry() -> ! {
         static __exception_vectors_start: u64;
     }
 
+    cpu::init_core_id();
+    stack_guard::init();
+
+    // Before any driver is touched
+    let mmio_base = memory::mmio_base::detect();
+    unsafe { raspi3_hal::mmio::set_map(memory::map_mmio) };
+    interrupt::init();
+    perf::init();
+
+    // Catch everything from here on under QEMU, before any UART is up. The
+    // test kernel already reports on stdout through the PL011 UART.
+    if semihosting::is_available() && !cfg!(feature = "test_kernel") {
+        static SEMIHOSTING_CONSOLE: semihosting::SemihostingConsole =
+            semihosting::SemihostingConsole;
+
+        // The sink table is still empty, so this cannot fail
+        let _ = devices::virt::console::register(&SEMIHOSTING_CONSOLE);
+    }
+
     //------------------------------------------------------------
     // Instantiate GPIO device
     //------------------------------------------------------------
-    let gpio = hw::GPIO::new(memory::map::physical::GPIO_BASE);
+    // The only call, so it never panics
+    let gpio = hw::GPIO::take().unwrap();
 
     //------------------------------------------------------------
     // Instantiate MiniUart
     //------------------------------------------------------------
-    let mini_uart = hw::MiniUart::new(memory::map::physical::MINI_UART_BASE);
+    let mini_uart = hw::MiniUart::new(hw::Peripheral::MiniUart);
     mini_uart.init(&gpio);
+    let mini_uart_responds = mini_uart.responds();
 
     CONSOLE.lock(|c| {
         // Moves mini_uart into the global CONSOLE. It is not accessible anymore
@@ -78,13 +240,41 @@ fn kernel_entry() -> ! {
     });
     println!("\n[0] MiniUart online.");
 
+    // If the base is wrong, this is only seen on the semihosting console
+    assert!(
+        mini_uart_responds,
+        "No mini UART at peripheral base {:#010X}",
+        memory::mmio_base::base()
+    );
+    match mmio_base {
+        Ok(cpu) => info!("{}, peripherals at {:#010X}.", cpu, memory::mmio_base::base()),
+        Err(e) => warn!(
+            "Peripheral base detection failed: {:?}, using {:#010X}.",
+            e,
+            memory::mmio_base::base()
+        ),
+    }
+
+    {
+        use raspi3_boot::exception_level;
+
+        info!(
+            "Running in {:?}, booted in {:?}.",
+            exception_level::current(),
+            exception_level::boot_el()
+        );
+    }
+
     //------------------------------------------------------------
     // Greet the user
     //------------------------------------------------------------
     print!("[1] Press a key to continue booting... ");
-    CONSOLE.lock(|c| {
-        c.getc();
-    });
+    // Nobody is there to press a key for the test kernel
+    if !cfg!(feature = "test_kernel") {
+        CONSOLE.lock(|c| {
+            c.getc();
+        });
+    }
     println!("Greetings fellow Rustacean!");
 
     // We are now in a state where every next step can fail, but we can handle
@@ -98,15 +288,52 @@ fn kernel_entry() -> ! {
             println!("[2][Error] Could not set up MMU. Aborting.");
             break 'init;
         };
-        println!("[2] MMU online.");
+        println!("[2] MMU online, kernel page tables in TTBR1.");
+
+        measure!(memory::print_layout(log::Level::Debug));
+
+        // Exercise mmu::map_region(): Alias a page of the BSS in the unmapped
+        // part of the second GiB, and try to remap the kernel code RW.
+        {
+            use memory::{kernel_mem_range::AttributeFields, map, mmu};
+
+            #[repr(align(4096))]
+            struct Page([u64; 512]);
+            static mut PAGE: Page = Page([0; 512]);
+
+            const ALIAS: usize = map::KERNEL_OFFSET + 0x4020_0000;
+            const KERNEL_CODE: usize = 0x8_0000;
+
+            let aliased = unsafe {
+                let page = memory::virt_to_phys(&PAGE as *const _ as usize);
+
+                mmu::map_region(ALIAS, page, 4096, AttributeFields::default()).is_ok() && {
+                    core::ptr::write_volatile(ALIAS as *mut u64, 0x600D_CAFE);
+                    core::ptr::read_volatile(&PAGE.0[0]) == 0x600D_CAFE
+                }
+            };
+
+            let rejected = match unsafe {
+                let virt = memory::phys_to_virt(KERNEL_CODE);
 
-        memory::print_layout();
+                mmu::map_region(virt, KERNEL_CODE, 4096, AttributeFields::default())
+            } {
+                Err(mmu::MapError::Conflict) => true,
+                _ => false,
+            };
+
+            info!(
+                "map_region(): alias {}, conflicting remap rejected {}.",
+                if aliased { "PASS" } else { "FAIL" },
+                if rejected { "PASS" } else { "FAIL" }
+            );
+        }
 
         //------------------------------------------------------------
         // Instantiate Videocore Mailbox
         //------------------------------------------------------------
         let mut v_mbox;
-        match hw::VideocoreMbox::new(memory::map::physical::VIDEOCORE_MBOX_BASE) {
+        match hw::VideocoreMbox::new() {
             Ok(i) => {
                 println!("[3] Videocore Mailbox set up (DMA mem heap allocation successful).");
                 v_mbox = i;
@@ -118,36 +345,234 @@ fn kernel_entry() -> ! {
             }
         }
 
+        // A kernel for the wrong board gets the interrupts and the GPIO pulls
+        // wrong, so better stop right here.
+        match board::revision(&mut v_mbox).map(board::check) {
+            Some(Ok(())) => info!("Built for and running on a {}.", board::BOARD.name()),
+            Some(Err(board::BoardError::Mismatch(actual))) => {
+                println!(
+                    "[3][Error] This kernel is built for a {}, but runs on a {}. {}",
+                    board::BOARD.name(),
+                    actual.name(),
+                    if actual == board::Board::Rpi4 {
+                        "Rebuild with `--features rpi4`."
+                    } else {
+                        "Rebuild without `--features rpi4`."
+                    }
+                );
+                cpu::wait_forever();
+            }
+            Some(Err(e)) => warn!("Unsupported board: {:?}", e),
+            None => warn!("Could not read the board revision."),
+        }
+
         //------------------------------------------------------------
-        // Instantiate PL011 UART and replace MiniUart with it in CONSOLE
+        // Read the boot-time options from cmdline.txt
         //------------------------------------------------------------
-        let pl011_uart = hw::PL011Uart::new(memory::map::physical::PL011_UART_BASE);
+        match cmdline::init(&mut v_mbox) {
+            Ok(len) => {
+                log::init();
+                info!("Command line: {} bytes, log level {:?}.", len, log::level());
+            }
+            Err(e) => error!("Could not read the command line: {:?}", e),
+        }
 
-        // uart.init() will reconfigure the GPIO, which causes a race against
-        // the MiniUart that is still putting out characters on the physical
-        // line that are already buffered in its TX FIFO.
-        //
-        // To ensure the CPU doesn't rewire the GPIO before the MiniUart has put
-        // its last character, explicitly flush it before rewiring.
-        //
-        // If you switch to an output that happens to not use the same pair of
-        // physical wires (e.g. the Framebuffer), you don't need to do this,
-        // because flush() is anyways called implicitly by replace_with(). This
-        // is just a special case.
-        CONSOLE.lock(|c| c.flush());
-        match pl011_uart.init(&mut v_mbox, &gpio) {
+        for arg in cmdline::args() {
+            debug!("  {} = {}", arg.key, arg.value.unwrap_or("(flag)"));
+        }
+
+        //------------------------------------------------------------
+        // Take over the ACT LED, as heartbeat and panic indicator
+        //------------------------------------------------------------
+        match hw::VideocoreMbox::new() {
+            Ok(led_mbox) => match led::ActLed::init(led_mbox, &gpio) {
+                Ok(()) => {
+                    led::ActLed::blink(2, 200_000);
+                    info!("ACT LED online.");
+                }
+                Err(e) => error!("ACT LED init failed: {:?}", e),
+            },
+
+            Err(_) => error!("No mailbox for the ACT LED."),
+        }
+
+        //------------------------------------------------------------
+        // Instantiate PL011 UART and replace MiniUart with it in CONSOLE,
+        // unless the command line asks to stay on the MiniUart
+        //------------------------------------------------------------
+        let console = cmdline::console();
+        if console == cmdline::ConsoleChoice::Framebuffer {
+            warn!("There is no framebuffer console yet, using the PL011 UART.");
+        }
+
+        if console == cmdline::ConsoleChoice::MiniUart {
+            println!("[4] Staying on the MiniUart, as requested by the command line.");
+        } else {
+            let pl011_uart = hw::PL011Uart::new(hw::Peripheral::Pl011Uart);
+
+            // uart.init() will reconfigure the GPIO, which causes a race against
+            // the MiniUart that is still putting out characters on the physical
+            // line that are already buffered in its TX FIFO.
+            //
+            // To ensure the CPU doesn't rewire the GPIO before the MiniUart has put
+            // its last character, explicitly flush it before rewiring.
+            //
+            // If you switch to an output that happens to not use the same pair of
+            // physical wires (e.g. the Framebuffer), you don't need to do this,
+            // because flush() is anyways called implicitly by replace_with(). This
+            // is just a special case.
+            CONSOLE.lock(|c| c.flush());
+            match pl011_uart.init(&mut v_mbox, &gpio, 115_200, false) {
+                Ok(_) => {
+                    CONSOLE.lock(|c| {
+                        c.replace_with(pl011_uart.into());
+                    });
+
+                    println!("[4] PL011 UART online. Output switched to it.");
+
+                    if console == cmdline::ConsoleChoice::BinLog {
+                        binlog::init();
+                        println!("[4] Log records are binary frames from now on.");
+                    }
+                }
+
+                Err(_) => println!(
+                    "[4][Error] PL011 UART init failed. \
+                     Trying to continue with MiniUart."
+                ),
+            }
+        }
+
+        match hw::videocore_mbox::board_info(&mut v_mbox) {
+            Ok(info) => {
+                info!("Board: {}", info);
+                rand::set_board_serial(info.serial);
+                info!(
+                    "  ARM memory: {} MiB at {:#010x}",
+                    info.arm_memory.size >> 20,
+                    info.arm_memory.base
+                );
+                info!(
+                    "  VC memory:  {} MiB at {:#010x}",
+                    info.vc_memory.size >> 20,
+                    info.vc_memory.base
+                );
+            }
+
+            Err(e) => error!("Could not read the board info: {:?}", e),
+        }
+
+        {
+            use hw::videocore_mbox::edid::{self, EdidError, Source};
+
+            // A framebuffer would use this mode
+            match edid::display_mode(&mut v_mbox) {
+                (mode, Source::Edid) => info!("Display mode: {} from the EDID", mode),
+                (mode, Source::Fallback(EdidError::Mailbox(_)))
+                | (mode, Source::Fallback(EdidError::NoEdid)) => {
+                    info!("Display mode: {} (fallback, no EDID)", mode)
+                }
+                (mode, Source::Fallback(e)) => {
+                    warn!("Display mode: {} (fallback, EDID rejected: {:?})", mode, e)
+                }
+            }
+        }
+
+        //------------------------------------------------------------
+        // What the firmware's device tree says, if it passed one
+        //------------------------------------------------------------
+        match dtb::fdt() {
             Ok(_) => {
-                CONSOLE.lock(|c| {
-                    c.replace_with(pl011_uart.into());
-                });
+                info!("Device tree:");
+                for region in dtb::memory_regions().into_iter().flatten() {
+                    info!(
+                        "  Memory:      {} MiB at {:#010x}",
+                        region.size >> 20,
+                        region.base
+                    );
+                }
+                info!("  bootargs:    {}", dtb::bootargs().unwrap_or("-"));
+                info!("  stdout-path: {}", dtb::stdout_path().unwrap_or("-"));
+            }
 
-                println!("[4] PL011 UART online. Output switched to it.");
+            Err(e) => info!("No device tree ({:?}), using the mailbox's ARM memory.", e),
+        }
+
+        //------------------------------------------------------------
+        // Map the Videocore's share of the SDRAM non-cacheable
+        //------------------------------------------------------------
+        match memory::memory_map(&mut v_mbox) {
+            Ok(map) => {
+                info!(
+                    "ARM/VC split at {:#010X}, {} MiB for the Videocore.",
+                    map.vc.base,
+                    map.vc.size >> 20
+                );
+
+                if unsafe { memory::mmu::reload() }.is_ok() {
+                    memory::print_layout(log::Level::Info);
+                } else {
+                    error!("Could not remap the Videocore SDRAM.");
+                }
+
+                memory::heap::init();
             }
 
-            Err(_) => println!(
-                "[4][Error] PL011 UART init failed. \
-                 Trying to continue with MiniUart."
-            ),
+            Err(e) => error!("Could not read the memory split: {:?}", e),
+        }
+
+        {
+            use hw::videocore_mbox::{clock, Clock};
+
+            match (
+                clock::get_rate(&mut v_mbox, Clock::Arm),
+                clock::get_max_rate(&mut v_mbox, Clock::Arm),
+            ) {
+                (Ok(rate), Ok(max)) => info!(
+                    "ARM clock: {} MHz (max {} MHz)",
+                    rate / 1_000_000,
+                    max / 1_000_000
+                ),
+                _ => error!("Could not read the ARM clock rate."),
+            }
+        }
+
+        //------------------------------------------------------------
+        // Watch the SoC temperature for a few seconds
+        //------------------------------------------------------------
+        {
+            use hw::videocore_mbox::thermal;
+
+            let max = thermal::max_temperature_millicelsius(&mut v_mbox).unwrap_or(0);
+            for i in 0..THERMAL_SAMPLES {
+                if i != 0 {
+                    delays::wait_usec(2_000_000);
+                }
+
+                match thermal::temperature_millicelsius(&mut v_mbox) {
+                    Ok(t) => info!(
+                        "SoC temperature: {}.{} C (limit {} C)",
+                        t / 1000,
+                        (t % 1000) / 100,
+                        max / 1000
+                    ),
+                    Err(e) => error!("Could not read the SoC temperature: {:?}", e),
+                }
+            }
+
+            match thermal::throttle_status(&mut v_mbox) {
+                Ok(f) if f.0 == 0 => info!("No throttling or under-voltage since boot."),
+                Ok(f) => info!(
+                    "Throttling: under-voltage {}/{}, capped {}/{}, throttled {}/{} (now/since boot)",
+                    f.under_voltage(),
+                    f.under_voltage_occurred(),
+                    f.frequency_capped(),
+                    f.frequency_capped_occurred(),
+                    f.throttled(),
+                    f.throttled_occurred()
+                ),
+                Err(e) => error!("Could not read the throttle status: {:?}", e),
+            }
         }
 
         //------------------------------------------------------------
@@ -164,23 +589,724 @@ fn kernel_entry() -> ! {
             break 'init;
         }
 
+        if cfg!(feature = "test_kernel") {
+            test_kernel::run();
+        }
+
+        // The PL011 console keeps running, the stub takes the MiniUart
+        if cmdline::flag("gdb") {
+            if cmdline::console() == cmdline::ConsoleChoice::MiniUart {
+                warn!("The MiniUart is the console, no GDB stub.");
+            } else {
+                let uart = hw::MiniUart::new(hw::Peripheral::MiniUart);
+                uart.init(&gpio);
+                gdbstub::init(uart);
+
+                println!("[5] GDB stub on the MiniUart, waiting for GDB.");
+                gdbstub::wait_for_gdb();
+            }
+        }
+
         // Cause an exception by accessing a virtual address for which no
         // address translations have been set up.
         //
-        // This line of code accesses the address 3 GiB, but page tables are
-        // only set up for the range [0..1] GiB.
+        // This line of code accesses the address 3 GiB. That is in the lower
+        // half of the address space, where the TTBR0 walks are disabled.
         let big_addr: u64 = 3 * 1024 * 1024 * 1024;
         unsafe { core::ptr::read_volatile(big_addr as *mut u64) };
 
-        println!("[i] Whoa! We recovered from an exception.");
+        info!("Whoa! We recovered from an exception.");
+
+        // The same access again, but announced as expected. The handler skips
+        // it without a report, and the outcome can be checked in code.
+        let faulted = exception::expect_fault(big_addr as usize..=big_addr as usize + 7, || {
+            unsafe { core::ptr::read_volatile(big_addr as *const u64) };
+        });
+        info!(
+            "MMU test, reading unmapped 3 GiB faults: {}",
+            if faulted { "PASS" } else { "FAIL" }
+        );
+
+        // W^X: Code must not be writable, and data must not be executable
+        {
+            extern "C" {
+                static __ro_start: u64;
+            }
+
+            let code = unsafe { &__ro_start as *const _ as usize };
+            let write_to_code = exception::expect_fault(code..=code + 3, || unsafe {
+                let insn = core::ptr::read_volatile(code as *const u32);
+                core::ptr::write_volatile(code as *mut u32, insn);
+            });
+
+            // A `ret` on the stack. If it executes, it simply returns.
+            let ret_insn: [u32; 1] = [0xD65F_03C0];
+            let stack = ret_insn.as_ptr() as usize;
+            let exec_from_stack = exception::expect_fault(stack..=stack + 3, || unsafe {
+                let f: extern "C" fn() = core::mem::transmute(stack);
+                f();
+            });
+
+            info!(
+                "MMU test, writing code faults: {}, executing the stack faults: {}",
+                if write_to_code { "PASS" } else { "FAIL" },
+                if exec_from_stack { "PASS" } else { "FAIL" }
+            );
+        }
+
+        //------------------------------------------------------------
+        // Wake the secondary cores
+        //------------------------------------------------------------
+        {
+            // Cores that printed their hello. The cores are started one after
+            // another, so that they never print at the same time.
+            static ONLINE: AtomicU32 = AtomicU32::new(0);
+
+            fn secondary_hello() -> ! {
+                use raspi3_boot::exception_level;
+
+                info!("  Core {} online in {:?}.", smp::core_id(), exception_level::current());
+                ONLINE.fetch_add(1, Ordering::Release);
+
+                smp::idle_core()
+            }
+
+            for core in 1..smp::NUM_CORES {
+                let before = ONLINE.load(Ordering::Acquire);
+
+                if let Err(e) = smp::start_core(core, secondary_hello) {
+                    error!("Could not start core {}: {:?}", core, e);
+                    continue;
+                }
+
+                let deadline = delays::deadline(100_000);
+                let online =
+                    delays::poll_timeout(deadline, || ONLINE.load(Ordering::Acquire) != before);
+                if online.is_err() {
+                    error!("Core {} did not come up.", core);
+                }
+            }
+
+            info!(
+                "Secondary cores online: {} of {}.",
+                ONLINE.load(Ordering::Acquire),
+                smp::NUM_CORES - 1
+            );
+
+            // Round-robin an IPI that has the receiving core print its ID
+            static ANSWERED: AtomicU32 = AtomicU32::new(0);
+
+            fn print_id() {
+                info!("  Core {} got the IPI.", smp::core_id());
+                ANSWERED.fetch_add(1, Ordering::Release);
+            }
+
+            for core in 1..smp::NUM_CORES {
+                let before = ANSWERED.load(Ordering::Acquire);
+
+                if let Err(e) = smp::ipi::call(core, print_id) {
+                    error!("Could not send an IPI to core {}: {:?}", core, e);
+                    continue;
+                }
+
+                let deadline = delays::deadline(100_000);
+                let answered =
+                    delays::poll_timeout(deadline, || ANSWERED.load(Ordering::Acquire) != before);
+                if answered.is_err() {
+                    error!("Core {} did not answer the IPI.", core);
+                }
+            }
+
+            info!(
+                "Idle wakeups of cores 1 to 3: {}, {}, {}",
+                smp::idle_wakeups(1),
+                smp::idle_wakeups(2),
+                smp::idle_wakeups(3)
+            );
+        }
+
+        //------------------------------------------------------------
+        // Software breakpoint and single-stepping
+        //------------------------------------------------------------
+        fn on_brk(e: &exception::ExceptionContext) {
+            info!("Breakpoint hook called, resuming after {:#010X}.", e.elr_el1);
+        }
+
+        debug::set_brk_handler(on_brk);
+        unsafe { asm!("brk #0x42" :::: "volatile") };
+
+        static STEPPED: AtomicU32 = AtomicU32::new(0);
+
+        fn on_step(_e: &exception::ExceptionContext) {
+            STEPPED.fetch_add(1, Ordering::Relaxed);
+        }
+
+        debug::set_step_handler(on_step);
+        debug::single_step(true);
+        let sum: u64 = (1..=10).sum();
+        debug::single_step(false);
+        info!(
+            "Stepped {} instructions computing {}.",
+            STEPPED.load(Ordering::Relaxed),
+            sum
+        );
+
+        //------------------------------------------------------------
+        // Call into the kernel with svc
+        //------------------------------------------------------------
+        let msg = "[i] Hello from syscall::nr::WRITE!\n";
+        let written = syscall!(syscall::nr::WRITE, 1, msg.as_ptr(), msg.len());
+        let slept = syscall!(syscall::nr::SLEEP_US, 10_000);
+        let unknown = syscall!(0x42);
+        info!(
+            "Syscalls returned: write {}, sleep_us {}, unknown {}",
+            written, slept, unknown
+        );
+
+        //------------------------------------------------------------
+        // Sleep on the ARM timer IRQ instead of spinning
+        //------------------------------------------------------------
+        info!("Uptime: {}", time::Hms::uptime());
+        print!("[6] Waiting 1 second (ARM timer IRQ + wfe): ");
+        let start = time::Instant::now();
+        delays::wait_usec_irq(1_000_000);
+        println!("OK ({} us elapsed)", start.elapsed().as_micros());
+        info!("Uptime: {}", time::Hms::uptime());
+
+        //------------------------------------------------------------
+        // Periodic timer callbacks
+        //------------------------------------------------------------
+        static HEARTBEATS: AtomicU32 = AtomicU32::new(0);
+
+        fn heartbeat() {
+            HEARTBEATS.fetch_add(1, Ordering::Relaxed);
+        }
+
+        match timer::schedule_periodic(100_000, heartbeat) {
+            Some(handle) => {
+                cpu::local_irq_enable();
+                delays::wait_usec(1_000_000);
+                cpu::local_irq_disable();
+                timer::cancel(handle);
+
+                println!(
+                    "[7] Periodic 100 ms timer callback fired {} times in 1 second.",
+                    HEARTBEATS.load(Ordering::Relaxed)
+                );
+            }
+
+            None => println!("[7][Error] Could not schedule a periodic timer callback."),
+        }
+
+        //------------------------------------------------------------
+        // Start the kernel tick
+        //------------------------------------------------------------
+        if tick::init() {
+            let (start, start_ticks) = (time::Instant::now(), tick::ticks());
+            tick::sleep_ticks(10);
+            let slept = tick::ticks() - start_ticks;
+
+            info!(
+                "Kernel tick running: slept {} ticks ({} us) in {} us.",
+                slept,
+                tick::ticks_to_us(slept),
+                start.elapsed().as_micros()
+            );
+        } else {
+            error!("Could not start the kernel tick.");
+        }
+
+        //------------------------------------------------------------
+        // Keep an eye on the SoC temperature from now on
+        //------------------------------------------------------------
+        match hw::VideocoreMbox::new() {
+            Ok(thermal_mbox) => match thermal::init(thermal_mbox, THERMAL_THROTTLE) {
+                Ok(t) => info!(
+                    "Thermal watchdog online at {}.{} C.",
+                    t / 1000,
+                    (t % 1000) / 100
+                ),
+                Err(e) => error!("Thermal watchdog: {:?}", e),
+            },
+            Err(_) => error!("No mailbox buffer for the thermal watchdog."),
+        }
+
+        //------------------------------------------------------------
+        // Measure a delay with the PMU cycle counter
+        //------------------------------------------------------------
+        match delays::read_cycles() {
+            Some(start) => {
+                delays::wait_usec(100);
+                let cycles = delays::read_cycles().unwrap_or(start) - start;
+
+                println!("[8] A 100 us delay took {} CPU cycles.", cycles);
+            }
+
+            None => println!("[8] PMU cycle counter not accessible, skipping measurement."),
+        }
+
+        //------------------------------------------------------------
+        // Long delays, cross-checked with the BCM System Timer
+        //------------------------------------------------------------
+        if LONG_DELAY_TEST {
+            let sys_tmr = hw::SysTmr::new(hw::Peripheral::SysTimer);
+
+            print!("[9] Waiting 5 minutes (ARM timer IRQ + wfe): ");
+            let st_start = sys_tmr.get_system_timer();
+            let start = time::Instant::now();
+            delays::wait_usec_irq(5 * 60 * 1_000_000);
+
+            if st_start != 0 {
+                let st_elapsed = sys_tmr.get_system_timer().wrapping_sub(st_start);
+                println!("OK ({} us elapsed on the System Timer)", st_elapsed);
+            } else {
+                // QEMU does not emulate the System Timer
+                println!("OK ({} us elapsed)", start.elapsed().as_micros());
+            }
+        }
+
+        //------------------------------------------------------------
+        // Drive the PL011 UART by IRQ
+        //------------------------------------------------------------
+        let uart_irq = CONSOLE.lock(|c| match c.output() {
+            devices::virt::Output::PL011Uart(uart) if UART_RX_FIQ => uart.enable_rx_fiq().is_ok(),
+            devices::virt::Output::PL011Uart(uart) => {
+                uart.enable_rx_irq().is_ok() && uart.enable_tx_irq().is_ok()
+            }
+            _ => false,
+        });
+
+        if uart_irq {
+            cpu::local_irq_enable();
+
+            if UART_RX_FIQ {
+                println!("[10] PL011 UART receives by FIQ now.");
+            } else {
+                println!("[10] PL011 UART receives and sends by IRQ now.");
+            }
+        } else {
+            println!("[10] Console is not the PL011 UART, keeping polled I/O.");
+        }
+
+        //------------------------------------------------------------
+        // Loopback self-test of the PL011 UART
+        //------------------------------------------------------------
+        let selftest = CONSOLE.lock(|c| match c.output() {
+            devices::virt::Output::PL011Uart(uart) => Some(uart.selftest()),
+            _ => None,
+        });
+
+        match selftest {
+            Some(Ok(())) => println!("[11] PL011 UART loopback self-test passed."),
+            Some(Err(e)) => println!("[11][Error] PL011 UART loopback self-test: {:?}", e),
+            None => (),
+        }
+
+        //------------------------------------------------------------
+        // Report presses of a pushbutton between GPIO21 and GND by IRQ
+        //------------------------------------------------------------
+        fn button_pressed(pin: usize) {
+            info!("Button on GPIO{} pressed.", pin);
+        }
+
+        let button = gpio
+            .take_pin(BUTTON_PIN)
+            .map(|pin| pin.into_input(hw::GpioPull::Up));
+
+        match button.as_ref().map(|b| b.on_edge(hw::GpioEdge::Falling, 20_000, button_pressed)) {
+            Some(Ok(())) => println!("[12] Pushbutton on GPIO{} reported by IRQ.", BUTTON_PIN),
+            Some(Err(e)) => println!("[12][Error] GPIO edge IRQ: {:?}", e),
+            None => println!("[12][Error] GPIO{} is already in use.", BUTTON_PIN),
+        }
+
+        //------------------------------------------------------------
+        // Check the pull-up/down path of the second GPIO bank
+        //------------------------------------------------------------
+        // GPIO47 is clocked through GPPUDCLK1. With nothing driving the pin,
+        // its level must follow the pull resistor. It is the ACT LED on some
+        // boards, which then owns it.
+        match gpio.take_pin(47) {
+            Some(pin) => {
+                let pin = pin.into_input(hw::GpioPull::Up);
+                delays::wait_usec(100);
+                let up = pin.is_high();
+
+                let pin = pin.into_input(hw::GpioPull::Down);
+                delays::wait_usec(100);
+                let down = pin.is_low();
+
+                pin.into_input(hw::GpioPull::Off);
+
+                if up && down {
+                    println!("[13] GPIO47 follows its pull-up/down resistor.");
+                } else {
+                    println!(
+                        "[13][Error] GPIO47 pull-up/down readback: up -> {}, down -> {}",
+                        if up { "high" } else { "low" },
+                        if down { "low" } else { "high" }
+                    );
+                }
+            }
+
+            None => println!("[13] GPIO47 is in use, by the ACT LED on this board. Skipped."),
+        }
+
+        //------------------------------------------------------------
+        // Fade an LED on GPIO18 in and out with the PWM
+        //------------------------------------------------------------
+        let cm = hw::ClockManager::new(hw::Peripheral::ClockManager);
+        let pwm = hw::Pwm::new(hw::Peripheral::Pwm);
+
+        // 19.2 MHz / 2 / 1024 = 9.4 kHz, way too fast to see any flicker
+        const RANGE: u32 = 1024;
+        match pwm.init(&cm, &gpio, hw::PwmChannel::One, RANGE, 2, hw::PwmMode::Balanced) {
+            Ok(()) => {
+                print!("[14] Fading the LED on GPIO18... ");
+                pwm.enable(hw::PwmChannel::One);
+
+                for duty in (0..RANGE).step_by(16).chain((0..RANGE).rev().step_by(16)) {
+                    pwm.set_duty(hw::PwmChannel::One, duty);
+                    delays::wait_msec(10);
+                }
+
+                pwm.disable(hw::PwmChannel::One);
+                println!("OK");
+            }
+
+            Err(e) => println!("[14][Error] PWM init failed: {:?}", e),
+        }
+
+        //------------------------------------------------------------
+        // Copy and fill memory with the DMA engine
+        //------------------------------------------------------------
+        match hw::dma::init(&mut v_mbox) {
+            Ok(mask) => {
+                use hw::dma;
+
+                // Chains that raised the IRQ of their channel
+                static COMPLETED: AtomicU32 = AtomicU32::new(0);
+
+                fn dma_done() {
+                    COMPLETED.fetch_add(1, Ordering::Release);
+                }
+
+                let mut src = [0u8; 4096];
+                let mut dst = [0u8; 4096];
+                for (i, byte) in src.iter_mut().enumerate() {
+                    *byte = i as u8;
+                }
+
+                // By IRQ, on a channel of our own
+                let by_irq = dma::Channel::claim().and_then(|mut channel| {
+                    channel.set_callback(Some(dma_done))?;
+
+                    let before = COMPLETED.load(Ordering::Acquire);
+                    let cb = dma::ControlBlock::new(
+                        dst.as_mut_ptr() as usize,
+                        src.as_ptr() as usize,
+                        src.len(),
+                    );
+                    channel.start(&cb)?;
+
+                    let deadline = delays::deadline(100_000);
+                    delays::poll_timeout(deadline, || COMPLETED.load(Ordering::Acquire) != before)
+                        .map_err(|_| dma::DmaError::Timeout)?;
+                    channel.wait(0)
+                });
+                let irq_copy = by_irq.is_ok() && dst[..] == src[..];
+
+                // And the blocking helpers
+                unsafe { dma::dma_fill(dst.as_mut_ptr(), 0, dst.len()) };
+                let filled = dst.iter().all(|&byte| byte == 0);
+                unsafe { dma::dma_copy(dst.as_mut_ptr(), src.as_ptr(), src.len()) };
+                let copied = dst[..] == src[..];
+
+                println!(
+                    "[15] DMA channels {:#06x}, copy by IRQ: {}, fill: {}, copy: {}",
+                    mask,
+                    if irq_copy { "PASS" } else { "FAIL" },
+                    if filled { "PASS" } else { "FAIL" },
+                    if copied { "PASS" } else { "FAIL" }
+                );
+                if let Err(e) = by_irq {
+                    println!("[15][Error] DMA by IRQ: {:?}", e);
+                }
+            }
+
+            Err(e) => println!("[15][Error] Could not query the DMA channels: {:?}", e),
+        }
+
+        //------------------------------------------------------------
+        // Scan the I2C bus on GPIO2/3
+        //------------------------------------------------------------
+        let i2c = hw::I2c::new(hw::Peripheral::I2c1);
+        match i2c.init(&mut v_mbox, &gpio, hw::I2cSpeed::Standard) {
+            Ok(()) => {
+                let devices = i2c.scan();
+                println!("[16] I2C devices on GPIO2/3: {}", devices);
+
+                if devices.contains(hw::ds3231::ADDR) {
+                    match walltime::init(i2c) {
+                        Ok(dt) => info!("DS3231 RTC: {} UTC", dt),
+                        Err(hw::ds3231::Ds3231Error::TimeInvalid) => {
+                            warn!("DS3231 RTC: time invalid, set it with `date`")
+                        }
+                        Err(e) => error!("DS3231 RTC: {:?}", e),
+                    }
+                }
+            }
+            Err(e) => println!("[16][Error] I2C init failed: {:?}", e),
+        }
+
+        //------------------------------------------------------------
+        // SPI loopback, with MOSI (GPIO10) jumpered to MISO (GPIO9)
+        //------------------------------------------------------------
+        let spi = hw::Spi::new(hw::Peripheral::Spi0);
+        match spi.init(&mut v_mbox, &gpio, 1_000_000, hw::SpiMode::Mode0, hw::SpiCs::Cs0) {
+            Ok(hz) => {
+                let pattern = [0x55, 0xAA, 0x00, 0xFF, 0x12, 0x34, 0x56, 0x78];
+                let mut buf = pattern;
+
+                match spi.transfer(&mut buf) {
+                    Ok(()) => println!(
+                        "[17] SPI loopback at {} Hz: {}",
+                        hz,
+                        if buf == pattern { "PASS" } else { "FAIL (no jumper?)" }
+                    ),
+                    Err(e) => println!("[17][Error] SPI transfer failed: {:?}", e),
+                }
+            }
+            Err(e) => println!("[17][Error] SPI init failed: {:?}", e),
+        }
+
+        //------------------------------------------------------------
+        // Output 12 MHz on GPIO4 with GPCLK0
+        //------------------------------------------------------------
+        match cm.configure_gp_clock(
+            &gpio,
+            hw::GpClock::Gp0,
+            hw::ClockSource::PllD,
+            hw::ClockMash::Stage1,
+            12_000_000,
+        ) {
+            Ok(hz) => println!("[18] GPCLK0 on GPIO4 runs at {} Hz", hz),
+            Err(e) => println!("[18][Error] GPCLK0 setup failed: {:?}", e),
+        }
+
+        //------------------------------------------------------------
+        // One-shot alarm on System Timer compare channel 1
+        //------------------------------------------------------------
+        let sys_tmr = hw::SysTmr::new(hw::Peripheral::SysTimer);
+        let now = sys_tmr.get_system_timer();
+
+        if now != 0 {
+            // The lower 32 bits of the System Timer when the alarm fired, with
+            // bit 0 set so that it is never zero
+            static FIRED_AT: AtomicU32 = AtomicU32::new(0);
+
+            fn alarm() {
+                let sys_tmr = hw::SysTmr::new(hw::Peripheral::SysTimer);
+                FIRED_AT.store(sys_tmr.get_system_timer() as u32 | 1, Ordering::Release);
+            }
+
+            let deadline = now + 100_000;
+            match sys_tmr.set_alarm(hw::SysTmrChannel::One, deadline, alarm) {
+                Ok(()) => {
+                    let fired = delays::poll_timeout(delays::deadline(200_000), || {
+                        FIRED_AT.load(Ordering::Acquire) != 0
+                    });
+                    let late = FIRED_AT.load(Ordering::Acquire).wrapping_sub(deadline as u32);
+
+                    match fired {
+                        Ok(()) => println!("[19] System Timer alarm fired {} us late: PASS", late),
+                        Err(_) => println!("[19] System Timer alarm: FAIL"),
+                    }
+                }
+                Err(e) => println!("[19][Error] System Timer alarm: {:?}", e),
+            }
+        } else {
+            println!("[19] No System Timer on QEMU, skipping the alarm.");
+        }
+
+        //------------------------------------------------------------
+        // Read the DS18B20 sensors on the 1-Wire bus
+        //------------------------------------------------------------
+        match gpio.take_pin(ONEWIRE_PIN).map(hw::onewire::Bus::new) {
+            Some(bus) => {
+                use hw::{ds18b20, onewire};
+
+                let mut roms = [onewire::Rom::default(); 4];
+                match bus.search(&mut roms) {
+                    Ok(found) => {
+                        let sensors = &roms[..found];
+                        println!("[20] {} 1-Wire device(s) on GPIO{}", found, ONEWIRE_PIN);
+
+                        for i in 0..DS18B20_SAMPLES {
+                            if i != 0 {
+                                delays::wait_msec(1000);
+                            }
+
+                            for rom in sensors.iter().filter(|r| r.family() == ds18b20::FAMILY) {
+                                match ds18b20::read_temp(&bus, rom) {
+                                    Ok(t) => println!(
+                                        "[20] {}: {}{}.{:03} C",
+                                        rom,
+                                        if t < 0 { "-" } else { "" },
+                                        t.abs() / 1000,
+                                        t.abs() % 1000
+                                    ),
+                                    Err(e) => println!("[20][Error] {}: {:?}", rom, e),
+                                }
+                            }
+                        }
+                    }
+                    Err(onewire::OwError::NoPresence) => {
+                        println!("[20] No 1-Wire devices on GPIO{}", ONEWIRE_PIN)
+                    }
+                    Err(e) => println!("[20][Error] 1-Wire search failed: {:?}", e),
+                }
+            }
+            None => println!("[20][Error] GPIO{} is already in use.", ONEWIRE_PIN),
+        }
+
+        //------------------------------------------------------------
+        // Beep on the headphone jack, by the CPU and then by DMA
+        //------------------------------------------------------------
+        {
+            use hw::audio;
+
+            let by_cpu = audio::play(audio::BEEP, audio::BEEP_SAMPLE_RATE);
+            let by_dma = audio::play_async(audio::BEEP, audio::BEEP_SAMPLE_RATE).and_then(|p| {
+                // Free to do something else in the meantime
+                let _ = delays::poll_timeout(delays::deadline(1_000_000), || p.is_done());
+                p.wait()
+            });
+
+            match (by_cpu, by_dma) {
+                (Ok(()), Ok(())) => println!("[21] Beeped on the headphone jack, twice."),
+                (Err(e), _) | (_, Err(e)) => println!("[21][Error] Audio playback: {:?}", e),
+            }
+        }
+
+        //------------------------------------------------------------
+        // Put the heap through its paces
+        //------------------------------------------------------------
+        {
+            use alloc::{boxed::Box, string::String, vec::Vec};
+            use core::fmt::Write;
+
+            let (total, _, _) = memory::heap::usage();
+
+            if total == 0 {
+                println!("[22][Error] No heap, the memory map is unknown.");
+            } else {
+                let mut rng = rand::Rng::seeded_from_hw();
+
+                match memory::heap::stress_test(10_000, &mut rng) {
+                    Ok(()) => {
+                        let squares: Vec<u64> = (1..=8).map(|i| i * i).collect();
+                        let sum = Box::new(squares.iter().sum::<u64>());
+                        let mut s = String::new();
+                        let _ = write!(s, "{:?} sum to {}", squares, sum);
+
+                        println!("[22] Heap of {} MiB: stress test PASS, {}", total >> 20, s);
+                    }
+                    Err(e) => println!(
+                        "[22] Heap stress test: FAIL ({:?}), seed={:#x}",
+                        e,
+                        rng.seed()
+                    ),
+                }
+            }
+        }
+
+        //------------------------------------------------------------
+        // Take turns with two cooperative tasks
+        //------------------------------------------------------------
+        {
+            const ROUNDS: usize = 3;
+            const PING_US: u64 = 30_000;
+            const PONG_US: u64 = 20_000;
+
+            static mut STACK_PING: [u8; 8192] = [0; 8192];
+            static mut STACK_PONG: [u8; 8192] = [0; 8192];
+
+            fn ping() {
+                for i in 1..=ROUNDS {
+                    println!("[23] Ping {}/{}", i, ROUNDS);
+                    task::sleep_us(PING_US);
+                }
+            }
+
+            fn pong() {
+                for i in 1..=ROUNDS {
+                    println!("[23] Pong {}/{}", i, ROUNDS);
+                    task::sleep_us(PONG_US);
+                }
+            }
+
+            let spawned = task::spawn(ping, unsafe { &mut STACK_PING })
+                .and_then(|a| task::spawn(pong, unsafe { &mut STACK_PONG }).map(|b| (a, b)));
+
+            match spawned {
+                Ok((a, b)) => {
+                    while task::is_alive(a) || task::is_alive(b) {
+                        task::sleep_us(PONG_US);
+                    }
+                    println!("[23] Cooperative tasks: PASS, {} and {} returned", a, b);
+                }
+                Err(e) => println!("[23][Error] Spawning the tasks failed: {:?}", e),
+            }
+        }
+
+        //------------------------------------------------------------
+        // What the data cache saves, counted by the PMU
+        //------------------------------------------------------------
+        {
+            use alloc::vec::Vec;
+            use perf::Event;
+
+            const EVENTS: &[Event] = &[Event::L1dAccess, Event::L1dRefill, Event::InstRetired];
+
+            // Half of the A53's 32 KiB L1D, so it stays in there
+            let buf: Vec<u64> = (0..2048).collect();
+            let sum = || {
+                let s: u64 = buf.iter().map(|x| unsafe { core::ptr::read_volatile(x) }).sum();
+                assert_eq!(s, 2047 * 2048 / 2);
+            };
+
+            cache::clean_invalidate_dcache_range(buf.as_ptr() as usize, buf.len() * 8);
+            match (perf::measure(EVENTS, &sum), perf::measure(EVENTS, &sum)) {
+                (Ok(cold), Ok(warm)) => {
+                    println!("[24] Summing 16 KiB, cold cache: {}", cold);
+                    println!("[24] Summing 16 KiB, warm cache: {}", warm);
+                }
+                (Err(e), _) | (_, Err(e)) => println!("[24] No PMU event counts: {:?}", e),
+            }
+        }
+
+        //------------------------------------------------------------
+        // Run a program at EL0, and one that crashes there
+        //------------------------------------------------------------
+        for (name, program) in &[("hello", user::hello()), ("crash", user::crash())] {
+            match user::run(program) {
+                Ok(outcome) => println!("[25] User program {}: {:?}", name, outcome),
+                Err(e) => println!("[25][Error] User program {} did not run: {:?}", name, e),
+            }
+        }
+    }
+
+    // The test kernel only gets here if booting failed before the tests
+    if cfg!(feature = "test_kernel") {
+        qemu::qemu_exit(false);
     }
 
     //------------------------------------------------------------
-    // Start a command prompt
+    // Sum up the hardware, and drop into the debug shell
     //------------------------------------------------------------
-    CONSOLE.lock(|c| {
-        c.command_prompt();
-    })
+    banner::print();
+    shell::run()
 }
 
 raspi3_boot::entry!(kernel_entry);
+raspi3_boot::secondary_entry!(smp::secondary_main);
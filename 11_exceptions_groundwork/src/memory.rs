@@ -22,37 +22,67 @@
  * SOFTWARE.
  */
 
-use crate::println;
+use crate::devices::hw::videocore_mbox::{
+    self, ArmRegion, VcRegion, VideocoreMbox, VideocoreMboxError,
+};
+use crate::{dtb, log};
+use core::cmp;
 use core::fmt;
 use core::ops::RangeInclusive;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
-mod bump_allocator;
-pub use bump_allocator::BumpAllocator;
+mod dma_pool;
+pub use dma_pool::{dma_alloc, dma_free, DmaRegion};
+
+pub mod heap;
+
+pub mod mmio_base;
 
 pub mod mmu;
 
 /// System memory map.
 #[rustfmt::skip]
 pub mod map {
-    pub const START:                   usize =             0x0000_0000;
-    pub const END:                     usize =             0x3FFF_FFFF;
-
+    /// Physical address 0 in the kernel's half of the virtual address space.
+    pub const KERNEL_OFFSET:           usize = raspi3_boot::higher_half::KERNEL_OFFSET;
+
+    pub const START:                   usize = KERNEL_OFFSET + 0x0000_0000;
+    /// All four GiB, so that the peripherals can be mapped wherever
+    /// `mmio_base` finds them. SDRAM above the first GiB, which only the
+    /// larger Pi 4 models have, is not described by the layout and stays
+    /// unmapped, like everything else that the layout does not describe.
+    pub const END:                     usize = KERNEL_OFFSET + 0xFFFF_FFFF;
+
+    /// The peripherals at their Pi 3 addresses, or the Pi 4 ones with the
+    /// `rpi4` feature. See `memory::mmio_base` for other boards.
     pub mod physical {
-        pub const MMIO_BASE:           usize =             0x3F00_0000;
-        pub const VIDEOCORE_MBOX_BASE: usize = MMIO_BASE + 0x0000_B880;
-        pub const GPIO_BASE:           usize = MMIO_BASE + 0x0020_0000;
-        pub const PL011_UART_BASE:     usize = MMIO_BASE + 0x0020_1000;
-        pub const MINI_UART_BASE:      usize = MMIO_BASE + 0x0021_5000;
-        pub const MMIO_END:            usize =             super::END;
+        // The peripherals themselves are in raspi3_hal::mmio::Peripheral.
+        pub use raspi3_hal::mmio::{LOCAL_CTRL_BASE, MMIO_BASE};
+
+        #[cfg(not(feature = "rpi4"))]
+        pub const MMIO_END:            usize =             0x3FFF_FFFF;
+        #[cfg(feature = "rpi4")]
+        pub const MMIO_END:            usize =             0xFF7F_FFFF;
+
+        /// Where the Videocore's share of the SDRAM ends, the latest.
+        #[cfg(not(feature = "rpi4"))]
+        pub const VC_SDRAM_END:        usize = MMIO_BASE - 1;
+        #[cfg(feature = "rpi4")]
+        pub const VC_SDRAM_END:        usize =             0x3FFF_FFFF;
+
+        // ARM local peripherals (core timers, core interrupt routing, ...).
+        #[cfg(not(feature = "rpi4"))]
+        pub const LOCAL_CTRL_END:      usize =             0x4003_FFFF;
+        #[cfg(feature = "rpi4")]
+        pub const LOCAL_CTRL_END:      usize =             0xFFFF_FFFF;
     }
 
     pub mod virt {
-        pub const KERN_STACK_START:    usize =             super::START;
-        pub const KERN_STACK_END:      usize =             0x0007_FFFF;
+        use super::START;
 
         // The second 2 MiB block.
-        pub const DMA_HEAP_START:      usize =             0x0020_0000;
-        pub const DMA_HEAP_END:        usize =             0x005F_FFFF;
+        pub const DMA_HEAP_START:      usize =     START + 0x0020_0000;
+        pub const DMA_HEAP_END:        usize =     START + 0x005F_FFFF;
     }
 }
 
@@ -77,7 +107,9 @@ pub mod kernel_mem_range {
     #[allow(dead_code)]
     #[derive(Copy, Clone)]
     pub enum Translation {
-        Identity,
+        /// The output address is `virt_to_phys()` of the virtual one.
+        Linear,
+        /// The range starts at the given output address.
         Offset(usize),
     }
 
@@ -108,39 +140,46 @@ pub mod kernel_mem_range {
 
 use kernel_mem_range::*;
 
+/// The exclusive end of the ARM's share of the SDRAM. Everything from here up
+/// to the MMIO base belongs to the Videocore.
+///
+/// Until `memory_map()` asked the firmware, all SDRAM below the MMIO base is
+/// assumed to belong to the ARM.
+static ARM_MEMORY_END: AtomicUsize = AtomicUsize::new(map::physical::MMIO_BASE);
+
+/// The virtual address range of the kernel heap, empty until `memory_map()`
+/// found the RAM for it.
+static HEAP_START: AtomicUsize = AtomicUsize::new(1);
+static HEAP_END: AtomicUsize = AtomicUsize::new(0);
+
+/// Name of the heap's region in the kernel memory layout, see `region()`.
+pub const HEAP: &str = "Kernel heap";
+
+/// The names of the regions that hold the stacks of the cores, see
+/// `stack_region()`.
+const STACKS: [&str; 3] = ["Kernel stack", "Exception stack", "Secondary core stacks"];
+
 /// A virtual memory layout that is agnostic of the paging granularity that the
 /// hardware MMU will use.
 ///
 /// Contains only special ranges, aka anything that is _not_ normal cacheable
 /// DRAM.
-static KERNEL_VIRTUAL_LAYOUT: [Descriptor; 5] = [
+static KERNEL_VIRTUAL_LAYOUT: [Descriptor; 12] = [
     Descriptor {
-        name: "Kernel stack",
+        name: "Kernel code",
         virtual_range: || {
-            RangeInclusive::new(map::virt::KERN_STACK_START, map::virt::KERN_STACK_END)
-        },
-        translation: Translation::Identity,
-        attribute_fields: AttributeFields {
-            mem_attributes: MemAttributes::CacheableDRAM,
-            acc_perms: AccessPermissions::ReadWrite,
-            execute_never: true,
-        },
-    },
-    Descriptor {
-        name: "Kernel code and RO data",
-        virtual_range: || {
-            // Using the linker script, we ensure that the RO area is consecutive and 4
+            // Using the linker script, we ensure that the code is consecutive and 4
             // KiB aligned, and we export the boundaries via symbols:
             //
-            // [__ro_start, __ro_end)
+            // [__ro_start, __text_end)
             extern "C" {
-                // The inclusive start of the read-only area, aka the address of the
+                // The inclusive start of the code, aka the address of the
                 // first byte of the area.
                 static __ro_start: u64;
 
-                // The exclusive end of the read-only area, aka the address of
-                // the first byte _after_ the RO area.
-                static __ro_end: u64;
+                // The exclusive end of the code, aka the address of the first
+                // byte _after_ it.
+                static __text_end: u64;
             }
 
             unsafe {
@@ -148,11 +187,11 @@ static KERNEL_VIRTUAL_LAYOUT: [Descriptor; 5] = [
                 // inclusive end
                 RangeInclusive::new(
                     &__ro_start as *const _ as usize,
-                    &__ro_end as *const _ as usize - 1,
+                    &__text_end as *const _ as usize - 1,
                 )
             }
         },
-        translation: Translation::Identity,
+        translation: Translation::Linear,
         attribute_fields: AttributeFields {
             mem_attributes: MemAttributes::CacheableDRAM,
             acc_perms: AccessPermissions::ReadOnly,
@@ -160,21 +199,131 @@ static KERNEL_VIRTUAL_LAYOUT: [Descriptor; 5] = [
         },
     },
     Descriptor {
-        name: "Kernel data and BSS",
+        name: "Kernel RO data",
         virtual_range: || {
             extern "C" {
+                static __rodata_start: u64;
                 static __ro_end: u64;
+            }
+
+            unsafe {
+                RangeInclusive::new(
+                    &__rodata_start as *const _ as usize,
+                    &__ro_end as *const _ as usize - 1,
+                )
+            }
+        },
+        translation: Translation::Linear,
+        attribute_fields: AttributeFields {
+            mem_attributes: MemAttributes::CacheableDRAM,
+            acc_perms: AccessPermissions::ReadOnly,
+            execute_never: true,
+        },
+    },
+    Descriptor {
+        name: "Kernel data and BSS",
+        virtual_range: || {
+            extern "C" {
+                static __data_start: u64;
                 static __bss_end: u64;
             }
 
             unsafe {
                 RangeInclusive::new(
-                    &__ro_end as *const _ as usize,
+                    &__data_start as *const _ as usize,
                     &__bss_end as *const _ as usize - 1,
                 )
             }
         },
-        translation: Translation::Identity,
+        translation: Translation::Linear,
+        attribute_fields: AttributeFields {
+            mem_attributes: MemAttributes::CacheableDRAM,
+            acc_perms: AccessPermissions::ReadWrite,
+            execute_never: true,
+        },
+    },
+    Descriptor {
+        name: "Page table pool",
+        virtual_range: || {
+            extern "C" {
+                static __page_table_pool_start: u64;
+                static __page_table_pool_end: u64;
+            }
+
+            unsafe {
+                RangeInclusive::new(
+                    &__page_table_pool_start as *const _ as usize,
+                    &__page_table_pool_end as *const _ as usize - 1,
+                )
+            }
+        },
+        translation: Translation::Linear,
+        attribute_fields: AttributeFields {
+            mem_attributes: MemAttributes::CacheableDRAM,
+            acc_perms: AccessPermissions::ReadWrite,
+            execute_never: true,
+        },
+    },
+    Descriptor {
+        name: "Kernel stack",
+        virtual_range: || {
+            extern "C" {
+                static __kernel_stack_start: u64;
+                static __kernel_stack_end: u64;
+            }
+
+            unsafe {
+                RangeInclusive::new(
+                    &__kernel_stack_start as *const _ as usize,
+                    &__kernel_stack_end as *const _ as usize - 1,
+                )
+            }
+        },
+        translation: Translation::Linear,
+        attribute_fields: AttributeFields {
+            mem_attributes: MemAttributes::CacheableDRAM,
+            acc_perms: AccessPermissions::ReadWrite,
+            execute_never: true,
+        },
+    },
+    Descriptor {
+        name: "Exception stack",
+        virtual_range: || {
+            extern "C" {
+                static __exception_stack_start: u64;
+                static __exception_stack_end: u64;
+            }
+
+            unsafe {
+                RangeInclusive::new(
+                    &__exception_stack_start as *const _ as usize,
+                    &__exception_stack_end as *const _ as usize - 1,
+                )
+            }
+        },
+        translation: Translation::Linear,
+        attribute_fields: AttributeFields {
+            mem_attributes: MemAttributes::CacheableDRAM,
+            acc_perms: AccessPermissions::ReadWrite,
+            execute_never: true,
+        },
+    },
+    Descriptor {
+        name: "Secondary core stacks",
+        virtual_range: || {
+            extern "C" {
+                static __secondary_stacks_start: u64;
+                static __secondary_exception_stacks_end: u64;
+            }
+
+            unsafe {
+                RangeInclusive::new(
+                    &__secondary_stacks_start as *const _ as usize,
+                    &__secondary_exception_stacks_end as *const _ as usize - 1,
+                )
+            }
+        },
+        translation: Translation::Linear,
         attribute_fields: AttributeFields {
             mem_attributes: MemAttributes::CacheableDRAM,
             acc_perms: AccessPermissions::ReadWrite,
@@ -184,7 +333,37 @@ static KERNEL_VIRTUAL_LAYOUT: [Descriptor; 5] = [
     Descriptor {
         name: "DMA heap pool",
         virtual_range: || RangeInclusive::new(map::virt::DMA_HEAP_START, map::virt::DMA_HEAP_END),
-        translation: Translation::Identity,
+        translation: Translation::Linear,
+        attribute_fields: AttributeFields {
+            mem_attributes: MemAttributes::NonCacheableDRAM,
+            acc_perms: AccessPermissions::ReadWrite,
+            execute_never: true,
+        },
+    },
+    Descriptor {
+        name: HEAP,
+        virtual_range: || {
+            RangeInclusive::new(
+                HEAP_START.load(Ordering::Relaxed),
+                HEAP_END.load(Ordering::Relaxed),
+            )
+        },
+        translation: Translation::Linear,
+        attribute_fields: AttributeFields {
+            mem_attributes: MemAttributes::CacheableDRAM,
+            acc_perms: AccessPermissions::ReadWrite,
+            execute_never: true,
+        },
+    },
+    Descriptor {
+        name: "Videocore SDRAM",
+        virtual_range: || {
+            RangeInclusive::new(
+                phys_to_virt(ARM_MEMORY_END.load(Ordering::Relaxed)),
+                phys_to_virt(cmp::min(map::physical::VC_SDRAM_END, mmio_base::base() - 1)),
+            )
+        },
+        translation: Translation::Linear,
         attribute_fields: AttributeFields {
             mem_attributes: MemAttributes::NonCacheableDRAM,
             acc_perms: AccessPermissions::ReadWrite,
@@ -193,8 +372,28 @@ static KERNEL_VIRTUAL_LAYOUT: [Descriptor; 5] = [
     },
     Descriptor {
         name: "Device MMIO",
-        virtual_range: || RangeInclusive::new(map::physical::MMIO_BASE, map::physical::MMIO_END),
-        translation: Translation::Identity,
+        virtual_range: || {
+            RangeInclusive::new(
+                phys_to_virt(mmio_base::base()),
+                phys_to_virt(mmio_base::rebase(map::physical::MMIO_END)),
+            )
+        },
+        translation: Translation::Linear,
+        attribute_fields: AttributeFields {
+            mem_attributes: MemAttributes::Device,
+            acc_perms: AccessPermissions::ReadWrite,
+            execute_never: true,
+        },
+    },
+    Descriptor {
+        name: "Local peripherals MMIO",
+        virtual_range: || {
+            RangeInclusive::new(
+                phys_to_virt(map::physical::LOCAL_CTRL_BASE),
+                phys_to_virt(map::physical::LOCAL_CTRL_END),
+            )
+        },
+        translation: Translation::Linear,
         attribute_fields: AttributeFields {
             mem_attributes: MemAttributes::Device,
             acc_perms: AccessPermissions::ReadWrite,
@@ -203,38 +402,112 @@ static KERNEL_VIRTUAL_LAYOUT: [Descriptor; 5] = [
     },
 ];
 
+/// A region of the kernel memory layout, with virtual addresses
+#[derive(Copy, Clone)]
+pub struct Region {
+    pub name: &'static str,
+    pub start: usize,
+    /// Inclusive
+    pub end: usize,
+    /// The output address of `start`
+    pub phys: usize,
+    pub attrs: AttributeFields,
+}
+
+impl Region {
+    pub fn size(&self) -> usize {
+        self.end - self.start + 1
+    }
+
+    pub fn contains(&self, addr: usize) -> bool {
+        self.start <= addr && addr <= self.end
+    }
+}
+
+/// The special regions of the kernel memory layout, i.e. everything that is
+/// not plain cacheable DRAM, plus the heap.
+///
+/// Regions that are empty right now, like the heap before `memory_map()`, are
+/// left out. The MMU code builds the page tables from this, so it is the one
+/// place that knows where what lives.
+pub fn layout() -> impl Iterator<Item = Region> {
+    KERNEL_VIRTUAL_LAYOUT.iter().filter_map(|d| {
+        let range = (d.virtual_range)();
+        let (start, end) = (*range.start(), *range.end());
+        if start > end {
+            return None;
+        }
+
+        Some(Region {
+            name: d.name,
+            start,
+            end,
+            phys: match d.translation {
+                Translation::Linear => virt_to_phys(start),
+                Translation::Offset(a) => a,
+            },
+            attrs: d.attribute_fields,
+        })
+    })
+}
+
+/// The region of the layout with the given name, if it is not empty.
+pub fn region(name: &str) -> Option<Region> {
+    layout().find(|r| r.name == name)
+}
+
+/// The region of the core stacks that `addr` is in, if any. The stacks of
+/// tasks are not in the layout, see `task::current_stack()`.
+pub fn stack_region(addr: usize) -> Option<Region> {
+    layout().find(|r| STACKS.contains(&r.name) && r.contains(addr))
+}
+
 /// For a given virtual address, find and return the output address and
 /// according attributes.
 ///
-/// If the address is not covered in VIRTUAL_LAYOUT, return a default for normal
+/// If the address is not covered by `layout()`, return a default for normal
 /// cacheable DRAM.
 fn get_virt_addr_properties(virt_addr: usize) -> Result<(usize, AttributeFields), &'static str> {
-    if virt_addr > map::END {
+    if virt_addr < map::START || virt_addr > map::END {
         return Err("Address out of range.");
     }
 
-    for i in KERNEL_VIRTUAL_LAYOUT.iter() {
-        if (i.virtual_range)().contains(&virt_addr) {
-            let output_addr = match i.translation {
-                Translation::Identity => virt_addr,
-                Translation::Offset(a) => a + (virt_addr - (i.virtual_range)().start()),
-            };
+    match layout().find(|r| r.contains(virt_addr)) {
+        Some(r) => Ok((r.phys + (virt_addr - r.start), r.attrs)),
+        None => Ok((virt_to_phys(virt_addr), AttributeFields::default())),
+    }
+}
+
+/// The inclusive end of the run of 4 KiB pages, starting with the page at
+/// `virt_addr`, that all have the same properties.
+///
+/// Like for `get_virt_addr_properties()`, the properties of a page are those
+/// of its first byte.
+fn layout_segment_end(virt_addr: usize) -> usize {
+    let mut end = map::END;
+
+    for r in layout() {
+        // The first and the last page that start inside the region
+        let first = aligned_addr_unchecked(r.start, mmu::FOUR_KIB);
+        let last = r.end & !(mmu::FOUR_KIB - 1);
+        if first > last {
+            continue;
+        }
 
-            return Ok((output_addr, i.attribute_fields));
+        if first > virt_addr {
+            end = cmp::min(end, first - 1);
+        } else if last >= virt_addr {
+            end = cmp::min(end, last + mmu::FOUR_KIB - 1);
         }
     }
 
-    Ok((virt_addr, AttributeFields::default()))
+    end
 }
 
-/// Human-readable output of a Descriptor.
-impl fmt::Display for Descriptor {
+/// Human-readable output of a Region.
+impl fmt::Display for Region {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        // Call the function to which self.range points, and dereference the
-        // result, which causes Rust to copy the value.
-        let start = *(self.virtual_range)().start();
-        let end = *(self.virtual_range)().end();
-        let size = end - start + 1;
+        let size = self.size();
 
         // log2(1024)
         const KIB_RSHIFT: u32 = 10;
@@ -250,18 +523,18 @@ impl fmt::Display for Descriptor {
             (size, "Byte")
         };
 
-        let attr = match self.attribute_fields.mem_attributes {
+        let attr = match self.attrs.mem_attributes {
             MemAttributes::CacheableDRAM => "C",
             MemAttributes::NonCacheableDRAM => "NC",
             MemAttributes::Device => "Dev",
         };
 
-        let acc_p = match self.attribute_fields.acc_perms {
+        let acc_p = match self.attrs.acc_perms {
             AccessPermissions::ReadOnly => "RO",
             AccessPermissions::ReadWrite => "RW",
         };
 
-        let xn = if self.attribute_fields.execute_never {
+        let xn = if self.attrs.execute_never {
             "PXN"
         } else {
             "PX"
@@ -269,18 +542,106 @@ impl fmt::Display for Descriptor {
 
         write!(
             f,
-            "      {:#010X} - {:#010X} | {: >3} {} | {: <3} {} {: <3} | {}",
-            start, end, size, unit, attr, acc_p, xn, self.name
+            "      {:#018X} - {:#018X} | {: >3} {: <4} | {: <3} {} {: <3} | {}",
+            self.start, self.end, size, unit, attr, acc_p, xn, self.name
         )
     }
 }
 
-/// Print the kernel memory layout.
-pub fn print_layout() {
-    println!("[i] Kernel memory layout:");
+/// The name of the region in the kernel memory layout that `addr` lies in.
+pub fn region_name(addr: usize) -> &'static str {
+    if addr < map::START || addr > map::END {
+        return "Unmapped";
+    }
+
+    layout()
+        .find(|r| r.contains(addr))
+        .map_or("Normal DRAM", |r| r.name)
+}
+
+/// Print the kernel memory layout as a table, at the given log level.
+pub fn print_layout(level: log::Level) {
+    log!(level, "Kernel memory layout:");
+
+    for r in layout() {
+        log!(level, "{}", r);
+    }
+}
+
+/// The physical RAM layout, as reported by the firmware
+pub struct MemoryMap {
+    pub arm: ArmRegion,
+    pub vc: VcRegion,
+}
+
+/// Ask the firmware where the Videocore's share of the SDRAM starts, and give
+/// the RAM between the kernel image and it to the heap.
+///
+/// From now on, the Videocore's share and the heap are part of the kernel
+/// memory layout. Call `mmu::reload()` afterwards to map the former
+/// non-cacheable.
+pub fn memory_map(v_mbox: &mut VideocoreMbox) -> Result<MemoryMap, VideocoreMboxError> {
+    extern "C" {
+        // The exclusive end of the kernel image, aka the address of the first
+        // byte after the BSS.
+        static __kernel_end: u64;
+    }
+
+    let (mut arm, vc) = videocore_mbox::memory_split(v_mbox)?;
+
+    // The device tree describes the ARM's RAM as well, and takes precedence
+    // if the firmware passed one.
+    if let Some(region) = dtb::memory_regions().and_then(|mut regions| regions.next()) {
+        arm = region;
+    }
+
+    // The MMU maps this part of the address space in 2 MiB blocks, so round
+    // down to the block that the Videocore's share starts in.
+    let arm_end = (arm.base + arm.size) & !(mmu::TWO_MIB - 1);
+    ARM_MEMORY_END.store(arm_end, Ordering::Relaxed);
+
+    let kernel_end = unsafe { &__kernel_end as *const _ as usize };
+    let free_start = aligned_addr_unchecked(
+        virt_to_phys(cmp::max(kernel_end, map::virt::DMA_HEAP_END + 1)),
+        mmu::FOUR_KIB,
+    );
+
+    HEAP_START.store(phys_to_virt(free_start), Ordering::Relaxed);
+    HEAP_END.store(phys_to_virt(arm_end) - 1, Ordering::Relaxed);
+
+    Ok(MemoryMap { arm, vc })
+}
+
+/// The physical address behind `virt` in the kernel's half of the address
+/// space.
+#[inline]
+pub fn virt_to_phys(virt: usize) -> usize {
+    virt - map::KERNEL_OFFSET
+}
+
+/// The virtual address in the kernel's half of the address space under which
+/// the physical address `phys` is mapped.
+#[inline]
+pub fn phys_to_virt(phys: usize) -> usize {
+    phys + map::KERNEL_OFFSET
+}
+
+/// The address under which the MMIO register at the physical address `phys`
+/// can be accessed.
+///
+/// `phys` is moved to the detected peripheral base first, see `mmio_base`.
+/// That is the physical address itself while the MMU is off, and its alias in
+/// the kernel's half of the address space once it is on. It is installed as
+/// the map of `raspi3_hal::mmio::base()`, which all drivers go through, so it
+/// is the one place that decides where the MMIO is.
+#[inline]
+pub fn map_mmio(phys: usize) -> usize {
+    let phys = mmio_base::rebase(phys);
 
-    for i in KERNEL_VIRTUAL_LAYOUT.iter() {
-        println!("{}", i);
+    if mmu::mmu_enabled() {
+        phys_to_virt(phys)
+    } else {
+        phys
     }
 }
 
@@ -0,0 +1,228 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+//! A pool of non-cacheable memory for DMA.
+//!
+//! Devices that access memory on their own, like the DMA engine or the
+//! Videocore, do not see the ARM's data cache. Buffers and control blocks that
+//! are shared with them are taken from the "DMA heap pool" of the kernel memory
+//! layout, which is mapped as normal non-cacheable memory, so that they need no
+//! cache maintenance.
+//!
+//! The free memory is kept in a small list of blocks, sorted by address.
+//! Neighbouring blocks are merged again when a region is freed.
+
+use super::{map, virt_to_phys};
+use crate::sync::NullLock;
+use core::ptr;
+
+/// Sizes and alignments are rounded up to this. It is the cache line size of
+/// the Cortex-A53, and covers the alignment of the DMA engine's control blocks.
+const GRANULE: usize = 64;
+
+/// The number of free blocks that the pool can track.
+const MAX_FREE_BLOCKS: usize = 32;
+
+/// The first GiB of SDRAM, as seen uncached by the Videocore and the DMA
+/// engine.
+const BUS_ALIAS_UNCACHED: u32 = 0xC000_0000;
+
+/// A region of DMA memory.
+///
+/// Must be given back with `dma_free()`, otherwise it is leaked.
+pub struct DmaRegion {
+    virt: usize,
+    /// Rounded up to the pool's granule
+    len: usize,
+}
+
+impl DmaRegion {
+    /// The address under which the CPU accesses the region.
+    pub fn virt_addr(&self) -> usize {
+        self.virt
+    }
+
+    /// The address under which devices access the region.
+    pub fn bus_addr(&self) -> u32 {
+        // The pool lies in the first GiB, see memory::map::virt.
+        virt_to_phys(self.virt) as u32 | BUS_ALIAS_UNCACHED
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Block {
+    start: usize,
+    size: usize,
+}
+
+const EMPTY_BLOCK: Block = Block { start: 0, size: 0 };
+
+struct FreeList {
+    blocks: [Block; MAX_FREE_BLOCKS],
+    len: usize,
+    initialized: bool,
+}
+
+static FREE_LIST: NullLock<FreeList> = NullLock::new(FreeList {
+    blocks: [EMPTY_BLOCK; MAX_FREE_BLOCKS],
+    len: 0,
+    initialized: false,
+});
+
+#[inline]
+fn round_up(value: usize, alignment: usize) -> usize {
+    (value + (alignment - 1)) & !(alignment - 1)
+}
+
+impl FreeList {
+    /// Initially, the whole pool is one free block.
+    fn init_once(&mut self) {
+        if !self.initialized {
+            let start = map::virt::DMA_HEAP_START;
+
+            self.blocks[0] = Block {
+                start,
+                size: map::virt::DMA_HEAP_END + 1 - start,
+            };
+            self.len = 1;
+            self.initialized = true;
+        }
+    }
+
+    fn insert(&mut self, index: usize, block: Block) {
+        for i in (index..self.len).rev() {
+            self.blocks[i + 1] = self.blocks[i];
+        }
+        self.blocks[index] = block;
+        self.len += 1;
+    }
+
+    fn remove(&mut self, index: usize) {
+        for i in index..self.len - 1 {
+            self.blocks[i] = self.blocks[i + 1];
+        }
+        self.len -= 1;
+    }
+
+    /// First fit. The parts of the block in front of and behind the region
+    /// stay in the list.
+    fn alloc(&mut self, len: usize, align: usize) -> Option<usize> {
+        for i in 0..self.len {
+            let block = self.blocks[i];
+            let start = round_up(block.start, align);
+            let end = block.start + block.size;
+
+            if start + len > end {
+                continue;
+            }
+
+            let front = Block {
+                start: block.start,
+                size: start - block.start,
+            };
+            let back = Block {
+                start: start + len,
+                size: end - (start + len),
+            };
+
+            // Splitting a block in the middle needs an additional entry
+            if front.size > 0 && back.size > 0 && self.len == MAX_FREE_BLOCKS {
+                continue;
+            }
+
+            self.remove(i);
+            if back.size > 0 {
+                self.insert(i, back);
+            }
+            if front.size > 0 {
+                self.insert(i, front);
+            }
+
+            return Some(start);
+        }
+
+        None
+    }
+
+    fn free(&mut self, start: usize, len: usize) {
+        // The first block behind the region
+        let next = self.blocks[..self.len]
+            .iter()
+            .position(|b| b.start > start)
+            .unwrap_or(self.len);
+
+        let merges_prev = next > 0 && {
+            let prev = self.blocks[next - 1];
+            prev.start + prev.size == start
+        };
+        let merges_next = next < self.len && start + len == self.blocks[next].start;
+
+        match (merges_prev, merges_next) {
+            (true, true) => {
+                self.blocks[next - 1].size += len + self.blocks[next].size;
+                self.remove(next);
+            }
+            (true, false) => self.blocks[next - 1].size += len,
+            (false, true) => {
+                self.blocks[next].start = start;
+                self.blocks[next].size += len;
+            }
+            (false, false) => {
+                // With the list full, the region is lost
+                if self.len < MAX_FREE_BLOCKS {
+                    self.insert(next, Block { start, size: len });
+                }
+            }
+        }
+    }
+}
+
+/// Allocate `len` bytes of zeroed DMA memory, aligned to `align`, which must
+/// be a power of two.
+///
+/// Returns `None` if the pool has no large enough block left.
+pub fn dma_alloc(len: usize, align: usize) -> Option<DmaRegion> {
+    if len == 0 || !align.is_power_of_two() {
+        return None;
+    }
+
+    let len = round_up(len, GRANULE);
+    let align = if align > GRANULE { align } else { GRANULE };
+
+    let virt = FREE_LIST.lock(|list| {
+        list.init_once();
+        list.alloc(len, align)
+    })?;
+
+    unsafe { ptr::write_bytes(virt as *mut u8, 0, len) };
+
+    Some(DmaRegion { virt, len })
+}
+
+/// Give a region back to the pool.
+pub fn dma_free(region: DmaRegion) {
+    FREE_LIST.lock(|list| {
+        list.init_once();
+        list.free(region.virt, region.len)
+    });
+}
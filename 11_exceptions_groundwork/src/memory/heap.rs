@@ -0,0 +1,315 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! The kernel heap, behind `#[global_allocator]`.
+//!
+//! It takes the RAM between the end of the kernel image and the start of the
+//! Videocore's share, which `memory_map()` puts into the kernel memory layout. Free blocks are kept in a
+//! singly linked list, sorted by address, whose nodes live in the free blocks
+//! themselves. Allocation is first fit and splits the block it takes from.
+//! Freeing merges the region with its free neighbours, so the list does not
+//! fragment into ever smaller blocks.
+//!
+//! All sizes and addresses are multiples of `MIN_BLOCK`, which is large enough
+//! for a node. Splitting a block therefore never leaves a remainder that is
+//! too small to be put back into the list.
+
+use crate::{memory, rand::Rng, sync::SpinLock};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    mem, ptr,
+};
+
+/// A free block. Stored in its first bytes.
+struct Node {
+    size: usize,
+    next: *mut Node,
+}
+
+/// The smallest block, and the granule of all sizes and addresses
+const MIN_BLOCK: usize = 16;
+
+#[inline]
+fn round_up(value: usize, alignment: usize) -> usize {
+    (value + (alignment - 1)) & !(alignment - 1)
+}
+
+/// The block size and alignment actually used for `layout`
+fn block_layout(layout: &Layout) -> Option<(usize, usize)> {
+    let size = layout.size().max(1).checked_add(MIN_BLOCK - 1)? & !(MIN_BLOCK - 1);
+
+    Some((size, layout.align().max(MIN_BLOCK)))
+}
+
+struct FreeList {
+    /// A node without memory of its own, in front of the first free block
+    head: Node,
+    /// The bytes that the heap was initialized with
+    total: usize,
+}
+
+// The nodes are only accessed with the lock held.
+unsafe impl Send for FreeList {}
+
+impl FreeList {
+    /// Put the block at `start` into the list, merged with its neighbours if
+    /// it touches them.
+    unsafe fn insert(&mut self, start: usize, size: usize) {
+        // The last node in front of the block
+        let mut prev: *mut Node = &mut self.head;
+        while !(*prev).next.is_null() && ((*prev).next as usize) < start {
+            prev = (*prev).next;
+        }
+
+        let next = (*prev).next;
+        let prev_is_head = prev == &mut self.head as *mut Node;
+        debug_assert!(next.is_null() || start + size <= next as usize, "heap: double free");
+        debug_assert!(prev_is_head || prev as usize + (*prev).size <= start, "heap: double free");
+
+        let node = start as *mut Node;
+        ptr::write(node, Node { size, next });
+
+        if !next.is_null() && start + size == next as usize {
+            (*node).size += (*next).size;
+            (*node).next = (*next).next;
+        }
+
+        if !prev_is_head && prev as usize + (*prev).size == start {
+            (*prev).size += (*node).size;
+            (*prev).next = (*node).next;
+        } else {
+            (*prev).next = node;
+        }
+    }
+
+    /// First fit
+    unsafe fn alloc(&mut self, size: usize, align: usize) -> *mut u8 {
+        let mut prev: *mut Node = &mut self.head;
+
+        while !(*prev).next.is_null() {
+            let node = (*prev).next;
+            let start = node as usize;
+            let end = start + (*node).size;
+            let alloc_start = round_up(start, align);
+
+            match alloc_start.checked_add(size) {
+                Some(alloc_end) if alloc_end <= end => {
+                    // Unlink the block, and put back what is left in front of and
+                    // behind the allocation.
+                    (*prev).next = (*node).next;
+
+                    if end > alloc_end {
+                        self.insert(alloc_end, end - alloc_end);
+                    }
+                    if alloc_start > start {
+                        self.insert(start, alloc_start - start);
+                    }
+
+                    return alloc_start as *mut u8;
+                }
+                _ => prev = node,
+            }
+        }
+
+        ptr::null_mut()
+    }
+
+    /// The free bytes, and the number of blocks they are spread over
+    fn usage(&self) -> (usize, usize) {
+        let mut free = 0;
+        let mut blocks = 0;
+        let mut node = self.head.next;
+
+        while !node.is_null() {
+            unsafe {
+                free += (*node).size;
+                node = (*node).next;
+            }
+            blocks += 1;
+        }
+
+        (free, blocks)
+    }
+}
+
+pub struct Heap(SpinLock<FreeList>);
+
+impl Heap {
+    pub const fn new() -> Heap {
+        Heap(SpinLock::new(FreeList {
+            head: Node {
+                size: 0,
+                next: ptr::null_mut(),
+            },
+            total: 0,
+        }))
+    }
+}
+
+unsafe impl GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match block_layout(&layout) {
+            Some((size, align)) => self.0.lock_irqsave(|list| list.alloc(size, align)),
+            None => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Some((size, _)) = block_layout(&layout) {
+            self.0.lock_irqsave(|list| list.insert(ptr as usize, size));
+        }
+    }
+}
+
+#[global_allocator]
+static HEAP: Heap = Heap::new();
+
+/// Hand the RAM of the heap's region in the kernel memory layout, see
+/// `memory::region()`, to the heap. Only the first call has an effect.
+///
+/// Until then, every allocation fails. So does this call if the region is
+/// still empty, i.e. `memory_map()` did not run yet.
+pub fn init() {
+    let region = match memory::region(memory::HEAP) {
+        Some(r) => r,
+        None => return,
+    };
+
+    let start = round_up(region.start, MIN_BLOCK);
+    let end = (region.end + 1) & !(MIN_BLOCK - 1);
+    if end <= start + mem::size_of::<Node>() {
+        return;
+    }
+
+    HEAP.0.lock_irqsave(|list| {
+        if list.total == 0 {
+            list.total = end - start;
+            unsafe { list.insert(start, end - start) };
+        }
+    });
+}
+
+/// The heap's size, its free bytes, and the number of free blocks
+pub fn usage() -> (usize, usize, usize) {
+    HEAP.0.lock_irqsave(|list| {
+        let (free, blocks) = list.usage();
+
+        (list.total, free, blocks)
+    })
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StressError {
+    /// An allocation of this many bytes failed
+    OutOfMemory(usize),
+    /// An allocation was not aligned as requested
+    Misaligned(usize),
+    /// The pattern written to the allocation at this address changed
+    Corrupted(usize),
+    /// Less memory was free after the test than before
+    Leaked(usize),
+    /// The free memory was split into more blocks than before
+    NotCoalesced(usize),
+}
+
+/// Allocate and free blocks of random sizes and alignments in random order,
+/// and check that none of them overlap, and that all memory comes back in one
+/// piece afterwards. The same seed of `rng` gives the same sequence.
+///
+/// Each block is filled with a pattern when it is allocated, which is checked
+/// before it is freed. Must not run concurrently with other users of the heap.
+pub fn stress_test(rounds: usize, rng: &mut Rng) -> Result<(), StressError> {
+    const SLOTS: usize = 64;
+
+    let mut slots: [Option<(usize, Layout, u8)>; SLOTS] = [None; SLOTS];
+    let (_, free_before, blocks_before) = usage();
+
+    let check = |addr: usize, len: usize, pattern: u8| {
+        let bytes = unsafe { core::slice::from_raw_parts(addr as *const u8, len) };
+        match bytes
+            .iter()
+            .enumerate()
+            .position(|(i, &b)| b != pattern.wrapping_add(i as u8))
+        {
+            Some(i) => Err(StressError::Corrupted(addr + i)),
+            None => Ok(()),
+        }
+    };
+
+    let mut result = Ok(());
+    for _ in 0..rounds {
+        let r = rng.next_u64();
+        let slot = (r % SLOTS as u64) as usize;
+
+        if let Some((addr, layout, pattern)) = slots[slot].take() {
+            result = check(addr, layout.size(), pattern);
+            unsafe { HEAP.dealloc(addr as *mut u8, layout) };
+        } else {
+            let size = 1 + ((r >> 8) % 4096) as usize;
+            let align = 1 << ((r >> 20) % 9);
+            let pattern = (r >> 32) as u8;
+
+            let layout = match Layout::from_size_align(size, align) {
+                Ok(layout) => layout,
+                Err(_) => continue,
+            };
+            let addr = unsafe { HEAP.alloc(layout) } as usize;
+
+            if addr == 0 {
+                result = Err(StressError::OutOfMemory(size));
+            } else {
+                for i in 0..size {
+                    unsafe { ptr::write((addr + i) as *mut u8, pattern.wrapping_add(i as u8)) };
+                }
+                slots[slot] = Some((addr, layout, pattern));
+
+                if addr % align != 0 {
+                    result = Err(StressError::Misaligned(addr));
+                }
+            }
+        }
+
+        if result.is_err() {
+            break;
+        }
+    }
+
+    for (addr, layout, pattern) in slots.iter_mut().filter_map(Option::take) {
+        if result.is_ok() {
+            result = check(addr, layout.size(), pattern);
+        }
+        unsafe { HEAP.dealloc(addr as *mut u8, layout) };
+    }
+    result?;
+
+    let (_, free_after, blocks_after) = usage();
+    if free_after < free_before {
+        return Err(StressError::Leaked(free_before - free_after));
+    }
+    if blocks_after > blocks_before {
+        return Err(StressError::NotCoalesced(blocks_after));
+    }
+
+    Ok(())
+}
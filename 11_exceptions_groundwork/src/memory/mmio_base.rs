@@ -0,0 +1,132 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Finding the peripherals at runtime.
+//!
+//! The addresses of `raspi3_hal::mmio::Peripheral` are the ones of the Pi 3,
+//! where the peripherals start at `0x3F00_0000`, or of the Pi 4 with the
+//! `rpi4` feature. Other SoCs put them elsewhere, so `map_mmio()` moves every
+//! address in that window to the base that was detected for the board we run
+//! on. The drivers never notice.
+//!
+//! `detect()` runs first thing in `kernel_entry()`, before any driver is
+//! touched, and picks the base by the part number of the CPU in MIDR_EL1. As
+//! soon as the mailbox works, `refine()` double-checks it against the SoC
+//! that the board revision names.
+//!
+//! The boot code picks its early page tables by MIDR_EL1 as well, see
+//! `raspi3_boot::higher_half`, and the kernel's own tables cover all four GiB,
+//! with the detected window as device memory. So every base of the tables
+//! below is mapped and used, including the BCM2835's `0x2000_0000`, which
+//! only a board revision can name, as that SoC cannot run AArch64.
+
+use super::map;
+use crate::cpu::regs::MIDR_EL1;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use register::cpu::RegisterReadOnly;
+
+/// The base, once `detect()` ran. Until then, the Pi 3's.
+static BASE: AtomicUsize = AtomicUsize::new(map::physical::MMIO_BASE);
+
+/// The peripheral base by the part number in MIDR_EL1. The Cortex-A7 runs
+/// in AArch32 only, it is in here for completeness.
+#[rustfmt::skip]
+const BY_PART: [(u64, &str, usize); 3] = [
+    (0xC07, "Cortex-A7 (BCM2836)",  0x3F00_0000),
+    (0xD03, "Cortex-A53 (BCM2837)", 0x3F00_0000),
+    (0xD08, "Cortex-A72 (BCM2711)", 0xFE00_0000),
+];
+
+/// The peripheral base by the processor field of new-style board revision
+/// codes.
+const BY_PROCESSOR: [usize; 4] = [0x2000_0000, 0x3F00_0000, 0x3F00_0000, 0xFE00_0000];
+
+#[derive(Debug)]
+pub enum MmioBaseError {
+    /// MIDR_EL1 or the board revision name an unknown CPU or SoC.
+    Unknown(u64),
+}
+pub type Result<T> = ::core::result::Result<T, MmioBaseError>;
+
+/// The physical address the peripherals start at.
+#[inline]
+pub fn base() -> usize {
+    BASE.load(Ordering::Relaxed)
+}
+
+/// Move `phys` from the Pi 3's peripheral window to the detected one.
+/// Addresses outside of the window, like the ARM local peripherals, stay
+/// unchanged.
+#[inline]
+pub fn rebase(phys: usize) -> usize {
+    if (map::physical::MMIO_BASE..=map::physical::MMIO_END).contains(&phys) {
+        phys - map::physical::MMIO_BASE + base()
+    } else {
+        phys
+    }
+}
+
+/// Switch to `new_base`.
+///
+/// Once the kernel's page tables are up, they must be reloaded for the new
+/// window to be mapped, see `memory::mmu::reload()`.
+fn set(new_base: usize) -> usize {
+    BASE.store(new_base, Ordering::Relaxed);
+
+    new_base
+}
+
+/// Pick the base by the CPU we run on. Returns the name of the CPU.
+///
+/// Must run on the boot core before any driver is used.
+pub fn detect() -> Result<&'static str> {
+    let part = (MIDR_EL1.get() >> 4) & 0xFFF;
+
+    match BY_PART.iter().find(|(p, _, _)| *p == part) {
+        Some((_, name, new_base)) => {
+            set(*new_base);
+
+            Ok(*name)
+        }
+        None => Err(MmioBaseError::Unknown(part)),
+    }
+}
+
+/// Check the base against the SoC named by the board revision code from the
+/// firmware, and switch to the SoC's base if they disagree.
+///
+/// Old-style revision codes are all BCM2835 boards, which cannot run this
+/// kernel, so only new-style codes are considered.
+pub fn refine(revision: u32) -> Result<usize> {
+    if revision & (1 << 23) == 0 {
+        return Err(MmioBaseError::Unknown(u64::from(revision)));
+    }
+
+    let processor = ((revision >> 12) & 0xF) as usize;
+    match BY_PROCESSOR.get(processor) {
+        Some(&new_base) if new_base == base() => Ok(new_base),
+        Some(&new_base) => Ok(set(new_base)),
+        None => Err(MmioBaseError::Unknown(u64::from(revision))),
+    }
+}
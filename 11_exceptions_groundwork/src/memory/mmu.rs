@@ -22,13 +22,28 @@
  * SOFTWARE.
  */
 
-use crate::memory::{get_virt_addr_properties, AttributeFields};
+use crate::{cache, smp, stack_guard};
+use crate::cpu::regs::TTBR1_EL1;
+use crate::memory::{
+    get_virt_addr_properties, layout_segment_end, map::KERNEL_OFFSET, phys_to_virt, virt_to_phys,
+    AccessPermissions, AttributeFields,
+};
+use core::{
+    ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 use cortex_a::{barrier, regs::*};
 use register::register_bitfields;
 
 register_bitfields! {u64,
     // AArch64 Reference Manual page 2150
     STAGE1_DESCRIPTOR [
+        /// Unprivileged execute-never
+        UXN      OFFSET(54) NUMBITS(1) [
+            False = 0,
+            True = 1
+        ],
+
         /// Privileged execute-never
         PXN      OFFSET(53) NUMBITS(1) [
             False = 0,
@@ -39,6 +54,12 @@ register_bitfields! {u64,
         LVL2_OUTPUT_ADDR_4KiB    OFFSET(21) NUMBITS(27) [], // [47:21]
         NEXT_LVL_TABLE_ADDR_4KiB OFFSET(12) NUMBITS(36) [], // [47:12]
 
+        /// Not global, i.e. the translation is tagged with the ASID
+        NG       OFFSET(11) NUMBITS(1) [
+            False = 0,
+            True = 1
+        ],
+
         /// Access flag
         AF       OFFSET(10) NUMBITS(1) [
             False = 0,
@@ -74,19 +95,34 @@ register_bitfields! {u64,
     ]
 }
 
-const FOUR_KIB: usize = 4 * 1024;
+pub const FOUR_KIB: usize = 4 * 1024;
 const FOUR_KIB_SHIFT: usize = 12; // log2(4 * 1024)
 
-const TWO_MIB: usize = 2 * 1024 * 1024;
+pub(super) const TWO_MIB: usize = 2 * 1024 * 1024;
 const TWO_MIB_SHIFT: usize = 21; // log2(2 * 1024 * 1024)
 
+#[derive(Debug)]
+pub enum MapError {
+    /// Addresses and sizes must be multiples of 4 KiB
+    Unaligned,
+    /// Outside of the kernel's address space covered by the LVL2 tables
+    OutOfRange,
+    /// Part of the region is already mapped differently
+    Conflict,
+    /// The page table pool is exhausted
+    OutOfTables,
+    /// Outside of the part of user space that the user tables cover
+    NotUserSpace,
+}
+pub type Result<T> = ::core::result::Result<T, MapError>;
+
 /// A descriptor pointing to the next page table.
 struct TableDescriptor(register::FieldValue<u64, STAGE1_DESCRIPTOR::Register>);
 
 impl TableDescriptor {
-    fn new(next_lvl_table_addr: usize) -> Result<TableDescriptor, &'static str> {
+    fn new(next_lvl_table_addr: usize) -> Result<TableDescriptor> {
         if next_lvl_table_addr % FOUR_KIB != 0 {
-            return Err("TableDescriptor: Address is not 4 KiB aligned.");
+            return Err(MapError::Unaligned);
         }
 
         let shifted = next_lvl_table_addr >> FOUR_KIB_SHIFT;
@@ -137,6 +173,10 @@ fn into_mmu_attributes(
         STAGE1_DESCRIPTOR::PXN::False
     };
 
+    // The access permissions keep EL0 away from kernel data, but not from
+    // executing kernel code
+    desc += STAGE1_DESCRIPTOR::UXN::True;
+
     desc
 }
 
@@ -146,12 +186,9 @@ fn into_mmu_attributes(
 struct Lvl2BlockDescriptor(register::FieldValue<u64, STAGE1_DESCRIPTOR::Register>);
 
 impl Lvl2BlockDescriptor {
-    fn new(
-        output_addr: usize,
-        attribute_fields: AttributeFields,
-    ) -> Result<Lvl2BlockDescriptor, &'static str> {
+    fn new(output_addr: usize, attribute_fields: AttributeFields) -> Result<Lvl2BlockDescriptor> {
         if output_addr % TWO_MIB != 0 {
-            return Err("BlockDescriptor: Address is not 2 MiB aligned.");
+            return Err(MapError::Unaligned);
         }
 
         let shifted = output_addr >> TWO_MIB_SHIFT;
@@ -176,12 +213,9 @@ impl Lvl2BlockDescriptor {
 struct PageDescriptor(register::FieldValue<u64, STAGE1_DESCRIPTOR::Register>);
 
 impl PageDescriptor {
-    fn new(
-        output_addr: usize,
-        attribute_fields: AttributeFields,
-    ) -> Result<PageDescriptor, &'static str> {
+    fn new(output_addr: usize, attribute_fields: AttributeFields) -> Result<PageDescriptor> {
         if output_addr % FOUR_KIB != 0 {
-            return Err("PageDescriptor: Address is not 4 KiB aligned.");
+            return Err(MapError::Unaligned);
         }
 
         let shifted = output_addr >> FOUR_KIB_SHIFT;
@@ -228,17 +262,14 @@ fn set_up_mair() {
 }
 
 trait BaseAddr {
-    fn base_addr_u64(&self) -> u64;
-    fn base_addr_usize(&self) -> usize;
+    fn phys_base_addr(&self) -> usize;
 }
 
 impl BaseAddr for [u64; 512] {
-    fn base_addr_u64(&self) -> u64 {
-        self as *const u64 as u64
-    }
-
-    fn base_addr_usize(&self) -> usize {
-        self as *const u64 as usize
+    /// The tables are statics in the kernel's half of the address space, but
+    /// descriptors and TTBR1 need their physical address.
+    fn phys_base_addr(&self) -> usize {
+        virt_to_phys(self as *const u64 as usize)
     }
 }
 
@@ -251,95 +282,440 @@ struct PageTable {
     entries: [u64; NUM_ENTRIES_4KIB],
 }
 
-/// The LVL2 page table containng the 2 MiB entries.
-static mut LVL2_TABLE: PageTable = PageTable {
+/// An empty page table, used for initializing the statics below.
+const EMPTY_TABLE: PageTable = PageTable {
     entries: [0; NUM_ENTRIES_4KIB],
 };
 
-/// The LVL3 page table containing the 4 KiB entries.
+/// Number of 1 GiB LVL1 entries needed to cover the kernel's address space.
 ///
-/// The first entry of the LVL2_TABLE will forward to this table.
-static mut LVL3_TABLE: PageTable = PageTable {
-    entries: [0; NUM_ENTRIES_4KIB],
-};
+/// On the Pi 3, the first GiB holds DRAM and the peripheral MMIO, the second
+/// GiB holds the ARM local peripherals starting at 0x4000_0000. On the Pi 4,
+/// the peripheral MMIO and the ARM local peripherals are at the end of the
+/// fourth GiB. Both builds cover all four, so that the peripherals can be
+/// mapped at whatever base `memory::mmio_base` detects.
+const NUM_LVL2_TABLES: usize = 4;
+
+/// The LVL0 page table containing the 512 GiB entries, of which only the first
+/// one is used. Walks of the 48 bit wide kernel half start here.
+static mut LVL0_TABLE: PageTable = EMPTY_TABLE;
+
+/// The LVL1 page table containing the 1 GiB entries.
+static mut LVL1_TABLE: PageTable = EMPTY_TABLE;
+
+/// The LVL2 page tables containing the 2 MiB entries. One table for each GiB
+/// of address space.
+static mut LVL2_TABLES: [PageTable; NUM_LVL2_TABLES] =
+    [EMPTY_TABLE, EMPTY_TABLE, EMPTY_TABLE, EMPTY_TABLE];
+
+/// Bits [47:12] of a descriptor, holding the output or next table address.
+const OUTPUT_ADDR_MASK: u64 = 0x0000_FFFF_FFFF_F000;
+
+/// Set in table and page descriptors, clear in block descriptors.
+const TYPE_TABLE_OR_PAGE: u64 = 0b10;
+
+/// Index of the next unused table in the page table pool.
+static NEXT_POOL_TABLE: AtomicUsize = AtomicUsize::new(0);
+
+/// Take a zeroed LVL3 table from the pool, which is reserved in the linker
+/// script.
+unsafe fn alloc_lvl3_table() -> Result<&'static mut PageTable> {
+    extern "C" {
+        static __page_table_pool_start: u64;
+        static __page_table_pool_end: u64;
+    }
+
+    let start = &__page_table_pool_start as *const _ as usize;
+    let end = &__page_table_pool_end as *const _ as usize;
+
+    let nr = NEXT_POOL_TABLE.load(Ordering::Relaxed);
+    let addr = start + nr * FOUR_KIB;
+    if addr >= end {
+        return Err(MapError::OutOfTables);
+    }
+    NEXT_POOL_TABLE.store(nr + 1, Ordering::Relaxed);
+
+    // The pool is not part of the BSS, so it is not zeroed at boot.
+    let table = &mut *(addr as *mut PageTable);
+    ptr::write_volatile(table, EMPTY_TABLE);
+
+    Ok(table)
+}
+
+fn is_valid(desc: u64) -> bool {
+    desc & 0b1 != 0
+}
+
+/// Write `desc` into `entry`, unless it holds a different valid mapping and
+/// `replace` is false.
+fn set_entry(entry: &mut u64, desc: u64, replace: bool) -> Result<()> {
+    if is_valid(*entry) && *entry != desc && !replace {
+        return Err(MapError::Conflict);
+    }
+
+    *entry = desc;
+
+    Ok(())
+}
+
+/// Whether the MMU is switched on.
+pub fn mmu_enabled() -> bool {
+    SCTLR_EL1.is_set(SCTLR_EL1::M)
+}
 
-/// Set up identity mapped page tables for the first 1 GiB of address space.
+/// The LVL3 table behind the LVL2 entry `lvl2`, created on demand.
 ///
-/// The first 2 MiB are 4 KiB granule, the rest 2 MiB.
-pub unsafe fn init() -> Result<(), &'static str> {
-    // Prepare the memory attribute indirection register.
-    set_up_mair();
+/// An existing 2 MiB block is split into 512 pages with the same output
+/// addresses and attributes, so that single pages can be remapped.
+unsafe fn lvl3_table(lvl2: &mut u64) -> Result<&'static mut PageTable> {
+    if is_valid(*lvl2) && *lvl2 & TYPE_TABLE_OR_PAGE != 0 {
+        let table = phys_to_virt((*lvl2 & OUTPUT_ADDR_MASK) as usize);
+
+        return Ok(&mut *(table as *mut PageTable));
+    }
+
+    let table = alloc_lvl3_table()?;
+
+    if is_valid(*lvl2) {
+        let attributes = *lvl2 & !OUTPUT_ADDR_MASK;
+        let output_addr = *lvl2 & OUTPUT_ADDR_MASK;
+
+        for (i, entry) in table.entries.iter_mut().enumerate() {
+            *entry = attributes | TYPE_TABLE_OR_PAGE | (output_addr + (i * FOUR_KIB) as u64);
+        }
+
+        // Break-before-make: The block must be gone from the TLBs before the
+        // table replaces it.
+        if mmu_enabled() {
+            *lvl2 = 0;
+            cache::tlb_invalidate_all();
+        }
+    }
+
+    *lvl2 = TableDescriptor::new(table.entries.phys_base_addr())?.value();
+
+    Ok(table)
+}
+
+/// Map `size` bytes at `virt` to `phys`, using 2 MiB blocks where alignment
+/// allows, and 4 KiB pages elsewhere.
+///
+/// With `replace`, existing mappings are overwritten instead of being a
+/// conflict.
+unsafe fn map(
+    virt: usize,
+    phys: usize,
+    size: usize,
+    attributes: AttributeFields,
+    replace: bool,
+) -> Result<()> {
+    if (virt | phys | size) % FOUR_KIB != 0 {
+        return Err(MapError::Unaligned);
+    }
+
+    // Offsets into the kernel's half, which the tables below LVL0 are indexed by
+    let start = virt.checked_sub(KERNEL_OFFSET).ok_or(MapError::OutOfRange)?;
+    if size == 0 || start.checked_add(size - 1).map_or(true, |last| last >> 30 >= NUM_LVL2_TABLES) {
+        return Err(MapError::OutOfRange);
+    }
+
+    let mut offset = 0;
+    while offset < size {
+        let (v, p) = (start + offset, phys + offset);
+        let lvl2 = &mut LVL2_TABLES[v >> 30].entries[(v >> TWO_MIB_SHIFT) % NUM_ENTRIES_4KIB];
+
+        // A block, unless a table already holds finer grained mappings
+        let is_table = is_valid(*lvl2) && *lvl2 & TYPE_TABLE_OR_PAGE != 0;
+        if v % TWO_MIB == 0 && p % TWO_MIB == 0 && size - offset >= TWO_MIB && !is_table {
+            set_entry(lvl2, Lvl2BlockDescriptor::new(p, attributes)?.value(), replace)?;
+            offset += TWO_MIB;
+            continue;
+        }
+
+        let table = lvl3_table(lvl2)?;
+        let entry = &mut table.entries[(v >> FOUR_KIB_SHIFT) % NUM_ENTRIES_4KIB];
+        set_entry(entry, PageDescriptor::new(p, attributes)?.value(), replace)?;
+        offset += FOUR_KIB;
+    }
+
+    Ok(())
+}
+
+/// Map `size` bytes at virtual address `virt` in the kernel's half of the
+/// address space to physical address `phys`.
+///
+/// All three must be multiples of 4 KiB. 2 MiB blocks are split into pages as
+/// needed. Fails if a part of the region is already mapped differently. If the
+/// MMU is on, the TLBs are flushed afterwards, so the new mapping is in effect
+/// right away.
+///
+/// Splitting a block temporarily unmaps it, so it must not hold the code or
+/// the stack of the caller.
+pub unsafe fn map_region(
+    virt: usize,
+    phys: usize,
+    size: usize,
+    attributes: AttributeFields,
+) -> Result<()> {
+    let ret = map(virt, phys, size, attributes, false);
+    debug!("map_region({:#010X}, {:#010X}, {:#X}): {:?}", virt, phys, size, ret);
+
+    // A single page is cheaper to flush on its own, unless a block was split
+    // for it, which flushed everything anyways.
+    if mmu_enabled() {
+        if size == FOUR_KIB {
+            cache::tlb_invalidate_page(virt);
+        } else {
+            cache::tlb_invalidate_all();
+        }
+    }
+
+    ret
+}
 
-    // Point the first 2 MiB of virtual addresses to the follow-up LVL3
-    // page-table.
-    LVL2_TABLE.entries[0] = match TableDescriptor::new(LVL3_TABLE.entries.base_addr_usize()) {
-        Err(s) => return Err(s),
-        Ok(d) => d.value(),
+/// Run `f` with the 4 KiB page that holds `virt` mapped writable, e.g. to
+/// patch code. Pages that are writable anyways are left alone.
+///
+/// The page keeps its other attributes, so code in it stays executable.
+pub unsafe fn with_page_writable<F, R>(virt: usize, f: F) -> Result<R>
+where
+    F: FnOnce() -> R,
+{
+    let page = virt & !(FOUR_KIB - 1);
+    let (phys, attributes) = get_virt_addr_properties(page).map_err(|_| MapError::OutOfRange)?;
+
+    if let AccessPermissions::ReadWrite = attributes.acc_perms {
+        return Ok(f());
+    }
+
+    let writable = AttributeFields {
+        acc_perms: AccessPermissions::ReadWrite,
+        ..attributes
     };
+    map(page, phys, FOUR_KIB, writable, true)?;
+    cache::tlb_invalidate_page(page);
 
-    // Fill the rest of the LVL2 (2 MiB) entries as block descriptors.
-    //
-    // Notice the skip(1) which makes the iteration start at the second 2 MiB
-    // block (0x20_0000).
-    for (block_descriptor_nr, entry) in LVL2_TABLE.entries.iter_mut().enumerate().skip(1) {
-        let virt_addr = block_descriptor_nr << TWO_MIB_SHIFT;
+    let ret = f();
 
-        let (output_addr, attribute_fields) = match get_virt_addr_properties(virt_addr) {
-            Err(s) => return Err(s),
-            Ok((a, b)) => (a, b),
-        };
+    map(page, phys, FOUR_KIB, attributes, true)?;
+    cache::tlb_invalidate_page(page);
 
-        let block_desc = match Lvl2BlockDescriptor::new(output_addr, attribute_fields) {
-            Err(s) => return Err(s),
-            Ok(desc) => desc,
-        };
+    Ok(ret)
+}
 
-        *entry = block_desc.value();
+/// Remove the mapping of the 4 KiB page at `virt`, so that accesses to it
+/// fault. A 2 MiB block is split into pages for it.
+///
+/// The caller flushes the TLBs.
+unsafe fn unmap_page(virt: usize) -> Result<()> {
+    if virt % FOUR_KIB != 0 {
+        return Err(MapError::Unaligned);
     }
 
-    // Finally, fill the single LVL3 table (4 KiB granule).
-    for (page_descriptor_nr, entry) in LVL3_TABLE.entries.iter_mut().enumerate() {
-        let virt_addr = page_descriptor_nr << FOUR_KIB_SHIFT;
+    let v = virt.checked_sub(KERNEL_OFFSET).ok_or(MapError::OutOfRange)?;
+    if v >> 30 >= NUM_LVL2_TABLES {
+        return Err(MapError::OutOfRange);
+    }
+
+    let lvl2 = &mut LVL2_TABLES[v >> 30].entries[(v >> TWO_MIB_SHIFT) % NUM_ENTRIES_4KIB];
+    let table = lvl3_table(lvl2)?;
+    table.entries[(v >> FOUR_KIB_SHIFT) % NUM_ENTRIES_4KIB] = 0;
 
-        let (output_addr, attribute_fields) = match get_virt_addr_properties(virt_addr) {
-            Err(s) => return Err(s),
-            Ok((a, b)) => (a, b),
-        };
+    Ok(())
+}
 
-        let page_desc = match PageDescriptor::new(output_addr, attribute_fields) {
-            Err(s) => return Err(s),
-            Ok(desc) => desc,
-        };
+/// Fill the page tables according to the kernel memory layout, one run of
+/// pages with the same properties at a time. Addresses beyond `map::END`,
+/// addresses above the SDRAM that the layout does not cover, and the stack
+/// guard pages are left unmapped.
+unsafe fn populate_tables() -> Result<()> {
+    use crate::memory::map;
 
-        *entry = page_desc.value();
+    // Point the LVL1 (1 GiB) entries to the LVL2 tables.
+    for (entry, lvl2_table) in LVL1_TABLE.entries.iter_mut().zip(LVL2_TABLES.iter()) {
+        *entry = TableDescriptor::new(lvl2_table.entries.phys_base_addr())?.value();
     }
 
-    // Point to the LVL2 table base address in TTBR0.
-    TTBR0_EL1.set_baddr(LVL2_TABLE.entries.base_addr_u64());
-
-    // Configure various settings of stage 1 of the EL1 translation regime.
-    let ips = ID_AA64MMFR0_EL1.read(ID_AA64MMFR0_EL1::PARange);
-    TCR_EL1.write(
-        TCR_EL1::TBI0::Ignored
-            + TCR_EL1::IPS.val(ips)
-            + TCR_EL1::TG0::KiB_4 // 4 KiB granule
-            + TCR_EL1::SH0::Inner
-            + TCR_EL1::ORGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
-            + TCR_EL1::IRGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
-            + TCR_EL1::EPD0::EnableTTBR0Walks
-            + TCR_EL1::T0SZ.val(34), // Start walks at level 2
-    );
+    let mut virt_addr = map::START;
+    loop {
+        let end = layout_segment_end(virt_addr);
+        let (output_addr, attribute_fields) =
+            get_virt_addr_properties(virt_addr).map_err(|_| MapError::OutOfRange)?;
+
+        // Above the SDRAM, only what the layout describes is mapped. That
+        // leaves the hole up to the Pi 4's peripherals unmapped.
+        let covered = virt_addr <= phys_to_virt(map::physical::VC_SDRAM_END)
+            || crate::memory::layout().any(|r| r.contains(virt_addr));
+
+        if covered {
+            trace!("{:#010X} - {:#010X} -> {:#010X}", virt_addr, end, output_addr);
+            map(virt_addr, output_addr, end - virt_addr + 1, attribute_fields, true)?;
+        }
 
-    // Switch the MMU on.
-    //
-    // First, force all previous changes to be seen before the MMU is enabled.
+        if end >= map::END {
+            break;
+        }
+        virt_addr = end + 1;
+    }
+
+    for core in 0..smp::NUM_CORES {
+        unmap_page(stack_guard::guard_page(core))?;
+    }
+
+    Ok(())
+}
+
+/// Set up the kernel's page tables for the first 4 GiB of physical address
+/// space, mapped at `KERNEL_OFFSET`, and switch TTBR1 over to them.
+///
+/// The MMU is already on, running on the boot code's early tables (see
+/// `raspi3_boot::higher_half`), which also configured TCR_EL1 and disabled the
+/// TTBR0 walks. TTBR0 stays free for user space.
+pub unsafe fn init() -> Result<()> {
+    // Prepare the memory attribute indirection register.
+    set_up_mair();
+
+    populate_tables()?;
+
+    // Point the first LVL0 (512 GiB) entry to the LVL1 table.
+    LVL0_TABLE.entries[0] = TableDescriptor::new(LVL1_TABLE.entries.phys_base_addr())?.value();
+
+    // The new tables keep all the addresses of the early ones, so the code
+    // that is running right now stays mapped across the switch. Only its
+    // permissions change, hence the TLB invalidation.
+    barrier::dsb(barrier::SY);
+    TTBR1_EL1.set(LVL0_TABLE.entries.phys_base_addr() as u64);
     barrier::isb(barrier::SY);
 
-    // Enable the MMU and turn on data and instruction caching.
-    SCTLR_EL1.modify(SCTLR_EL1::M::Enable + SCTLR_EL1::C::Cacheable + SCTLR_EL1::I::Cacheable);
+    cache::tlb_invalidate_all();
+    stack_guard::set_protected();
+
+    debug!(
+        "TTBR1_EL1 at {:#010X}, {} LVL3 tables taken from the pool.",
+        LVL0_TABLE.entries.phys_base_addr(),
+        NEXT_POOL_TABLE.load(Ordering::Relaxed)
+    );
+
+    Ok(())
+}
+
+/// Switch a secondary core from the early tables over to the kernel's, which
+/// `init()` already filled on the boot core.
+pub unsafe fn init_secondary() {
+    set_up_mair();
 
-    // Force MMU init to complete before next instruction
+    barrier::dsb(barrier::SY);
+    TTBR1_EL1.set(LVL0_TABLE.entries.phys_base_addr() as u64);
     barrier::isb(barrier::SY);
 
+    cache::tlb_invalidate_all();
+}
+
+/// Rebuild the page tables after the kernel memory layout changed, e.g. after
+/// `memory::memory_map()` learned where the Videocore's SDRAM starts.
+///
+/// No cache maintenance is done, so only the attributes of memory that the
+/// kernel did not touch yet may change.
+pub unsafe fn reload() -> Result<()> {
+    let ret = populate_tables();
+
+    cache::tlb_invalidate_all();
+
+    ret
+}
+
+/// Start of the 2 MiB of user space that the user tables cover. The first
+/// 2 MiB stay unmapped, so that null pointers fault.
+pub const USER_START: usize = TWO_MIB;
+pub const USER_END: usize = USER_START + TWO_MIB - 1;
+
+/// How EL0 may access a user page. The kernel can neither write nor execute
+/// it, so that a user program can not slip code into the kernel.
+#[derive(Copy, Clone, Debug)]
+pub enum UserAccess {
+    ReadExecute,
+    ReadWrite,
+}
+
+// The address space of EL0, walked through TTBR0. TCR_EL1.T0SZ of the boot
+// code makes it 2 GiB, so walks start at LVL1. Only one 2 MiB range of it has
+// a LVL3 table.
+static mut USER_LVL1_TABLE: PageTable = EMPTY_TABLE;
+static mut USER_LVL2_TABLE: PageTable = EMPTY_TABLE;
+static mut USER_LVL3_TABLE: PageTable = EMPTY_TABLE;
+
+/// A page descriptor for user space. Unlike the kernel's, it is not global,
+/// so the TLBs tag it with the ASID of the address space.
+fn user_page_descriptor(output_addr: usize, access: UserAccess) -> Result<u64> {
+    if output_addr % FOUR_KIB != 0 {
+        return Err(MapError::Unaligned);
+    }
+
+    let perms = match access {
+        UserAccess::ReadExecute => {
+            STAGE1_DESCRIPTOR::AP::RO_EL1_EL0 + STAGE1_DESCRIPTOR::UXN::False
+        }
+        UserAccess::ReadWrite => STAGE1_DESCRIPTOR::AP::RW_EL1_EL0 + STAGE1_DESCRIPTOR::UXN::True,
+    };
+
+    Ok((STAGE1_DESCRIPTOR::VALID::True
+        + STAGE1_DESCRIPTOR::AF::True
+        + STAGE1_DESCRIPTOR::NG::True
+        + STAGE1_DESCRIPTOR::SH::InnerShareable
+        + STAGE1_DESCRIPTOR::AttrIndx.val(mair::NORMAL)
+        + STAGE1_DESCRIPTOR::PXN::True
+        + perms
+        + STAGE1_DESCRIPTOR::TYPE::Table
+        + STAGE1_DESCRIPTOR::NEXT_LVL_TABLE_ADDR_4KiB.val((output_addr >> FOUR_KIB_SHIFT) as u64))
+    .value)
+}
+
+/// Empty the user address space, and link its tables.
+///
+/// User space must not be active, see `enter_user_space()`.
+pub unsafe fn clear_user_space() -> Result<()> {
+    USER_LVL1_TABLE = EMPTY_TABLE;
+    USER_LVL2_TABLE = EMPTY_TABLE;
+    USER_LVL3_TABLE = EMPTY_TABLE;
+
+    USER_LVL1_TABLE.entries[0] =
+        TableDescriptor::new(USER_LVL2_TABLE.entries.phys_base_addr())?.value();
+    USER_LVL2_TABLE.entries[USER_START >> TWO_MIB_SHIFT] =
+        TableDescriptor::new(USER_LVL3_TABLE.entries.phys_base_addr())?.value();
+
     Ok(())
 }
+
+/// Map the 4 KiB page at `virt` in user space to `phys`.
+pub unsafe fn map_user_page(virt: usize, phys: usize, access: UserAccess) -> Result<()> {
+    if virt % FOUR_KIB != 0 {
+        return Err(MapError::Unaligned);
+    }
+    if virt < USER_START || virt > USER_END {
+        return Err(MapError::NotUserSpace);
+    }
+
+    let entry = &mut USER_LVL3_TABLE.entries[(virt >> FOUR_KIB_SHIFT) % NUM_ENTRIES_4KIB];
+    set_entry(entry, user_page_descriptor(phys, access)?, false)
+}
+
+/// Switch TTBR0 to the user tables, tagged with `asid`, and turn on the walks
+/// of the lower half that the boot code turned off.
+pub unsafe fn enter_user_space(asid: u8) {
+    barrier::dsb(barrier::SY);
+    TTBR0_EL1.set(USER_LVL1_TABLE.entries.phys_base_addr() as u64 | (u64::from(asid) << 48));
+    TCR_EL1.modify(TCR_EL1::EPD0::EnableTTBR0Walks);
+    barrier::isb(barrier::SY);
+}
+
+/// Turn the walks of the lower half off again, and drop whatever the TLBs
+/// cached for `asid`, so that the next user space starts clean.
+pub unsafe fn leave_user_space(asid: u8) {
+    TCR_EL1.modify(TCR_EL1::EPD0::DisableTTBR0Walks);
+    barrier::isb(barrier::SY);
+
+    barrier::dsb(barrier::SY);
+    asm!("tlbi aside1, $0" :: "r"(u64::from(asid) << 48) :: "volatile");
+    barrier::dsb(barrier::SY);
+    barrier::isb(barrier::SY);
+}
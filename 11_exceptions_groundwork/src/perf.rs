@@ -0,0 +1,168 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+
+//! Hardware event counts from the PMU, around a piece of code.
+//!
+//! `measure()` puts each requested event into one of the programmable
+//! counters, resets them, runs the code, and reads them back together with
+//! the cycle counter. The Cortex-A53 has six programmable counters, and
+//! PMCR_EL0.N tells how many there are, so asking for more is an error
+//! instead of silently dropped events.
+//!
+//! The boot code does not let EL2 trap PMU accesses of EL1, see
+//! `raspi3_boot`. `init()` additionally opens the PMU to EL0.
+
+use crate::{cpu::regs::*, delays};
+use core::fmt;
+use cortex_a::barrier;
+use register::cpu::RegisterReadWrite;
+
+/// Most counters that `Counts` holds. The architecture allows up to 31.
+pub const MAX_EVENTS: usize = 6;
+
+const PMCR_P: u64 = 1 << 1; // reset all event counters
+const PMCR_N_SHIFT: u64 = 11;
+const PMCR_N_MASK: u64 = 0x1F;
+
+// PMUSERENR_EL0
+const PMUSERENR_EN: u64 = 1 << 0; // EL0 may use all PMU registers
+
+/// Common architectural events, by their number
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    L1dRefill = 0x03,
+    L1dAccess = 0x04,
+    InstRetired = 0x08,
+    BranchMispredict = 0x10,
+}
+
+impl Event {
+    pub fn name(self) -> &'static str {
+        match self {
+            Event::L1dRefill => "L1D refills",
+            Event::L1dAccess => "L1D accesses",
+            Event::InstRetired => "instructions",
+            Event::BranchMispredict => "branch mispredicts",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PerfError {
+    /// There is no PMU, or it does not count, e.g. on QEMU
+    NoPmu,
+    /// More events than the PMU has counters
+    TooManyEvents { available: usize },
+}
+
+pub type Result<T> = ::core::result::Result<T, PerfError>;
+
+/// What `measure()` counted
+#[derive(Copy, Clone, Debug)]
+pub struct Counts {
+    pub cycles: u64,
+    events: [Option<(Event, u64)>; MAX_EVENTS],
+}
+
+impl Counts {
+    /// The count of `event`, if it was measured
+    pub fn get(&self, event: Event) -> Option<u64> {
+        self.iter().find(|&(e, _)| e == event).map(|(_, n)| n)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Event, u64)> + '_ {
+        self.events.iter().filter_map(|e| *e)
+    }
+}
+
+impl fmt::Display for Counts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} cycles", self.cycles)?;
+        for (event, n) in self.iter() {
+            write!(f, ", {} {}", n, event.name())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Let EL0 use the PMU too, e.g. from a task.
+pub fn init() {
+    PMUSERENR_EL0.set(PMUSERENR_EN);
+}
+
+/// How many programmable counters the PMU of this core has
+pub fn num_counters() -> usize {
+    ((PMCR_EL0.get() >> PMCR_N_SHIFT) & PMCR_N_MASK) as usize
+}
+
+/// Count `events` and cycles while `f` runs.
+///
+/// Uses the counters of the calling core, so `f` must not migrate.
+pub fn measure<F: FnOnce()>(events: &[Event], f: F) -> Result<Counts> {
+    // Also enables the PMU and checks that it counts at all
+    delays::read_cycles().ok_or(PerfError::NoPmu)?;
+
+    let available = num_counters().min(MAX_EVENTS);
+    if events.len() > available {
+        return Err(PerfError::TooManyEvents { available });
+    }
+
+    let mask = (1u64 << events.len()) - 1;
+    for (i, &event) in events.iter().enumerate() {
+        PMSELR_EL0.set(i as u64);
+        unsafe { barrier::isb(barrier::SY) };
+
+        // Zero filter bits count in EL0 and EL1
+        PMXEVTYPER_EL0.set(event as u64);
+    }
+    PMOVSCLR_EL0.set(mask);
+    PMCR_EL0.set(PMCR_EL0.get() | PMCR_P);
+    PMCNTENSET_EL0.set(mask);
+
+    // Others use the cycle counter as well, so it keeps running
+    unsafe { barrier::isb(barrier::SY) };
+    let start = PMCCNTR_EL0.get();
+
+    f();
+
+    unsafe { barrier::isb(barrier::SY) };
+    let cycles = PMCCNTR_EL0.get().wrapping_sub(start);
+    PMCNTENCLR_EL0.set(mask);
+
+    let mut counts = Counts {
+        cycles,
+        events: [None; MAX_EVENTS],
+    };
+    for (i, &event) in events.iter().enumerate() {
+        PMSELR_EL0.set(i as u64);
+        unsafe { barrier::isb(barrier::SY) };
+
+        // The event counters are 32 bit wide on the Cortex-A53
+        counts.events[i] = Some((event, PMXEVCNTR_EL0.get() & 0xFFFF_FFFF));
+    }
+
+    Ok(counts)
+}
@@ -0,0 +1,39 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Ending a QEMU run with an exit status, for the test kernel.
+//!
+//! Uses the `SYS_EXIT` call of the Arm semihosting interface, which QEMU only
+//! implements if started with `-semihosting`. The `raspi3` machine has no
+//! equivalent of the PC's `isa-debug-exit` device, so this is the only way.
+
+use crate::semihosting;
+
+/// Terminate QEMU, with exit status 0 if `success`, or 1 otherwise.
+///
+/// On real hardware, and in kernels built without the `semihosting` feature,
+/// the core just halts, which is just as final.
+pub fn qemu_exit(success: bool) -> ! {
+    semihosting::sys_exit(if success { 0 } else { 1 })
+}
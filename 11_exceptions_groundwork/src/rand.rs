@@ -0,0 +1,150 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+
+//! Seedable pseudo-random numbers, for tests that must be reproducible.
+//!
+//! `Rng` is xoshiro256**, whose state is expanded from a single `u64` seed
+//! by SplitMix64, as its authors recommend. The same seed always gives the
+//! same numbers.
+//!
+//! `Rng::seeded_from_hw()` picks the seed itself and logs it. A `seed=` on
+//! the command line overrides it, which replays a run that failed. Without
+//! one, the seed comes from the hardware RNG, or if that does not answer,
+//! from the counter of the generic timer mixed with the board serial.
+
+use crate::{cmdline, devices::hw, memory, time};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Mixed into the fallback seed, see `set_board_serial()`
+static BOARD_SERIAL: AtomicU64 = AtomicU64::new(0);
+
+/// Make seeds of boards that boot at the same counter value differ.
+pub fn set_board_serial(serial: u64) {
+    BOARD_SERIAL.store(serial, Ordering::Relaxed);
+}
+
+/// A tiny generator, good for seeding and mixing, but not much else
+pub struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    pub fn from_seed(seed: u64) -> SplitMix64 {
+        SplitMix64 { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+/// xoshiro256**
+pub struct Rng {
+    s: [u64; 4],
+    seed: u64,
+}
+
+impl Rng {
+    pub fn from_seed(seed: u64) -> Rng {
+        let mut sm = SplitMix64::from_seed(seed);
+        let s = [sm.next_u64(), sm.next_u64(), sm.next_u64(), sm.next_u64()];
+
+        Rng { s, seed }
+    }
+
+    /// Seed from `seed=`, the hardware RNG or the counter, and log the seed.
+    pub fn seeded_from_hw() -> Rng {
+        let (seed, source) = match cmdline::value("seed").and_then(parse_seed) {
+            Some(seed) => (seed, "command line"),
+            None => match hw_seed() {
+                Some(seed) => (seed, "hardware RNG"),
+                None => (
+                    time::Instant::now().ticks() ^ BOARD_SERIAL.load(Ordering::Relaxed),
+                    "counter and board serial",
+                ),
+            },
+        };
+
+        info!("Random seed {:#018x} from the {}, replay with seed=", seed, source);
+
+        Rng::from_seed(seed)
+    }
+
+    /// The seed that this generator started from
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let result = self.s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = self.s[1] << 17;
+
+        self.s[2] ^= self.s[0];
+        self.s[3] ^= self.s[1];
+        self.s[1] ^= self.s[2];
+        self.s[0] ^= self.s[3];
+        self.s[2] ^= t;
+        self.s[3] = self.s[3].rotate_left(45);
+
+        result
+    }
+
+    #[allow(dead_code)]
+    pub fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+}
+
+fn parse_seed(s: &str) -> Option<u64> {
+    if s.starts_with("0x") || s.starts_with("0X") {
+        u64::from_str_radix(&s[2..], 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+/// The Pi 4 has another RNG, which `hw::Rng` does not drive.
+/// Whether the hardware RNG delivers numbers.
+pub fn hw_rng_present() -> bool {
+    hw_seed().is_some()
+}
+
+fn hw_seed() -> Option<u64> {
+    if cfg!(feature = "rpi4") {
+        return None;
+    }
+
+    let rng = hw::Rng::new(hw::Peripheral::Rng);
+    rng.init();
+    rng.read_u64().ok()
+}
@@ -0,0 +1,114 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! A fixed-size, lock-free byte ring buffer.
+//!
+//! Safe for exactly one producer and one consumer running concurrently, e.g.
+//! an IRQ handler pushing received bytes and the kernel popping them. With
+//! several cores on either side, put it behind a `sync::SpinLock`.
+
+use core::{
+    cell::UnsafeCell,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Capacity in bytes. Must be a power of two.
+const RING_BUFFER_SIZE: usize = 256;
+
+pub struct RingBuffer {
+    buf: UnsafeCell<[u8; RING_BUFFER_SIZE]>,
+    /// Free-running index of the next byte to write. Only the producer stores.
+    head: AtomicUsize,
+    /// Free-running index of the next byte to read. Only the consumer stores.
+    tail: AtomicUsize,
+    dropped: AtomicUsize,
+}
+
+unsafe impl Sync for RingBuffer {}
+
+impl RingBuffer {
+    pub const fn new() -> RingBuffer {
+        RingBuffer {
+            buf: UnsafeCell::new([0; RING_BUFFER_SIZE]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    /// Append a byte. Producer side.
+    ///
+    /// If the buffer is full, the new byte is dropped and counted, and the
+    /// bytes already in the buffer are kept (drop-newest).
+    pub fn push(&self, byte: u8) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+
+        if head.wrapping_sub(tail) == RING_BUFFER_SIZE {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+
+        unsafe { (*self.buf.get())[head % RING_BUFFER_SIZE] = byte };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+
+        true
+    }
+
+    /// Remove the oldest byte. Consumer side.
+    pub fn pop(&self) -> Option<u8> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+
+        if head == tail {
+            return None;
+        }
+
+        let byte = unsafe { (*self.buf.get())[tail % RING_BUFFER_SIZE] };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+
+        Some(byte)
+    }
+
+    /// Number of bytes currently in the buffer.
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        let tail = self.tail.load(Ordering::Acquire);
+
+        head.wrapping_sub(tail)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() == RING_BUFFER_SIZE
+    }
+
+    /// Number of bytes that were dropped because the buffer was full.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
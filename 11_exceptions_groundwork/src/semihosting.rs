@@ -0,0 +1,143 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Output to the host console and exit via the Arm semihosting interface.
+//!
+//! Semihosting requests are `hlt #0xF000` instructions, with the operation
+//! number in `x0` and its argument in `x1`. QEMU serves them if started with
+//! `-semihosting`, and a JTAG debugger can do the same. That makes it the
+//! only output that works before the UARTs are up.
+//!
+//! Without anyone serving the requests, `hlt` raises an exception, and there
+//! is no safe way to find out if somebody is listening. The calls are
+//! therefore only compiled in with the `semihosting` feature, and do nothing
+//! otherwise. The test kernel enables it, see `make test`.
+
+use crate::{cpu, devices::virt::ConsoleOps};
+use core::fmt;
+
+/// Semihosting operation numbers
+const SYS_WRITE0: u64 = 0x04;
+const SYS_EXIT: u64 = 0x18;
+
+/// Reason code for a regular exit, which makes QEMU use the subcode as its
+/// exit status
+const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x2_0026;
+
+/// Whether the semihosting calls are compiled in.
+pub fn is_available() -> bool {
+    cfg!(feature = "semihosting")
+}
+
+/// Issue semihosting operation `op`, with `arg` as parameter. Returns
+/// whatever the operation leaves in `x0`.
+fn call(op: u64, arg: u64) -> u64 {
+    if !is_available() {
+        return 0;
+    }
+
+    let ret: u64;
+    unsafe {
+        asm!("hlt #0xF000"
+             : "={x0}"(ret)
+             : "{x0}"(op), "{x1}"(arg)
+             : "memory"
+             : "volatile");
+    }
+
+    ret
+}
+
+/// Write `string` to the host console.
+///
+/// `SYS_WRITE0` takes a NUL terminated string, so `string` is copied in
+/// pieces into a buffer on the stack. NUL characters within `string` are
+/// dropped.
+pub fn sys_write0(string: &str) {
+    const CHUNK: usize = 64;
+
+    let mut buf = [0u8; CHUNK + 1];
+    let mut len = 0;
+
+    for &byte in string.as_bytes().iter().filter(|&&b| b != 0) {
+        buf[len] = byte;
+        len += 1;
+
+        if len == CHUNK {
+            call(SYS_WRITE0, buf.as_ptr() as u64);
+            len = 0;
+        }
+    }
+
+    if len > 0 {
+        buf[len] = 0;
+        call(SYS_WRITE0, buf.as_ptr() as u64);
+    }
+}
+
+/// Terminate the session, and QEMU with exit status `code`.
+///
+/// Without the `semihosting` feature, or if nobody serves the request, the
+/// core halts instead.
+pub fn sys_exit(code: u32) -> ! {
+    let block: [u64; 2] = [ADP_STOPPED_APPLICATION_EXIT, u64::from(code)];
+
+    call(SYS_EXIT, &block as *const _ as u64);
+
+    cpu::wait_forever()
+}
+
+/// The host console as a console sink.
+///
+/// Registered first thing in `kernel_entry()` if semihosting is available,
+/// so that everything from the very first `println!()` on shows up on the
+/// host, even if bringing up the UARTs fails.
+pub struct SemihostingConsole;
+
+impl Drop for SemihostingConsole {
+    fn drop(&mut self) {}
+}
+
+impl ConsoleOps for SemihostingConsole {
+    fn putc(&self, c: char) {
+        let mut buf = [0u8; 4];
+        sys_write0(c.encode_utf8(&mut buf));
+    }
+
+    fn puts(&self, string: &str) {
+        sys_write0(string);
+    }
+
+    fn is_ready(&self) -> bool {
+        is_available()
+    }
+}
+
+impl fmt::Write for SemihostingConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.puts(s);
+
+        Ok(())
+    }
+}
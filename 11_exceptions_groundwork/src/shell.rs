@@ -0,0 +1,419 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! A small monitor on the console, for bring-up.
+//!
+//! `run()` reads lines with the line editing of `ConsoleOps::getline()`, splits
+//! them at whitespace, and calls the command named by the first word with the
+//! other words as arguments. A command that was called wrong returns an error
+//! message, usually its usage, which the shell prints.
+//!
+//! The shell is an `async` task of the `executor`, so that it does not keep
+//! the core busy while waiting for input. Next to it, a second task blinks the
+//! ACT LED as a heartbeat. The commands themselves run synchronously.
+//!
+//! Besides the built-in commands, up to `MAX_COMMANDS` more can be added with
+//! `register()`.
+
+use crate::{
+    cpu, debug,
+    devices::{
+        hw::{self, videocore_mbox::thermal},
+        virt::{
+            console::{self, GlobalConsole},
+            ConsoleOps,
+        },
+    },
+    banner, exception, executor, led, memory,
+    sync::SpinLock,
+    tick, time, timer, walltime,
+};
+use core::ptr;
+
+/// A command gets the arguments after its name, and returns a message if it
+/// failed.
+pub type Command = fn(&[&str]) -> Result<(), &'static str>;
+
+const BUILTIN: &[(&str, Command)] = &[
+    ("date", date),
+    ("help", help),
+    ("md", md),
+    ("mw", mw),
+    ("reset", reset),
+    ("sysinfo", sysinfo),
+    ("temp", temp),
+    ("ticks", ticks),
+    ("watch", watch),
+];
+
+/// How many commands `register()` takes.
+pub const MAX_COMMANDS: usize = 8;
+
+static COMMANDS: SpinLock<[Option<(&str, Command)>; MAX_COMMANDS]> =
+    SpinLock::new([None; MAX_COMMANDS]);
+
+/// Longest line that the shell reads
+const LINE_LEN: usize = 80;
+
+/// Most words in a line, including the command
+const MAX_ARGS: usize = 8;
+
+#[derive(Debug)]
+pub enum ShellError {
+    TooManyCommands,
+    /// A command of this name exists already
+    NameTaken,
+}
+
+/// Add `cmd` to the shell, to be called as `name`.
+#[allow(dead_code)]
+pub fn register(name: &'static str, cmd: Command) -> Result<(), ShellError> {
+    if find(name).is_some() {
+        return Err(ShellError::NameTaken);
+    }
+
+    COMMANDS.lock(|commands| {
+        let slot = commands
+            .iter_mut()
+            .find(|c| c.is_none())
+            .ok_or(ShellError::TooManyCommands)?;
+        *slot = Some((name, cmd));
+
+        Ok(())
+    })
+}
+
+fn find(name: &str) -> Option<Command> {
+    if let Some(&(_, cmd)) = BUILTIN.iter().find(|(n, _)| *n == name) {
+        return Some(cmd);
+    }
+
+    COMMANDS.lock(|commands| {
+        commands
+            .iter()
+            .filter_map(|c| *c)
+            .find(|(n, _)| *n == name)
+            .map(|(_, cmd)| cmd)
+    })
+}
+
+/// Parse a decimal or a 0x-prefixed hexadecimal number.
+pub fn parse_number(s: &str) -> Option<usize> {
+    if s.starts_with("0x") || s.starts_with("0X") {
+        usize::from_str_radix(&s[2..], 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+/// On and off time of the heartbeat
+const HEARTBEAT_ON_US: u64 = 100_000;
+const HEARTBEAT_OFF_US: u64 = 900_000;
+
+/// Run the shell on the global console, forever, and blink the heartbeat
+/// next to it.
+pub fn run() -> ! {
+    if led::ActLed::is_available() && executor::spawn(heartbeat()).is_err() {
+        warn!("No heartbeat, the executor is full.");
+    }
+
+    match executor::spawn(shell()) {
+        Ok(()) => executor::run(),
+
+        // Without the heap, the shell can still run on its own
+        Err(e) => {
+            warn!("Shell without the executor: {:?}", e);
+            executor::block_on(shell());
+        }
+    }
+
+    // The shell never returns
+    cpu::wait_forever()
+}
+
+async fn heartbeat() {
+    loop {
+        led::ActLed::on();
+        timer::sleep_us(HEARTBEAT_ON_US).await;
+        led::ActLed::off();
+        timer::sleep_us(HEARTBEAT_OFF_US).await;
+    }
+}
+
+/// `ConsoleOps::getline()`, but waiting for each character asynchronously.
+async fn getline<'a>(console: &GlobalConsole, buf: &'a mut [u8]) -> &'a str {
+    let mut len = 0;
+
+    while !console::edit_line(console, buf, &mut len, console.read_char().await) {}
+
+    console::line_str(buf, len)
+}
+
+async fn shell() {
+    let console = GlobalConsole;
+    let mut buf = [0u8; LINE_LEN];
+
+    loop {
+        console.puts("\n$> ");
+        let line = getline(&console, &mut buf).await;
+        console.puts("\n");
+
+        let mut argv = [""; MAX_ARGS];
+        let mut argc = 0;
+        for word in line.split_whitespace() {
+            if argc == MAX_ARGS {
+                break;
+            }
+
+            argv[argc] = word;
+            argc += 1;
+        }
+
+        if argc == 0 {
+            continue;
+        }
+
+        match find(argv[0]) {
+            Some(cmd) => {
+                if let Err(msg) = cmd(&argv[1..argc]) {
+                    println!("{}: {}", argv[0], msg);
+                }
+            }
+
+            None => {
+                println!("Unknown command: {}", argv[0]);
+                let _ = help(&[]);
+            }
+        }
+    }
+}
+
+/// Parse `YYYY-MM-DD` and `HH:MM:SS`.
+fn parse_date_time(date: &str, time: &str) -> Option<walltime::DateTime> {
+    let mut d = date.split('-').map(|s| s.parse::<u16>().ok());
+    let mut t = time.split(':').map(|s| s.parse::<u8>().ok());
+
+    let dt = walltime::DateTime {
+        year: d.next()??,
+        month: d.next()?? as u8,
+        day: d.next()?? as u8,
+        hour: t.next()??,
+        minute: t.next()??,
+        second: t.next()??,
+    };
+
+    if d.next().is_some() || t.next().is_some() || !dt.is_valid() {
+        return None;
+    }
+
+    Some(dt)
+}
+
+fn date(args: &[&str]) -> Result<(), &'static str> {
+    const USAGE: &str = "usage: date [YYYY-MM-DD HH:MM:SS]";
+
+    match args {
+        [] => (),
+        [d, t] => {
+            let dt = parse_date_time(d, t).ok_or(USAGE)?;
+            walltime::set(&dt).map_err(|_| "could not set the RTC")?;
+        }
+        _ => return Err(USAGE),
+    }
+
+    match walltime::now() {
+        Some(dt) => println!("{} UTC", dt),
+        None => println!("No RTC, uptime {}", time::Hms::uptime()),
+    }
+
+    Ok(())
+}
+
+fn help(_args: &[&str]) -> Result<(), &'static str> {
+    print!("Commands:");
+    for (name, _) in BUILTIN {
+        print!(" {}", name);
+    }
+    COMMANDS.lock(|commands| {
+        for (name, _) in commands.iter().filter_map(|c| *c) {
+            print!(" {}", name);
+        }
+    });
+    println!();
+
+    Ok(())
+}
+
+/// Hex dump memory. Addresses that are not mapped read as zero, instead of
+/// faulting.
+fn md(args: &[&str]) -> Result<(), &'static str> {
+    const USAGE: &str = "usage: md <addr> <len>";
+
+    let (addr, len) = match args {
+        [addr, len] => (
+            parse_number(addr).ok_or(USAGE)?,
+            parse_number(len).ok_or(USAGE)?,
+        ),
+        _ => return Err(USAGE),
+    };
+
+    // Whole lines, like `dump()` reads them
+    let first = addr & !0xF;
+    let last = addr.saturating_add(len.max(1) - 1) | 0xF;
+
+    if exception::expect_fault(first..=last, || GlobalConsole.dump(addr, len)) {
+        println!("(Parts are not mapped, they read as zero.)");
+    }
+
+    Ok(())
+}
+
+/// Write a 32 bit word.
+fn mw(args: &[&str]) -> Result<(), &'static str> {
+    const USAGE: &str = "usage: mw <addr> <value>";
+
+    let (addr, value) = match args {
+        [addr, value] => (
+            parse_number(addr).ok_or(USAGE)?,
+            parse_number(value).ok_or(USAGE)?,
+        ),
+        _ => return Err(USAGE),
+    };
+
+    if addr % 4 != 0 {
+        return Err("the address must be 4 byte aligned");
+    }
+    if value > u32::max_value() as usize {
+        return Err("the value must fit into 32 bits");
+    }
+
+    let faulted = exception::expect_fault(addr..=addr + 3, || unsafe {
+        ptr::write_volatile(addr as *mut u32, value as u32)
+    });
+    if faulted {
+        return Err("the address is not mapped, or read-only");
+    }
+
+    Ok(())
+}
+
+fn reset(_args: &[&str]) -> Result<(), &'static str> {
+    println!("Resetting...");
+    GlobalConsole.flush();
+
+    hw::Watchdog::new(hw::Peripheral::Pm).reset()
+}
+
+fn sysinfo(_args: &[&str]) -> Result<(), &'static str> {
+    banner::print();
+
+    Ok(())
+}
+
+fn temp(_args: &[&str]) -> Result<(), &'static str> {
+    let mut v_mbox = hw::VideocoreMbox::new()
+        .map_err(|_| "no mailbox buffer")?;
+    let t = thermal::temperature_millicelsius(&mut v_mbox).map_err(|_| "mailbox call failed")?;
+
+    println!("{}.{} C", t / 1000, (t % 1000) / 100);
+
+    let state = match crate::thermal::state() {
+        Some(state) => state,
+        None => return Ok(()),
+    };
+
+    println!("Watchdog: {:?}, readings oldest first:", state);
+    for s in crate::thermal::history().iter().filter_map(|s| *s) {
+        let t = s.millicelsius;
+        println!("  -{:>3} s  {}.{} C", s.at.elapsed().as_secs(), t / 1000, (t % 1000) / 100);
+    }
+
+    Ok(())
+}
+
+fn ticks(_args: &[&str]) -> Result<(), &'static str> {
+    let ticks = tick::ticks();
+
+    println!(
+        "{} ticks ({} ms), uptime {}",
+        ticks,
+        tick::ticks_to_us(ticks) / 1000,
+        time::Hms::uptime()
+    );
+
+    Ok(())
+}
+
+/// Set, list and clear hardware watchpoints. The length is the largest that
+/// the address is aligned to, up to 8 bytes.
+fn watch(args: &[&str]) -> Result<(), &'static str> {
+    use debug::{ReadWrite, WatchLen};
+    const USAGE: &str = "usage: watch [<addr> [r|w|rw] | clear <n>]";
+
+    match args {
+        [] => {
+            for (n, wp) in debug::watchpoints().iter().enumerate() {
+                if let Some(wp) = wp {
+                    println!("{}: {:#010X}, {} bytes, {:?}", n, wp.addr, wp.len as usize, wp.kind);
+                }
+            }
+
+            Ok(())
+        }
+
+        ["clear", n] => {
+            let n = parse_number(n).ok_or(USAGE)?;
+            let handle = debug::watchpoint_handle(n).ok_or("no such watchpoint")?;
+
+            debug::clear_watchpoint(handle).map_err(|_| "no such watchpoint")
+        }
+
+        [addr] | [addr, _] => {
+            let addr = parse_number(addr).ok_or(USAGE)?;
+            let kind = match args.get(1) {
+                None | Some(&"w") => ReadWrite::Write,
+                Some(&"r") => ReadWrite::Read,
+                Some(&"rw") => ReadWrite::Both,
+                Some(_) => return Err(USAGE),
+            };
+            let len = match addr % 8 {
+                0 => WatchLen::Double,
+                4 => WatchLen::Word,
+                2 | 6 => WatchLen::Half,
+                _ => WatchLen::Byte,
+            };
+
+            match debug::set_watchpoint(addr, len, kind) {
+                Ok(handle) => {
+                    println!("Watchpoint {} set.", handle.index());
+                    Ok(())
+                }
+
+                Err(_) => Err("all watchpoints are in use"),
+            }
+        }
+
+        _ => Err(USAGE),
+    }
+}
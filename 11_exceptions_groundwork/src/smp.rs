@@ -0,0 +1,196 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Starting the secondary cores.
+//!
+//! The firmware's armstub keeps cores 1 to 3 in a spin loop, waiting for an
+//! address to show up in their spin table mailbox at 0xE0, 0xE8 and 0xF0.
+//! `start_core()` writes the physical address of
+//! `raspi3_boot::_secondary_entry()` there, which gives the core its own
+//! stacks, drops it to EL1 and switches on the MMU. `secondary_main()` then
+//! moves it to the kernel's page tables and exception vectors, and calls the
+//! entry function of the caller's choice.
+//!
+//! Once started, cores can be signalled with the IPIs in `ipi`. Data that
+//! each core needs a copy of goes into a `PerCore`.
+
+pub mod ipi;
+
+use crate::{cache, cpu, interrupt, memory, perf};
+use core::{
+    ptr,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+use cortex_a::{asm, barrier, regs::*};
+
+pub use crate::cpu::core_id;
+
+pub const NUM_CORES: usize = 4;
+
+/// Physical addresses of the spin table mailboxes. Core 0 has none.
+const SPIN_TABLE: [usize; NUM_CORES] = [0, 0xE0, 0xE8, 0xF0];
+
+/// The entry functions handed to `start_core()`. Zero while not started.
+static ENTRIES: [AtomicUsize; NUM_CORES] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+
+/// One `T` for each core.
+///
+/// A core only gets at its own copy, through `with()` and with IRQs masked,
+/// so that the code can not end up on another core halfway through once
+/// there is a scheduler. Mutable data is kept in a `Cell` or an atomic.
+pub struct PerCore<T> {
+    data: [T; NUM_CORES],
+}
+
+unsafe impl<T: Send> Sync for PerCore<T> {}
+
+impl<T> PerCore<T> {
+    pub const fn new(data: [T; NUM_CORES]) -> PerCore<T> {
+        PerCore { data }
+    }
+
+    /// Call `f` with the copy of the executing core.
+    pub fn with<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&T) -> R,
+    {
+        let daif = cpu::local_irq_save();
+        let ret = f(&self.data[core_id()]);
+        cpu::local_irq_restore(daif);
+
+        ret
+    }
+
+    /// The copy of `core`, for reading it from other cores. Only for data that
+    /// is safe to share anyways.
+    pub fn of(&self, core: usize) -> &T
+    where
+        T: Sync,
+    {
+        &self.data[core]
+    }
+}
+
+/// How often each core woke up in `idle_core()`.
+static IDLE_WAKEUPS: PerCore<AtomicU64> = PerCore::new([
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+]);
+
+#[derive(Debug)]
+pub enum SmpError {
+    /// Core 0 is the boot core, and there are only four.
+    InvalidCore,
+    AlreadyStarted,
+}
+pub type Result<T> = ::core::result::Result<T, SmpError>;
+
+/// Release `core` from the firmware's spin loop, and have it call `entry`.
+///
+/// `entry` runs in EL1 with all interrupts masked. Each core can only be
+/// started once.
+pub fn start_core(core: usize, entry: fn() -> !) -> Result<()> {
+    if core == 0 || core >= NUM_CORES {
+        return Err(SmpError::InvalidCore);
+    }
+
+    ENTRIES[core]
+        .compare_exchange(0, entry as usize, Ordering::AcqRel, Ordering::Relaxed)
+        .map_err(|_| SmpError::AlreadyStarted)?;
+
+    extern "C" {
+        fn _secondary_entry() -> !;
+    }
+    let entry_phys = memory::virt_to_phys(_secondary_entry as usize) as u64;
+
+    // The spinning core reads the mailbox with its caches still off, so the
+    // write must reach memory before the core is woken up.
+    let mailbox = memory::phys_to_virt(SPIN_TABLE[core]);
+    unsafe { ptr::write_volatile(mailbox as *mut u64, entry_phys) };
+    cache::clean_dcache_range(mailbox, 8);
+
+    cpu::sev();
+
+    Ok(())
+}
+
+/// Mask all interrupts and sleep forever.
+pub fn park_core() -> ! {
+    cpu::local_irq_disable();
+    cpu::local_fiq_disable();
+
+    cpu::wait_forever()
+}
+
+/// Serve IPIs, and sleep in between, forever.
+pub fn idle_core() -> ! {
+    ipi::enable();
+    cpu::local_irq_enable();
+
+    loop {
+        asm::wfe();
+        IDLE_WAKEUPS.with(|wakeups| wakeups.fetch_add(1, Ordering::Relaxed));
+    }
+}
+
+/// How often `core` woke up in `idle_core()` so far.
+pub fn idle_wakeups(core: usize) -> u64 {
+    IDLE_WAKEUPS.of(core).load(Ordering::Relaxed)
+}
+
+/// Where `raspi3_boot` leaves a secondary core, in the higher half but still on
+/// the early page tables.
+pub fn secondary_main() -> ! {
+    extern "C" {
+        static __exception_vectors_start: u64;
+    }
+
+    cpu::init_core_id();
+
+    unsafe {
+        memory::mmu::init_secondary();
+
+        VBAR_EL1.set(&__exception_vectors_start as *const _ as u64);
+        barrier::isb(barrier::SY);
+    }
+
+    interrupt::init_core();
+    perf::init();
+
+    match ENTRIES[core_id()].load(Ordering::Acquire) {
+        0 => park_core(),
+        entry => {
+            let entry: fn() -> ! = unsafe { core::mem::transmute(entry) };
+
+            entry()
+        }
+    }
+}
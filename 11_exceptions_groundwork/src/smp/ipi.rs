@@ -0,0 +1,224 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Inter-processor interrupts through the mailboxes of the ARM local
+//! peripherals.
+//!
+//! Each core has four 32 bit mailboxes, of which mailbox 0 is used here. A
+//! write to its set register ORs the bits in, and the core's IRQ is pending
+//! for as long as any bit is set. The receiving core clears the bits by
+//! writing them back, and calls the handler of every bit it found.
+//!
+//! The lowest bits are taken by the IPIs that the kernel knows itself, the
+//! others can be given a handler with `register_handler()`.
+//!
+//! On the Pi 4, the mailboxes do not reach the GIC. There, the bits are kept
+//! in memory, one word per core, and the receiving core is kicked with an SGI,
+//! see `interrupt::send_ipi()`. The semantics stay the same.
+
+use crate::sync::SpinLock;
+#[cfg(not(feature = "rpi4"))]
+use crate::{devices::hw, memory};
+#[cfg(feature = "rpi4")]
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Call the function that was handed to `call()`.
+pub const CALL: u32 = 1 << 0;
+/// Park the core for good.
+#[allow(dead_code)]
+pub const STOP: u32 = 1 << 1;
+/// Do nothing. Taking the IRQ is enough to wake a core from `wfe` or `wfi`.
+#[allow(dead_code)]
+pub const WAKE: u32 = 1 << 2;
+
+/// Bits below this one are reserved for the IPIs above.
+const FIRST_FREE_BIT: usize = 3;
+
+/// Handlers for bits `FIRST_FREE_BIT` to 31.
+static HANDLERS: SpinLock<[Option<fn()>; 32]> = SpinLock::new([None; 32]);
+
+/// The functions that `CALL` runs, one per core. Zero while none is queued.
+static CALL_FNS: [AtomicUsize; super::NUM_CORES] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+
+#[derive(Debug)]
+pub enum IpiError {
+    InvalidCore,
+    /// The bit is reserved, or out of range.
+    InvalidBit,
+    AlreadyRegistered,
+    /// The core did not run the previous `call()` yet.
+    Busy,
+}
+pub type Result<T> = ::core::result::Result<T, IpiError>;
+
+#[cfg(not(feature = "rpi4"))]
+fn local_ctrl() -> hw::LocalCtrl {
+    hw::LocalCtrl::new(hw::Peripheral::LocalCtrl)
+}
+
+/// The pending bits of each core, in place of the mailboxes
+#[cfg(feature = "rpi4")]
+static PENDING: [AtomicU32; super::NUM_CORES] = [
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+    AtomicU32::new(0),
+];
+
+/// OR `bits` into the mailbox of `core`, which pends its IRQ.
+#[cfg(not(feature = "rpi4"))]
+fn raise(core: usize, bits: u32) {
+    local_ctrl().mailbox0_set(core, bits);
+}
+
+#[cfg(feature = "rpi4")]
+fn raise(core: usize, bits: u32) {
+    PENDING[core].fetch_or(bits, Ordering::AcqRel);
+    crate::interrupt::send_ipi(core);
+}
+
+/// Take all bits that are pending for `core`.
+#[cfg(not(feature = "rpi4"))]
+fn take(core: usize) -> u32 {
+    let local_ctrl = local_ctrl();
+    let bits = local_ctrl.mailbox0_read(core);
+    local_ctrl.mailbox0_clear(core, bits);
+
+    bits
+}
+
+#[cfg(feature = "rpi4")]
+fn take(core: usize) -> u32 {
+    PENDING[core].swap(0, Ordering::AcqRel)
+}
+
+/// Drop the pending bits of `core`, and unmask its IPI.
+#[cfg(not(feature = "rpi4"))]
+fn unmask(core: usize) {
+    let local_ctrl = local_ctrl();
+
+    local_ctrl.mailbox0_clear(core, !0);
+    local_ctrl.enable_mailbox0_irq(core);
+}
+
+#[cfg(feature = "rpi4")]
+fn unmask(core: usize) {
+    PENDING[core].store(0, Ordering::Release);
+    crate::interrupt::enable_ipi();
+}
+
+/// Have `handler` called on the receiving core whenever `1 << bit` is sent.
+#[allow(dead_code)]
+pub fn register_handler(bit: usize, handler: fn()) -> Result<()> {
+    if bit < FIRST_FREE_BIT || bit >= 32 {
+        return Err(IpiError::InvalidBit);
+    }
+
+    HANDLERS.lock_irqsave(|handlers| match handlers[bit] {
+        Some(_) => Err(IpiError::AlreadyRegistered),
+        None => {
+            handlers[bit] = Some(handler);
+            Ok(())
+        }
+    })
+}
+
+/// Raise `bits` in mailbox 0 of `core`.
+///
+/// Bits that are still pending from an earlier `send()` are merged with the
+/// new ones, so each handler runs once for all sends that happened before the
+/// core got to it.
+pub fn send(core: usize, bits: u32) -> Result<()> {
+    if core >= super::NUM_CORES {
+        return Err(IpiError::InvalidCore);
+    }
+
+    raise(core, bits);
+
+    Ok(())
+}
+
+/// Have `core` call `f` in its IRQ handler.
+pub fn call(core: usize, f: fn()) -> Result<()> {
+    if core >= super::NUM_CORES {
+        return Err(IpiError::InvalidCore);
+    }
+
+    CALL_FNS[core]
+        .compare_exchange(0, f as usize, Ordering::AcqRel, Ordering::Relaxed)
+        .map_err(|_| IpiError::Busy)?;
+
+    send(core, CALL)
+}
+
+/// Let IPIs in on the executing core.
+///
+/// Bits that were set before are dropped. IRQs must be unmasked on top for
+/// the handlers to run.
+pub fn enable() {
+    unmask(super::core_id());
+}
+
+/// Called from the IRQ handler when mailbox 0 of the executing core is
+/// pending.
+pub fn irq_handler() {
+    let core = super::core_id();
+
+    // Clear before dispatching, so that a bit that is sent again while its
+    // handler runs is not lost, but pends a new IRQ.
+    let bits = take(core);
+
+    if bits & CALL != 0 {
+        match CALL_FNS[core].swap(0, Ordering::AcqRel) {
+            0 => (),
+            f => {
+                let f: fn() = unsafe { core::mem::transmute(f) };
+                f();
+            }
+        }
+    }
+
+    if bits & STOP != 0 {
+        super::park_core();
+    }
+
+    // WAKE needs no handling.
+
+    for bit in FIRST_FREE_BIT..32 {
+        if bits & (1 << bit) == 0 {
+            continue;
+        }
+
+        // Copy the handler out, so that the lock is not held while it runs.
+        if let Some(handler) = HANDLERS.lock_irqsave(|handlers| handlers[bit]) {
+            handler();
+        }
+    }
+}
@@ -0,0 +1,141 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Catching stack overflows.
+//!
+//! Each core's kernel stack sits right above a 4 KiB guard page, reserved in
+//! the linker script. The kernel's page tables leave the guard pages
+//! unmapped, so the first access of an overflowing stack is a data abort with
+//! FAR_EL1 in a guard page, which the synchronous exception handler reports
+//! as a stack overflow.
+//!
+//! Until `mmu::init()` installed these tables, the guard pages are mapped
+//! like any other memory. For that time, `init()` fills them with a canary
+//! pattern, which `check()` looks at on every kernel tick. The stacks of
+//! tasks can not have a guard page of their own, they are plain arrays, so
+//! they always get the canary: `task::spawn()` puts it into their lowest
+//! bytes.
+
+use crate::{cpu, memory::mmu, task};
+use core::{
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+pub const GUARD_SIZE: usize = mmu::FOUR_KIB;
+
+pub const CANARY: u64 = 0x57AC_C0DE_57AC_C0DE;
+
+/// Secondary cores whose stacks are reserved in the linker script
+const SECONDARY_CORES: usize = 3;
+
+extern "C" {
+    static __kernel_stack_guard_start: u64;
+    static __secondary_stacks_start: u64;
+    static __secondary_stacks_end: u64;
+}
+
+/// Whether the kernel's page tables, without the guard pages, are in use.
+static PROTECTED: AtomicBool = AtomicBool::new(false);
+
+/// The start of the guard page below the kernel stack of `core`.
+///
+/// The secondary cores' stacks are laid out like `_secondary_entry()`
+/// expects, three equally sized slots of a guard page and a stack.
+pub fn guard_page(core: usize) -> usize {
+    unsafe {
+        if core == 0 {
+            return &__kernel_stack_guard_start as *const _ as usize;
+        }
+
+        let start = &__secondary_stacks_start as *const _ as usize;
+        let slot = (&__secondary_stacks_end as *const _ as usize - start) / SECONDARY_CORES;
+
+        start + (core - 1) * slot
+    }
+}
+
+/// The core whose guard page `addr` is in, if any.
+pub fn guard_owner(addr: usize) -> Option<usize> {
+    (0..=SECONDARY_CORES).find(|&core| {
+        let guard = guard_page(core);
+
+        guard <= addr && addr < guard + GUARD_SIZE
+    })
+}
+
+/// Fill `len` bytes from `start`, rounded inwards to whole words, with the
+/// canary.
+pub fn fill_canary(start: usize, len: usize) {
+    let first = (start + 7) & !7;
+
+    for addr in (first..(start + len) & !7).step_by(8) {
+        unsafe { ptr::write_volatile(addr as *mut u64, CANARY) };
+    }
+}
+
+/// Whether `fill_canary()`'s pattern is still all there.
+pub fn canary_intact(start: usize, len: usize) -> bool {
+    let first = (start + 7) & !7;
+
+    (first..(start + len) & !7)
+        .step_by(8)
+        .all(|addr| unsafe { ptr::read_volatile(addr as *const u64) } == CANARY)
+}
+
+/// Write the canary into all guard pages. Must run on the boot core before
+/// the secondary cores are started, and before `mmu::init()` unmaps them.
+pub fn init() {
+    for core in 0..=SECONDARY_CORES {
+        fill_canary(guard_page(core), GUARD_SIZE);
+    }
+}
+
+/// Called by `mmu::init()` once the guard pages are unmapped. From now on,
+/// they fault instead of holding a canary.
+pub fn set_protected() {
+    PROTECTED.store(true, Ordering::Release);
+}
+
+/// Report a stack overflow and halt the executing core.
+pub fn overflow(whose: core::fmt::Arguments) -> ! {
+    eprintln!("[!] Stack overflow {}. Halting CPU.", whose);
+
+    cpu::wait_forever()
+}
+
+/// Check the canaries, called on every kernel tick.
+pub fn check() {
+    if !PROTECTED.load(Ordering::Acquire) {
+        for core in 0..=SECONDARY_CORES {
+            if !canary_intact(guard_page(core), GUARD_SIZE) {
+                overflow(format_args!("on core {}", core));
+            }
+        }
+    }
+
+    if let Some(id) = task::overflowed() {
+        overflow(format_args!("in {} on core {}", id, cpu::core_id()));
+    }
+}
@@ -22,7 +22,17 @@
  * SOFTWARE.
  */
 
-use core::cell::UnsafeCell;
+//! Locks for data that is shared between cores and IRQ handlers.
+//!
+//! `NullLock` only marks the places that need one. `SpinLock` is the real
+//! thing, a ticket lock that hands out the lock in the order it was asked for.
+
+use crate::cpu;
+use core::{
+    cell::UnsafeCell,
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+};
+use cortex_a::asm;
 
 pub struct NullLock<T> {
     data: UnsafeCell<T>,
@@ -49,3 +59,134 @@ impl<T> NullLock<T> {
         f(unsafe { &mut *self.data.get() })
     }
 }
+
+/// No core holds the lock, see `SpinLock::owner`.
+const NO_OWNER: usize = usize::max_value();
+
+/// A ticket spinlock.
+///
+/// Every core that wants the lock draws a ticket, and waits in `wfe` until its
+/// number is served. The unlock is a store-release followed by `sev`, which
+/// wakes the waiters to check again.
+///
+/// The lock is not recursive: A core that asks for a lock it already holds
+/// waits for itself forever. Debug builds assert against that instead. An IRQ
+/// handler that takes a lock which the interrupted code on the same core
+/// holds is the same deadlock, so locks that IRQ handlers use must be taken
+/// with `lock_irqsave()` everywhere.
+pub struct SpinLock<T> {
+    next_ticket: AtomicU32,
+    now_serving: AtomicU32,
+    /// The ID of the core that holds the lock, or `NO_OWNER`
+    owner: AtomicUsize,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+
+/// Releases the lock, and restores the interrupt mask if it was saved, when
+/// dropped.
+struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+    daif: Option<u32>,
+}
+
+impl<'a, T> Drop for SpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.unlock();
+
+        if let Some(daif) = self.daif {
+            cpu::local_irq_restore(daif);
+        }
+    }
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(data: T) -> SpinLock<T> {
+        SpinLock {
+            next_ticket: AtomicU32::new(0),
+            now_serving: AtomicU32::new(0),
+            owner: AtomicUsize::new(NO_OWNER),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    fn acquire(&self) {
+        if cfg!(debug_assertions) {
+            assert_ne!(
+                self.owner.load(Ordering::Relaxed),
+                cpu::core_id(),
+                "recursive SpinLock on the same core"
+            );
+        }
+
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        while self.now_serving.load(Ordering::Acquire) != ticket {
+            asm::wfe();
+        }
+
+        self.owner.store(cpu::core_id(), Ordering::Relaxed);
+    }
+
+    fn unlock(&self) {
+        self.owner.store(NO_OWNER, Ordering::Relaxed);
+
+        // Only the holder writes `now_serving`, so no read-modify-write needed
+        let serving = self.now_serving.load(Ordering::Relaxed);
+        self.now_serving.store(serving.wrapping_add(1), Ordering::Release);
+
+        crate::event::notify();
+    }
+
+    /// Call `f` with the lock held.
+    pub fn lock<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut T) -> R,
+    {
+        self.acquire();
+        let _guard = SpinLockGuard {
+            lock: self,
+            daif: None,
+        };
+
+        f(unsafe { &mut *self.data.get() })
+    }
+
+    /// Call `f` with the lock held and IRQs masked on the executing core, so
+    /// that the lock can be shared with IRQ handlers. The previous mask is
+    /// restored afterwards.
+    pub fn lock_irqsave<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut T) -> R,
+    {
+        // Mask first, or an IRQ handler could spin on the lock right after
+        // it was taken.
+        let daif = cpu::local_irq_save();
+        self.acquire();
+        let _guard = SpinLockGuard {
+            lock: self,
+            daif: Some(daif),
+        };
+
+        f(unsafe { &mut *self.data.get() })
+    }
+
+    /// Whether the executing core holds the lock right now.
+    pub fn held_by_current_core(&self) -> bool {
+        self.owner.load(Ordering::Relaxed) == cpu::core_id()
+    }
+
+    /// Call `f` without taking the lock.
+    ///
+    /// # Safety
+    ///
+    /// Only for code that interrupted the holder on the same core, like an
+    /// exception handler or a panic, and that would deadlock otherwise. `f`
+    /// must cope with the data in whatever state the holder left it.
+    pub unsafe fn lock_unchecked<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut T) -> R,
+    {
+        f(&mut *self.data.get())
+    }
+}
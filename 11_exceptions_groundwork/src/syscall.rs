@@ -0,0 +1,153 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! System calls via `svc #N`.
+//!
+//! The immediate of the `svc` instruction selects the call, which the
+//! exception handler reads back from the ISS of ESR_EL1. Up to four arguments
+//! are passed in x0-x3, and the return value comes back in x0. Negative
+//! return values are errors, see [`Error`].
+//!
+//! The kernel itself may call them, and so may a user program at EL0, see
+//! `user`. Pointers from user programs are checked to be readable by EL0, so
+//! that a program can not make the kernel read kernel memory for it.
+//!
+//! Use the [`syscall!`] macro on the caller side:
+//!
+//! ```
+//! let msg = "Hello\n";
+//! let written = syscall!(syscall::nr::WRITE, 1, msg.as_ptr(), msg.len());
+//! ```
+
+use crate::{delays, print};
+use core::{slice, str};
+
+/// The system call numbers, used as the `svc` immediate.
+pub mod nr {
+    /// `write(fd, buf, len)`: Write `len` bytes at `buf` to the console.
+    /// `fd` must be 1 (stdout) or 2 (stderr). Returns the number of bytes
+    /// written.
+    pub const WRITE: u16 = 0;
+
+    /// `sleep_us(n)`: Sleep for `n` microseconds on the ARM timer.
+    pub const SLEEP_US: u16 = 1;
+
+    /// `exit(code)`: End the user program, see `user::run()`. There is
+    /// nothing to exit from for the kernel, so it gets `NoSuchSyscall`.
+    pub const EXIT: u16 = 2;
+}
+
+/// Errors, returned negated in x0 like the Linux errno values.
+#[derive(Debug, Copy, Clone)]
+pub enum Error {
+    BadFileDescriptor = 9,
+    BadAddress = 14,
+    NoSuchSyscall = 38,
+}
+
+pub type Result<T> = ::core::result::Result<T, Error>;
+
+/// Who made the call, the kernel at EL1 or a user program at EL0
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Caller {
+    Kernel,
+    User,
+}
+
+type Handler = fn(&[u64; 4], Caller) -> Result<u64>;
+
+/// The syscall table, indexed by the system call number.
+static TABLE: [Handler; 2] = [sys_write, sys_sleep_us];
+
+/// Run the system call `nr` and turn the outcome into the value for x0.
+pub fn dispatch(nr: u16, args: &[u64; 4], caller: Caller) -> i64 {
+    let ret = match TABLE.get(usize::from(nr)) {
+        Some(handler) => handler(args, caller),
+        None => Err(Error::NoSuchSyscall),
+    };
+
+    match ret {
+        Ok(val) => val as i64,
+        Err(e) => -(e as i64),
+    }
+}
+
+/// Whether EL0 may read all of the `len` bytes at `addr`, asked the MMU page
+/// by page with the address translation instruction.
+fn user_readable(addr: usize, len: usize) -> bool {
+    let last = match len.checked_sub(1).map(|n| addr.checked_add(n)) {
+        None => return true,
+        Some(None) => return false,
+        Some(Some(last)) => last,
+    };
+
+    let mut page = addr & !0xFFF;
+    loop {
+        let par: u64;
+        unsafe {
+            asm!("at s1e0r, $1
+                  isb
+                  mrs $0, PAR_EL1"
+                 : "=r"(par)
+                 : "r"(page)
+                 :
+                 : "volatile")
+        };
+
+        // PAR_EL1.F: the translation failed
+        if par & 1 != 0 {
+            return false;
+        }
+        if page == last & !0xFFF {
+            return true;
+        }
+        page += 0x1000;
+    }
+}
+
+fn sys_write(args: &[u64; 4], caller: Caller) -> Result<u64> {
+    let (fd, buf, len) = (args[0], args[1] as *const u8, args[2] as usize);
+
+    if fd != 1 && fd != 2 {
+        return Err(Error::BadFileDescriptor);
+    }
+
+    if buf.is_null() || (caller == Caller::User && !user_readable(buf as usize, len)) {
+        return Err(Error::BadAddress);
+    }
+
+    let bytes = unsafe { slice::from_raw_parts(buf, len) };
+    match str::from_utf8(bytes) {
+        Ok(s) => print!("{}", s),
+        Err(_) => bytes.iter().for_each(|&b| print!("{}", b as char)),
+    }
+
+    Ok(len as u64)
+}
+
+fn sys_sleep_us(args: &[u64; 4], _caller: Caller) -> Result<u64> {
+    delays::wait_usec_irq(args[0]);
+
+    Ok(0)
+}
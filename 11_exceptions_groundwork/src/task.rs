@@ -0,0 +1,464 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Cooperative multitasking on the boot core.
+//!
+//! Tasks run until they call `yield_now()`, which switches to the next task
+//! in a round-robin ready queue. A switch saves and restores only the
+//! callee-saved registers, see `task.S`. The code that booted the kernel is
+//! task 0, so it takes part in the round-robin like any other task.
+//!
+//! `sleep_us()` takes a task off the ready queue and onto a sleep list
+//! ordered by deadline. The kernel tick moves it back once the deadline has
+//! passed. If no task is ready, the core runs the deferred work of the
+//! `workqueue` and idles in `wfi` until a task is ready again.
+//!
+//! A task that returns from its function ends up in `task_exit()`, which
+//! marks it dead and switches away for good. The reaper in `schedule()` and
+//! `spawn()` then frees its slot, from the stack of another task.
+
+use crate::{cpu, stack_guard, sync::SpinLock, tick, time, timer, workqueue};
+use core::{fmt, time::Duration};
+use cortex_a::asm;
+
+global_asm!(include_str!("task.S"));
+
+extern "C" {
+    fn cpu_switch_to(prev: *mut TaskContext, next: *const TaskContext);
+    fn task_trampoline();
+}
+
+pub const MAX_TASKS: usize = 8;
+
+/// Stacks smaller than this are refused. An exception taken by the task
+/// stores its frame on the task's stack, too.
+const MIN_STACK_SIZE: usize = 1024;
+
+/// Bytes at the bottom of each stack that hold the canary of `stack_guard`
+const CANARY_SIZE: usize = 64;
+
+/// The registers that survive a call, as saved by `cpu_switch_to`.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct TaskContext {
+    x19_x28: [u64; 10],
+    fp: u64,
+    sp: u64,
+    lr: u64,
+}
+
+impl TaskContext {
+    const fn new() -> TaskContext {
+        TaskContext {
+            x19_x28: [0; 10],
+            fp: 0,
+            sp: 0,
+            lr: 0,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TaskId(usize);
+
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "task {}", self.0)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    Free,
+    Ready,
+    Running,
+    /// On the sleep list, waiting for its deadline
+    Sleeping,
+    /// Returned from its function, waiting for the reaper
+    Dead,
+}
+
+#[derive(Copy, Clone)]
+struct Task {
+    state: State,
+    /// Counter value of the ARM generic timer to wake up at, if sleeping
+    wake_at: u64,
+    /// Start and end of the stack, or zero for the boot code's
+    stack: (usize, usize),
+    ctx: TaskContext,
+}
+
+impl Task {
+    const fn new(state: State) -> Task {
+        Task {
+            state,
+            wake_at: 0,
+            stack: (0, 0),
+            ctx: TaskContext::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TaskError {
+    StackTooSmall,
+    TooManyTasks,
+}
+
+pub type Result<T> = ::core::result::Result<T, TaskError>;
+
+struct Scheduler {
+    tasks: [Task; MAX_TASKS],
+    current: usize,
+    /// The ready queue, a ring of `len` task numbers starting at `head`
+    queue: [usize; MAX_TASKS],
+    head: usize,
+    len: usize,
+    /// The sleeping tasks, `num_sleeping` of them, earliest deadline first
+    sleepers: [usize; MAX_TASKS],
+    num_sleeping: usize,
+}
+
+impl Scheduler {
+    const fn new() -> Scheduler {
+        const FREE: Task = Task::new(State::Free);
+
+        Scheduler {
+            // The boot code is running already
+            tasks: [
+                Task::new(State::Running),
+                FREE,
+                FREE,
+                FREE,
+                FREE,
+                FREE,
+                FREE,
+                FREE,
+            ],
+            current: 0,
+            queue: [0; MAX_TASKS],
+            head: 0,
+            len: 0,
+            sleepers: [0; MAX_TASKS],
+            num_sleeping: 0,
+        }
+    }
+
+    fn push(&mut self, task: usize) {
+        // A task is queued at most once, so the ring can not overflow
+        self.queue[(self.head + self.len) % MAX_TASKS] = task;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<usize> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let task = self.queue[self.head];
+        self.head = (self.head + 1) % MAX_TASKS;
+        self.len -= 1;
+
+        Some(task)
+    }
+
+    /// Put the current task on the sleep list.
+    ///
+    /// Tasks with the same deadline wake up in the order they went to sleep.
+    fn sleep_current(&mut self, wake_at: u64) {
+        let current = self.current;
+        let tasks = &self.tasks;
+        let pos = self.sleepers[..self.num_sleeping]
+            .iter()
+            .position(|&t| !timer::is_due(tasks[t].wake_at, wake_at))
+            .unwrap_or(self.num_sleeping);
+
+        for i in (pos..self.num_sleeping).rev() {
+            self.sleepers[i + 1] = self.sleepers[i];
+        }
+        self.sleepers[pos] = current;
+        self.num_sleeping += 1;
+
+        self.tasks[current].state = State::Sleeping;
+        self.tasks[current].wake_at = wake_at;
+    }
+
+    /// Move the tasks whose deadline has passed to the ready queue.
+    fn wake_expired(&mut self, now: u64) {
+        let expired = self.sleepers[..self.num_sleeping]
+            .iter()
+            .take_while(|&&t| timer::is_due(self.tasks[t].wake_at, now))
+            .count();
+
+        let sleepers = self.sleepers;
+        for &task in &sleepers[..expired] {
+            self.tasks[task].state = State::Ready;
+            self.push(task);
+        }
+
+        for i in expired..self.num_sleeping {
+            self.sleepers[i - expired] = self.sleepers[i];
+        }
+        self.num_sleeping -= expired;
+    }
+
+    /// Free the slots of dead tasks, except for the current one, whose stack
+    /// is still in use.
+    fn reap(&mut self) {
+        let current = self.current;
+
+        for (_, task) in self
+            .tasks
+            .iter_mut()
+            .enumerate()
+            .filter(|(i, t)| *i != current && t.state == State::Dead)
+        {
+            task.state = State::Free;
+        }
+    }
+
+    /// Make the next ready task the current one.
+    ///
+    /// Returns the contexts to switch between, or None if no other task is
+    /// ready.
+    fn next(&mut self) -> Option<(*mut TaskContext, *const TaskContext)> {
+        let next = self.pop()?;
+        let prev = self.current;
+
+        // A sleeping task that woke up again before another one was ready
+        if next == prev {
+            self.tasks[next].state = State::Running;
+            return None;
+        }
+
+        if self.tasks[prev].state == State::Running {
+            self.tasks[prev].state = State::Ready;
+            self.push(prev);
+        }
+        self.tasks[next].state = State::Running;
+        self.current = next;
+
+        Some((&mut self.tasks[prev].ctx, &self.tasks[next].ctx))
+    }
+}
+
+/// Shared with the tick's IRQ handler, so always taken with
+/// `lock_irqsave()` outside of it.
+static SCHEDULER: SpinLock<Scheduler> = SpinLock::new(Scheduler::new());
+
+/// Switch to the next ready task, or idle until there is one.
+///
+/// Returns once the current task is running again. The context pointers stay
+/// valid outside of the lock because the task table is static, and a task's
+/// context is only written by switching away from it.
+fn schedule() {
+    loop {
+        let (contexts, running) = SCHEDULER.lock_irqsave(|s| {
+            s.reap();
+            let contexts = s.next();
+
+            (contexts, s.tasks[s.current].state == State::Running)
+        });
+
+        if let Some((prev, next)) = contexts {
+            unsafe { cpu_switch_to(prev, next) };
+            return;
+        }
+        if running {
+            return;
+        }
+
+        // Nothing is ready, a good time for the deferred work. Then check
+        // again with IRQs masked, so that an IRQ can not sneak in between the
+        // check and the `wfi`. A pending IRQ ends the `wfi` nevertheless, and
+        // is taken once IRQs are unmasked.
+        workqueue::run_pending();
+
+        let daif = cpu::local_irq_save();
+        if SCHEDULER.lock(|s| s.len == 0) && workqueue::is_empty() {
+            asm::wfi();
+        }
+        cpu::local_irq_enable();
+        cpu::local_irq_restore(daif);
+    }
+}
+
+/// Create a task that runs `entry` on `stack`, and queue it.
+///
+/// It gets to run the next time the current task yields.
+pub fn spawn(entry: fn(), stack: &'static mut [u8]) -> Result<TaskId> {
+    debug_assert_eq!(cpu::core_id(), 0, "tasks only run on the boot core");
+
+    if stack.len() < MIN_STACK_SIZE {
+        return Err(TaskError::StackTooSmall);
+    }
+
+    // The stack grows down from its end, which the AAPCS wants 16 byte aligned
+    let bottom = stack.as_mut_ptr() as usize;
+    let top = (bottom + stack.len()) & !0xF;
+    stack_guard::fill_canary(bottom, CANARY_SIZE);
+
+    SCHEDULER.lock_irqsave(|s| {
+        s.reap();
+
+        let id = s
+            .tasks
+            .iter()
+            .position(|t| t.state == State::Free)
+            .ok_or(TaskError::TooManyTasks)?;
+
+        // The first switch "returns" into the trampoline, which finds the
+        // function to call in x19.
+        let mut ctx = TaskContext::new();
+        ctx.x19_x28[0] = entry as usize as u64;
+        ctx.sp = top as u64;
+        ctx.lr = task_trampoline as usize as u64;
+
+        s.tasks[id] = Task {
+            state: State::Ready,
+            wake_at: 0,
+            stack: (bottom, top),
+            ctx,
+        };
+        s.push(id);
+
+        Ok(TaskId(id))
+    })
+}
+
+/// Give the CPU to the next ready task. Returns right away if there is none,
+/// or once it is the current task's turn again.
+pub fn yield_now() {
+    schedule();
+}
+
+/// Wait for something to happen, e.g. for an IRQ, without keeping the other
+/// tasks from running.
+///
+/// Switches to the next ready task if there is one. Otherwise, runs the
+/// deferred work and sleeps in `wfe` until the next event. Callers check
+/// their condition again afterwards. The secondary cores have no tasks, and
+/// only do the latter.
+pub fn idle() {
+    if cpu::core_id() == 0 && SCHEDULER.lock_irqsave(|s| s.len > 0) {
+        yield_now();
+        return;
+    }
+
+    // `queue_work()` sends an event, so work queued after this is not
+    // stuck until the next unrelated event.
+    if workqueue::run_pending() == 0 {
+        asm::wfe();
+    }
+}
+
+/// Sleep for at least `us` microseconds, letting the other tasks run.
+///
+/// The task is woken up by the first kernel tick after the deadline, so the
+/// sleep is rounded up to the tick. Without a running tick, the task keeps
+/// yielding until the deadline has passed instead.
+pub fn sleep_us(us: u64) {
+    let wake_at = (time::Instant::now() + Duration::from_micros(us)).ticks();
+
+    if !tick::is_running() {
+        while !timer::is_due(wake_at, time::Instant::now().ticks()) {
+            yield_now();
+        }
+        return;
+    }
+
+    SCHEDULER.lock_irqsave(|s| s.sleep_current(wake_at));
+    schedule();
+}
+
+/// Called by the kernel tick, in its IRQ handler.
+pub fn on_tick() {
+    let now = time::Instant::now().ticks();
+
+    SCHEDULER.lock(|s| s.wake_expired(now));
+}
+
+/// The task that is executing this code.
+#[allow(dead_code)]
+pub fn current() -> TaskId {
+    SCHEDULER.lock_irqsave(|s| TaskId(s.current))
+}
+
+/// Start and end of the current task's stack, or None for the boot code,
+/// which runs on the kernel stack, and for the secondary cores.
+///
+/// Meant for fault and panic reports, so it does not take the lock, which
+/// the faulting code might hold.
+pub fn current_stack() -> Option<(usize, usize)> {
+    if cpu::core_id() != 0 {
+        return None;
+    }
+
+    let stack = unsafe { SCHEDULER.lock_unchecked(|s| s.tasks[s.current].stack) };
+    if stack.1 == 0 {
+        return None;
+    }
+
+    Some(stack)
+}
+
+/// A task whose stack canary was overwritten, if any. Called by
+/// `stack_guard::check()` in the tick's IRQ handler.
+pub fn overflowed() -> Option<TaskId> {
+    SCHEDULER.lock(|s| {
+        s.tasks
+            .iter()
+            .position(|t| {
+                t.state != State::Free
+                    && t.stack.1 != 0
+                    && !stack_guard::canary_intact(t.stack.0, CANARY_SIZE)
+            })
+            .map(TaskId)
+    })
+}
+
+/// Whether the task has not returned from its function yet.
+pub fn is_alive(id: TaskId) -> bool {
+    SCHEDULER.lock_irqsave(|s| match s.tasks[id.0].state {
+        State::Ready | State::Running | State::Sleeping => true,
+        State::Free | State::Dead => false,
+    })
+}
+
+/// Where `task_trampoline` goes once a task's function returned.
+///
+/// Task 0 never gets here, so there is always a task to switch to, if
+/// maybe only after idling until it wakes up.
+#[no_mangle]
+extern "C" fn task_exit() -> ! {
+    SCHEDULER.lock_irqsave(|s| {
+        let current = s.current;
+        s.tasks[current].state = State::Dead;
+    });
+
+    schedule();
+
+    // A dead task is never switched back to
+    cpu::wait_forever()
+}
@@ -0,0 +1,442 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! The test kernel, built with `make test`, which enables the `test_kernel`
+//! feature.
+//!
+//! It boots like the normal kernel, but instead of the demos, it runs the
+//! tests below once the exception vectors are in place, and ends QEMU with
+//! the result. A failing `assert_or_exit!` or `assert_eq_or_exit!`, a panic
+//! or a boot that never gets here all exit with a failure.
+//!
+//! Tests only check code that works the same in QEMU and on the board, i.e.
+//! computations and the heap, but no device that QEMU does not emulate.
+
+use crate::{
+    banner, binlog, cpu, delays,
+    devices::hw::{onewire, pl011_uart, videocore_mbox, Deadline},
+    exception::EsrEL1,
+    executor, memory, qemu, rand, time, timer, user,
+};
+use alloc::format;
+use core::{
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+
+struct Test {
+    name: &'static str,
+    run: fn(),
+}
+
+const TESTS: &[Test] = &[
+    Test {
+        name: "time::ticks_to_duration() and duration_to_ticks()",
+        run: tick_conversions,
+    },
+    Test {
+        name: "time::Hms",
+        run: hms_display,
+    },
+    Test {
+        name: "sys_timer::Deadline",
+        run: deadlines,
+    },
+    Test {
+        name: "videocore_mbox::PropertyMessage",
+        run: property_message,
+    },
+    Test {
+        name: "videocore_mbox::edid",
+        run: edid,
+    },
+    Test {
+        name: "onewire::crc8()",
+        run: crc8,
+    },
+    Test {
+        name: "pl011_uart::baud_divisors()",
+        run: pl011_divisors,
+    },
+    Test {
+        name: "rand::SplitMix64 and rand::Rng",
+        run: rand_known_answers,
+    },
+    Test {
+        name: "rand::Rng bit balance",
+        run: rand_bit_balance,
+    },
+    Test {
+        name: "memory::heap",
+        run: heap_stress,
+    },
+    Test {
+        name: "executor and timer::sleep_us()",
+        run: executor_sleep,
+    },
+    Test {
+        name: "binlog frames",
+        run: binlog_frames,
+    },
+    Test {
+        name: "banner::sysinfo()",
+        run: sysinfo,
+    },
+    Test {
+        name: "user::run()",
+        run: user_programs,
+    },
+];
+
+/// Run all tests, and end QEMU with success if all of them pass.
+pub fn run() -> ! {
+    println!("[T] Running {} tests.", TESTS.len());
+
+    for t in TESTS {
+        print!("[T]   {} ... ", t.name);
+        (t.run)();
+        println!("ok");
+    }
+
+    println!("[T] All tests passed.");
+    qemu::qemu_exit(true)
+}
+
+fn tick_conversions() {
+    let frq = time::frequency();
+    assert_or_exit!(frq != 0);
+
+    assert_eq_or_exit!(time::ticks_to_duration(frq), Duration::from_secs(1));
+    assert_eq_or_exit!(time::duration_to_ticks(Duration::from_secs(3)), 3 * frq);
+
+    // Rounds up, a single nanosecond still needs a tick
+    assert_eq_or_exit!(time::duration_to_ticks(Duration::from_nanos(1)), 1);
+    assert_eq_or_exit!(
+        time::duration_to_ticks(Duration::from_secs(u64::max_value())),
+        u64::max_value()
+    );
+
+    // The round trip never comes back short, and at most one tick long
+    let tick = time::ticks_to_duration(1) + Duration::from_nanos(1);
+    for &ns in &[1, 999, 52_083, 1_000_000, 123_456_789, 86_400_000_000_123] {
+        let d = Duration::from_nanos(ns);
+        let back = time::ticks_to_duration(time::duration_to_ticks(d));
+
+        assert_or_exit!(back >= d);
+        assert_or_exit!(back - d <= tick);
+    }
+}
+
+fn hms_display() {
+    let hms = time::Hms(Duration::from_millis(3_723_004));
+    assert_eq_or_exit!(format!("{}", hms), "01:02:03.004");
+
+    let hms = time::Hms(Duration::from_micros(359_999_999_999));
+    assert_eq_or_exit!(format!("{}", hms), "99:59:59.999");
+}
+
+/// The wrapping arithmetic of deadlines, right at the wrap of the counter.
+fn deadlines() {
+    const MAX: u64 = u64::max_value();
+
+    let d = Deadline::after(MAX - 10, 20);
+    assert_or_exit!(!d.expired_at(MAX - 10));
+    assert_or_exit!(!d.expired_at(MAX));
+    assert_or_exit!(!d.expired_at(8));
+    assert_or_exit!(d.expired_at(9));
+    assert_or_exit!(d.expired_at(1_000));
+    assert_eq_or_exit!(d.remaining_us_at(MAX - 10), 20);
+    assert_eq_or_exit!(d.remaining_us_at(0), 9);
+    assert_eq_or_exit!(d.remaining_us_at(9), 0);
+    assert_eq_or_exit!(d.remaining_us_at(1_000), 0);
+
+    // A deadline of 0 has passed already, and one that never comes never does
+    assert_or_exit!(Deadline::after(MAX, 0).expired_at(MAX));
+    let never = Deadline::after(MAX - 10, MAX);
+    assert_or_exit!(!never.expired_at(MAX - 10));
+    assert_or_exit!(!never.expired_at(0));
+    assert_eq_or_exit!(never.remaining_us_at(MAX - 10), MAX / 2);
+
+    // The earlier one wins, also if it is the one before the wrap
+    let early = Deadline::after(MAX - 10, 5);
+    assert_eq_or_exit!(d.min(early), early);
+    assert_eq_or_exit!(early.min(d), early);
+
+    // Read from the counter, on QEMU the generic timer
+    assert_or_exit!(!delays::deadline(1_000_000).expired());
+    assert_or_exit!(delays::poll_timeout(delays::deadline(1_000), || false).is_err());
+    assert_or_exit!(delays::poll_timeout(delays::deadline(0), || true).is_ok());
+}
+
+fn property_message() {
+    use videocore_mbox::{tag, Clock, PropertyMessage, Response, Tag, VideocoreMboxError};
+
+    let msg = PropertyMessage::new()
+        .with(Tag::GetClockRate { clock: Clock::Uart })
+        .with(Tag::GetBoardRevision);
+
+    let mut buf = [0xFFFF_FFFF; 16];
+    assert_eq_or_exit!(msg.serialize(&mut buf), Ok(12));
+    assert_eq_or_exit!(
+        buf[..12],
+        [
+            48,
+            videocore_mbox::REQUEST,
+            tag::GETCLKRATE,
+            8, // Two values for the response
+            0,
+            Clock::Uart as u32,
+            0,
+            tag::GETBOARDREV,
+            4,
+            0,
+            0,
+            tag::LAST
+        ]
+    );
+
+    // Too small for the end tag
+    assert_eq_or_exit!(
+        msg.serialize(&mut buf[..11]),
+        Err(VideocoreMboxError::BufferTooSmall)
+    );
+
+    // What the firmware answers
+    buf[1] = 0x8000_0000;
+    buf[4] = 0x8000_0008;
+    buf[6] = 48_000_000;
+    buf[9] = 0x8000_0004;
+    buf[10] = 0x00a0_2082;
+
+    // A panic ends the test kernel with a failure, too
+    let resp = msg.decode(&buf).unwrap();
+
+    assert_or_exit!(match resp.get(0) {
+        Some(Response::ClockRate {
+            clock: 2,
+            rate: 48_000_000,
+        }) => true,
+        _ => false,
+    });
+    assert_or_exit!(match resp.by_id(tag::GETBOARDREV) {
+        Some(Response::BoardRevision(0x00a0_2082)) => true,
+        _ => false,
+    });
+
+    // A tag without the response bit was not understood
+    buf[9] = 0x0000_0004;
+    assert_or_exit!(match msg.decode(&buf) {
+        Err(VideocoreMboxError::TagRejected(tag::GETBOARDREV)) => true,
+        _ => false,
+    });
+}
+
+fn edid() {
+    use videocore_mbox::edid::{self, EdidError, Mode};
+
+    let mut block = [0u8; edid::BLOCK_LEN];
+    block[..8].copy_from_slice(&[0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00]);
+
+    // Detailed timing of 1920x1080 at 148.5 MHz
+    block[54..62].copy_from_slice(&[0x02, 0x3A, 0x80, 0x18, 0x71, 0x38, 0x2D, 0x40]);
+    assert_or_exit!(match edid::preferred_mode(&block) {
+        Ok(Mode {
+            width: 1920,
+            height: 1080,
+        }) => true,
+        _ => false,
+    });
+
+    let sum = block.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+    assert_or_exit!(!edid::checksum_ok(&block));
+    block[127] = sum.wrapping_neg();
+    assert_or_exit!(edid::checksum_ok(&block));
+
+    block[54] = 0;
+    block[55] = 0;
+    assert_or_exit!(match edid::preferred_mode(&block) {
+        Err(EdidError::NoPreferredTiming) => true,
+        _ => false,
+    });
+
+    block[0] = 0xFF;
+    assert_or_exit!(match edid::preferred_mode(&block) {
+        Err(EdidError::BadHeader) => true,
+        _ => false,
+    });
+}
+
+fn crc8() {
+    // The example ROM code from Maxim's application note 27
+    let rom = [0x02, 0x1C, 0xB8, 0x01, 0x00, 0x00, 0x00, 0xA2];
+
+    assert_eq_or_exit!(onewire::crc8(&rom[..7]), 0xA2);
+    assert_eq_or_exit!(onewire::crc8(&rom), 0);
+    assert_eq_or_exit!(onewire::crc8(&[]), 0);
+}
+
+/// The divisors for the 48 MHz UART clock that the kernel asks the firmware
+/// for, and the rates that do not fit into IBRD.
+fn pl011_divisors() {
+    const CLOCK: u32 = 48_000_000;
+
+    // 26.042 and 3.255, with the fraction in 64ths
+    assert_or_exit!(match pl011_uart::baud_divisors(CLOCK, 115_200) {
+        Ok((26, 3)) => true,
+        _ => false,
+    });
+    assert_or_exit!(match pl011_uart::baud_divisors(CLOCK, 921_600) {
+        Ok((3, 16)) => true,
+        _ => false,
+    });
+
+    // IBRD would be 0, or exceed its 16 bits
+    assert_or_exit!(pl011_uart::baud_divisors(CLOCK, 4_000_000).is_err());
+    assert_or_exit!(pl011_uart::baud_divisors(CLOCK, 10).is_err());
+    assert_or_exit!(pl011_uart::baud_divisors(CLOCK, 0).is_err());
+}
+
+fn rand_known_answers() {
+    // From the reference implementations
+    let mut sm = rand::SplitMix64::from_seed(0);
+    assert_eq_or_exit!(sm.next_u64(), 0xE220_A839_7B1D_CDAF);
+    assert_eq_or_exit!(sm.next_u64(), 0x6E78_9E6A_A1B9_65F4);
+    assert_eq_or_exit!(sm.next_u64(), 0x06C4_5D18_8009_454F);
+
+    let mut rng = rand::Rng::from_seed(0);
+    assert_eq_or_exit!(rng.next_u64(), 0x99EC_5F36_CB75_F2B4);
+    assert_eq_or_exit!(rng.next_u64(), 0xBF6E_1F78_4956_452A);
+
+    // Same seed, same bytes, also for a length that is not a multiple of 8
+    let (mut a, mut b) = ([0u8; 13], [0u8; 13]);
+    rand::Rng::from_seed(0x5EED).fill_bytes(&mut a);
+    rand::Rng::from_seed(0x5EED).fill_bytes(&mut b);
+    assert_eq_or_exit!(a, b);
+    assert_eq_or_exit!(&a[..8], &0xEF33_F170_5524_4B74u64.to_le_bytes()[..]);
+}
+
+/// Half of the bits should be set. Over 65536 bits, the standard deviation is
+/// 128, so this fails only for a broken generator.
+fn rand_bit_balance() {
+    let mut rng = rand::Rng::from_seed(0x5EED);
+    let ones: u32 = (0..1024).map(|_| rng.next_u64().count_ones()).sum();
+
+    assert_or_exit!(ones > 32_768 - 640 && ones < 32_768 + 640);
+}
+
+fn heap_stress() {
+    let (total, _, _) = memory::heap::usage();
+    assert_or_exit!(total != 0);
+
+    let mut rng = rand::Rng::from_seed(0x5EED);
+    assert_eq_or_exit!(memory::heap::stress_test(2_000, &mut rng), Ok(()));
+}
+
+/// Two tasks that sleep for different times finish in the order of their
+/// deadlines, not of spawning, and the sleeps are not cut short.
+fn executor_sleep() {
+    // The ids of the finished tasks as decimal digits, the first one highest
+    static FINISHED: AtomicU32 = AtomicU32::new(0);
+
+    async fn sleeper(us: u64, id: u32) {
+        timer::sleep_us(us).await;
+        FINISHED.store(FINISHED.load(Ordering::Relaxed) * 10 + id, Ordering::Relaxed);
+    }
+
+    let start = time::Instant::now();
+    assert_or_exit!(executor::spawn(sleeper(20_000, 1)).is_ok());
+    assert_or_exit!(executor::spawn(sleeper(10_000, 2)).is_ok());
+    cpu::irq_unmasked(executor::run);
+
+    assert_or_exit!(start.elapsed() >= Duration::from_millis(20));
+    assert_eq_or_exit!(FINISHED.load(Ordering::Relaxed), 21);
+
+    assert_eq_or_exit!(executor::block_on(async { 42 }), 42);
+}
+
+/// The same frame is the self-test of utils/binlog_decode.rb, so that the
+/// kernel and the decoder agree on the format.
+fn binlog_frames() {
+    assert_eq_or_exit!(binlog::crc16(b"123456789"), 0x29B1);
+
+    let mut frame = [0u8; binlog::MAX_FRAME];
+    let len = binlog::encode_record(3, 1_234_567, 5, b"hi", &mut frame);
+    assert_eq_or_exit!(
+        frame[..len],
+        [
+            0x04, 0x0C, 0xB1, 0x02, 0x05, 0x03, 0x87, 0xD6, 0x12, 0x01, 0x01, 0x01, 0x01, 0x02,
+            0x05, 0x05, 0x68, 0x69, 0x4A, 0x06
+        ]
+    );
+
+    // A run of 254 non-zero bytes ends a block without an implied zero
+    let data = [0xAAu8; 254];
+    let mut out = [0u8; 256];
+    assert_eq_or_exit!(binlog::cobs_encode(&data, &mut out), Some(256));
+    assert_eq_or_exit!((out[0], out[255]), (0xFF, 0x01));
+    assert_eq_or_exit!(binlog::cobs_encode(&data, &mut out[..255]), None);
+
+    assert_eq_or_exit!(binlog::module_id("kernel8"), 0);
+    assert_eq_or_exit!(binlog::module_id("kernel8::memory::mmu"), 11);
+    assert_eq_or_exit!(binlog::module_id("kernel8::memory::heap"), 10);
+    assert_eq_or_exit!(binlog::module_id("kernel8::devices::hw::pl011_uart"), 3);
+}
+
+/// QEMU has neither the RTC nor most of the clocks, which must show up as
+/// absent instead of failing the whole inventory.
+fn sysinfo() {
+    let info = banner::sysinfo();
+
+    assert_eq_or_exit!(info.exception_level, 1);
+    assert_eq_or_exit!(info.peripheral_base, memory::mmio_base::base());
+    assert_or_exit!(!info.rtc);
+
+    let text = format!("{}", info);
+    assert_or_exit!(text.contains("RTC:             not present"));
+    assert_or_exit!(text.contains("Exception level: EL1"));
+}
+
+/// A program at EL0 exits with its code, and the kernel carries on after one
+/// that faults. Each run gets a fresh address space, so the same program runs
+/// again.
+fn user_programs() {
+    use memory::mmu::USER_START;
+    use user::Outcome;
+
+    for _ in 0..2 {
+        assert_or_exit!(match user::run(user::hello()).unwrap() {
+            Outcome::Exited(0) => true,
+            _ => false,
+        });
+    }
+
+    // A Data Abort from EL0, of the store to its own code
+    let code = USER_START as u64;
+    assert_or_exit!(match user::run(user::crash()).unwrap() {
+        Outcome::Crashed { esr, far, elr } => {
+            EsrEL1(esr).ec() == 0x24 && far == Some(code) && elr == code + 4
+        }
+        _ => false,
+    });
+}
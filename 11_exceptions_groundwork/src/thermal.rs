@@ -0,0 +1,282 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+
+//! A watchdog for the SoC temperature.
+//!
+//! After `init()`, the temperature is read from the Videocore every
+//! `SAMPLE_INTERVAL_US`. The timer callback only queues the reading, the
+//! mailbox call itself runs from the work queue. The last `HISTORY_LEN`
+//! readings are kept for the shell.
+//!
+//! The readings are sorted into three states. Each state is left a few
+//! degrees below where it was entered, so that a reading that wobbles around
+//! a threshold does not flip the state back and forth. On every change of
+//! state, the change is logged and the callbacks of `register_callback()`
+//! are called, from the work queue.
+//!
+//! With `throttle` set, `init()` also takes the ARM clock down to its minimum
+//! when the SoC gets hot, and restores it once the SoC is back to nominal.
+
+use crate::{
+    devices::hw::{
+        self,
+        videocore_mbox::{self, clock, thermal, Clock},
+    },
+    sync::{NullLock, SpinLock},
+    time, timer, workqueue,
+};
+
+/// How often the temperature is read
+const SAMPLE_INTERVAL_US: u64 = 5_000_000;
+
+/// How many readings are kept
+pub const HISTORY_LEN: usize = 16;
+
+/// How many callbacks `register_callback()` takes
+pub const MAX_CALLBACKS: usize = 4;
+
+// Thresholds in millidegrees Celsius, entering and leaving each state. The
+// firmware itself starts throttling at 80 C on its own.
+const WARM_ENTER: u32 = 70_000;
+const WARM_LEAVE: u32 = 65_000;
+const HOT_ENTER: u32 = 80_000;
+const HOT_LEAVE: u32 = 75_000;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum State {
+    Nominal,
+    Warm,
+    Hot,
+}
+
+impl State {
+    /// The state after a reading of `t` millidegrees, coming from `self`.
+    fn next(self, t: u32) -> State {
+        match self {
+            _ if t >= HOT_ENTER => State::Hot,
+            State::Hot if t >= HOT_LEAVE => State::Hot,
+            State::Nominal if t < WARM_ENTER => State::Nominal,
+            _ if t >= WARM_LEAVE => State::Warm,
+            _ => State::Nominal,
+        }
+    }
+}
+
+/// Called with the old and the new state.
+pub type Callback = fn(State, State);
+
+#[derive(Debug)]
+pub enum ThermalError {
+    Mailbox(videocore_mbox::VideocoreMboxError),
+    TooManyCallbacks,
+}
+
+pub type Result<T> = ::core::result::Result<T, ThermalError>;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Sample {
+    pub at: time::Instant,
+    pub millicelsius: u32,
+}
+
+struct Monitor {
+    v_mbox: hw::VideocoreMbox<'static>,
+    throttle: bool,
+    state: State,
+    /// The ARM clock from before throttling, to restore it
+    saved_rate: Option<u32>,
+    history: [Option<Sample>; HISTORY_LEN],
+    /// Where the next sample goes
+    next: usize,
+}
+
+/// Only touched from `init()` and the work queue, and read by the shell.
+static MONITOR: NullLock<Option<Monitor>> = NullLock::new(None);
+
+static CALLBACKS: SpinLock<[Option<Callback>; MAX_CALLBACKS]> =
+    SpinLock::new([None; MAX_CALLBACKS]);
+
+/// Take over `v_mbox`, read the temperature once, and start reading it
+/// periodically.
+///
+/// Pass a mailbox of its own, the readings happen in the background.
+pub fn init(mut v_mbox: hw::VideocoreMbox<'static>, throttle: bool) -> Result<u32> {
+    let t = thermal::temperature_millicelsius(&mut v_mbox).map_err(ThermalError::Mailbox)?;
+
+    MONITOR.lock(|m| {
+        *m = Some(Monitor {
+            v_mbox,
+            throttle,
+            state: State::Nominal,
+            saved_rate: None,
+            history: [None; HISTORY_LEN],
+            next: 0,
+        })
+    });
+    record(t);
+
+    if timer::schedule_periodic(SAMPLE_INTERVAL_US, queue_sample).is_none() {
+        warn!("No timer slot for the thermal watchdog.");
+    }
+
+    Ok(t)
+}
+
+/// Have `f` called on every change of state.
+#[allow(dead_code)]
+pub fn register_callback(f: Callback) -> Result<()> {
+    CALLBACKS.lock(|callbacks| {
+        let slot = callbacks
+            .iter_mut()
+            .find(|c| c.is_none())
+            .ok_or(ThermalError::TooManyCallbacks)?;
+        *slot = Some(f);
+
+        Ok(())
+    })
+}
+
+fn queue_sample() {
+    let _ = workqueue::queue_work(sample, 0);
+}
+
+fn sample(_: usize) {
+    let result = MONITOR.lock(|m| {
+        m.as_mut()
+            .map(|m| thermal::temperature_millicelsius(&mut m.v_mbox))
+    });
+
+    match result {
+        Some(Ok(t)) => record(t),
+        Some(Err(e)) => warn!("Could not read the SoC temperature: {:?}", e),
+        None => (),
+    }
+}
+
+/// Add `t` to the history, and act on a change of state.
+fn record(t: u32) {
+    let change = MONITOR.lock(|m| {
+        let m = m.as_mut()?;
+
+        m.history[m.next] = Some(Sample {
+            at: time::Instant::now(),
+            millicelsius: t,
+        });
+        m.next = (m.next + 1) % HISTORY_LEN;
+
+        let old = m.state;
+        m.state = old.next(t);
+        if m.state == old {
+            return None;
+        }
+
+        if m.throttle {
+            throttle(m);
+        }
+
+        Some((old, m.state))
+    });
+
+    let (old, new) = match change {
+        Some(c) => c,
+        None => return,
+    };
+
+    let (deg, tenths) = (t / 1000, (t % 1000) / 100);
+    match new {
+        State::Nominal => info!("SoC temperature {}.{} C, {:?} again.", deg, tenths, new),
+        _ => warn!("SoC temperature {}.{} C, now {:?}.", deg, tenths, new),
+    }
+
+    let callbacks = CALLBACKS.lock(|c| *c);
+    for f in callbacks.iter().filter_map(|c| *c) {
+        f(old, new);
+    }
+}
+
+/// Drop the ARM clock to its minimum when hot, and restore it when nominal.
+fn throttle(m: &mut Monitor) {
+    match (m.state, m.saved_rate) {
+        (State::Hot, None) => {
+            let (rate, min) = match (
+                clock::get_rate(&mut m.v_mbox, Clock::Arm),
+                clock::get_min_rate(&mut m.v_mbox, Clock::Arm),
+            ) {
+                (Ok(rate), Ok(min)) => (rate, min),
+                _ => {
+                    error!("Could not read the ARM clock rate.");
+                    return;
+                }
+            };
+
+            match clock::set_rate(&mut m.v_mbox, Clock::Arm, min, true) {
+                Ok(got) => {
+                    warn!("ARM clock throttled to {} MHz.", got / 1_000_000);
+                    m.saved_rate = Some(rate);
+                }
+                Err(e) => error!("Could not throttle the ARM clock: {:?}", e),
+            }
+        }
+
+        (State::Nominal, Some(rate)) => {
+            match clock::set_rate(&mut m.v_mbox, Clock::Arm, rate, true) {
+                Ok(got) => info!("ARM clock restored to {} MHz.", got / 1_000_000),
+                Err(e) => error!("Could not restore the ARM clock: {:?}", e),
+            }
+            m.saved_rate = None;
+        }
+
+        _ => (),
+    }
+}
+
+/// The latest reading in millidegrees Celsius, if the watchdog runs.
+#[allow(dead_code)]
+pub fn current() -> Option<u32> {
+    MONITOR.lock(|m| {
+        let m = m.as_ref()?;
+        m.history[(m.next + HISTORY_LEN - 1) % HISTORY_LEN].map(|s| s.millicelsius)
+    })
+}
+
+/// The current state, or `None` if the watchdog does not run.
+pub fn state() -> Option<State> {
+    MONITOR.lock(|m| m.as_ref().map(|m| m.state))
+}
+
+/// The readings, oldest first. Slots without a reading yet are `None`.
+pub fn history() -> [Option<Sample>; HISTORY_LEN] {
+    let mut out = [None; HISTORY_LEN];
+
+    MONITOR.lock(|m| {
+        if let Some(m) = m.as_ref() {
+            for (i, s) in out.iter_mut().enumerate() {
+                *s = m.history[(m.next + i) % HISTORY_LEN];
+            }
+        }
+    });
+
+    out
+}
@@ -0,0 +1,92 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! The kernel tick, a counter incremented every `TICK_US` microseconds.
+//!
+//! The tick is a periodic callback of the `timer` module, so it shares the
+//! drift-free re-arming of the CNTP comparator with all other callbacks. Ticks
+//! only advance while IRQs are unmasked.
+
+use crate::{cpu, event, stack_guard, task, timer};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// Length of a tick in microseconds.
+pub const TICK_US: u64 = 10_000;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+fn on_tick() {
+    TICKS.fetch_add(1, Ordering::Release);
+    stack_guard::check();
+    task::on_tick();
+    event::notify();
+}
+
+/// Start counting ticks.
+///
+/// Returns false if there was no free timer slot. Starting twice is harmless.
+pub fn init() -> bool {
+    if RUNNING.load(Ordering::Acquire) {
+        return true;
+    }
+
+    if timer::schedule_periodic(TICK_US, on_tick).is_none() {
+        return false;
+    }
+
+    RUNNING.store(true, Ordering::Release);
+
+    true
+}
+
+/// Whether `init()` started the tick.
+pub fn is_running() -> bool {
+    RUNNING.load(Ordering::Acquire)
+}
+
+/// Ticks since `init()`.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Acquire)
+}
+
+/// Convert a number of ticks to microseconds.
+pub fn ticks_to_us(ticks: u64) -> u64 {
+    ticks * TICK_US
+}
+
+/// Sleep in `wfe` until the tick count advanced by `n`.
+///
+/// Returns right away if the tick is not running, because nobody would wake
+/// us up.
+pub fn sleep_ticks(n: u64) {
+    if !RUNNING.load(Ordering::Acquire) {
+        return;
+    }
+
+    let start = ticks();
+
+    cpu::irq_unmasked(|| event::wait_for(|| ticks().wrapping_sub(start) >= n));
+}
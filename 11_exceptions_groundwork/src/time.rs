@@ -0,0 +1,243 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Monotonic time on top of the ARM generic timer.
+//!
+//! `CNTPCT_EL0` is a free-running 64 bit counter ticking with the frequency
+//! reported by `CNTFRQ_EL0` (19.2 MHz on the RPi3). This module is the single
+//! place where ticks are converted to and from `core::time::Duration`.
+
+use crate::{devices::hw, memory};
+use core::{
+    fmt, ops,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use cortex_a::{barrier, regs::*};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// A point in time, measured in ticks of the ARM generic timer.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Instant(u64);
+
+impl Instant {
+    /// Read the current value of the counter.
+    pub fn now() -> Instant {
+        // Prevent that the counter is read ahead of preceding instructions.
+        unsafe { barrier::isb(barrier::SY) };
+
+        Instant(CNTPCT_EL0.get())
+    }
+
+    /// Time passed since `earlier`.
+    ///
+    /// The tick difference is computed with wrapping arithmetic, so the result
+    /// stays correct if the counter rolled over in between.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        ticks_to_duration(self.0.wrapping_sub(earlier.0))
+    }
+
+    /// Time passed since this instant was taken.
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    /// The raw counter value.
+    pub fn ticks(&self) -> u64 {
+        self.0
+    }
+}
+
+impl ops::Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        Instant(self.0.wrapping_add(duration_to_ticks(rhs)))
+    }
+}
+
+impl ops::Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+/// Cached value of `CNTFRQ_EL0`, zero until it was read for the first time.
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// The frequency of the counter in Hz.
+///
+/// `CNTFRQ_EL0` is only read on the first call, so this is cheap enough for
+/// tight loops.
+pub fn frequency() -> u64 {
+    let frq = FREQUENCY.load(Ordering::Relaxed);
+    if frq != 0 {
+        return frq;
+    }
+
+    let frq = u64::from(CNTFRQ_EL0.get());
+    FREQUENCY.store(frq, Ordering::Relaxed);
+
+    frq
+}
+
+/// Convert counter ticks into a `Duration`.
+///
+/// Whole seconds and the remainder are converted separately. That way, the
+/// intermediate results can not overflow, and no precision is lost for
+/// frequencies that are not a multiple of 1 MHz.
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    let frq = frequency();
+
+    let secs = ticks / frq;
+    let nanos = (ticks % frq) * NANOS_PER_SEC / frq;
+
+    Duration::new(secs, nanos as u32)
+}
+
+/// Convert a `Duration` into counter ticks.
+///
+/// Rounds up, so that a wait based on the result never ends early. Saturates
+/// at `u64::max_value()` for durations that are too long to be counted.
+pub fn duration_to_ticks(d: Duration) -> u64 {
+    let frq = frequency();
+
+    let sub_sec_ticks =
+        (u64::from(d.subsec_nanos()) * frq + (NANOS_PER_SEC - 1)) / NANOS_PER_SEC;
+
+    d.as_secs()
+        .checked_mul(frq)
+        .and_then(|t| t.checked_add(sub_sec_ticks))
+        .unwrap_or(u64::max_value())
+}
+
+/// Microseconds since boot.
+///
+/// The counter starts at zero when the SoC comes out of reset, so this
+/// includes the time spent in the firmware before the kernel was started.
+pub fn uptime() -> u64 {
+    ticks_to_duration(Instant::now().ticks()).as_micros() as u64
+}
+
+/// A `Duration` that is displayed as `HH:MM:SS.mmm`.
+///
+/// Works without an allocator, e.g. `println!("{}", time::Hms::uptime())`.
+pub struct Hms(pub Duration);
+
+impl Hms {
+    /// The current uptime.
+    pub fn uptime() -> Hms {
+        Hms(Duration::from_micros(uptime()))
+    }
+}
+
+impl fmt::Display for Hms {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let secs = self.0.as_secs();
+        let millis = self.0.subsec_millis();
+
+        write!(
+            f,
+            "{:02}:{:02}:{:02}.{:03}",
+            secs / 3600,
+            (secs / 60) % 60,
+            secs % 60,
+            millis
+        )
+    }
+}
+
+/// Write the current uptime as `HH:MM:SS.mmm` into any `fmt::Write` sink.
+pub fn write_uptime<W: fmt::Write>(w: &mut W) -> fmt::Result {
+    write!(w, "{}", Hms::uptime())
+}
+
+/// A stopwatch for benchmarking code paths, counting in microseconds.
+///
+/// Uses the BCM System Timer. QEMU does not emulate it and reads constant zero
+/// from it, so in that case the ARM generic timer is used instead.
+pub struct Stopwatch {
+    sys_tmr: Option<hw::SysTmr>,
+    start: u64,
+    last_lap: u64,
+}
+
+impl Stopwatch {
+    /// Create and start a stopwatch.
+    pub fn start() -> Stopwatch {
+        let sys_tmr = hw::SysTmr::new(hw::Peripheral::SysTimer);
+        let sys_tmr = if sys_tmr.get_system_timer() != 0 {
+            Some(sys_tmr)
+        } else {
+            None
+        };
+
+        let mut sw = Stopwatch {
+            sys_tmr,
+            start: 0,
+            last_lap: 0,
+        };
+        sw.start = sw.now();
+        sw.last_lap = sw.start;
+
+        sw
+    }
+
+    fn now(&self) -> u64 {
+        match self.sys_tmr {
+            Some(ref sys_tmr) => sys_tmr.get_system_timer(),
+            None => uptime(),
+        }
+    }
+
+    /// Microseconds since the previous lap, or since start for the first one.
+    pub fn lap(&mut self) -> u64 {
+        let now = self.now();
+        let lap = now.wrapping_sub(self.last_lap);
+        self.last_lap = now;
+
+        lap
+    }
+
+    /// Stop the stopwatch and return the microseconds since start.
+    pub fn stop(self) -> u64 {
+        self.now().wrapping_sub(self.start)
+    }
+}
+
+/// Run `f` and return its result together with the microseconds it took.
+///
+/// See also the `measure!` macro, which additionally prints the result.
+pub fn measure<F, R>(f: F) -> (R, u64)
+where
+    F: FnOnce() -> R,
+{
+    let sw = Stopwatch::start();
+    let ret = f();
+
+    (ret, sw.stop())
+}
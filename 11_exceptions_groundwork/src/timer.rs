@@ -0,0 +1,393 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Callbacks driven by the EL1 physical timer (CNTP).
+//!
+//! All callbacks share the single comparator of the core. It is always
+//! programmed via `CNTP_CVAL_EL0` to the earliest deadline of all scheduled
+//! callbacks.
+//!
+//! Periodic callbacks are re-armed by adding their period to the previous
+//! deadline, not to the current time, so that no drift accumulates. If a
+//! deadline was missed completely, e.g. because a callback ran longer than its
+//! period, the missed periods are skipped instead of being queued up. They are
+//! counted in `missed_periods()`.
+//!
+//! For `async` code, `sleep_us()` returns a future. All pending sleeps share a
+//! single one-shot callback for the earliest of their deadlines, which wakes
+//! the tasks that are due and re-arms itself for the next one.
+
+use crate::{cpu::regs::*, interrupt, sync, time};
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU32, Ordering},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+use cortex_a::regs::*;
+
+/// Maximum number of concurrently scheduled callbacks.
+const NUM_SLOTS: usize = 8;
+
+#[derive(Copy, Clone)]
+struct Slot {
+    callback: Option<fn()>,
+
+    /// Period in ticks. Zero for one-shot callbacks.
+    period: u64,
+
+    /// Counter value of the next expiry.
+    deadline: u64,
+
+    /// Incremented on each allocation of the slot, so that stale handles can
+    /// not cancel a newer callback.
+    generation: u32,
+}
+
+const EMPTY_SLOT: Slot = Slot {
+    callback: None,
+    period: 0,
+    deadline: 0,
+    generation: 0,
+};
+
+/// Shared by all cores, and by the IRQ handler. Always taken with
+/// `lock_irqsave()`.
+static SLOTS: sync::SpinLock<[Slot; NUM_SLOTS]> = sync::SpinLock::new([EMPTY_SLOT; NUM_SLOTS]);
+
+static MISSED_PERIODS: AtomicU32 = AtomicU32::new(0);
+
+/// Handle to a scheduled callback, used for cancelling it.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct Handle {
+    slot: usize,
+    generation: u32,
+}
+
+/// Check if `deadline` has been reached at counter value `now`.
+///
+/// Works across a wrap-around of the counter, as long as the two values are
+/// less than 2^63 ticks apart.
+pub fn is_due(deadline: u64, now: u64) -> bool {
+    now.wrapping_sub(deadline) as i64 >= 0
+}
+
+/// Program the comparator for the earliest deadline, or switch the timer off if
+/// nothing is scheduled.
+fn rearm(slots: &[Slot; NUM_SLOTS]) {
+    let now = time::Instant::now().ticks();
+
+    let next = slots
+        .iter()
+        .filter(|s| s.callback.is_some())
+        .map(|s| s.deadline)
+        .min_by_key(|d| d.wrapping_sub(now) as i64);
+
+    match next {
+        // If the deadline already passed, the IRQ fires right away.
+        Some(deadline) => {
+            CNTP_CVAL_EL0.set(deadline);
+            CNTP_CTL_EL0.write(CNTP_CTL_EL0::ENABLE::SET + CNTP_CTL_EL0::IMASK::CLEAR);
+            interrupt::enable_core_timer();
+        }
+
+        None => {
+            CNTP_CTL_EL0.write(CNTP_CTL_EL0::ENABLE::CLEAR + CNTP_CTL_EL0::IMASK::SET);
+            interrupt::disable_core_timer();
+        }
+    }
+}
+
+fn schedule(delay: Duration, period: Duration, callback: fn()) -> Option<Handle> {
+    let delay = time::duration_to_ticks(delay);
+    let period = time::duration_to_ticks(period);
+
+    schedule_ticks(delay, period, callback)
+}
+
+fn schedule_ticks(delay: u64, period: u64, callback: fn()) -> Option<Handle> {
+    SLOTS.lock_irqsave(|slots| {
+        let nr = slots.iter().position(|s| s.callback.is_none())?;
+        let slot = &mut slots[nr];
+
+        slot.callback = Some(callback);
+        slot.period = period;
+        slot.deadline = time::Instant::now().ticks().wrapping_add(delay);
+        slot.generation = slot.generation.wrapping_add(1);
+
+        let handle = Handle {
+            slot: nr,
+            generation: slot.generation,
+        };
+
+        rearm(slots);
+
+        Some(handle)
+    })
+}
+
+/// Call `callback` every `period_us` microseconds from IRQ context.
+///
+/// Returns `None` if the period is zero or all slots are in use.
+pub fn schedule_periodic(period_us: u64, callback: fn()) -> Option<Handle> {
+    if period_us == 0 {
+        return None;
+    }
+
+    let period = Duration::from_micros(period_us);
+
+    schedule(period, period, callback)
+}
+
+/// Call `callback` once from IRQ context after `delay` has passed.
+///
+/// Returns `None` if all slots are in use.
+pub fn schedule_oneshot(delay: Duration, callback: fn()) -> Option<Handle> {
+    schedule(delay, Duration::from_secs(0), callback)
+}
+
+/// Cancel a scheduled callback.
+///
+/// Does nothing if the callback already fired (one-shot) or was cancelled.
+pub fn cancel(handle: Handle) {
+    SLOTS.lock_irqsave(|slots| {
+        let slot = &mut slots[handle.slot];
+
+        if slot.generation == handle.generation {
+            slot.callback = None;
+        }
+
+        rearm(slots);
+    })
+}
+
+/// Number of periods that were skipped because a deadline was missed.
+pub fn missed_periods() -> u32 {
+    MISSED_PERIODS.load(Ordering::Relaxed)
+}
+
+/// Handler for the non-secure physical timer IRQ.
+pub fn irq_handler() {
+    let now = time::Instant::now().ticks();
+
+    for nr in 0..NUM_SLOTS {
+        // Take the callback out of the lock before calling it, so that the
+        // callback itself can schedule or cancel callbacks.
+        let callback = SLOTS.lock_irqsave(|slots| {
+            let slot = &mut slots[nr];
+            let callback = slot.callback?;
+
+            if !is_due(slot.deadline, now) {
+                return None;
+            }
+
+            if slot.period == 0 {
+                slot.callback = None;
+            } else {
+                slot.deadline = slot.deadline.wrapping_add(slot.period);
+
+                if is_due(slot.deadline, now) {
+                    let missed = now.wrapping_sub(slot.deadline) / slot.period + 1;
+
+                    slot.deadline = slot.deadline.wrapping_add(missed * slot.period);
+                    MISSED_PERIODS.fetch_add(missed as u32, Ordering::Relaxed);
+                }
+            }
+
+            Some(callback)
+        });
+
+        if let Some(f) = callback {
+            f();
+        }
+    }
+
+    SLOTS.lock_irqsave(|slots| rearm(slots));
+}
+
+/// Most `sleep_us()` futures that can wait at the same time. Further ones are
+/// polled over and over again instead of being woken.
+const MAX_SLEEPERS: usize = 8;
+
+struct Sleeper {
+    /// Tells a sleeper apart from a later one in the same slot
+    id: u32,
+    deadline: u64,
+    waker: Waker,
+}
+
+struct Sleepers {
+    slots: [Option<Sleeper>; MAX_SLEEPERS],
+    next_id: u32,
+    /// The callback for the earliest deadline, and that deadline
+    armed: Option<(Handle, u64)>,
+}
+
+/// Taken with `lock_irqsave()`, like `SLOTS`. `SLOTS` is taken within, never
+/// the other way round.
+static SLEEPERS: sync::SpinLock<Sleepers> = sync::SpinLock::new(Sleepers {
+    slots: [None, None, None, None, None, None, None, None],
+    next_id: 0,
+    armed: None,
+});
+
+impl Sleepers {
+    /// Store the waker of the sleeper in `slot`, or of a new one. Returns its
+    /// slot and id, or None if all slots are taken.
+    fn register(
+        &mut self,
+        slot: Option<(usize, u32)>,
+        deadline: u64,
+        waker: &Waker,
+    ) -> Option<(usize, u32)> {
+        let (nr, id) = match slot {
+            Some((nr, id)) if self.slots[nr].as_ref().map(|s| s.id) == Some(id) => (nr, id),
+            _ => {
+                let nr = self.slots.iter().position(Option::is_none)?;
+                self.next_id = self.next_id.wrapping_add(1);
+
+                (nr, self.next_id)
+            }
+        };
+
+        self.slots[nr] = Some(Sleeper {
+            id,
+            deadline,
+            waker: waker.clone(),
+        });
+        self.arm();
+
+        Some((nr, id))
+    }
+
+    fn remove(&mut self, (nr, id): (usize, u32)) {
+        if self.slots[nr].as_ref().map(|s| s.id) == Some(id) {
+            self.slots[nr] = None;
+        }
+    }
+
+    /// Make sure that the callback fires for the earliest deadline.
+    fn arm(&mut self) {
+        let now = time::Instant::now().ticks();
+        let earliest = self
+            .slots
+            .iter()
+            .flatten()
+            .map(|s| s.deadline)
+            .min_by_key(|d| d.wrapping_sub(now) as i64);
+
+        let earliest = match (earliest, self.armed) {
+            (None, _) => return,
+            (Some(earliest), Some((_, armed))) if is_due(armed, earliest) => return,
+            (Some(earliest), _) => earliest,
+        };
+
+        if let Some((handle, _)) = self.armed.take() {
+            cancel(handle);
+        }
+
+        let delay = earliest.wrapping_sub(now);
+        match schedule_ticks(if delay as i64 > 0 { delay } else { 0 }, 0, wake_sleepers) {
+            Some(handle) => self.armed = Some((handle, earliest)),
+
+            // No callback slot left, so let them poll instead
+            None => self.wake_all(),
+        }
+    }
+
+    /// Wake and forget all sleepers that are due at `now`.
+    fn wake_due(&mut self, now: u64) {
+        for slot in self.slots.iter_mut() {
+            if slot.as_ref().map_or(false, |s| is_due(s.deadline, now)) {
+                if let Some(s) = slot.take() {
+                    s.waker.wake();
+                }
+            }
+        }
+    }
+
+    fn wake_all(&mut self) {
+        for slot in self.slots.iter_mut() {
+            if let Some(s) = slot.take() {
+                s.waker.wake();
+            }
+        }
+    }
+}
+
+/// The one-shot callback of `Sleepers::arm()`, in IRQ context.
+fn wake_sleepers() {
+    SLEEPERS.lock_irqsave(|s| {
+        s.armed = None;
+        s.wake_due(time::Instant::now().ticks());
+        s.arm();
+    });
+}
+
+/// The future of `sleep_us()`.
+pub struct Sleep {
+    deadline: u64,
+    /// Slot and id in `SLEEPERS`, once it waits there
+    sleeper: Option<(usize, u32)>,
+}
+
+/// Wait for at least `us` microseconds, in `async` code.
+///
+/// Unlike `task::sleep_us()`, the wake up does not wait for the kernel tick.
+pub fn sleep_us(us: u64) -> Sleep {
+    let delay = time::duration_to_ticks(Duration::from_micros(us));
+
+    Sleep {
+        deadline: time::Instant::now().ticks().wrapping_add(delay),
+        sleeper: None,
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if is_due(self.deadline, time::Instant::now().ticks()) {
+            return Poll::Ready(());
+        }
+
+        let (deadline, sleeper) = (self.deadline, self.sleeper);
+        self.sleeper = SLEEPERS.lock_irqsave(|s| s.register(sleeper, deadline, cx.waker()));
+
+        if self.sleeper.is_none() {
+            cx.waker().wake_by_ref();
+        }
+
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(sleeper) = self.sleeper {
+            SLEEPERS.lock_irqsave(|s| s.remove(sleeper));
+        }
+    }
+}
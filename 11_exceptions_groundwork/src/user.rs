@@ -0,0 +1,226 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Small programs at EL0, in an address space of their own.
+//!
+//! `run()` copies a flat binary to a user code page, maps it and a stack page
+//! into the user tables behind TTBR0, and `eret`s to it in EL0t. The kernel
+//! half stays mapped, but EL0 can neither read nor execute it.
+//!
+//! The program talks to the kernel with `svc`, through the same system calls
+//! as the kernel itself, see `syscall`. `exit` and any fault end it: The
+//! exception handler restores the registers that `__user_enter` saved, and
+//! returns into `run()` instead of to the program. That leaves the program's
+//! code and stack behind in place, but nothing on the kernel's side.
+
+use crate::{
+    cache, cpu,
+    exception::{EsrEL1, ExceptionContext},
+    memory::{
+        self,
+        mmu::{self, MapError, UserAccess},
+    },
+    print, println, sync,
+};
+use cortex_a::regs::*;
+
+global_asm!(include_str!("user.S"));
+
+extern "C" {
+    fn __user_enter(entry: usize, sp: usize, spsr: u64, kernel: *mut KernelContext);
+    fn __user_return();
+
+    static __user_hello_start: u8;
+    static __user_hello_end: u8;
+    static __user_crash_start: u8;
+    static __user_crash_end: u8;
+}
+
+const PAGE_SIZE: usize = 4096;
+
+const CODE_ADDR: usize = mmu::USER_START;
+const STACK_ADDR: usize = mmu::USER_END + 1 - PAGE_SIZE;
+
+/// The one address space that user programs run in
+const ASID: u8 = 1;
+
+/// EL0t, with debug exceptions, SErrors and FIQs masked. IRQs stay enabled,
+/// so that the kernel tick keeps going.
+const SPSR_EL0T: u64 = 0b11_0100_0000;
+
+#[repr(C, align(4096))]
+struct Page([u8; PAGE_SIZE]);
+
+static mut CODE: Page = Page([0; PAGE_SIZE]);
+static mut STACK: Page = Page([0; PAGE_SIZE]);
+
+/// What of the kernel's state the program must not clobber, as saved by
+/// `__user_enter`.
+#[repr(C)]
+struct KernelContext {
+    x19_x30: [u64; 12],
+    sp: u64,
+    spsr: u64,
+}
+
+static KERNEL: sync::NullLock<KernelContext> = sync::NullLock::new(KernelContext {
+    x19_x30: [0; 12],
+    sp: 0,
+    spsr: 0,
+});
+
+static OUTCOME: sync::NullLock<Option<Outcome>> = sync::NullLock::new(None);
+
+/// How a user program ended
+#[derive(Copy, Clone, Debug)]
+pub enum Outcome {
+    /// With the `exit` system call and this code
+    Exited(i64),
+    /// With an exception other than a system call
+    Crashed { esr: u64, far: Option<u64>, elr: u64 },
+}
+
+#[derive(Debug)]
+pub enum UserError {
+    /// The program does not fit into the code page
+    TooBig,
+    Map(MapError),
+}
+
+impl From<MapError> for UserError {
+    fn from(e: MapError) -> UserError {
+        UserError::Map(e)
+    }
+}
+
+pub type Result<T> = ::core::result::Result<T, UserError>;
+
+/// Greets on the console, checks that the kernel refuses to print kernel
+/// memory for it, and exits with 0.
+pub fn hello() -> &'static [u8] {
+    unsafe { program(&__user_hello_start, &__user_hello_end) }
+}
+
+/// Writes to its own code, which crashes it with a permission fault.
+pub fn crash() -> &'static [u8] {
+    unsafe { program(&__user_crash_start, &__user_crash_end) }
+}
+
+fn program(start: &'static u8, end: &'static u8) -> &'static [u8] {
+    let len = end as *const u8 as usize - start as *const u8 as usize;
+
+    unsafe { core::slice::from_raw_parts(start, len) }
+}
+
+/// Run the flat binary `program` at EL0 until it exits or crashes.
+///
+/// It starts at its first byte, with all registers zero and the stack
+/// pointer at the top of a 4 KiB stack.
+pub fn run(program: &[u8]) -> Result<Outcome> {
+    if program.len() > PAGE_SIZE {
+        return Err(UserError::TooBig);
+    }
+
+    unsafe {
+        let code = CODE.0.as_mut_ptr() as usize;
+        let stack = STACK.0.as_mut_ptr() as usize;
+
+        CODE.0[..program.len()].copy_from_slice(program);
+        CODE.0[program.len()..].iter_mut().for_each(|b| *b = 0);
+        STACK.0.iter_mut().for_each(|b| *b = 0);
+        cache::clean_dcache_range(code, PAGE_SIZE);
+        cache::invalidate_icache_all();
+
+        mmu::clear_user_space()?;
+        mmu::map_user_page(CODE_ADDR, memory::virt_to_phys(code), UserAccess::ReadExecute)?;
+        mmu::map_user_page(STACK_ADDR, memory::virt_to_phys(stack), UserAccess::ReadWrite)?;
+
+        OUTCOME.lock(|o| *o = None);
+        mmu::enter_user_space(ASID);
+
+        // Back to EL1t, with whatever was masked before
+        let ctx = KERNEL.lock(|k| {
+            k.spsr = u64::from(DAIF.get()) | 0b0100;
+            k as *mut KernelContext
+        });
+        __user_enter(CODE_ADDR, STACK_ADDR + PAGE_SIZE, SPSR_EL0T, ctx);
+
+        mmu::leave_user_space(ASID);
+    }
+
+    // Only an exit or a crash gets back here, and both set the outcome
+    Ok(OUTCOME.lock(|o| o.take()).unwrap())
+}
+
+/// Handle an exception from EL0 that is not a system call for `syscall`.
+///
+/// `exit` ends the program with its code in x0, anything else crashes it.
+/// Either way, the handler returns into `run()`.
+pub fn handle_exception(e: &mut ExceptionContext, esr: EsrEL1) {
+    if esr.is_svc() {
+        let code = e.gpr.x[0] as i64;
+        exit(e, Outcome::Exited(code));
+        return;
+    }
+
+    let far = if esr.far_valid() {
+        Some(cpu::regs::FAR_EL1.get())
+    } else {
+        None
+    };
+
+    print!("user program crashed: {}", esr.ec_name());
+    if esr.is_abort() {
+        match esr.fault_status() {
+            (status, Some(level)) => print!(", {}, level {}", status, level),
+            (status, None) => print!(", {}", status),
+        }
+    }
+    if let Some(far) = far {
+        print!(" at {:#x}", far);
+    }
+    println!(", pc {:#x}", e.elr_el1);
+
+    let outcome = Outcome::Crashed {
+        esr: esr.0,
+        far,
+        elr: e.elr_el1,
+    };
+    exit(e, outcome);
+}
+
+/// Make the exception return of `e` go back to the kernel, to `run()`.
+fn exit(e: &mut ExceptionContext, outcome: Outcome) {
+    OUTCOME.lock(|o| *o = Some(outcome));
+
+    KERNEL.lock(|k| {
+        e.gpr.x[19..].copy_from_slice(&k.x19_x30);
+        e.gpr.x[0] = 0;
+        e.elr_el1 = __user_return as usize as u64;
+        e.spsr_el1 = k.spsr;
+
+        // The handler runs on SP_EL1, so this is the stack that `eret` finds
+        unsafe { asm!("msr SP_EL0, $0" :: "r"(k.sp) :: "volatile") };
+    });
+}
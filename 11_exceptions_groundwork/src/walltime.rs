@@ -0,0 +1,242 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Wall-clock time, from a DS3231 real-time clock.
+//!
+//! `init()` reads the RTC once, and from then on `now()` extrapolates from the
+//! generic timer, so that a timestamp does not cost an I2C transaction. Every
+//! `RESYNC_INTERVAL_US`, the RTC is read again from the work queue, to keep
+//! the counter's drift in check.
+//!
+//! Without an RTC, or if it lost the time, `now()` returns `None`, and the log
+//! and the shell fall back to the uptime.
+
+use crate::{
+    devices::hw::{self, ds3231},
+    sync::NullLock,
+    time, timer, workqueue,
+};
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// How often the RTC is read again
+const RESYNC_INTERVAL_US: u64 = 600_000_000;
+
+/// A date and time in UTC
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    /// 1 to 12
+    pub month: u8,
+    /// 1 to 31
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+pub fn is_leap_year(year: u16) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+pub fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+impl DateTime {
+    /// Whether all fields are in range, including the day for the month.
+    pub fn is_valid(&self) -> bool {
+        self.year >= 1970
+            && (1..=12).contains(&self.month)
+            && self.day >= 1
+            && self.day <= days_in_month(self.year, self.month)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+
+    /// Days since 1970-01-01
+    fn days(&self) -> u64 {
+        let mut days = 0;
+        for year in 1970..self.year {
+            days += if is_leap_year(year) { 366 } else { 365 };
+        }
+        for month in 1..self.month {
+            days += u64::from(days_in_month(self.year, month));
+        }
+
+        days + u64::from(self.day) - 1
+    }
+
+    /// Seconds since 1970-01-01 00:00:00
+    pub fn to_unix(&self) -> u64 {
+        ((self.days() * 24 + u64::from(self.hour)) * 60 + u64::from(self.minute)) * 60
+            + u64::from(self.second)
+    }
+
+    pub fn from_unix(secs: u64) -> DateTime {
+        let mut days = secs / 86_400;
+        let rem = secs % 86_400;
+
+        let mut year = 1970;
+        loop {
+            let len = if is_leap_year(year) { 366 } else { 365 };
+            if days < len {
+                break;
+            }
+            days -= len;
+            year += 1;
+        }
+
+        let mut month = 1;
+        while days >= u64::from(days_in_month(year, month)) {
+            days -= u64::from(days_in_month(year, month));
+            month += 1;
+        }
+
+        DateTime {
+            year,
+            month,
+            day: days as u8 + 1,
+            hour: (rem / 3600) as u8,
+            minute: (rem / 60 % 60) as u8,
+            second: (rem % 60) as u8,
+        }
+    }
+
+    /// Day of the week, 0 being Sunday
+    pub fn weekday(&self) -> u8 {
+        // 1970-01-01 was a Thursday
+        ((self.days() + 4) % 7) as u8
+    }
+}
+
+/// Formats as `YYYY-MM-DD HH:MM:SS`
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// The bus the RTC is on, once `init()` found it
+static RTC: NullLock<Option<hw::I2c>> = NullLock::new(None);
+
+/// Milliseconds since 1970 at `BASE_TICKS`, zero while the time is unknown.
+///
+/// The two are not updated together. A reader that races with a re-sync gets
+/// a timestamp that is off by the drift since the last one, which is fine for
+/// log lines.
+static BASE_MS: AtomicU64 = AtomicU64::new(0);
+static BASE_TICKS: AtomicU64 = AtomicU64::new(0);
+
+fn set_base(dt: &DateTime) {
+    BASE_MS.store(0, Ordering::Relaxed);
+    BASE_TICKS.store(time::Instant::now().ticks(), Ordering::Relaxed);
+    BASE_MS.store(dt.to_unix() * 1000, Ordering::Relaxed);
+}
+
+/// Take over the DS3231 on `i2c`, and read the time from it.
+///
+/// Also starts the periodic re-sync, which needs the timer and the work queue.
+pub fn init(i2c: hw::I2c) -> ds3231::Result<DateTime> {
+    let result = ds3231::read(&i2c);
+
+    if let Ok(dt) = result {
+        set_base(&dt);
+    }
+
+    RTC.lock(|rtc| *rtc = Some(i2c));
+    if timer::schedule_periodic(RESYNC_INTERVAL_US, queue_resync).is_none() {
+        warn!("No timer slot for the RTC re-sync.");
+    }
+
+    result
+}
+
+fn queue_resync() {
+    let _ = workqueue::queue_work(resync, 0);
+}
+
+/// Read the RTC again, and restart the extrapolation from there.
+fn resync(_: usize) {
+    let result = RTC.lock(|rtc| rtc.as_ref().map(ds3231::read));
+
+    match result {
+        Some(Ok(dt)) => {
+            if let Some(ms) = now_ms() {
+                let drift = ms as i64 - (dt.to_unix() * 1000) as i64;
+                debug!("RTC re-sync, counter was {} ms off.", drift);
+            }
+            set_base(&dt);
+        }
+        Some(Err(e)) => warn!("RTC re-sync failed: {:?}", e),
+        None => (),
+    }
+}
+
+/// Set the RTC, and the wall-clock time along with it.
+pub fn set(dt: &DateTime) -> ds3231::Result<()> {
+    RTC.lock(|rtc| match rtc {
+        Some(i2c) => ds3231::set(i2c, dt),
+        None => Err(ds3231::Ds3231Error::NotFound),
+    })?;
+
+    set_base(dt);
+
+    Ok(())
+}
+
+/// Whether `init()` took over an RTC.
+pub fn rtc_present() -> bool {
+    RTC.lock(|rtc| rtc.is_some())
+}
+
+/// Milliseconds since 1970, if the time is known.
+pub fn now_ms() -> Option<u64> {
+    let base_ms = BASE_MS.load(Ordering::Relaxed);
+    if base_ms == 0 {
+        return None;
+    }
+
+    let now = time::Instant::now().ticks();
+    let elapsed = now.saturating_sub(BASE_TICKS.load(Ordering::Relaxed));
+
+    Some(base_ms + time::ticks_to_duration(elapsed).as_millis() as u64)
+}
+
+/// The current date and time, if known.
+pub fn now() -> Option<DateTime> {
+    now_ms().map(|ms| DateTime::from_unix(ms / 1000))
+}
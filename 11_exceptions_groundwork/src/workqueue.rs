@@ -0,0 +1,144 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Deferred work for the bottom halves of IRQ handlers.
+//!
+//! An IRQ handler only acknowledges its device and hands the rest of the job
+//! to `queue_work()`, which is cheap enough for IRQ context on any core. The
+//! queued functions run later with IRQs unmasked, from `run_pending()`. The
+//! kernel calls that whenever a core waits in `task::idle()` or idles in the
+//! scheduler.
+//!
+//! The queue has a fixed size. Work that does not fit is dropped, but
+//! counted, and `run_pending()` warns about it.
+
+use crate::{cpu, event, sync::SpinLock};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+const QUEUE_LEN: usize = 32;
+
+#[derive(Copy, Clone)]
+struct Work {
+    f: fn(usize),
+    arg: usize,
+}
+
+struct Queue {
+    items: [Option<Work>; QUEUE_LEN],
+    head: usize,
+    len: usize,
+}
+
+/// Shared with IRQ handlers, so always taken with `lock_irqsave()`.
+static QUEUE: SpinLock<Queue> = SpinLock::new(Queue {
+    items: [None; QUEUE_LEN],
+    head: 0,
+    len: 0,
+});
+
+/// Work items dropped because the queue was full
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+
+/// `DROPPED` at the time of the last warning
+static DROPPED_REPORTED: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Debug)]
+pub enum WorkqueueError {
+    Full,
+}
+
+pub type Result<T> = ::core::result::Result<T, WorkqueueError>;
+
+/// Queue `f(arg)` to run later, outside of IRQ context.
+///
+/// Items run in the order they were queued. If the queue is full, the item
+/// is dropped and counted in `dropped()`.
+pub fn queue_work(f: fn(usize), arg: usize) -> Result<()> {
+    let queued = QUEUE.lock_irqsave(|q| {
+        if q.len == QUEUE_LEN {
+            return false;
+        }
+
+        q.items[(q.head + q.len) % QUEUE_LEN] = Some(Work { f, arg });
+        q.len += 1;
+
+        true
+    });
+
+    if !queued {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+        return Err(WorkqueueError::Full);
+    }
+
+    // Wake up a core that waits in `task::idle()`
+    event::notify();
+
+    Ok(())
+}
+
+fn pop() -> Option<Work> {
+    QUEUE.lock_irqsave(|q| {
+        if q.len == 0 {
+            return None;
+        }
+
+        let work = q.items[q.head].take();
+        q.head = (q.head + 1) % QUEUE_LEN;
+        q.len -= 1;
+
+        work
+    })
+}
+
+/// Whether no work is queued.
+pub fn is_empty() -> bool {
+    QUEUE.lock_irqsave(|q| q.len == 0)
+}
+
+/// The number of work items that were dropped because the queue was full.
+#[allow(dead_code)]
+pub fn dropped() -> u32 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Run the queued work, including what gets queued meanwhile, with IRQs
+/// unmasked. Returns the number of items that ran.
+///
+/// Must not be called from IRQ context.
+pub fn run_pending() -> usize {
+    let mut ran = 0;
+
+    while let Some(work) = pop() {
+        cpu::irq_unmasked(|| (work.f)(work.arg));
+        ran += 1;
+    }
+
+    let dropped = DROPPED.load(Ordering::Relaxed);
+    let reported = DROPPED_REPORTED.swap(dropped, Ordering::Relaxed);
+    if dropped != reported {
+        warn!("Work queue full, dropped {} items.", dropped.wrapping_sub(reported));
+    }
+
+    ran
+}
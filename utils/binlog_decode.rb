@@ -0,0 +1,151 @@
+#!/usr/bin/env ruby
+#
+# MIT License
+#
+# Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+#
+# Permission is hereby granted, free of charge, to any person obtaining a copy
+# of this software and associated documentation files (the "Software"), to deal
+# in the Software without restriction, including without limitation the rights
+# to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+# copies of the Software, and to permit persons to whom the Software is
+# furnished to do so, subject to the following conditions:
+#
+# The above copyright notice and this permission notice shall be included in all
+# copies or substantial portions of the Software.
+#
+# THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+# IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+# FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+# AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+# LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+# OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+# SOFTWARE.
+#
+
+
+# Host side of the binary log of 11_exceptions_groundwork, see src/binlog.rs
+# there. Decodes the frames that the kernel sends with console=binlog, and
+# passes everything else through as text.
+#
+# Usage: binlog_decode.rb <serial device or capture file>
+#        binlog_decode.rb --self-test
+
+MAGIC = 0xB10C
+DICTIONARY = 0
+HEADER_LEN = 15
+LEVELS = { 1 => 'ERROR', 2 => 'WARN', 3 => 'INFO', 4 => 'DEBUG', 5 => 'TRACE' }.freeze
+
+# The frame of the test kernel's binlog test, for --self-test
+SELF_TEST_FRAME = [0x04, 0x0C, 0xB1, 0x02, 0x05, 0x03, 0x87, 0xD6, 0x12, 0x01,
+                   0x01, 0x01, 0x01, 0x02, 0x05, 0x05, 0x68, 0x69, 0x4A, 0x06]
+                  .pack('C*').freeze
+
+def crc16(data)
+  data.each_byte.reduce(0xFFFF) do |crc, byte|
+    crc ^= byte << 8
+    8.times { crc = crc & 0x8000 != 0 ? ((crc << 1) ^ 0x1021) & 0xFFFF : (crc << 1) & 0xFFFF }
+    crc
+  end
+end
+
+# Returns nil if the frame is not valid COBS
+def cobs_decode(frame)
+  out = ''.b
+  i = 0
+
+  while i < frame.bytesize
+    code = frame.getbyte(i)
+    return nil if code.zero? || i + code > frame.bytesize
+
+    out << frame.byteslice(i + 1, code - 1)
+    i += code
+    out << "\x00".b if code != 0xFF && i < frame.bytesize
+  end
+
+  out
+end
+
+Record = Struct.new(:level, :timestamp_us, :module_id, :payload)
+
+# Returns nil for anything that is not a complete record, e.g. text
+def parse_record(frame)
+  record = cobs_decode(frame)
+  return nil if record.nil? || record.bytesize < HEADER_LEN + 2
+
+  magic, len, level, timestamp_us, module_id = record.unpack('S<S<CQ<S<')
+  return nil if magic != MAGIC || record.bytesize != HEADER_LEN + len + 2
+
+  crc = record.byteslice(HEADER_LEN + len, 2).unpack1('S<')
+  return nil if crc != crc16(record.byteslice(0, HEADER_LEN + len))
+
+  Record.new(level, timestamp_us, module_id, record.byteslice(HEADER_LEN, len))
+end
+
+class Decoder
+  def initialize
+    @modules = {}
+  end
+
+  # Returns the line to print for a chunk between two zero bytes
+  def decode(chunk)
+    record = parse_record(chunk)
+    return chunk.force_encoding('UTF-8').scrub if record.nil?
+
+    if record.level == DICTIONARY
+      @modules[record.module_id] = record.payload.force_encoding('UTF-8').scrub
+      return nil
+    end
+
+    us = record.timestamp_us
+    format("[%4d.%03d] %-5s %s: %s\n",
+           us / 1_000_000, (us / 1000) % 1000,
+           LEVELS.fetch(record.level, record.level.to_s),
+           @modules.fetch(record.module_id, "module #{record.module_id}"),
+           record.payload.force_encoding('UTF-8').scrub)
+  end
+end
+
+def self_test
+  record = parse_record(SELF_TEST_FRAME)
+  abort 'Self-test failed: Frame not decoded' if record.nil?
+
+  expected = Record.new(3, 1_234_567, 5, 'hi'.b)
+  abort "Self-test failed: #{record.inspect}" if record != expected
+  abort 'Self-test failed: CRC check value' if crc16('123456789') != 0x29B1
+
+  corrupted = SELF_TEST_FRAME.dup
+  corrupted.setbyte(16, 0x6A)
+  abort 'Self-test failed: Corrupted frame decoded' unless parse_record(corrupted).nil?
+
+  puts 'Self-test passed.'
+end
+
+if ARGV[0] == '--self-test'
+  self_test
+  exit
+end
+
+abort 'Usage: binlog_decode.rb <serial device or capture file>' if ARGV.length != 1
+
+decoder = Decoder.new
+File.open(ARGV[0], 'rb') do |input|
+  chunk = ''.b
+
+  loop do
+    byte = input.read(1)
+    break if byte.nil?
+
+    if byte != "\x00"
+      chunk << byte
+      next
+    end
+
+    line = decoder.decode(chunk)
+    $stdout.write(line) unless line.nil?
+    $stdout.flush
+    chunk = ''.b
+  end
+
+  $stdout.write(decoder.decode(chunk)) unless chunk.empty?
+end
@@ -0,0 +1,84 @@
+#!/usr/bin/env ruby
+#
+# MIT License
+#
+# Copyright (c) 2018-2019 Andre Richter <andre.o.richter@gmail.com>
+#
+# Permission is hereby granted, free of charge, to any person obtaining a copy
+# of this software and associated documentation files (the "Software"), to deal
+# in the Software without restriction, including without limitation the rights
+# to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+# copies of the Software, and to permit persons to whom the Software is
+# furnished to do so, subject to the following conditions:
+#
+# The above copyright notice and this permission notice shall be included in all
+# copies or substantial portions of the Software.
+#
+# THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+# IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+# FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+# AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+# LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+# OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+# SOFTWARE.
+#
+
+# Host side of the loader's CRC protocol, see 06_raspbootin64/README.md.
+#
+# Usage: raspbootcrc.rb <serial device> <kernel image>
+
+require 'zlib'
+
+MAGIC = 'CRCK'.freeze
+MAX_ATTEMPTS = 3
+
+# The loader repeats three breaks and the 'C' of XMODEM-CRC while waiting
+HANDSHAKE = "\x03\x03\x03C".b.freeze
+
+def wait_for_loader(tty)
+  seen = ''.b
+
+  until seen.end_with?(HANDSHAKE)
+    seen << tty.read(1)
+    seen = seen.byteslice(1..-1) if seen.bytesize > HANDSHAKE.bytesize
+  end
+end
+
+def send_kernel(tty, kernel)
+  size = [kernel.bytesize].pack('V')
+  crc = [Zlib.crc32(kernel)].pack('V')
+
+  MAX_ATTEMPTS.times do |attempt|
+    tty.write(MAGIC + size + crc)
+
+    reply = tty.read(6)
+    abort('The size was not echoed correctly.') unless reply[0, 4] == size
+    abort('The kernel is too big for the loader.') unless reply[4, 2] == 'OK'
+
+    tty.write(kernel)
+    return true if tty.read(2) == 'OK'
+
+    puts "Checksum mismatch in attempt #{attempt + 1}."
+  end
+
+  false
+end
+
+device, image = ARGV
+abort("Usage: #{$PROGRAM_NAME} <serial device> <kernel image>") unless device && image
+
+system("stty -F #{device} 115200 raw -echo") || abort('Could not configure the serial port.')
+kernel = File.binread(image)
+
+File.open(device, 'r+b') do |tty|
+  tty.sync = true
+
+  puts 'Waiting for the loader...'
+  wait_for_loader(tty)
+
+  abort('Giving up.') unless send_kernel(tty, kernel)
+  puts "Sent #{kernel.bytesize} bytes."
+
+  # Show what the new kernel prints
+  loop { $stdout.write(tty.readpartial(1024)) }
+end